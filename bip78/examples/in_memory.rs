@@ -0,0 +1,78 @@
+//! Sender and receiver talking to each other without any network
+//!
+//! The sender pays the official test vector, the receiver contributes the input of the official
+//! proposal and the sender validates the answer. The transport is a function call here, a real
+//! sender POSTs `request.body` to `request.url` and a real receiver gets the body and the query
+//! from its HTTP server. See `payjoin-client` for a sender and a receiver using bitcoind.
+//!
+//! Run with `cargo run --example in_memory --features sender,receiver`.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use bip78::bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bip78::bitcoin::{OutPoint, Transaction};
+use bip78::receiver::{OriginalChecks, PayjoinReceiver, PrevoutStatus, Request, RequestMeta};
+use bip78::sender::PayjoinSender;
+use bip78::testing::{self, MockHeaders};
+
+/// Stands in for the node of the receiver.
+struct Node {
+    locked: Mutex<HashSet<OutPoint>>,
+}
+
+impl OriginalChecks for Node {
+    type Error = std::io::Error;
+
+    fn prevout_status(&self, _: &OutPoint) -> Result<PrevoutStatus, Self::Error> {
+        // `gettxout` in bitcoind
+        Ok(PrevoutStatus::Unspent)
+    }
+
+    fn can_broadcast(&self, _: &Transaction) -> Result<bool, Self::Error> {
+        // `testmempoolaccept` in bitcoind
+        Ok(true)
+    }
+
+    fn lock_inputs(&self, outpoints: &[OutPoint]) -> Result<bool, Self::Error> {
+        let mut locked = self.locked.lock().unwrap();
+        if outpoints.iter().any(|outpoint| locked.contains(outpoint)) {
+            return Ok(false);
+        }
+        locked.extend(outpoints);
+        Ok(true)
+    }
+}
+
+fn main() {
+    // sender
+    let uri = testing::URI.parse::<bip78::Uri>().expect("valid URI");
+    let payee = testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone();
+    let (request, context) = PayjoinSender::new(uri)
+        .psbt(testing::original_psbt())
+        .fee_contribution_rate(2)
+        .build()
+        .expect("valid original PSBT");
+    println!("sender: POST {}", request.url);
+
+    // receiver, the wallet owns the input contributed in the official proposal
+    let vector = bip78::bitcoin::consensus::deserialize::<Psbt>(&base64::decode(testing::PROPOSAL_PSBT).unwrap()).unwrap();
+    let coin = (vector.global.unsigned_tx.input[1].previous_output, vector.inputs[1].clone());
+    let signed = vector.inputs[1].clone();
+    let receiver = PayjoinReceiver::builder()
+        .checks(Node { locked: Mutex::new(HashSet::new()), })
+        .utxo_source(move || Ok::<_, std::io::Error>(vec![coin.clone()]), move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone()))
+        .build();
+    let query = request.url.split_once('?').map_or("", |(_, query)| query);
+    let response = receiver.process(Request {
+        body: &request.body,
+        query,
+        headers: MockHeaders::new(request.body.len() as u64),
+        issued_script: &payee,
+        meta: RequestMeta::default(),
+    });
+    println!("receiver: {} ({} bytes)", response.status, response.body.len());
+
+    // sender again
+    let proposal = context.process_response_bytes(&response.body).expect("the proposal is valid");
+    println!("sender: accepted proposal {} spending {} inputs, sign and broadcast it", proposal.global.unsigned_tx.txid(), proposal.inputs.len());
+}
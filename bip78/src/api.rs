@@ -0,0 +1,501 @@
+//! Serializable types for driving BIP78 from other languages
+//!
+//! This module is available with `json` feature. It contains data transfer objects for each step
+//! of the flow so that you can wrap the library in an HTTP microservice and call it from
+//! non-Rust code. The types only implement `serde` traits, pick any format you like (the field
+//! names are designed for JSON).
+//!
+//! ## Schema stability
+//!
+//! All binary data (PSBTs, transactions) are base64 strings and amounts are integers in
+//! satoshis. Every top-level object has `schemaVersion` field. New optional fields may be added
+//! in the same version, any other change bumps `SCHEMA_VERSION`. Inputs with different version
+//! are rejected so that the caller doesn't silently get a different behavior.
+//!
+//! State that must not leave the process (`sender::Context`, receiver proposals) is not
+//! serializable - keep it in memory between the calls.
+
+/// Version of the schema of all types in this module.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[cfg(any(feature = "sender", feature = "receiver"))]
+fn encode_psbt(psbt: &bitcoin::util::psbt::PartiallySignedTransaction) -> String {
+    base64::encode(bitcoin::consensus::serialize(psbt))
+}
+
+#[cfg(feature = "sender")]
+pub use self::sender::*;
+
+#[cfg(feature = "sender")]
+mod sender {
+    use std::convert::TryFrom;
+    use serde::{Serialize, Deserialize};
+    use crate::sender::{Params, Request, Context, ValidationReport, ValidationError, CreateRequestError};
+    use crate::ErrorCode;
+    use super::{SCHEMA_VERSION, encode_psbt};
+
+    /// Error returned from any step of the sender.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ErrorResponse {
+        pub schema_version: u32,
+        /// Well-known BIP78 error code if available.
+        pub error_code: Option<String>,
+        /// Human-readable message safe to be displayed.
+        pub message: String,
+        /// The other side misbehaved - see `sender::ValidationError::is_protocol_violation()`.
+        pub protocol_violation: bool,
+    }
+
+    impl ErrorResponse {
+        fn new(error_code: Option<ErrorCode>, message: &impl std::fmt::Display, protocol_violation: bool) -> Self {
+            ErrorResponse {
+                schema_version: SCHEMA_VERSION,
+                error_code: error_code.map(|code| code.as_str().to_owned()),
+                message: message.to_string(),
+                protocol_violation,
+            }
+        }
+
+        fn invalid_input(message: &impl std::fmt::Display) -> Self {
+            ErrorResponse::new(None, message, false)
+        }
+    }
+
+    fn check_schema_version(version: u32) -> Result<(), ErrorResponse> {
+        if version == SCHEMA_VERSION {
+            Ok(())
+        } else {
+            Err(ErrorResponse::invalid_input(&format_args!("unsupported schema version {}, expected {}", version, SCHEMA_VERSION)))
+        }
+    }
+
+    /// Parameters of the sender, see `sender::Params`.
+    #[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct SenderParams {
+        /// Maximum fee contribution in satoshis, `None` disables contribution.
+        #[serde(default)]
+        pub max_fee_contribution: Option<u64>,
+        /// Index of the change output, auto-detected if `None`.
+        #[serde(default)]
+        pub change_index: Option<usize>,
+        #[serde(default)]
+        pub clamp_fee_contribution: bool,
+        #[serde(default)]
+        pub disable_output_substitution: bool,
+    }
+
+    impl SenderParams {
+        pub fn to_params(&self) -> Params {
+            let params = match self.max_fee_contribution {
+                Some(max) => Params::default().fee_contribution(bitcoin::Amount::from_sat(max), self.change_index),
+                None => Params::default(),
+            };
+            params
+                .clamp_fee_contribution(self.clamp_fee_contribution)
+                .always_disable_output_substitution(self.disable_output_substitution)
+        }
+    }
+
+    /// Input of the first step of the sender.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase", deny_unknown_fields)]
+    pub struct CreateRequest {
+        pub schema_version: u32,
+        /// BIP21 URI containing `pj` parameter.
+        pub uri: String,
+        /// Signed and finalized original PSBT in base64.
+        pub psbt: String,
+        #[serde(default)]
+        pub params: SenderParams,
+    }
+
+    impl CreateRequest {
+        /// Creates the request to be sent to the receiver.
+        ///
+        /// Keep the returned `Context` to validate the response.
+        pub fn create_request(&self) -> Result<(RequestData, Context), ErrorResponse> {
+            check_schema_version(self.schema_version)?;
+            let uri = crate::Uri::try_from(&*self.uri).map_err(|error| ErrorResponse::invalid_input(&error))?;
+            let psbt = base64::decode(&self.psbt)
+                .map_err(|error| ErrorResponse::invalid_input(&error))
+                .and_then(|bytes| bitcoin::consensus::deserialize(&bytes).map_err(|error| ErrorResponse::invalid_input(&error)))?;
+            let (request, context) = uri.create_request(psbt, self.params.to_params())?;
+            Ok((RequestData::from(&request), context))
+        }
+    }
+
+    /// HTTP request that should be sent to the receiver, see `sender::Request`.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct RequestData {
+        pub schema_version: u32,
+        pub url: String,
+        /// Body of the request - base64-encoded PSBT, send it as `text/plain`.
+        pub body: String,
+        pub protocol_version: u32,
+        /// Timeout of the request in milliseconds.
+        pub timeout_ms: Option<u64>,
+        /// URLs to try if `url` fails, in order of preference.
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        pub fallback_urls: Vec<String>,
+    }
+
+    impl From<&Request> for RequestData {
+        fn from(value: &Request) -> Self {
+            RequestData {
+                schema_version: SCHEMA_VERSION,
+                url: value.url.clone(),
+                body: String::from_utf8(value.body.clone()).expect("body is base64"),
+                protocol_version: value.version.number(),
+                timeout_ms: value.timeout.map(|timeout| timeout.as_millis() as u64),
+                fallback_urls: value.fallback_urls.clone(),
+            }
+        }
+    }
+
+    /// Successfully validated response, see `sender::ValidationReport`.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ValidatedResponse {
+        pub schema_version: u32,
+        /// The PSBT to sign and broadcast in base64.
+        pub psbt: String,
+        pub warnings: Vec<String>,
+    }
+
+    impl From<&ValidationReport> for ValidatedResponse {
+        fn from(value: &ValidationReport) -> Self {
+            ValidatedResponse {
+                schema_version: SCHEMA_VERSION,
+                psbt: encode_psbt(&value.psbt),
+                warnings: value.warnings.iter().map(ToString::to_string).collect(),
+            }
+        }
+    }
+
+    impl From<CreateRequestError> for ErrorResponse {
+        fn from(value: CreateRequestError) -> Self {
+            ErrorResponse::new(None, &value, false)
+        }
+    }
+
+    impl From<ValidationError> for ErrorResponse {
+        fn from(value: ValidationError) -> Self {
+            ErrorResponse::new(value.receiver_error_code(), &value, value.is_protocol_violation())
+        }
+    }
+}
+
+#[cfg(feature = "receiver")]
+pub use self::receiver::*;
+
+#[cfg(feature = "receiver")]
+mod receiver {
+    use serde::{Serialize, Deserialize};
+    use crate::ErrorCode;
+    use crate::receiver::{RequestError, CheckError, ContributionError, Proposal, Accounting, PartyAmounts};
+    use super::{SCHEMA_VERSION, encode_psbt};
+
+    /// Result of a check of the receiver.
+    ///
+    /// `passed == false` means the request was rejected and `response` should be sent to the
+    /// sender.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct CheckResult {
+        pub schema_version: u32,
+        pub passed: bool,
+        pub response: Option<ErrorResponseData>,
+    }
+
+    /// Error response that should be sent to the sender.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ErrorResponseData {
+        pub http_status: u16,
+        pub error_code: String,
+        /// Body of the HTTP response (JSON defined by BIP78).
+        pub body: String,
+        /// Detailed message for the operator, it may differ from the one in the body.
+        pub internal_message: String,
+    }
+
+    impl ErrorResponseData {
+        fn new(code: ErrorCode, body: String, message: &impl std::fmt::Display) -> Self {
+            ErrorResponseData {
+                http_status: if code == ErrorCode::Unavailable { 503 } else { 400 },
+                error_code: code.as_str().to_owned(),
+                body,
+                internal_message: message.to_string(),
+            }
+        }
+    }
+
+    impl CheckResult {
+        pub fn passed() -> Self {
+            CheckResult {
+                schema_version: SCHEMA_VERSION,
+                passed: true,
+                response: None,
+            }
+        }
+
+        /// Converts the result of any check into `CheckResult`, the value is discarded.
+        pub fn from_result<T, E: Into<ErrorResponseData>>(result: Result<T, E>) -> Self {
+            match result {
+                Ok(_) => CheckResult::passed(),
+                Err(error) => CheckResult {
+                    schema_version: SCHEMA_VERSION,
+                    passed: false,
+                    response: Some(error.into()),
+                },
+            }
+        }
+    }
+
+    impl From<RequestError> for ErrorResponseData {
+        fn from(value: RequestError) -> Self {
+            ErrorResponseData::new(value.error_code(), value.to_json(), &value)
+        }
+    }
+
+    impl From<CheckError> for ErrorResponseData {
+        fn from(value: CheckError) -> Self {
+            ErrorResponseData::new(value.error_code(), value.to_json(), &value)
+        }
+    }
+
+    impl From<ContributionError> for ErrorResponseData {
+        fn from(value: ContributionError) -> Self {
+            ErrorResponseData::new(value.error_code(), value.to_json(), &value)
+        }
+    }
+
+    /// Overview of the proposal for logging and auditing.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct ProposalSummary {
+        pub schema_version: u32,
+        /// The proposal PSBT in base64.
+        pub psbt: String,
+        pub txid: String,
+        pub original_txid: String,
+        pub input_count: usize,
+        pub output_count: usize,
+        /// Value moved by each party, missing if the paid script wasn't verified.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pub accounting: Option<AccountingData>,
+        /// The receiver substituted its output, send `SUBSTITUTED_HEADER` with the response.
+        #[serde(default)]
+        pub output_substituted: bool,
+    }
+
+    impl ProposalSummary {
+        /// The txid of the payjoin transaction once `psbt` is fully signed.
+        ///
+        /// `txid` is the id of the unsigned transaction which differs unless all inputs are
+        /// native SegWit. The proposal contains unsigned inputs of the sender so this is `None`
+        /// until the sender signed them and `psbt` was replaced with the signed PSBT. Also `None`
+        /// if `psbt` is not a valid PSBT. See `psbt::final_txid()`.
+        pub fn expected_txid(&self) -> Option<bitcoin::Txid> {
+            let bytes = base64::decode(&self.psbt).ok()?;
+            let psbt = bitcoin::consensus::deserialize(&bytes).ok()?;
+            crate::psbt::final_txid(&psbt).ok()
+        }
+
+        /// Rows for accounting systems, one for each party.
+        ///
+        /// Empty if the summary has no accounting data.
+        pub fn export(&self) -> Vec<AccountingRow> {
+            let accounting = match &self.accounting {
+                Some(accounting) => accounting,
+                None => return Vec::new(),
+            };
+            vec![(Party::Sender, &accounting.sender), (Party::Receiver, &accounting.receiver)]
+                .into_iter()
+                .map(|(party, amounts)| AccountingRow {
+                    txid: self.txid.clone(),
+                    original_txid: self.original_txid.clone(),
+                    party,
+                    amounts: amounts.clone(),
+                })
+                .collect()
+        }
+
+        /// Rows of `export()` as CSV with a header line.
+        pub fn export_csv(&self) -> String {
+            let mut csv = String::from("txid,original_txid,party,gross_sent,net_sent,counterparty_contribution,fee_paid\n");
+            for row in self.export() {
+                let party = match row.party {
+                    Party::Sender => "sender",
+                    Party::Receiver => "receiver",
+                };
+                csv.push_str(&format!("{},{},{},{},{},{},{}\n", row.txid, row.original_txid, party, row.amounts.gross_sent, row.amounts.net_sent, row.amounts.counterparty_contribution, row.amounts.fee_paid));
+            }
+            csv
+        }
+    }
+
+    impl From<&Proposal> for ProposalSummary {
+        fn from(value: &Proposal) -> Self {
+            let tx = &value.psbt().global.unsigned_tx;
+            ProposalSummary {
+                schema_version: SCHEMA_VERSION,
+                psbt: encode_psbt(value.psbt()),
+                txid: tx.txid().to_string(),
+                original_txid: value.original_txid().to_string(),
+                input_count: tx.input.len(),
+                output_count: tx.output.len(),
+                accounting: value.accounting().as_ref().map(AccountingData::from),
+                output_substituted: value.output_substituted(),
+            }
+        }
+    }
+
+    /// Value moved by each party of the proposal, see `receiver::Accounting`.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AccountingData {
+        pub sender: PartyAmountsData,
+        pub receiver: PartyAmountsData,
+    }
+
+    impl From<&Accounting> for AccountingData {
+        fn from(value: &Accounting) -> Self {
+            AccountingData {
+                sender: PartyAmountsData::from(&value.sender),
+                receiver: PartyAmountsData::from(&value.receiver),
+            }
+        }
+    }
+
+    /// Amounts of one party, see `receiver::PartyAmounts`.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct PartyAmountsData {
+        /// Value of the inputs of the party.
+        pub gross_sent: u64,
+        /// Inputs minus outputs of the party, negative for the receiver.
+        pub net_sent: i64,
+        /// Value of the inputs of the other party.
+        pub counterparty_contribution: u64,
+        pub fee_paid: u64,
+    }
+
+    impl From<&PartyAmounts> for PartyAmountsData {
+        fn from(value: &PartyAmounts) -> Self {
+            PartyAmountsData {
+                gross_sent: value.gross_sent.as_sat(),
+                net_sent: value.net_sent.as_sat(),
+                counterparty_contribution: value.counterparty_contribution.as_sat(),
+                fee_paid: value.fee_paid.as_sat(),
+            }
+        }
+    }
+
+    #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub enum Party {
+        Sender,
+        Receiver,
+    }
+
+    /// Row of `ProposalSummary::export()`.
+    #[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    pub struct AccountingRow {
+        pub txid: String,
+        pub original_txid: String,
+        pub party: Party,
+        #[serde(flatten)]
+        pub amounts: PartyAmountsData,
+    }
+}
+
+#[cfg(all(test, any(feature = "sender", feature = "receiver")))]
+mod tests {
+    use super::*;
+
+    #[cfg(feature = "sender")]
+    #[test]
+    fn create_request() {
+        let input = r#"{
+            "schemaVersion": 1,
+            "uri": "$URI",
+            "psbt": "$PSBT",
+            "params": { "maxFeeContribution": 182 }
+        }"#.replace("$URI", crate::testing::URI).replace("$PSBT", crate::testing::ORIGINAL_PSBT);
+        let input = serde_json::from_str::<CreateRequest>(&input).unwrap();
+        let (request, _) = input.create_request().unwrap();
+        assert_eq!(request.url, "https://example.com/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182");
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["protocolVersion"], 1);
+        assert_eq!(json["body"], crate::testing::ORIGINAL_PSBT);
+
+        let input = CreateRequest { schema_version: 2, ..input };
+        let error = input.create_request().err().unwrap();
+        assert_eq!(error.message, "unsupported schema version 2, expected 1");
+        assert!(serde_json::from_str::<CreateRequest>(r#"{"schemaVersion":1,"uri":"","psbt":"","foo":0}"#).is_err());
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn check_result() {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let headers = crate::testing::MockHeaders::new(body.len() as u64);
+        let result = crate::receiver::UncheckedProposal::from_request_bytes(body, "v=2", headers);
+        let json = serde_json::to_value(CheckResult::from_result(result)).unwrap();
+        assert_eq!(json["passed"], false);
+        assert_eq!(json["response"]["httpStatus"], 400);
+        assert_eq!(json["response"]["errorCode"], "version-unsupported");
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn export_accounting() {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let headers = crate::testing::MockHeaders::new(body.len() as u64);
+        let payee = crate::testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone();
+        let proposal = crate::receiver::UncheckedProposal::from_request_bytes(body, "v=1", headers)
+            .unwrap()
+            .check_pays_issued_script(&payee)
+            .unwrap()
+            .this_is_purely_interactive_wallet()
+            .assume_locked();
+        let summary = ProposalSummary::from(&proposal);
+        assert!(!summary.output_substituted);
+        let rows = summary.export();
+        assert_eq!(rows.len(), 2);
+        let json = serde_json::to_value(&rows[1]).unwrap();
+        assert_eq!(json["party"], "receiver");
+        assert_eq!(json["netSent"], -2_000_000);
+        assert_eq!(json["feePaid"], 0);
+        let csv = summary.export_csv();
+        let lines = csv.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], "txid,original_txid,party,gross_sent,net_sent,counterparty_contribution,fee_paid");
+        assert!(lines[1].ends_with(",sender,97983400,2000332,0,332"), "{}", lines[1]);
+        assert!(ProposalSummary { accounting: None, ..summary }.export().is_empty());
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn expected_txid() {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let headers = crate::testing::MockHeaders::new(body.len() as u64);
+        let proposal = crate::receiver::UncheckedProposal::from_request_bytes(body, "v=1", headers)
+            .unwrap()
+            .this_is_purely_interactive_wallet()
+            .assume_locked();
+        let summary = ProposalSummary::from(&proposal);
+        // nothing was contributed so the signed inputs of the sender are still valid
+        let original_tx = crate::testing::original_psbt().extract_tx();
+        assert_eq!(summary.expected_txid(), Some(original_tx.txid()));
+        assert_ne!(summary.txid, original_tx.txid().to_string());
+
+        let unsigned = ProposalSummary { psbt: crate::testing::PROPOSAL_PSBT.to_owned(), ..summary.clone() };
+        assert_eq!(unsigned.expected_txid(), None);
+        assert_eq!(ProposalSummary { psbt: "invalid".to_owned(), ..summary }.expected_txid(), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Transaction conventions of wallets
+//!
+//! Wallets differ in the version, lock time and sequence numbers of their transactions, e.g.
+//! Bitcoin Core sets the lock time to the current height (anti-fee-sniping) and signals RBF. A
+//! payjoin mixing conventions of two wallets is easy to spot, so the receiver funds its inputs
+//! following the conventions of the original transaction.
+
+use bitcoin::Transaction;
+
+/// Lock times below this are block heights, above it UNIX timestamps.
+const LOCK_TIME_THRESHOLD: u32 = 500_000_000;
+/// Sequence numbers below this signal replaceability (BIP125).
+const MAX_RBF_SEQUENCE: u32 = 0xfffffffd;
+/// Inputs with this sequence number don't enforce the lock time.
+const FINAL_SEQUENCE: u32 = 0xffffffff;
+
+/// Version, lock time and sequence number used by the wallet that created a transaction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TxConventions {
+    pub version: i32,
+    pub lock_time: u32,
+    /// Sequence number of the first input, BIP78 requires all inputs to use it.
+    pub sequence: u32,
+}
+
+impl TxConventions {
+    /// Reads the conventions from `transaction`, `None` if it has no inputs.
+    pub fn from_transaction(transaction: &Transaction) -> Option<Self> {
+        Some(TxConventions {
+            version: transaction.version,
+            lock_time: transaction.lock_time,
+            sequence: transaction.input.first()?.sequence,
+        })
+    }
+
+    /// Returns `true` if the transaction signals replaceability, pass this as `replaceable` when
+    /// funding with `walletcreatefundedpsbt`.
+    pub fn signals_rbf(&self) -> bool {
+        self.sequence <= MAX_RBF_SEQUENCE
+    }
+
+    /// Returns `true` if the lock time is non-zero and enforced by the sequence number.
+    pub fn enforces_lock_time(&self) -> bool {
+        self.lock_time != 0 && self.sequence != FINAL_SEQUENCE
+    }
+
+    /// Returns `true` if the lock time looks like anti-fee-sniping - a block height close to the
+    /// current one.
+    ///
+    /// Bitcoin Core sometimes sets the lock time up to 100 blocks back.
+    pub fn anti_fee_sniping(&self, current_height: u32) -> bool {
+        self.enforces_lock_time()
+            && self.lock_time < LOCK_TIME_THRESHOLD
+            && self.lock_time <= current_height
+            && current_height - self.lock_time <= 100
+    }
+
+    /// Returns `true` if `transaction` extending `original` follows these conventions.
+    ///
+    /// The version and lock time must be equal, the inputs added to `original` must signal RBF if
+    /// and only if any input of `original` does and the lock time must stay enforced if it was.
+    /// The inputs of `original` aren't compared with each other, wallets may mix their sequence
+    /// numbers.
+    pub fn preserved_by(&self, original: &Transaction, transaction: &Transaction) -> bool {
+        let original_signals_rbf = original.input.iter().any(|input| input.sequence <= MAX_RBF_SEQUENCE);
+        transaction.version == self.version
+            && transaction.lock_time == self.lock_time
+            && transaction.input
+                .iter()
+                .filter(|input| !original.input.iter().any(|original| original.previous_output == input.previous_output))
+                .all(|input| (input.sequence <= MAX_RBF_SEQUENCE) == original_signals_rbf)
+            && (!self.enforces_lock_time() || transaction.input.iter().any(|input| input.sequence != FINAL_SEQUENCE))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Transaction spending outpoints with vouts 0, 1, ... and given sequence numbers.
+    fn transaction(lock_time: u32, sequences: &[u32]) -> Transaction {
+        let input = sequences
+            .iter()
+            .enumerate()
+            .map(|(vout, &sequence)| {
+                let previous_output = bitcoin::OutPoint { vout: vout as u32, ..Default::default() };
+                bitcoin::TxIn { previous_output, script_sig: Default::default(), sequence, witness: Vec::new(), }
+            })
+            .collect();
+        Transaction { version: 2, lock_time, input, output: Vec::new(), }
+    }
+
+    #[test]
+    fn conventions() {
+        let original = transaction(700_000, &[0xfffffffd]);
+        let conventions = TxConventions::from_transaction(&original).unwrap();
+        assert!(conventions.signals_rbf());
+        assert!(conventions.anti_fee_sniping(700_050));
+        assert!(!conventions.anti_fee_sniping(699_999));
+        assert!(!conventions.anti_fee_sniping(700_101));
+        assert!(conventions.preserved_by(&original, &transaction(700_000, &[0xfffffffd, 0xfffffffd])));
+        assert!(!conventions.preserved_by(&original, &transaction(700_000, &[0xfffffffd, 0xfffffffe])));
+        assert!(!conventions.preserved_by(&original, &transaction(0, &[0xfffffffd, 0xfffffffd])));
+
+        let conventions = TxConventions::from_transaction(&transaction(700_000, &[0xffffffff])).unwrap();
+        assert!(!conventions.enforces_lock_time());
+        assert!(!conventions.anti_fee_sniping(700_000));
+        assert!(TxConventions::from_transaction(&transaction(0, &[])).is_none());
+    }
+
+    #[test]
+    fn mixed_sequences() {
+        // only one input signals RBF so the whole transaction does
+        for original in [transaction(700_000, &[0xfffffffd, 0xffffffff]), transaction(700_000, &[0xffffffff, 0xfffffffd])] {
+            let conventions = TxConventions::from_transaction(&original).unwrap();
+            let proposal = |added| {
+                let mut proposal = transaction(700_000, &[0, 0, added]);
+                proposal.input[..2].clone_from_slice(&original.input);
+                proposal
+            };
+            assert!(conventions.preserved_by(&original, &original));
+            assert!(conventions.preserved_by(&original, &proposal(0xfffffffd)));
+            assert!(!conventions.preserved_by(&original, &proposal(0xfffffffe)));
+        }
+        let original = transaction(700_000, &[0xfffffffe, 0xffffffff]);
+        let conventions = TxConventions::from_transaction(&original).unwrap();
+        assert!(conventions.preserved_by(&original, &transaction(700_000, &[0xfffffffe, 0xffffffff, 0xffffffff])));
+        assert!(!conventions.preserved_by(&original, &transaction(700_000, &[0xfffffffe, 0xffffffff, 0xfffffffd])));
+    }
+}
@@ -0,0 +1,58 @@
+use std::fmt;
+
+/// Well-known error codes defined by BIP78.
+///
+/// The receiver sends these in the `errorCode` field of JSON response.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The payjoin endpoint is not available for now.
+    Unavailable,
+    /// The receiver added some inputs but could not bump the fee of the payjoin proposal.
+    NotEnoughMoney,
+    /// This version of payjoin is not supported.
+    VersionUnsupported,
+    /// The receiver rejected the original PSBT.
+    OriginalPsbtRejected,
+}
+
+impl ErrorCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Unavailable => "unavailable",
+            ErrorCode::NotEnoughMoney => "not-enough-money",
+            ErrorCode::VersionUnsupported => "version-unsupported",
+            ErrorCode::OriginalPsbtRejected => "original-psbt-rejected",
+        }
+    }
+
+    /// Returns the well-known code or `None` if the code is unknown.
+    pub fn parse(code: &str) -> Option<Self> {
+        match code {
+            "unavailable" => Some(ErrorCode::Unavailable),
+            "not-enough-money" => Some(ErrorCode::NotEnoughMoney),
+            "version-unsupported" => Some(ErrorCode::VersionUnsupported),
+            "original-psbt-rejected" => Some(ErrorCode::OriginalPsbtRejected),
+            _ => None,
+        }
+    }
+
+    /// Returns the description of the error that is safe to show to the user.
+    ///
+    /// The message sent by the receiver should not be displayed since a malicious receiver
+    /// could use it to trick the user.
+    pub fn description(&self) -> &'static str {
+        match self {
+            ErrorCode::Unavailable => "the payjoin endpoint is not available for now",
+            ErrorCode::NotEnoughMoney => "the receiver added some inputs but could not bump the fee of the payjoin proposal",
+            ErrorCode::VersionUnsupported => "this version of payjoin is not supported",
+            ErrorCode::OriginalPsbtRejected => "the receiver rejected the original PSBT",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
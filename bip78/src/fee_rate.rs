@@ -1,24 +1,32 @@
 use crate::weight::Weight;
+use std::convert::TryFrom;
 use std::ops::{Mul, Div};
 
+/// Fee rate in satoshis per 1000 weight units
+///
+/// Satoshis per weight unit are too coarse: common rates like 2 sat/vB would round down to zero.
+/// The rates may come from the other party so all arithmetic saturates instead of overflowing.
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Debug, Hash)]
 pub(crate) struct FeeRate(u64);
 
 impl FeeRate {
+    /// The rate at which a 1 vB transaction would pay `MAX_MONEY`, higher rates can't be paid.
+    pub(crate) const MAX: FeeRate = FeeRate(crate::psbt::MAX_MONEY * 250);
+
     pub(crate) fn from_sat_per_vb(rate: u64) -> Self {
-        FeeRate(rate * 4)
+        FeeRate(rate.saturating_mul(250))
     }
 
-    pub(crate) fn from_sat_per_wu(rate: u64) -> Self {
+    pub(crate) fn from_sat_per_kwu(rate: u64) -> Self {
         FeeRate(rate)
     }
 
-    pub(crate) fn to_sat_per_vb(self) -> u64 {
-        self.0 * 4
+    pub(crate) fn to_sat_per_kwu(self) -> u64 {
+        self.0
     }
 
-    pub(crate) fn to_sat_per_wu(self) -> u64 {
-        self.0
+    pub(crate) fn to_sat_per_vb(self) -> u64 {
+        self.0 / 250
     }
 }
 
@@ -28,7 +36,8 @@ impl Mul<Weight> for FeeRate {
     type Output = bitcoin::Amount;
 
     fn mul(self, rhs: Weight) -> Self::Output {
-        bitcoin::Amount::from_sat(self.0 * u64::from(rhs))
+        let fee = u128::from(self.0) * u128::from(u64::from(rhs)) / 1000;
+        bitcoin::Amount::from_sat(u64::try_from(fee).unwrap_or(u64::MAX))
     }
 }
 
@@ -36,7 +45,7 @@ impl Mul<u64> for FeeRate {
     type Output = Self;
 
     fn mul(self, rhs: u64) -> Self::Output {
-        FeeRate(self.0 * rhs)
+        FeeRate(self.0.saturating_mul(rhs))
     }
 }
 
@@ -44,7 +53,7 @@ impl Mul<FeeRate> for u64 {
     type Output = FeeRate;
 
     fn mul(self, rhs: FeeRate) -> Self::Output {
-        FeeRate(self * rhs.0)
+        FeeRate(self.saturating_mul(rhs.0))
     }
 }
 
@@ -55,3 +64,31 @@ impl Div<u64> for FeeRate {
         FeeRate(self.0 / rhs)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::FeeRate;
+    use crate::weight::Weight;
+    use bitcoin::Amount;
+
+    #[test]
+    fn saturates() {
+        assert_eq!(FeeRate::from_sat_per_vb(2).to_sat_per_kwu(), 500);
+        assert_eq!(FeeRate::from_sat_per_vb(u64::MAX / 250).to_sat_per_kwu(), u64::MAX / 250 * 250);
+        assert_eq!(FeeRate::from_sat_per_vb(u64::MAX / 250 + 1).to_sat_per_kwu(), u64::MAX);
+        assert_eq!((FeeRate::from_sat_per_kwu(u64::MAX / 2) * 2).to_sat_per_kwu(), u64::MAX - 1);
+        assert_eq!((FeeRate::from_sat_per_kwu(u64::MAX / 2 + 1) * 2).to_sat_per_kwu(), u64::MAX);
+        assert_eq!((3 * FeeRate::from_sat_per_kwu(u64::MAX / 2)).to_sat_per_kwu(), u64::MAX);
+    }
+
+    #[test]
+    fn fee() {
+        let weight = Weight::from_witness_data_size(1000);
+        assert_eq!(FeeRate::from_sat_per_vb(2) * weight, Amount::from_sat(500));
+        assert_eq!(FeeRate::from_sat_per_kwu(u64::MAX) * weight, Amount::from_sat(u64::MAX));
+        assert_eq!(FeeRate::from_sat_per_kwu(u64::MAX) * Weight::from_witness_data_size(1001), Amount::from_sat(u64::MAX));
+        // the result of the saturating division doesn't overflow either
+        let rate = Amount::from_sat(u64::MAX) / Weight::from_witness_data_size(1);
+        assert_eq!(rate * Weight::from_witness_data_size(2000), Amount::from_sat(u64::MAX));
+    }
+}
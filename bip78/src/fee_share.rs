@@ -0,0 +1,89 @@
+//! Splitting the fee of the contributed inputs between the parties
+//!
+//! BIP78 lets the sender pay for the inputs of the receiver up to `maxadditionalfeecontribution`
+//! but says nothing about how much of it the receiver should take. Some receivers want to share
+//! the fee proportionally instead, e.g. pay half of the fee of their inputs. `FeeShare` is the
+//! agreed proportion in parts per million. The receiver uses it when contributing
+//! (`ReceiverOptions::fee_share()`) and the sender can check the receiver stuck to it
+//! (`Params::fee_share()`). Both compute the split with integer arithmetic rounding the part of
+//! the sender down so they arrive at identical numbers.
+
+use std::fmt;
+use bitcoin::Amount;
+
+/// One million parts - the whole fee.
+const PPM: u64 = 1_000_000;
+
+/// Part of the fee of the contributed inputs paid by the sender.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub struct FeeShare {
+    sender_ppm: u32,
+}
+
+impl FeeShare {
+    /// The sender pays the whole fee of the contributed inputs (as far as it allows), this is
+    /// the default.
+    pub const SENDER_PAYS: FeeShare = FeeShare { sender_ppm: PPM as u32, };
+    /// The receiver pays the whole fee of its inputs.
+    pub const RECEIVER_PAYS: FeeShare = FeeShare { sender_ppm: 0, };
+
+    /// The sender pays `sender_ppm` parts per million of the fee, `None` if it exceeds a million.
+    pub fn from_ppm(sender_ppm: u32) -> Option<Self> {
+        if u64::from(sender_ppm) <= PPM {
+            Some(FeeShare { sender_ppm, })
+        } else {
+            None
+        }
+    }
+
+    /// The sender pays `sender_percent` percent of the fee, `None` if it exceeds 100.
+    pub fn from_percent(sender_percent: u8) -> Option<Self> {
+        FeeShare::from_ppm(u32::from(sender_percent) * 10_000)
+    }
+
+    /// Parts per million of the fee paid by the sender.
+    pub fn sender_ppm(&self) -> u32 {
+        self.sender_ppm
+    }
+
+    /// Part of `fee` paid by the sender, rounded down.
+    pub fn sender_part(&self, fee: Amount) -> Amount {
+        let part = u128::from(fee.as_sat()) * u128::from(self.sender_ppm) / u128::from(PPM);
+        Amount::from_sat(part as u64)
+    }
+
+    /// Splits `fee` into the parts of the sender and the receiver.
+    pub fn split(&self, fee: Amount) -> (Amount, Amount) {
+        let sender = self.sender_part(fee);
+        (sender, fee - sender)
+    }
+}
+
+impl Default for FeeShare {
+    fn default() -> Self {
+        FeeShare::SENDER_PAYS
+    }
+}
+
+impl fmt::Display for FeeShare {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ppm", self.sender_ppm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        let half = FeeShare::from_percent(50).unwrap();
+        assert_eq!(half.split(Amount::from_sat(183)), (Amount::from_sat(91), Amount::from_sat(92)));
+        assert_eq!(FeeShare::from_ppm(333_333).unwrap().sender_part(Amount::from_sat(3)), Amount::ZERO);
+        assert_eq!(FeeShare::SENDER_PAYS.split(Amount::from_sat(182)), (Amount::from_sat(182), Amount::ZERO));
+        assert_eq!(FeeShare::RECEIVER_PAYS.split(Amount::from_sat(182)), (Amount::ZERO, Amount::from_sat(182)));
+        assert_eq!(FeeShare::SENDER_PAYS.sender_part(Amount::from_sat(u64::MAX)), Amount::from_sat(u64::MAX));
+        assert!(FeeShare::from_ppm(1_000_001).is_none());
+        assert!(FeeShare::from_percent(101).is_none());
+    }
+}
@@ -1,6 +1,7 @@
 use std::fmt;
 use std::convert::{TryFrom, TryInto};
 use bitcoin::blockdata::script::{Script, Instructions, Instruction};
+use bitcoin::blockdata::opcodes::{self, Class};
 use bitcoin::blockdata::transaction::TxOut;
 use bitcoin::util::psbt::Input as PsbtInput;
 
@@ -12,6 +13,45 @@ fn unpack_p2sh(script_sig: &Script) -> Option<Script> {
     }
 }
 
+/// Returns the witness script of P2WSH input, preferring the finalized one.
+fn witness_script(txin: &PsbtInput) -> Option<Script> {
+    match &txin.final_script_witness {
+        Some(witness) => witness.last().map(|script| Script::from(script.clone())),
+        None => txin.witness_script.clone(),
+    }
+}
+
+fn push_num(instruction: Option<Result<Instruction, bitcoin::blockdata::script::Error>>) -> Option<u8> {
+    match instruction?.ok()? {
+        Instruction::Op(op) => match op.classify() {
+            Class::PushNum(num) if num > 0 => Some(num as u8),
+            _ => None,
+        },
+        Instruction::PushBytes(_) => None,
+    }
+}
+
+/// Parses `OP_m <key>... OP_n OP_CHECKMULTISIG` returning `(m, n)`.
+fn parse_multisig(script: &Script) -> Option<(u8, u8)> {
+    let mut instructions = script.instructions();
+    let required = push_num(instructions.next())?;
+    let mut keys = 0usize;
+    let total = loop {
+        match instructions.next()?.ok()? {
+            Instruction::PushBytes(key) if key.len() == 33 || key.len() == 65 => keys += 1,
+            instruction => break push_num(Some(Ok(instruction)))?,
+        }
+    };
+    match instructions.next()?.ok()? {
+        Instruction::Op(opcodes::all::OP_CHECKMULTISIG) => (),
+        _ => return None,
+    }
+    if instructions.next().is_some() || keys != usize::from(total) || required > total {
+        return None;
+    }
+    Some((required, total))
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(crate) enum InputType {
     P2Pk,
@@ -23,6 +63,30 @@ pub(crate) enum InputType {
 
 impl InputType {
     pub(crate) fn from_spent_input(txout: &TxOut, txin: &PsbtInput) -> Result<Self, InputTypeError> {
+        match Self::from_spent_input_script(txout, txin)? {
+            InputType::SegWitV0 { ty: SegWitV0Type::Script, nested } => {
+                let ty = witness_script(txin)
+                    .and_then(|script| parse_multisig(&script))
+                    .map(|(required, total)| SegWitV0Type::Multisig { required, total, })
+                    .unwrap_or(SegWitV0Type::Script);
+                Ok(InputType::SegWitV0 { ty, nested, })
+            },
+            input_type => Ok(input_type),
+        }
+    }
+
+    /// Returns the type ignoring multisig parameters.
+    ///
+    /// BIP78 requires the inputs to have the same script type, the exact script doesn't matter.
+    #[cfg(feature = "sender")]
+    pub(crate) fn script_type(&self) -> Self {
+        match self {
+            InputType::SegWitV0 { ty: SegWitV0Type::Multisig { .. }, nested } => InputType::SegWitV0 { ty: SegWitV0Type::Script, nested: *nested, },
+            input_type => *input_type,
+        }
+    }
+
+    fn from_spent_input_script(txout: &TxOut, txin: &PsbtInput) -> Result<Self, InputTypeError> {
         if txout.script_pubkey.is_p2pk() {
             Ok(InputType::P2Pk)
         } else if txout.script_pubkey.is_p2pkh() {
@@ -54,24 +118,38 @@ impl InputType {
         }
     }
 
-    pub(crate) fn expected_input_weight(&self) -> crate::weight::Weight {
+    /// Returns the weight of a typical input of this type.
+    ///
+    /// Returns `None` if the weight depends on the script which can't be known in advance.
+    pub(crate) fn expected_input_weight(&self) -> Option<crate::weight::Weight> {
         use InputType::*;
 
-        crate::weight::Weight::from_non_witness_data_size(match self {
-            P2Pk => unimplemented!(),
+        let size = match self {
+            P2Pk => 114,
             P2Pkh => 148,
-            P2Sh => unimplemented!(),
+            P2Sh => return None,
             SegWitV0 { ty: SegWitV0Type::Pubkey, nested: false } => 68,
             SegWitV0 { ty: SegWitV0Type::Pubkey, nested: true } => 91,
-            SegWitV0 { ty: SegWitV0Type::Script, nested: _ } => unimplemented!(),
-            Taproot => unimplemented!(),
-        })
+            SegWitV0 { ty: SegWitV0Type::Multisig { required, total, }, nested } => {
+                // empty item for CHECKMULTISIG bug, signatures and the script
+                let script_size = 3 + 34 * u64::from(*total);
+                let witness_size = crate::weight::varint_size(u64::from(*required) + 2) + 1 + 73 * u64::from(*required) + crate::weight::varint_size(script_size) + script_size;
+                // outpoint, sequence and script_sig pushing the witness program
+                let non_witness_size = if *nested { 41 + 35 } else { 41 };
+                return Some(crate::weight::Weight::from_non_witness_data_size(non_witness_size) + crate::weight::Weight::from_witness_data_size(witness_size));
+            },
+            SegWitV0 { ty: SegWitV0Type::Script, nested: _ } => return None,
+            Taproot => return None,
+        };
+        Some(crate::weight::Weight::from_non_witness_data_size(size))
     }
 }
 
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub(crate) enum SegWitV0Type {
     Pubkey,
+    /// Bare multisig script, parameters are only known for spent inputs.
+    Multisig { required: u8, total: u8, },
     Script,
 }
 
@@ -172,4 +250,46 @@ mod tests {
         let input_type = InputType::from_spent_input(&TxOut { script_pubkey: Script::new_p2sh(&segwit_script_hash), value: 42, }, &PsbtInput { final_script_sig: Some(script_sig), ..Default::default() }).unwrap();
         assert_eq!(input_type, InputType::SegWitV0 { ty: SegWitV0Type::Script, nested: true, });
     }
+
+    fn multisig_input(required: u8, total: u8, nested: bool) -> (TxOut, PsbtInput) {
+        let mut builder = bitcoin::blockdata::script::Builder::new().push_int(required.into());
+        for i in 0..total {
+            builder = builder.push_slice(&[2 + i % 2; 33]);
+        }
+        let script = builder.push_int(total.into()).push_opcode(opcodes::all::OP_CHECKMULTISIG).into_script();
+        let mut witness = vec![Vec::new()];
+        witness.extend((0..required).map(|_| vec![0x30; 72]));
+        witness.push(script.to_bytes());
+        let segwit_script = Script::new_v0_wsh(&script.wscript_hash());
+        let (script_pubkey, final_script_sig) = if nested {
+            (Script::new_p2sh(&segwit_script.script_hash()), Some(wrap_p2sh_script(&segwit_script)))
+        } else {
+            (segwit_script, None)
+        };
+        (TxOut { script_pubkey, value: 42, }, PsbtInput { final_script_sig, final_script_witness: Some(witness), ..Default::default() })
+    }
+
+    #[test]
+    fn test_p2wsh_multisig() {
+        let (txout, psbtin) = multisig_input(2, 3, false);
+        let input_type = InputType::from_spent_input(&txout, &psbtin).unwrap();
+        assert_eq!(input_type, InputType::SegWitV0 { ty: SegWitV0Type::Multisig { required: 2, total: 3, }, nested: false, });
+        assert_eq!(input_type.script_type(), InputType::SegWitV0 { ty: SegWitV0Type::Script, nested: false, });
+        let tx_weight = |psbtin: &PsbtInput| bitcoin::Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![bitcoin::TxIn { previous_output: Default::default(), script_sig: psbtin.final_script_sig.clone().unwrap_or_default(), sequence: 0, witness: psbtin.final_script_witness.clone().unwrap(), }],
+            output: Vec::new(),
+        }.get_weight() as u64;
+        // the weight of the transaction without the input is 4 * 10 and 2 for segwit marker
+        assert_eq!(u64::from(input_type.expected_input_weight().unwrap()), tx_weight(&psbtin) - 42);
+
+        let (txout, psbtin) = multisig_input(1, 2, true);
+        let input_type = InputType::from_spent_input(&txout, &psbtin).unwrap();
+        assert_eq!(input_type, InputType::SegWitV0 { ty: SegWitV0Type::Multisig { required: 1, total: 2, }, nested: true, });
+        assert_eq!(u64::from(input_type.expected_input_weight().unwrap()), tx_weight(&psbtin) - 42);
+
+        let psbtin = PsbtInput { final_script_witness: None, witness_script: Some(Script::new_op_return(&[42])), ..psbtin };
+        assert_eq!(InputType::from_spent_input(&txout, &psbtin).unwrap().expected_input_weight(), None);
+    }
 }
@@ -12,10 +12,14 @@
 //! 
 //! Additional advantage is it doesn't care whether you use `async`, blocking, `tokio`, `sync-std` `hyper`, `actix` or whatever.
 //! There are already too many frameworks in Rust so it's best avoiding directly introducing them into library code.
-//! The library currently only contains sender implementation but I want to add receiver too.
+//! The library contains both sender and receiver implementations.
 //!
 //! To use this library as a sender (client, payer), you need to enable `sender` Cargo feature.
-//! To use this library as a receiver (server, payee), you need to implement it and send a PR.
+//! To use this library as a receiver (server, payee), you need to enable `receiver` Cargo feature
+//! and build a `receiver::PayjoinReceiver` which processes the requests, or drive the individual
+//! steps starting with `receiver::UncheckedProposal` if you need more control.
+//! To drive the flow from other languages (e.g. via an HTTP microservice), enable `json` feature
+//! and use the types in `api` module.
 
 pub extern crate bitcoin;
 
@@ -23,11 +27,38 @@ pub extern crate bitcoin;
 pub mod sender;
 #[cfg(feature = "receiver")]
 pub mod receiver;
+#[doc(hidden)]
+pub mod testing;
+pub mod time;
+pub mod psbt;
+#[cfg(feature = "json")]
+pub mod api;
+#[cfg(feature = "nostr")]
+pub mod nostr;
 
 pub(crate) mod input_type;
+pub(crate) mod output_type;
 mod uri;
+mod pin;
+mod conventions;
+mod fee_share;
+mod limits;
+mod version;
+mod error_code;
 pub(crate) mod weight;
 pub(crate) mod fee_rate;
-pub(crate) mod psbt;
 
-pub use uri::{Uri, ParseUriError, Bip21Error, PjParseError};
+pub use uri::{Uri, PjExtras, ParseUriError, Bip21Error, PjParseError};
+pub use pin::{CertificatePin, ParsePinError};
+pub use conventions::TxConventions;
+pub use fee_share::FeeShare;
+pub use limits::Limits;
+pub use version::ProtocolVersion;
+pub use error_code::ErrorCode;
+
+/// Response header of the receiver announcing that it substituted its output.
+///
+/// The value is `1` if the output was substituted. The header is informational only, e.g. for
+/// showing the new address in the UI; the sender validates the proposal PSBT regardless of it.
+/// See `receiver::Proposal::output_substituted()`.
+pub const SUBSTITUTED_HEADER: &str = "X-Payjoin-Substituted";
@@ -0,0 +1,103 @@
+//! Bounds of resources spent on processing untrusted data
+//!
+//! Both parties parse data controlled by the other one - the receiver the request of anyone who
+//! knows the link, the sender the response of the receiver. All the bounds are collected in
+//! `Limits` so that they can be reviewed and tuned in one place. The defaults are derived from the
+//! consensus limits so that they never reject a valid transaction.
+
+use std::fmt;
+use bitcoin::Transaction;
+
+/// Maximum weight of a block, no transaction can be heavier.
+const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+/// Outpoint, empty script and sequence - the smallest possible input.
+const MIN_INPUT_WEIGHT: usize = 4 * (36 + 1 + 4);
+/// Value and empty script - the smallest possible output.
+const MIN_OUTPUT_WEIGHT: usize = 4 * (8 + 1);
+
+/// Bounds enforced by the sender and the receiver.
+///
+/// Used by `UncheckedProposal::from_request_with_limits()`, `Params::limits()` (enforced by
+/// `Context::process_response()` and friends) and the HTTP helpers via `Request::max_response_size`.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// Maximum size of the base64-encoded PSBT, also caps `Content-Length` of requests.
+    ///
+    /// Defaults to the size of a transaction as large as a block with the encoding overhead.
+    pub max_psbt_size: u64,
+    /// Maximum number of inputs of the transaction.
+    pub max_inputs: usize,
+    /// Maximum number of outputs of the transaction.
+    pub max_outputs: usize,
+    /// Maximum length of the URL of the request including the query.
+    ///
+    /// Defaults to 2048 which most HTTP servers and proxies accept.
+    pub max_url_length: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_psbt_size: MAX_BLOCK_WEIGHT as u64 * 4 / 3,
+            max_inputs: MAX_BLOCK_WEIGHT / MIN_INPUT_WEIGHT,
+            max_outputs: MAX_BLOCK_WEIGHT / MIN_OUTPUT_WEIGHT,
+            max_url_length: 2048,
+        }
+    }
+}
+
+impl Limits {
+    pub(crate) fn check_psbt_size(&self, size: u64) -> Result<(), LimitExceeded> {
+        LimitExceeded::check("size of the PSBT", size, self.max_psbt_size)
+    }
+
+    pub(crate) fn check_transaction(&self, transaction: &Transaction) -> Result<(), LimitExceeded> {
+        LimitExceeded::check("number of inputs", transaction.input.len() as u64, self.max_inputs as u64)?;
+        LimitExceeded::check("number of outputs", transaction.output.len() as u64, self.max_outputs as u64)
+    }
+
+    pub(crate) fn check_url_length(&self, length: usize) -> Result<(), LimitExceeded> {
+        LimitExceeded::check("length of the URL", length as u64, self.max_url_length as u64)
+    }
+}
+
+/// A value exceeded its bound in `Limits`.
+#[derive(Debug)]
+pub(crate) struct LimitExceeded {
+    what: &'static str,
+    actual: u64,
+    limit: u64,
+}
+
+impl LimitExceeded {
+    fn check(what: &'static str, actual: u64, limit: u64) -> Result<(), Self> {
+        if actual > limit {
+            Err(LimitExceeded { what, actual, limit, })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "the {} ({}) exceeds the limit of {}", self.what, self.actual, self.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let limits = Limits::default();
+        assert_eq!(limits.max_psbt_size, 5_333_333);
+        assert_eq!(limits.max_inputs, 24_390);
+        assert_eq!(limits.max_outputs, 111_111);
+        assert!(limits.check_psbt_size(5_333_333).is_ok());
+        assert_eq!(limits.check_psbt_size(5_333_334).unwrap_err().to_string(), "the size of the PSBT (5333334) exceeds the limit of 5333333");
+        let limits = Limits { max_inputs: 0, ..Default::default() };
+        assert!(limits.check_transaction(&crate::testing::original_psbt().global.unsigned_tx).is_err());
+    }
+}
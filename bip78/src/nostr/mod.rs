@@ -0,0 +1,516 @@
+//! Payjoin over Nostr relays (experimental)
+//!
+//! Receivers without any server can still receive payjoins: the link lists endpoints
+//! `nostr:<npub>?relay=<relay URL>` (one `pj` parameter per relay) and the HTTP request and
+//! response are carried in ephemeral events of kind `KIND` encrypted using NIP-44. The rest of the
+//! protocol is unchanged - the sender sends `Request::body` and the query of `Request::url`, the
+//! receiver passes them to `UncheckedProposal::from_request_bytes()` and sends back the proposal
+//! or the error.
+//!
+//! Like the rest of the crate this module doesn't do any I/O. It builds and opens the events and
+//! the messages exchanged with relays, connecting to the relays is up to the caller. The format
+//! is not standardized yet and may change.
+//!
+//! The sender should use fresh keys for each request so that its requests can't be linked.
+//!
+//! NIP-44 can't encrypt more than `MAX_MESSAGE_SIZE` bytes. The message is JSON containing the
+//! query and the base64 PSBT, so PSBTs larger than about 48 kB need an HTTP endpoint. Sealing
+//! such a message fails before anything is sent.
+
+mod nip44;
+
+use std::convert::TryInto;
+use std::fmt;
+use std::time::{SystemTime, UNIX_EPOCH};
+use bitcoin::bech32::{self, FromBase32, ToBase32};
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::secp256k1::{Message as SecpMessage, Secp256k1, SecretKey};
+use bitcoin::secp256k1::schnorrsig::{KeyPair, PublicKey, Signature};
+use rand::RngCore;
+use serde_json::{json, Value};
+
+/// Kind of the events carrying payjoin messages.
+///
+/// Ephemeral so that relays don't store them, both parties are online during the exchange.
+pub const KIND: u32 = 20078;
+
+/// Maximum size of the JSON message (query or status and the body) carried by an event.
+pub const MAX_MESSAGE_SIZE: usize = nip44::MAX_PLAINTEXT_LEN;
+
+/// Keys of a party, the public key identifies it to relays and the other party.
+#[derive(Clone)]
+pub struct Keys {
+    secret_key: SecretKey,
+    keypair: KeyPair,
+    public_key: PublicKey,
+}
+
+impl Keys {
+    /// Generates random keys.
+    pub fn generate() -> Self {
+        loop {
+            let mut secret = [0; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+            if let Ok(secret_key) = SecretKey::from_slice(&secret) {
+                return Keys::from_secret_key(secret_key);
+            }
+        }
+    }
+
+    pub fn from_secret_key(secret_key: SecretKey) -> Self {
+        let secp = Secp256k1::signing_only();
+        let keypair = KeyPair::from_seckey_slice(&secp, &secret_key[..]).expect("valid secret key");
+        let public_key = PublicKey::from_keypair(&secp, &keypair);
+        Keys { secret_key, keypair, public_key, }
+    }
+
+    pub fn secret_key(&self) -> &SecretKey {
+        &self.secret_key
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// Creates the event carrying the payjoin request to `receiver`.
+    ///
+    /// `query` is the query of `Request::url` without the `relay` parameter, see
+    /// `Endpoint::from_url()`.
+    pub fn seal_request(&self, receiver: &PublicKey, query: &str, body: &[u8]) -> Result<Event, Error> {
+        let body = std::str::from_utf8(body).map_err(|_| InternalError::BodyNotUtf8)?;
+        let plaintext = json!({ "query": query, "body": body, });
+        self.seal(receiver, vec![vec!["p".to_owned(), receiver.to_hex()]], &plaintext)
+    }
+
+    /// Creates the event carrying the response to `request` with HTTP status code `status`.
+    pub fn seal_response(&self, request: &Event, status: u16, body: &[u8]) -> Result<Event, Error> {
+        let body = std::str::from_utf8(body).map_err(|_| InternalError::BodyNotUtf8)?;
+        let plaintext = json!({ "status": status, "body": body, });
+        let tags = vec![
+            vec!["p".to_owned(), request.pubkey.to_hex()],
+            vec!["e".to_owned(), request.id.to_hex()],
+        ];
+        self.seal(&request.pubkey, tags, &plaintext)
+    }
+
+    fn seal(&self, recipient: &PublicKey, tags: Vec<Vec<String>>, plaintext: &Value) -> Result<Event, Error> {
+        let mut nonce = [0; 32];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let conversation_key = nip44::conversation_key(&self.secret_key, recipient);
+        let plaintext = plaintext.to_string();
+        if plaintext.len() > MAX_MESSAGE_SIZE {
+            return Err(InternalError::MessageTooLarge(plaintext.len()).into());
+        }
+        let content = nip44::encrypt(&conversation_key, &plaintext, &nonce).map_err(InternalError::Encryption)?;
+        let created_at = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        Ok(Event::sign(&self.keypair, self.public_key, created_at, tags, content))
+    }
+
+    /// Verifies and decrypts the event sent to us.
+    pub fn open(&self, event: &Event) -> Result<Opened, Error> {
+        if event.kind != KIND {
+            return Err(InternalError::UnexpectedKind(event.kind).into());
+        }
+        event.verify()?;
+        if event.tag("p") != Some(&*self.public_key.to_hex()) {
+            return Err(InternalError::NotForUs.into());
+        }
+        let conversation_key = nip44::conversation_key(&self.secret_key, &event.pubkey);
+        let plaintext = nip44::decrypt(&conversation_key, &event.content).map_err(InternalError::Encryption)?;
+        let plaintext = serde_json::from_str::<Value>(&plaintext).map_err(InternalError::Json)?;
+        let body = plaintext.get("body").and_then(Value::as_str).ok_or(InternalError::InvalidMessage)?.to_owned();
+        let message = match (plaintext.get("query").and_then(Value::as_str), plaintext.get("status").and_then(Value::as_u64)) {
+            (Some(query), None) => Message::Request { query: query.to_owned(), body, },
+            (None, Some(status)) if status <= u16::MAX.into() => Message::Response { status: status as u16, body, },
+            _ => return Err(InternalError::InvalidMessage.into()),
+        };
+        let reply_to = match event.tag("e") {
+            Some(id) => Some(<[u8; 32]>::from_hex(id).map_err(|_| InternalError::InvalidEvent("e tag"))?),
+            None => None,
+        };
+        Ok(Opened { sender: event.pubkey, reply_to, message, })
+    }
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Keys").field("public_key", &self.public_key).finish()
+    }
+}
+
+/// Returns the NIP-19 encoding of the public key (`npub1...`).
+pub fn encode_npub(public_key: &PublicKey) -> String {
+    bech32::encode("npub", public_key.serialize().to_base32()).expect("valid HRP")
+}
+
+/// Parses the NIP-19 encoding of a public key, case-insensitive.
+pub fn decode_npub(npub: &str) -> Result<PublicKey, Error> {
+    let (hrp, data) = bech32::decode(&npub.to_ascii_lowercase()).map_err(|_| InternalError::InvalidEndpoint("invalid npub"))?;
+    if hrp != "npub" {
+        return Err(InternalError::InvalidEndpoint("the key is not npub").into());
+    }
+    let key = Vec::<u8>::from_base32(&data).map_err(|_| InternalError::InvalidEndpoint("invalid npub"))?;
+    PublicKey::from_slice(&key).map_err(|_| InternalError::InvalidEndpoint("invalid public key").into())
+}
+
+/// Message carried by the event.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    /// The query and the body of the HTTP request.
+    Request { query: String, body: String, },
+    /// The status code and the body of the HTTP response.
+    Response { status: u16, body: String, },
+}
+
+/// Verified and decrypted event.
+#[derive(Debug, Clone)]
+pub struct Opened {
+    /// The public key of the sender of the event.
+    ///
+    /// Check that responses come from the receiver.
+    pub sender: PublicKey,
+    /// ID of the request this event responds to.
+    pub reply_to: Option<[u8; 32]>,
+    pub message: Message,
+}
+
+/// Receiver and relay of a `nostr:` endpoint.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Endpoint {
+    pub receiver: PublicKey,
+    /// URL of the relay (`wss://...`), it must not contain `&` or `#`.
+    pub relay: String,
+}
+
+impl Endpoint {
+    /// Splits the URL of the request (`Request::url`) to the endpoint and the query of the request.
+    pub fn from_url(url: &str) -> Result<(Endpoint, String), Error> {
+        let scheme_len = "nostr:".len();
+        if url.len() < scheme_len || !url[..scheme_len].eq_ignore_ascii_case("nostr:") {
+            return Err(InternalError::InvalidEndpoint("the scheme is not nostr").into());
+        }
+        let mut parts = url[scheme_len..].splitn(2, '?');
+        let receiver = decode_npub(parts.next().unwrap_or_default())?;
+        let mut relay = None;
+        let mut query = Vec::new();
+        for param in parts.next().unwrap_or_default().split('&').filter(|param| !param.is_empty()) {
+            match param.strip_prefix("relay=") {
+                Some(_) if relay.is_some() => return Err(InternalError::InvalidEndpoint("multiple relays").into()),
+                Some(value) => relay = Some(value.to_owned()),
+                None => query.push(param),
+            }
+        }
+        let relay = relay.ok_or(InternalError::InvalidEndpoint("missing relay"))?;
+        Ok((Endpoint { receiver, relay, }, query.join("&")))
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "nostr:{}?relay={}", encode_npub(&self.receiver), self.relay)
+    }
+}
+
+/// Signed Nostr event as defined in NIP-01.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Event {
+    pub id: [u8; 32],
+    pub pubkey: PublicKey,
+    pub created_at: u64,
+    pub kind: u32,
+    pub tags: Vec<Vec<String>>,
+    pub content: String,
+    pub sig: Signature,
+}
+
+impl Event {
+    fn sign(keypair: &KeyPair, pubkey: PublicKey, created_at: u64, tags: Vec<Vec<String>>, content: String) -> Self {
+        let id = Self::compute_id(&pubkey, created_at, KIND, &tags, &content);
+        let mut aux_rand = [0; 32];
+        rand::thread_rng().fill_bytes(&mut aux_rand);
+        let message = SecpMessage::from_slice(&id).expect("32 bytes");
+        let sig = Secp256k1::signing_only().schnorrsig_sign_with_aux_rand(&message, keypair, &aux_rand);
+        Event { id, pubkey, created_at, kind: KIND, tags, content, sig, }
+    }
+
+    fn compute_id(pubkey: &PublicKey, created_at: u64, kind: u32, tags: &[Vec<String>], content: &str) -> [u8; 32] {
+        let serialized = json!([0, pubkey.to_hex(), created_at, kind, tags, content]).to_string();
+        sha256::Hash::hash(serialized.as_bytes()).into_inner()
+    }
+
+    /// Checks that the ID matches the content and the signature is valid.
+    pub fn verify(&self) -> Result<(), Error> {
+        if Self::compute_id(&self.pubkey, self.created_at, self.kind, &self.tags, &self.content) != self.id {
+            return Err(InternalError::InvalidEvent("id").into());
+        }
+        let message = SecpMessage::from_slice(&self.id).expect("32 bytes");
+        // schnorrsig API of this version of secp256k1 requires signing context for verification
+        Secp256k1::new()
+            .schnorrsig_verify(&self.sig, &message, &self.pubkey)
+            .map_err(|_| InternalError::InvalidEvent("signature").into())
+    }
+
+    /// Returns the value of the first tag with `name`.
+    pub fn tag(&self, name: &str) -> Option<&str> {
+        self.tags
+            .iter()
+            .find(|tag| tag.first().map(String::as_str) == Some(name))
+            .and_then(|tag| tag.get(1))
+            .map(String::as_str)
+    }
+
+    pub fn to_json(&self) -> String {
+        self.to_value().to_string()
+    }
+
+    fn to_value(&self) -> Value {
+        json!({
+            "id": self.id.to_hex(),
+            "pubkey": self.pubkey.to_hex(),
+            "created_at": self.created_at,
+            "kind": self.kind,
+            "tags": self.tags,
+            "content": self.content,
+            "sig": self.sig.to_hex(),
+        })
+    }
+
+    /// Parses the event, doesn't verify it.
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        Self::from_value(&serde_json::from_str(json).map_err(InternalError::Json)?)
+    }
+
+    fn from_value(value: &Value) -> Result<Self, Error> {
+        let field = |name| value.get(name).ok_or(InternalError::InvalidEvent(name));
+        let string = |name| field(name)?.as_str().ok_or(InternalError::InvalidEvent(name));
+        let id = <[u8; 32]>::from_hex(string("id")?).map_err(|_| InternalError::InvalidEvent("id"))?;
+        let pubkey = Vec::<u8>::from_hex(string("pubkey")?)
+            .ok()
+            .and_then(|pubkey| PublicKey::from_slice(&pubkey).ok())
+            .ok_or(InternalError::InvalidEvent("pubkey"))?;
+        let sig = Vec::<u8>::from_hex(string("sig")?)
+            .ok()
+            .and_then(|sig| Signature::from_slice(&sig).ok())
+            .ok_or(InternalError::InvalidEvent("sig"))?;
+        let tags = serde_json::from_value(field("tags")?.clone()).map_err(|_| InternalError::InvalidEvent("tags"))?;
+        Ok(Event {
+            id,
+            pubkey,
+            created_at: field("created_at")?.as_u64().ok_or(InternalError::InvalidEvent("created_at"))?,
+            kind: field("kind")?.as_u64().and_then(|kind| kind.try_into().ok()).ok_or(InternalError::InvalidEvent("kind"))?,
+            tags,
+            content: string("content")?.to_owned(),
+            sig,
+        })
+    }
+}
+
+/// Subscription filter selecting payjoin events.
+#[derive(Debug, Clone)]
+pub struct Filter {
+    recipient: PublicKey,
+    reply_to: Option<[u8; 32]>,
+    since: u64,
+}
+
+impl Filter {
+    /// Requests sent to the receiver from now on.
+    pub fn requests_to(receiver: &PublicKey) -> Self {
+        let since = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs());
+        Filter { recipient: *receiver, reply_to: None, since, }
+    }
+
+    /// Responses to the request sent to the sender.
+    pub fn responses_to(request: &Event) -> Self {
+        Filter { recipient: request.pubkey, reply_to: Some(request.id), since: request.created_at, }
+    }
+
+    fn to_value(&self) -> Value {
+        let mut filter = json!({
+            "kinds": [KIND],
+            "#p": [self.recipient.to_hex()],
+            "since": self.since,
+        });
+        if let Some(reply_to) = &self.reply_to {
+            filter["#e"] = json!([reply_to.to_hex()]);
+        }
+        filter
+    }
+}
+
+/// Returns the message publishing the event.
+pub fn publish_message(event: &Event) -> String {
+    json!(["EVENT", event.to_value()]).to_string()
+}
+
+/// Returns the message subscribing to events matching the filter.
+pub fn subscribe_message(subscription_id: &str, filter: &Filter) -> String {
+    json!(["REQ", subscription_id, filter.to_value()]).to_string()
+}
+
+/// Returns the message closing the subscription.
+pub fn close_message(subscription_id: &str) -> String {
+    json!(["CLOSE", subscription_id]).to_string()
+}
+
+/// Message received from a relay.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum RelayMessage {
+    Event { subscription_id: String, event: Event, },
+    /// Result of publishing the event.
+    Ok { event_id: String, accepted: bool, message: String, },
+    EndOfStoredEvents { subscription_id: String, },
+    Closed { subscription_id: String, message: String, },
+    Notice { message: String, },
+    /// Other messages, e.g. authentication challenges, are ignored.
+    Other,
+}
+
+impl RelayMessage {
+    pub fn parse(message: &str) -> Result<Self, Error> {
+        let value = serde_json::from_str::<Value>(message).map_err(InternalError::Json)?;
+        let items = value.as_array().ok_or(InternalError::InvalidRelayMessage)?;
+        let string = |index: usize| items.get(index).and_then(Value::as_str).map(str::to_owned).ok_or(InternalError::InvalidRelayMessage);
+        let message = match &*string(0)? {
+            "EVENT" => RelayMessage::Event {
+                subscription_id: string(1)?,
+                event: Event::from_value(items.get(2).ok_or(InternalError::InvalidRelayMessage)?)?,
+            },
+            "OK" => RelayMessage::Ok {
+                event_id: string(1)?,
+                accepted: items.get(2).and_then(Value::as_bool).ok_or(InternalError::InvalidRelayMessage)?,
+                message: string(3).unwrap_or_default(),
+            },
+            "EOSE" => RelayMessage::EndOfStoredEvents { subscription_id: string(1)?, },
+            "CLOSED" => RelayMessage::Closed { subscription_id: string(1)?, message: string(2).unwrap_or_default(), },
+            "NOTICE" => RelayMessage::Notice { message: string(1)?, },
+            _ => RelayMessage::Other,
+        };
+        Ok(message)
+    }
+}
+
+#[derive(Debug)]
+pub struct Error(InternalError);
+
+#[derive(Debug)]
+enum InternalError {
+    Encryption(nip44::Error),
+    Json(serde_json::Error),
+    InvalidEvent(&'static str),
+    UnexpectedKind(u32),
+    NotForUs,
+    InvalidMessage,
+    InvalidRelayMessage,
+    InvalidEndpoint(&'static str),
+    BodyNotUtf8,
+    MessageTooLarge(usize),
+}
+
+impl From<InternalError> for Error {
+    fn from(value: InternalError) -> Self {
+        Error(value)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            InternalError::Encryption(_) => write!(f, "failed to encrypt or decrypt the message"),
+            InternalError::Json(_) => write!(f, "invalid JSON"),
+            InternalError::InvalidEvent(field) => write!(f, "the event has invalid {}", field),
+            InternalError::UnexpectedKind(kind) => write!(f, "unexpected kind of event {}", kind),
+            InternalError::NotForUs => write!(f, "the event is addressed to someone else"),
+            InternalError::InvalidMessage => write!(f, "the event doesn't contain a payjoin message"),
+            InternalError::InvalidRelayMessage => write!(f, "the message from the relay is invalid"),
+            InternalError::InvalidEndpoint(reason) => write!(f, "invalid nostr endpoint: {}", reason),
+            InternalError::BodyNotUtf8 => write!(f, "the body is not UTF-8"),
+            InternalError::MessageTooLarge(size) => write!(f, "the message has {} bytes but nostr endpoints can carry at most {} bytes, use an HTTP endpoint", size, MAX_MESSAGE_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.0 {
+            InternalError::Encryption(error) => Some(error),
+            InternalError::Json(error) => Some(error),
+            InternalError::InvalidEvent(_) => None,
+            InternalError::UnexpectedKind(_) => None,
+            InternalError::NotForUs => None,
+            InternalError::InvalidMessage => None,
+            InternalError::InvalidRelayMessage => None,
+            InternalError::InvalidEndpoint(_) => None,
+            InternalError::BodyNotUtf8 => None,
+            InternalError::MessageTooLarge(_) => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exchange() {
+        let receiver = Keys::generate();
+        let sender = Keys::generate();
+        let endpoint = Endpoint { receiver: *receiver.public_key(), relay: "wss://relay.example.com".to_owned(), };
+        let url = format!("{}&v=1&disableoutputsubstitution=1", endpoint);
+        let (parsed, query) = Endpoint::from_url(&url).unwrap();
+        assert_eq!(parsed, endpoint);
+        assert_eq!(query, "v=1&disableoutputsubstitution=1");
+
+        let request = sender.seal_request(&parsed.receiver, &query, crate::testing::ORIGINAL_PSBT.as_bytes()).unwrap();
+        let request = Event::from_json(&request.to_json()).unwrap();
+        let opened = receiver.open(&request).unwrap();
+        assert_eq!(opened.sender, *sender.public_key());
+        assert_eq!(opened.message, Message::Request { query, body: crate::testing::ORIGINAL_PSBT.to_owned(), });
+        // only the recipient can open it
+        assert!(Keys::generate().open(&request).is_err());
+
+        let response = receiver.seal_response(&request, 200, crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap();
+        let message = format!("[\"EVENT\",\"sub\",{}]", response.to_json());
+        let response = match RelayMessage::parse(&message).unwrap() {
+            RelayMessage::Event { subscription_id, event, } if subscription_id == "sub" => event,
+            message => panic!("unexpected message {:?}", message),
+        };
+        let opened = sender.open(&response).unwrap();
+        assert_eq!(opened.sender, *receiver.public_key());
+        assert_eq!(opened.reply_to, Some(request.id));
+        assert_eq!(opened.message, Message::Response { status: 200, body: crate::testing::PROPOSAL_PSBT.to_owned(), });
+
+        let mut forged = response;
+        forged.content = request.content;
+        assert!(sender.open(&forged).is_err());
+    }
+
+    #[test]
+    fn message_size() {
+        let receiver = Keys::generate();
+        let sender = Keys::generate();
+        let overhead = r#"{"body":"","query":"v=1"}"#.len();
+        let body = "A".repeat(MAX_MESSAGE_SIZE - overhead);
+        let request = sender.seal_request(receiver.public_key(), "v=1", body.as_bytes()).unwrap();
+        assert!(matches!(receiver.open(&request).unwrap().message, Message::Request { body: opened, .. } if opened == body));
+        let body = "A".repeat(MAX_MESSAGE_SIZE - overhead + 1);
+        let error = sender.seal_request(receiver.public_key(), "v=1", body.as_bytes()).unwrap_err();
+        assert!(matches!(error.0, InternalError::MessageTooLarge(size) if size == MAX_MESSAGE_SIZE + 1));
+        assert!(error.to_string().contains("at most 65535 bytes"));
+    }
+
+    #[test]
+    fn endpoint() {
+        let keys = Keys::from_secret_key(SecretKey::from_slice(&[0x01; 32]).unwrap());
+        let npub = encode_npub(keys.public_key());
+        assert!(npub.starts_with("npub1"));
+        assert_eq!(decode_npub(&npub.to_ascii_uppercase()).unwrap(), *keys.public_key());
+        assert!(Endpoint::from_url(&format!("nostr:{}?v=1", npub)).is_err());
+        assert!(Endpoint::from_url("https://example.com/pj?relay=wss://relay.example.com").is_err());
+        let uri = format!("bitcoin:3CZZi7aWFugaCdUCS15dgrUUViupmB8bVM?amount=0.02&pj=nostr:{}?relay=wss://relay.example.com", npub);
+        let uri = uri.parse::<crate::Uri>().unwrap();
+        assert_eq!(uri.endpoints().next(), Some(&*format!("nostr:{}?relay=wss://relay.example.com", npub)));
+    }
+}
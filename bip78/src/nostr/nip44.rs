@@ -0,0 +1,260 @@
+//! NIP-44 (version 2) encryption of event contents
+//!
+//! The conversation key is derived from ECDH of the two parties, each message uses a random
+//! nonce to derive ChaCha20 and HMAC-SHA256 keys. Plaintexts are padded to hide their exact
+//! length. See https://github.com/nostr-protocol/nips/blob/master/44.md
+
+use std::fmt;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use bitcoin::hashes::hmac::{Hmac, HmacEngine};
+use bitcoin::secp256k1::{PublicKey, SecretKey};
+use bitcoin::secp256k1::ecdh::SharedSecret;
+use bitcoin::secp256k1::schnorrsig;
+
+const VERSION: u8 = 2;
+const SALT: &[u8] = b"nip44-v2";
+/// Plaintexts are 1 to 65535 bytes long.
+pub(crate) const MAX_PLAINTEXT_LEN: usize = 65535;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    InvalidPlaintextLength(usize),
+    InvalidPayload,
+    UnsupportedVersion(u8),
+    InvalidMac,
+    InvalidPadding,
+    InvalidUtf8,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidPlaintextLength(len) => write!(f, "can't encrypt {} bytes, the length must be between 1 and {}", len, MAX_PLAINTEXT_LEN),
+            Error::InvalidPayload => write!(f, "the encrypted payload is malformed"),
+            Error::UnsupportedVersion(version) => write!(f, "unsupported encryption version {}", version),
+            Error::InvalidMac => write!(f, "the message authentication code doesn't match"),
+            Error::InvalidPadding => write!(f, "the padding of the decrypted message is invalid"),
+            Error::InvalidUtf8 => write!(f, "the decrypted message is not UTF-8"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Returns the key shared by the owner of `secret_key` and `public_key`.
+pub(crate) fn conversation_key(secret_key: &SecretKey, public_key: &schnorrsig::PublicKey) -> [u8; 32] {
+    // x-only keys are lifted to the point with even y
+    let mut point = [0x02; 33];
+    point[1..].copy_from_slice(&public_key.serialize());
+    let point = PublicKey::from_slice(&point).expect("x-only key is a valid point");
+    let shared_x = SharedSecret::new_with_hash(&point, secret_key, |x, _| x.into());
+    hmac(SALT, &[&shared_x[..]]).into_inner()
+}
+
+/// Encrypts `plaintext` using `nonce` which must be random and never reused.
+pub(crate) fn encrypt(conversation_key: &[u8; 32], plaintext: &str, nonce: &[u8; 32]) -> Result<String, Error> {
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, nonce);
+    let mut padded = pad(plaintext.as_bytes())?;
+    chacha20(&chacha_key, &chacha_nonce, 0, &mut padded);
+    let mac = hmac(&hmac_key, &[nonce, &padded]);
+    let mut payload = Vec::with_capacity(1 + 32 + padded.len() + 32);
+    payload.push(VERSION);
+    payload.extend_from_slice(nonce);
+    payload.extend_from_slice(&padded);
+    payload.extend_from_slice(&mac[..]);
+    Ok(base64::encode(&payload))
+}
+
+pub(crate) fn decrypt(conversation_key: &[u8; 32], payload: &str) -> Result<String, Error> {
+    // '#' marks future non-base64 encodings
+    if payload.starts_with('#') {
+        return Err(Error::UnsupportedVersion(0));
+    }
+    if payload.len() < 132 || payload.len() > 87472 {
+        return Err(Error::InvalidPayload);
+    }
+    let payload = base64::decode(payload).map_err(|_| Error::InvalidPayload)?;
+    if payload.len() < 99 || payload.len() > 65603 {
+        return Err(Error::InvalidPayload);
+    }
+    if payload[0] != VERSION {
+        return Err(Error::UnsupportedVersion(payload[0]));
+    }
+    let mut nonce = [0; 32];
+    nonce.copy_from_slice(&payload[1..33]);
+    let (ciphertext, mac) = payload[33..].split_at(payload.len() - 33 - 32);
+    let (chacha_key, chacha_nonce, hmac_key) = message_keys(conversation_key, &nonce);
+    let expected_mac = hmac(&hmac_key, &[&nonce, ciphertext]);
+    // constant time comparison
+    if mac.iter().zip(&expected_mac[..]).fold(0, |acc, (a, b)| acc | (a ^ b)) != 0 {
+        return Err(Error::InvalidMac);
+    }
+    let mut padded = ciphertext.to_vec();
+    chacha20(&chacha_key, &chacha_nonce, 0, &mut padded);
+    let plaintext = unpad(&padded)?;
+    String::from_utf8(plaintext.to_vec()).map_err(|_| Error::InvalidUtf8)
+}
+
+fn hmac(key: &[u8], data: &[&[u8]]) -> Hmac<sha256::Hash> {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    for data in data {
+        engine.input(data);
+    }
+    Hmac::from_engine(engine)
+}
+
+/// HKDF-expand of the conversation key to ChaCha20 key, ChaCha20 nonce and HMAC key.
+fn message_keys(conversation_key: &[u8; 32], nonce: &[u8; 32]) -> ([u8; 32], [u8; 12], [u8; 32]) {
+    let mut okm = Vec::with_capacity(96);
+    let mut previous = Vec::new();
+    for counter in 1..=3u8 {
+        let block = hmac(conversation_key, &[&previous, nonce, &[counter]]);
+        previous = block[..].to_vec();
+        okm.extend_from_slice(&block[..]);
+    }
+    let mut chacha_key = [0; 32];
+    let mut chacha_nonce = [0; 12];
+    let mut hmac_key = [0; 32];
+    chacha_key.copy_from_slice(&okm[..32]);
+    chacha_nonce.copy_from_slice(&okm[32..44]);
+    hmac_key.copy_from_slice(&okm[44..76]);
+    (chacha_key, chacha_nonce, hmac_key)
+}
+
+fn padded_len(len: usize) -> usize {
+    if len <= 32 {
+        return 32;
+    }
+    let next_power = 1 << (usize::BITS - (len - 1).leading_zeros());
+    let chunk = if next_power <= 256 { 32 } else { next_power / 8 };
+    chunk * ((len - 1) / chunk + 1)
+}
+
+fn pad(plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    if plaintext.is_empty() || plaintext.len() > MAX_PLAINTEXT_LEN {
+        return Err(Error::InvalidPlaintextLength(plaintext.len()));
+    }
+    let mut padded = Vec::with_capacity(2 + padded_len(plaintext.len()));
+    padded.extend_from_slice(&(plaintext.len() as u16).to_be_bytes());
+    padded.extend_from_slice(plaintext);
+    padded.resize(2 + padded_len(plaintext.len()), 0);
+    Ok(padded)
+}
+
+fn unpad(padded: &[u8]) -> Result<&[u8], Error> {
+    if padded.len() < 2 {
+        return Err(Error::InvalidPadding);
+    }
+    let len = u16::from_be_bytes([padded[0], padded[1]]) as usize;
+    if len == 0 || padded.len() != 2 + padded_len(len) {
+        return Err(Error::InvalidPadding);
+    }
+    Ok(&padded[2..(2 + len)])
+}
+
+/// ChaCha20 as defined in RFC 8439 (32-bit counter, 96-bit nonce), XORs the keystream into `data`.
+fn chacha20(key: &[u8; 32], nonce: &[u8; 12], counter: u32, data: &mut [u8]) {
+    for (index, chunk) in data.chunks_mut(64).enumerate() {
+        let keystream = chacha20_block(key, nonce, counter.wrapping_add(index as u32));
+        for (byte, key_byte) in chunk.iter_mut().zip(keystream.iter()) {
+            *byte ^= key_byte;
+        }
+    }
+}
+
+fn chacha20_block(key: &[u8; 32], nonce: &[u8; 12], counter: u32) -> [u8; 64] {
+    let word = |bytes: &[u8]| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    let mut state = [0u32; 16];
+    state[..4].copy_from_slice(&[0x6170_7865, 0x3320_646e, 0x7962_2d32, 0x6b20_6574]);
+    for (index, chunk) in key.chunks(4).enumerate() {
+        state[4 + index] = word(chunk);
+    }
+    state[12] = counter;
+    for (index, chunk) in nonce.chunks(4).enumerate() {
+        state[13 + index] = word(chunk);
+    }
+
+    let mut working = state;
+    for _ in 0..10 {
+        quarter_round(&mut working, 0, 4, 8, 12);
+        quarter_round(&mut working, 1, 5, 9, 13);
+        quarter_round(&mut working, 2, 6, 10, 14);
+        quarter_round(&mut working, 3, 7, 11, 15);
+        quarter_round(&mut working, 0, 5, 10, 15);
+        quarter_round(&mut working, 1, 6, 11, 12);
+        quarter_round(&mut working, 2, 7, 8, 13);
+        quarter_round(&mut working, 3, 4, 9, 14);
+    }
+    let mut block = [0; 64];
+    for (index, chunk) in block.chunks_mut(4).enumerate() {
+        chunk.copy_from_slice(&working[index].wrapping_add(state[index]).to_le_bytes());
+    }
+    block
+}
+
+fn quarter_round(state: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(16);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(12);
+    state[a] = state[a].wrapping_add(state[b]);
+    state[d] = (state[d] ^ state[a]).rotate_left(8);
+    state[c] = state[c].wrapping_add(state[d]);
+    state[b] = (state[b] ^ state[c]).rotate_left(7);
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::hex::{FromHex, ToHex};
+    use bitcoin::secp256k1::{Secp256k1, SecretKey};
+    use bitcoin::secp256k1::schnorrsig::{KeyPair, PublicKey};
+    use super::*;
+
+    fn keys(last_byte: u8) -> (SecretKey, PublicKey) {
+        let mut secret = [0; 32];
+        secret[31] = last_byte;
+        let secp = Secp256k1::new();
+        let keypair = KeyPair::from_seckey_slice(&secp, &secret).unwrap();
+        (SecretKey::from_slice(&secret).unwrap(), PublicKey::from_keypair(&secp, &keypair))
+    }
+
+    #[test]
+    fn chacha20_rfc8439() {
+        let key = <[u8; 32]>::from_hex("000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f").unwrap();
+        let nonce = [0, 0, 0, 0, 0, 0, 0, 0x4a, 0, 0, 0, 0];
+        let mut data = b"Ladies and Gentlemen of the class of '99: If I could offer you only one tip for the future, sunscreen would be it.".to_vec();
+        chacha20(&key, &nonce, 1, &mut data);
+        assert_eq!(data.to_hex(), "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0bf91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d807ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab77937365af90bbf74a35be6b40b8eedf2785e42874d");
+    }
+
+    #[test]
+    fn vector() {
+        let (secret_1, public_1) = keys(1);
+        let (secret_2, public_2) = keys(2);
+        let conversation_key = conversation_key(&secret_1, &public_2);
+        assert_eq!(conversation_key.to_hex(), "c41c775356fd92eadc63ff5a0dc1da211b268cbea22316767095b2871ea1412d");
+        assert_eq!(super::conversation_key(&secret_2, &public_1), conversation_key);
+
+        let mut nonce = [0; 32];
+        nonce[31] = 1;
+        let payload = encrypt(&conversation_key, "a", &nonce).unwrap();
+        assert_eq!(payload, "AgAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAABee0G5VSK0/9YypIObAtDKfYEAjD35uVkHyB0F4DwrcNaCXlCWZKaArsGrY6M9wnuTMxWfp1RTN9Xga8no+kF5Vsb");
+        assert_eq!(decrypt(&conversation_key, &payload).unwrap(), "a");
+
+        let mut tampered = base64::decode(&payload).unwrap();
+        tampered[40] ^= 1;
+        assert!(matches!(decrypt(&conversation_key, &base64::encode(&tampered)), Err(Error::InvalidMac)));
+        assert!(matches!(encrypt(&conversation_key, "", &nonce), Err(Error::InvalidPlaintextLength(0))));
+    }
+
+    #[test]
+    fn padding() {
+        let vectors = [(16, 32), (32, 32), (33, 64), (37, 64), (64, 64), (65, 96), (100, 128), (200, 224), (250, 256), (320, 320), (383, 384), (400, 448), (515, 640), (900, 1024), (65535, 65536)];
+        for &(len, padded) in vectors.iter() {
+            assert_eq!(padded_len(len), padded, "{}", len);
+        }
+        let padded = pad(b"payjoin").unwrap();
+        assert_eq!(padded.len(), 34);
+        assert_eq!(unpad(&padded).unwrap(), b"payjoin");
+    }
+}
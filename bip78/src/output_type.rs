@@ -1,25 +1,94 @@
-crate::weight::Weight;
+use bitcoin::blockdata::script::Script;
+use bitcoin::blockdata::opcodes;
+use crate::input_type::SegWitV0Type;
 
+/// Type of output as determined from its script pubkey.
+///
+/// Only standard script pubkeys that make sense as payment destinations are recognized.
+/// Witness programs of unknown versions are accepted so that we don't need a new release every
+/// time a new SegWit version gets activated.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 #[non_exhaustive]
-pub enum OutputType {
+pub(crate) enum OutputType {
     P2Pkh,
     P2Sh,
     SegWitV0 { ty: SegWitV0Type, nested: bool },
     Taproot,
+    /// Witness program of version without defined meaning yet (or of non-taproot size).
+    FutureSegWit { version: u8 },
 }
 
 impl OutputType {
-    pub(crate) fn output_only_weight(&self) -> Weight {
-        use OutputType::*;
-
-        match self {
-            P2Pkh => Weight::from_non_witness_data_size(1 /* OP_DUP */ + 1 /* OP_HASH160 */ + 1 /* OP_PUSH */ + 160 / 8 /* ripemd160 hash size */ + 1 /* OP_EQUALVERIFY */ + 1 /* OP_CHECKSIG */),
-            P2Sh => Weight::from_non_witness_data_size(1 /* OP_HASH160 */ + 1 /* OP_PUSH */ + 160 / 8 /* ripemd160 hash size */ + 1 /* OP_EQUAL */),
-            SegWitV0 { ty: _, nested: true } => Weight::from_non_witness_data_size(1 /* OP_HASH160 */ + 1 /* OP_PUSH */ + 160 / 8 /* ripemd160 hash size */ + 1 /* OP_EQUAL */),
-            SegWitV0 { ty: SegWitV0Type::Pubkey, nested: false } => Weight::from_non_witness_data_size(1 /* OP_PUSH0 */ + 1 /* OP_PUSH */ + 160 / 8 /* ripemd160 hash size */),
-            SegWitV0 { ty: SegWitV0Type::Script, nested: false } => Weight::from_non_witness_data_size(1 /* OP_PUSH0 */ + 1 /* OP_PUSH */ + 256 / 8 /* ripemd160 hash size */),
-            Taproot => Weight::from_non_witness_data_size(1 /* OP_PUSH0 */ + 1 /* OP_PUSH */ + 256 / 8 /* ripemd160 hash size */),
+    /// Returns `None` if the script is non-standard or not suitable as a payment destination.
+    ///
+    /// Note that nested SegWit can not be detected from script pubkey so it's reported as `P2Sh`.
+    pub(crate) fn from_script(script: &Script) -> Option<Self> {
+        if script.is_p2pkh() {
+            Some(OutputType::P2Pkh)
+        } else if script.is_p2sh() {
+            Some(OutputType::P2Sh)
+        } else if script.is_v0_p2wpkh() {
+            Some(OutputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: false, })
+        } else if script.is_v0_p2wsh() {
+            Some(OutputType::SegWitV0 { ty: SegWitV0Type::Script, nested: false, })
+        } else if script.is_witness_program() {
+            let bytes = script.as_bytes();
+            // v0 programs of other lengths are invalid
+            if bytes[0] == opcodes::all::OP_PUSHBYTES_0.into_u8() {
+                return None;
+            }
+            let version = bytes[0] - opcodes::all::OP_PUSHNUM_1.into_u8() + 1;
+            let program_len = bytes.len() - 2;
+            match (version, program_len) {
+                (1, 32) => Some(OutputType::Taproot),
+                (version, _) => Some(OutputType::FutureSegWit { version, }),
+            }
+        } else {
+            None
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::PublicKey;
+
+    fn witness_program(version: u8, program: &[u8]) -> Script {
+        Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(version).unwrap(), program)
+    }
+
+    #[test]
+    fn test_p2pkh() {
+        let script = Script::new_p2pkh(&PublicKey::from_slice(b"\x02\x50\x86\x3A\xD6\x4A\x87\xAE\x8A\x2F\xE8\x3C\x1A\xF1\xA8\x40\x3C\xB5\x3F\x53\xE4\x86\xD8\x51\x1D\xAD\x8A\x04\x88\x7E\x5B\x23\x52").unwrap().pubkey_hash());
+        assert_eq!(OutputType::from_script(&script), Some(OutputType::P2Pkh));
+    }
+
+    #[test]
+    fn test_p2wsh() {
+        let script = Script::new_v0_wsh(&Script::new_op_return(&[42]).wscript_hash());
+        assert_eq!(OutputType::from_script(&script), Some(OutputType::SegWitV0 { ty: SegWitV0Type::Script, nested: false, }));
+    }
+
+    #[test]
+    fn test_taproot() {
+        assert_eq!(OutputType::from_script(&witness_program(1, &[42; 32])), Some(OutputType::Taproot));
+    }
+
+    #[test]
+    fn test_future_segwit() {
+        assert_eq!(OutputType::from_script(&witness_program(1, &[42; 20])), Some(OutputType::FutureSegWit { version: 1, }));
+        assert_eq!(OutputType::from_script(&witness_program(16, &[42; 40])), Some(OutputType::FutureSegWit { version: 16, }));
+    }
+
+    #[test]
+    fn test_invalid_v0() {
+        assert_eq!(OutputType::from_script(&witness_program(0, &[42; 25])), None);
+    }
+
+    #[test]
+    fn test_non_standard() {
+        assert_eq!(OutputType::from_script(&Script::new_op_return(&[42])), None);
+        assert_eq!(OutputType::from_script(&Script::new()), None);
+    }
+}
@@ -0,0 +1,148 @@
+//! Pinning the TLS certificate of the endpoint
+//!
+//! A sender paying a large amount may not want to trust every certificate authority with the
+//! integrity of the payjoin. The receiver can put the SHA-256 hash of the public key
+//! (SubjectPublicKeyInfo) of its certificate into the link as `pjpin` and the sender then rejects
+//! any other certificate. Hashing the key rather than the whole certificate keeps the pin valid
+//! when the certificate is renewed with the same key. `pjpin` may repeat to list backup keys.
+
+use std::fmt;
+use std::str::FromStr;
+use bitcoin::hashes::{sha256, Hash};
+use bitcoin::hashes::hex::{FromHex, ToHex};
+
+/// SHA-256 hash of the SubjectPublicKeyInfo of a certificate, as in HPKP `pin-sha256`.
+///
+/// Written as 64 hex digits, case-insensitive so that QR-optimized links work.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub struct CertificatePin(sha256::Hash);
+
+impl CertificatePin {
+    /// Creates the pin from the DER encoding of the SubjectPublicKeyInfo.
+    pub fn from_spki(spki: &[u8]) -> Self {
+        CertificatePin(sha256::Hash::hash(spki))
+    }
+
+    /// Creates the pin of the key in the DER-encoded X.509 certificate.
+    ///
+    /// Returns `None` if the certificate is malformed.
+    pub fn from_certificate(certificate: &[u8]) -> Option<Self> {
+        spki_of_certificate(certificate).map(Self::from_spki)
+    }
+
+    /// Returns `true` if the DER-encoded X.509 certificate contains the pinned key.
+    ///
+    /// Malformed certificates never match.
+    pub fn matches_certificate(&self, certificate: &[u8]) -> bool {
+        Self::from_certificate(certificate) == Some(*self)
+    }
+}
+
+impl fmt::Display for CertificatePin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0.into_inner().to_hex())
+    }
+}
+
+impl FromStr for CertificatePin {
+    type Err = ParsePinError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 {
+            return Err(ParsePinError(s.to_owned()));
+        }
+        let bytes = Vec::<u8>::from_hex(&s.to_ascii_lowercase()).map_err(|_| ParsePinError(s.to_owned()))?;
+        sha256::Hash::from_slice(&bytes).map(CertificatePin).map_err(|_| ParsePinError(s.to_owned()))
+    }
+}
+
+/// The pin is not 64 hex digits.
+#[derive(Debug, Clone)]
+pub struct ParsePinError(String);
+
+impl fmt::Display for ParsePinError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid certificate pin \"{}\", expected SHA-256 hash in hex", self.0)
+    }
+}
+
+impl std::error::Error for ParsePinError {}
+
+struct DerElement<'a> {
+    tag: u8,
+    /// Including the tag and length.
+    encoded: &'a [u8],
+    contents: &'a [u8],
+    /// Data following the element.
+    rest: &'a [u8],
+}
+
+/// Splits the first DER element off `data`.
+fn der_element(data: &[u8]) -> Option<DerElement<'_>> {
+    let tag = *data.first()?;
+    let first_len = *data.get(1)?;
+    let (header_len, len) = if first_len < 0x80 {
+        (2, usize::from(first_len))
+    } else {
+        // long form, more than four bytes of length is absurd for a certificate
+        let len_bytes = usize::from(first_len & 0x7f);
+        if len_bytes == 0 || len_bytes > 4 {
+            return None;
+        }
+        let len = data.get(2..(2 + len_bytes))?.iter().fold(0usize, |len, byte| (len << 8) | usize::from(*byte));
+        (2 + len_bytes, len)
+    };
+    let end = header_len.checked_add(len)?;
+    let encoded = data.get(..end)?;
+    Some(DerElement { tag, encoded, contents: &encoded[header_len..], rest: &data[end..], })
+}
+
+const SEQUENCE: u8 = 0x30;
+// explicitly tagged version of TBSCertificate
+const VERSION: u8 = 0xa0;
+
+/// Returns the DER encoding of the SubjectPublicKeyInfo of an X.509 certificate.
+fn spki_of_certificate(certificate: &[u8]) -> Option<&[u8]> {
+    let sequence = |data| der_element(data).filter(|element| element.tag == SEQUENCE);
+    let tbs = sequence(sequence(certificate)?.contents)?;
+    let mut rest = tbs.contents;
+    if rest.first() == Some(&VERSION) {
+        rest = der_element(rest)?.rest;
+    }
+    // serialNumber, signature, issuer, validity, subject
+    for _ in 0..5 {
+        rest = der_element(rest)?.rest;
+    }
+    Some(sequence(rest)?.encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // self-signed P-256 certificate for example.com
+    const CERTIFICATE: &str = "MIIBhDCCASmgAwIBAgIUCclNa9CYnkVSIJloRPODhM47glgwCgYIKoZIzj0EAwIwFjEUMBIGA1UEAwwLZXhhbXBsZS5jb20wIBcNMjYxMDE2MTA0NjMyWhgPMjEyNjA5MjIxMDQ2MzJaMBYxFDASBgNVBAMMC2V4YW1wbGUuY29tMFkwEwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAE9Qw1dnz5uooJM1+cSk2YrtlKTDrGjDuqp11UPEvprwGmy4XOR74gWPrTszJ9PHwhEusofgDtuI2myjP8tGQu6aNTMFEwHQYDVR0OBBYEFLuOWSbs1OBibl7qG9PEOxflZLwrMB8GA1UdIwQYMBaAFLuOWSbs1OBibl7qG9PEOxflZLwrMA8GA1UdEwEB/wQFMAMBAf8wCgYIKoZIzj0EAwIDSQAwRgIhAJh7GOGviyzPlFYASVb2rBTXXJezGahOlerAY9ihFVnAAiEAmBuMwgtDMWBHb6FLTmNfgfG34rppclyboyqyafOUf1s=";
+    // openssl x509 -pubkey -noout | openssl pkey -pubin -outform der | openssl dgst -sha256
+    const PIN: &str = "c57588a265467e0b163025d2fb323feb2365eafb539693af307926d9d6b22f35";
+
+    #[test]
+    fn matches_certificate() {
+        let certificate = base64::decode(CERTIFICATE).unwrap();
+        let pin = PIN.parse::<CertificatePin>().unwrap();
+        assert_eq!(CertificatePin::from_certificate(&certificate), Some(pin));
+        assert!(pin.matches_certificate(&certificate));
+        assert!(!CertificatePin::from_spki(&[]).matches_certificate(&certificate));
+        for len in 0..certificate.len() {
+            assert!(!pin.matches_certificate(&certificate[..len]));
+        }
+    }
+
+    #[test]
+    fn parse() {
+        let pin = PIN.parse::<CertificatePin>().unwrap();
+        assert_eq!(pin.to_string(), PIN);
+        assert_eq!(PIN.to_ascii_uppercase().parse::<CertificatePin>().unwrap(), pin);
+        assert!(PIN[1..].parse::<CertificatePin>().is_err());
+        assert!(PIN.replace('c', "x").parse::<CertificatePin>().is_err());
+    }
+}
@@ -1,7 +1,7 @@
 //! Utilities to make work with PSBTs easier
 
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
-use bitcoin::{TxIn, TxOut};
+use bitcoin::{TxIn, TxOut, Txid};
 use bitcoin::util::psbt;
 use std::convert::TryInto;
 use std::fmt;
@@ -30,6 +30,164 @@ impl<'a> PsbtExt for &'a Psbt {
     }
 }
 
+/// Returns the txid the transaction will have once broadcasted.
+///
+/// The txid commits to `script_sig` of the inputs so the txid of the unsigned transaction
+/// differs unless all inputs are native SegWit. Use this to monitor the payjoin transaction once
+/// the proposal is fully signed, fails if any input isn't finalized yet.
+pub fn final_txid(psbt: &Psbt) -> Result<Txid, NotFinalized> {
+    let mut tx = psbt.global.unsigned_tx.clone();
+    for (index, (txin, input)) in tx.input.iter_mut().zip(&psbt.inputs).enumerate() {
+        if input.final_script_sig.is_none() && input.final_script_witness.is_none() {
+            return Err(NotFinalized { index, });
+        }
+        // the witness doesn't affect the txid
+        txin.script_sig = input.final_script_sig.clone().unwrap_or_default();
+    }
+    Ok(tx.txid())
+}
+
+/// An input of the PSBT is not finalized, see `final_txid()`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct NotFinalized {
+    index: usize,
+}
+
+impl NotFinalized {
+    /// Index of the first input that is not finalized.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl fmt::Display for NotFinalized {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "input #{} is not finalized", self.index)
+    }
+}
+
+impl std::error::Error for NotFinalized {}
+
+/// Returns the transaction of the PSBT with UTXO information and final scripts as JSON.
+///
+/// Meant for debugging, the format is not stable. Scripts and witness items are hex-encoded,
+/// values are in satoshis and unknown fields are `null`. Other PSBT fields are omitted.
+pub fn to_json(psbt: &Psbt) -> String {
+    use std::fmt::Write;
+    use bitcoin::hashes::hex::ToHex;
+
+    let tx = &psbt.global.unsigned_tx;
+    let mut json = String::new();
+    // writing to String never fails
+    let _ = writeln!(json, "{{\n  \"txid\": \"{}\",\n  \"version\": {},\n  \"lockTime\": {},\n  \"inputs\": [", tx.txid(), tx.version, tx.lock_time);
+    for (index, input) in psbt.input_pairs().enumerate() {
+        let prevout = input.previous_txout().ok();
+        let value = prevout.map_or_else(|| "null".to_owned(), |txout| txout.value.to_string());
+        let script_pubkey = prevout.map_or_else(|| "null".to_owned(), |txout| format!("\"{:x}\"", txout.script_pubkey));
+        let script_sig = input.psbtin.final_script_sig.as_ref().map_or_else(|| "null".to_owned(), |script| format!("\"{:x}\"", script));
+        let witness = input.psbtin.final_script_witness.as_ref().map_or_else(|| "null".to_owned(), |witness| {
+            let items = witness.iter().map(|item| format!("\"{}\"", item.to_hex())).collect::<Vec<_>>();
+            format!("[{}]", items.join(", "))
+        });
+        let separator = if index + 1 < tx.input.len() { "," } else { "" };
+        let _ = writeln!(json, "    {{ \"outpoint\": \"{}\", \"sequence\": {}, \"value\": {}, \"scriptPubKey\": {}, \"finalScriptSig\": {}, \"finalScriptWitness\": {} }}{}", input.txin.previous_output, input.txin.sequence, value, script_pubkey, script_sig, witness, separator);
+    }
+    json.push_str("  ],\n  \"outputs\": [\n");
+    for (index, output) in tx.output.iter().enumerate() {
+        let separator = if index + 1 < tx.output.len() { "," } else { "" };
+        let _ = writeln!(json, "    {{ \"value\": {}, \"scriptPubKey\": \"{:x}\" }}{}", output.value, output.script_pubkey, separator);
+    }
+    json.push_str("  ]\n}");
+    json
+}
+
+/// Maximum number of satoshis that can ever exist.
+pub(crate) const MAX_MONEY: u64 = 21_000_000 * 100_000_000;
+
+/// Key types of the taproot input fields (BIP371) - tap_key_sig to tap_merkle_root.
+///
+/// `bitcoin` doesn't know them yet so they end up in `unknown`.
+const PSBT_IN_TAP_FIELDS: std::ops::RangeInclusive<u8> = 0x13..=0x18;
+/// Key types of the taproot input fields other than signatures.
+const PSBT_IN_TAP_METADATA: std::ops::RangeInclusive<u8> = 0x15..=0x18;
+/// Key type of tap_bip32_derivation of an input.
+const PSBT_IN_TAP_BIP32_DERIVATION: u8 = 0x16;
+/// Key types of the taproot output fields - tap_internal_key, tap_tree and tap_bip32_derivation.
+const PSBT_OUT_TAP_FIELDS: std::ops::RangeInclusive<u8> = 0x05..=0x07;
+/// Key type of tap_bip32_derivation of an output.
+const PSBT_OUT_TAP_BIP32_DERIVATION: u8 = 0x07;
+
+/// Returns `true` if the input contains any taproot field.
+#[cfg(feature = "sender")]
+pub(crate) fn input_has_tap_fields(input: &psbt::Input) -> bool {
+    input.unknown.keys().any(|key| PSBT_IN_TAP_FIELDS.contains(&key.type_value))
+}
+
+/// Returns `true` if the input contains taproot key paths.
+#[cfg(feature = "sender")]
+pub(crate) fn input_has_tap_key_paths(input: &psbt::Input) -> bool {
+    input.unknown.keys().any(|key| key.type_value == PSBT_IN_TAP_BIP32_DERIVATION)
+}
+
+/// Returns `true` if the output contains taproot key paths.
+#[cfg(feature = "sender")]
+pub(crate) fn output_has_tap_key_paths(output: &psbt::Output) -> bool {
+    output.unknown.keys().any(|key| key.type_value == PSBT_OUT_TAP_BIP32_DERIVATION)
+}
+
+/// Removes the taproot fields of the input - signatures, internal key, scripts and merkle paths.
+#[cfg(feature = "receiver")]
+pub(crate) fn strip_input_tap_fields(input: &mut psbt::Input) {
+    input.unknown.retain(|key, _| !PSBT_IN_TAP_FIELDS.contains(&key.type_value));
+}
+
+/// Copies the taproot fields of `from` other than signatures, they would be invalid for another
+/// transaction.
+#[cfg(feature = "sender")]
+pub(crate) fn restore_input_tap_fields(to: &mut psbt::Input, from: &psbt::Input) {
+    for (key, value) in from.unknown.iter().filter(|(key, _)| PSBT_IN_TAP_METADATA.contains(&key.type_value)) {
+        to.unknown.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+}
+
+/// Copies the taproot fields of `from`.
+#[cfg(feature = "sender")]
+pub(crate) fn restore_output_tap_fields(to: &mut psbt::Output, from: &psbt::Output) {
+    for (key, value) in from.unknown.iter().filter(|(key, _)| PSBT_OUT_TAP_FIELDS.contains(&key.type_value)) {
+        to.unknown.entry(key.clone()).or_insert_with(|| value.clone());
+    }
+}
+
+/// Removes all fields not required by BIP78 from the proposal PSBT.
+///
+/// Inputs of the sender lose UTXO information and signatures as required by the specification.
+/// Receiver inputs are finalized so only the final scripts and the spent output are kept - full
+/// previous transaction is dropped if the witness output is available. Taproot fields (BIP371)
+/// are removed from all inputs and outputs, they would reveal internal keys and merkle paths of
+/// the receiver and the sender has its own in the original PSBT.
+#[cfg(any(test, feature = "receiver"))]
+pub(crate) fn minimize_proposal(psbt: &mut Psbt, is_sender_input: impl Fn(&bitcoin::OutPoint) -> bool) {
+    psbt.global.xpub.clear();
+    psbt.global.proprietary.clear();
+    psbt.global.unknown.clear();
+    for (txin, input) in psbt.global.unsigned_tx.input.iter().zip(&mut psbt.inputs) {
+        let is_sender = is_sender_input(&txin.previous_output);
+        let mut minimized = psbt::Input::default();
+        if !is_sender {
+            minimized.final_script_sig = input.final_script_sig.take();
+            minimized.final_script_witness = input.final_script_witness.take();
+            minimized.witness_utxo = input.witness_utxo.take();
+            if minimized.witness_utxo.is_none() {
+                minimized.non_witness_utxo = input.non_witness_utxo.take();
+            }
+        }
+        *input = minimized;
+    }
+    for output in &mut psbt.outputs {
+        *output = Default::default();
+    }
+}
+
 pub(crate) struct InputPair<'a> {
     pub txin: &'a TxIn,
     pub psbtin: &'a psbt::Input,
@@ -73,6 +231,35 @@ impl<'a> InputPair<'a> {
         }
     }
 
+    /// Returns sighash flags of the signatures in final scripts of the input.
+    ///
+    /// Signatures are recognized by their shape: DER-encoded ECDSA signatures anywhere and Schnorr
+    /// signatures in key path spends. Schnorr signatures without the flag byte commit to the same
+    /// data as `SIGHASH_ALL` so they are reported as such.
+    #[cfg(any(feature = "sender", feature = "receiver"))]
+    pub fn final_sighash_flags(&self) -> Vec<u8> {
+        use bitcoin::blockdata::script::Instruction;
+        use bitcoin::blockdata::transaction::SigHashType;
+
+        let is_der_signature = |item: &[u8]| item.len() >= 9 && item.len() <= 73 && item[0] == 0x30 && usize::from(item[1]) == item.len() - 3;
+        let mut flags = Vec::new();
+        if let Some(script_sig) = &self.psbtin.final_script_sig {
+            for instruction in script_sig.instructions() {
+                match instruction {
+                    Ok(Instruction::PushBytes(item)) if is_der_signature(item) => flags.push(item[item.len() - 1]),
+                    _ => (),
+                }
+            }
+        }
+        if let Some(witness) = &self.psbtin.final_script_witness {
+            match &**witness {
+                [signature] if signature.len() == 64 => flags.push(SigHashType::All as u8),
+                [signature] if signature.len() == 65 => flags.push(signature[64]),
+                items => flags.extend(items.iter().filter(|item| is_der_signature(item)).map(|item| item[item.len() - 1])),
+            }
+        }
+        flags
+    }
 }
 
 #[derive(Debug)]
@@ -143,3 +330,34 @@ impl std::error::Error for PsbtInputsError {
         Some(&self.error)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn final_txid() {
+        use bitcoin::consensus::deserialize;
+
+        let original = crate::testing::original_psbt();
+        let txid = super::final_txid(&original).unwrap();
+        assert_eq!(txid, original.clone().extract_tx().txid());
+        // the input of the sender is nested SegWit
+        assert_ne!(txid, original.global.unsigned_tx.txid());
+
+        let proposal = deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        assert_eq!(super::final_txid(&proposal), Err(NotFinalized { index: 0, }));
+    }
+
+    #[test]
+    fn to_json() {
+        let proposal = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let json = super::to_json(&proposal);
+        let value = serde_json::from_str::<serde_json::Value>(&json).unwrap();
+        assert_eq!(value["txid"], proposal.global.unsigned_tx.txid().to_string());
+        assert_eq!(value["inputs"][0]["finalScriptSig"], serde_json::Value::Null);
+        assert_eq!(value["inputs"][1]["value"], 2_000_000);
+        assert_eq!(value["inputs"][1]["finalScriptWitness"].as_array().unwrap().len(), 2);
+        assert_eq!(value["outputs"][1]["scriptPubKey"], "a914774096dbcf486743c22f4347e9b469febe8b677a87");
+    }
+}
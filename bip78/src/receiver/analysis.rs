@@ -0,0 +1,59 @@
+//! Describing proposals before they are sent
+//!
+//! Whether payjoining a payment is worth it depends on what it costs and what it does to the
+//! privacy of both parties. `Proposal::analyze()` estimates the properties of the signed
+//! proposal, `PayjoinReceiver::dry_run()` uses it to preview the proposal it would send without
+//! locking or signing anything.
+
+use bitcoin::{Amount, SignedAmount};
+
+/// Properties of the proposal once the contributed inputs are signed.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ProposalAnalysis {
+    /// Number of inputs contributed by the receiver.
+    pub contributed_inputs: usize,
+    /// Fee of the proposal.
+    pub fee: Amount,
+    /// Fee of the original transaction.
+    pub original_fee: Amount,
+    /// Part of the fee increase paid by the sender from its fee contribution.
+    pub sender_contribution: Amount,
+    /// Part of the fee increase paid by the receiver.
+    pub receiver_fee: Amount,
+    /// Expected weight of the signed transaction.
+    ///
+    /// Inputs of the receiver are assumed to have the typical size of the input type of the
+    /// sender, unsigned size is used if it's not known.
+    pub weight: u64,
+    /// Expected fee rate in sat/kvB.
+    pub fee_rate: u64,
+    /// `true` if the transaction exhibits the unnecessary input heuristic (UIH2), see
+    /// `Candidate::has_unnecessary_input()`.
+    pub has_unnecessary_input: bool,
+}
+
+/// Value moved by each party, see `Proposal::accounting()`.
+///
+/// A payjoin breaks the naive accounting of both wallets: the transaction spends inputs of the
+/// receiver, so "value of my inputs minus value of my outputs" no longer equals the payment.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct Accounting {
+    pub sender: PartyAmounts,
+    pub receiver: PartyAmounts,
+}
+
+/// Amounts of one party of the proposal.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct PartyAmounts {
+    /// Value of the inputs of this party.
+    pub gross_sent: Amount,
+    /// Decrease of the balance of this party (inputs minus outputs), negative for the receiver.
+    pub net_sent: SignedAmount,
+    /// Value of the inputs of the other party.
+    pub counterparty_contribution: Amount,
+    /// Part of the fee paid by this party.
+    pub fee_paid: Amount,
+}
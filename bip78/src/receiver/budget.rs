@@ -0,0 +1,206 @@
+//! How many inputs the receiver can afford to contribute
+//!
+//! Every contributed input costs fee. The sender may pay part of it (`maxadditionalfeecontribution`)
+//! but it also requires a minimum fee rate (`minfeerate`) so adding inputs the receiver doesn't
+//! pay for enough eventually gets the proposal rejected. `ContributionBudget` finds how far the
+//! receiver can go.
+
+use bitcoin::Amount;
+use crate::fee_rate::FeeRate;
+use crate::weight::Weight;
+use crate::FeeShare;
+
+/// Fee parameters of the sender and the original transaction, see `Proposal::sender_params()`.
+#[derive(Debug, Copy, Clone)]
+pub struct SenderParams {
+    pub(crate) max_fee_contribution: Amount,
+    pub(crate) min_fee_rate: Option<FeeRate>,
+    pub(crate) original_fee: Amount,
+    pub(crate) original_fee_rate: FeeRate,
+    pub(crate) original_weight: Weight,
+    pub(crate) input_weight: Option<Weight>,
+}
+
+impl SenderParams {
+    /// Fee the sender pays for inputs of the receiver at most, zero if it didn't offer any.
+    pub fn max_fee_contribution(&self) -> Amount {
+        self.max_fee_contribution
+    }
+
+    /// Minimum fee rate of the proposal in sat/kvB required by the sender.
+    pub fn min_fee_rate(&self) -> Option<u64> {
+        self.min_fee_rate.map(|rate| rate.to_sat_per_kwu().saturating_mul(4))
+    }
+
+    /// Fee rate of the original transaction in sat/kvB.
+    ///
+    /// Whole sat/vB would be too coarse, the sender checks the exact rate.
+    pub fn original_fee_rate(&self) -> u64 {
+        self.original_fee_rate.to_sat_per_kwu().saturating_mul(4)
+    }
+
+    /// Expected weight of an input of the same type as the inputs of the sender.
+    ///
+    /// `None` if the type is not supported for contributions.
+    pub fn expected_input_weight(&self) -> Option<u64> {
+        self.input_weight.map(u64::from)
+    }
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Step {
+    weight: Weight,
+    fee: Amount,
+    sender_contribution: Amount,
+}
+
+/// Costs of contributing the candidate inputs.
+///
+/// The candidates are contributed in the given order so the budget describes the first
+/// `max_inputs()` of them.
+#[derive(Debug, Clone)]
+pub struct ContributionBudget {
+    /// Totals after contributing each candidate the sender would accept.
+    steps: Vec<Step>,
+}
+
+impl ContributionBudget {
+    /// Computes the budget for contributing `candidates` (expected weights of the inputs).
+    ///
+    /// `fee_rate` in sat/kvB is the fee rate the receiver pays for its inputs, usually
+    /// `SenderParams::original_fee_rate()`. The sender pays as much of the fee as the BIP78 checks
+    /// of the sender allow: at most its maximum contribution and at most the original fee rate
+    /// times the added weight. Candidates after the first one that would lower the fee rate of
+    /// the proposal below the minimum of the sender are not included.
+    pub fn compute(sender_params: &SenderParams, fee_rate: u64, candidates: impl IntoIterator<Item=u64>) -> Self {
+        ContributionBudget::compute_with_fee_share(sender_params, fee_rate, FeeShare::default(), candidates)
+    }
+
+    /// Like `compute()` but the sender pays at most `fee_share` of the fee at the original fee
+    /// rate, see `ReceiverOptions::fee_share()`.
+    pub fn compute_with_fee_share(sender_params: &SenderParams, fee_rate: u64, fee_share: FeeShare, candidates: impl IntoIterator<Item=u64>) -> Self {
+        let fee_rate = FeeRate::from_sat_per_kwu(fee_rate / 4);
+        let mut steps = Vec::new();
+        let mut weight = Weight::ZERO;
+        for candidate in candidates {
+            weight += Weight::manual_from_u64(candidate);
+            let fee = fee_rate * weight;
+            if let Some(min_fee_rate) = sender_params.min_fee_rate {
+                if (sender_params.original_fee + fee) / (sender_params.original_weight + weight) < min_fee_rate {
+                    break;
+                }
+            }
+            let sender_contribution = fee_share.sender_part(sender_params.original_fee_rate * weight)
+                .min(sender_params.max_fee_contribution)
+                .min(fee);
+            steps.push(Step { weight, fee, sender_contribution, });
+        }
+        ContributionBudget {
+            steps,
+        }
+    }
+
+    /// Number of candidates that can be contributed without the sender rejecting the proposal.
+    pub fn max_inputs(&self) -> usize {
+        self.steps.len()
+    }
+
+    /// Like `max_inputs()` but also keeps the fee paid by the receiver at most `max_receiver_fee`.
+    pub fn max_inputs_within(&self, max_receiver_fee: Amount) -> usize {
+        self.steps
+            .iter()
+            .take_while(|step| step.fee - step.sender_contribution <= max_receiver_fee)
+            .count()
+    }
+
+    /// Total weight of the first `inputs` candidates, `None` if more than `max_inputs()`.
+    pub fn weight(&self, inputs: usize) -> Option<u64> {
+        self.step(inputs).map(|step| u64::from(step.weight))
+    }
+
+    /// Fee the sender pays for the first `inputs` candidates, `None` if more than `max_inputs()`.
+    pub fn sender_contribution(&self, inputs: usize) -> Option<Amount> {
+        self.step(inputs).map(|step| step.sender_contribution)
+    }
+
+    /// Fee the receiver pays for the first `inputs` candidates, `None` if more than `max_inputs()`.
+    pub fn receiver_fee(&self, inputs: usize) -> Option<Amount> {
+        self.step(inputs).map(|step| step.fee - step.sender_contribution)
+    }
+
+    fn step(&self, inputs: usize) -> Option<Step> {
+        match inputs {
+            0 => Some(Step { weight: Weight::ZERO, fee: Amount::ZERO, sender_contribution: Amount::ZERO, }),
+            _ => self.steps.get(inputs - 1).copied(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn params(max_fee_contribution: u64, min_fee_rate: Option<u64>) -> SenderParams {
+        SenderParams {
+            max_fee_contribution: Amount::from_sat(max_fee_contribution),
+            min_fee_rate: min_fee_rate.map(FeeRate::from_sat_per_vb),
+            // 2 sat/vB
+            original_fee: Amount::from_sat(1000),
+            original_fee_rate: FeeRate::from_sat_per_vb(2),
+            original_weight: Weight::manual_from_u64(2000),
+            input_weight: Some(Weight::manual_from_u64(272)),
+        }
+    }
+
+    #[test]
+    fn sender_contribution() {
+        let budget = ContributionBudget::compute(&params(200, None), 2000, vec![272; 3]);
+        assert_eq!(budget.max_inputs(), 3);
+        // 136 sat per input, the sender pays for the first one and part of the second one
+        assert_eq!(budget.sender_contribution(1), Some(Amount::from_sat(136)));
+        assert_eq!(budget.receiver_fee(1), Some(Amount::ZERO));
+        assert_eq!(budget.receiver_fee(2), Some(Amount::from_sat(72)));
+        assert_eq!(budget.receiver_fee(3), Some(Amount::from_sat(208)));
+        assert_eq!(budget.weight(3), Some(816));
+        assert_eq!(budget.receiver_fee(4), None);
+        assert_eq!(budget.max_inputs_within(Amount::ZERO), 1);
+        assert_eq!(budget.max_inputs_within(Amount::from_sat(100)), 2);
+    }
+
+    #[test]
+    fn min_fee_rate() {
+        // paying 1 sat/vB for the inputs lowers the fee rate of the proposal
+        let budget = ContributionBudget::compute(&params(0, Some(2)), 1000, vec![272; 3]);
+        assert_eq!(budget.max_inputs(), 0);
+        let budget = ContributionBudget::compute(&params(0, Some(1)), 1000, vec![272; 3]);
+        assert_eq!(budget.max_inputs(), 3);
+        assert_eq!(budget.max_inputs_within(Amount::from_sat(136)), 2);
+    }
+
+    #[test]
+    fn fee_share() {
+        let half = FeeShare::from_percent(50).unwrap();
+        let budget = ContributionBudget::compute_with_fee_share(&params(200, None), 2000, half, vec![272; 2]);
+        assert_eq!(budget.sender_contribution(1), Some(Amount::from_sat(68)));
+        assert_eq!(budget.receiver_fee(1), Some(Amount::from_sat(68)));
+        assert_eq!(budget.sender_contribution(2), Some(Amount::from_sat(136)));
+        assert_eq!(budget.receiver_fee(2), Some(Amount::from_sat(136)));
+        // the sender pays at most its maximum contribution
+        let budget = ContributionBudget::compute_with_fee_share(&params(100, None), 2000, half, vec![272; 2]);
+        assert_eq!(budget.sender_contribution(2), Some(Amount::from_sat(100)));
+    }
+
+    #[test]
+    fn fee_rates() {
+        let mut params = params(0, Some(1));
+        assert_eq!(params.original_fee_rate(), 2000);
+        assert_eq!(params.min_fee_rate(), Some(1000));
+        params.original_fee_rate = FeeRate::from_sat_per_kwu(501);
+        assert_eq!(params.original_fee_rate(), 2004);
+        // rates restored from snapshots aren't validated
+        params.original_fee_rate = FeeRate::from_sat_per_kwu(u64::MAX);
+        params.min_fee_rate = Some(FeeRate::from_sat_per_kwu(u64::MAX));
+        assert_eq!(params.original_fee_rate(), u64::MAX);
+        assert_eq!(params.min_fee_rate(), Some(u64::MAX));
+    }
+}
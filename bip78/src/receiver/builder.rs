@@ -0,0 +1,1623 @@
+//! Processing whole requests with a single call
+//!
+//! The stages in the parent module give you full control but most receivers perform the same
+//! checks in the same order. `PayjoinReceiver` does that for you: it runs the checks, locks the
+//! inputs of the sender, contributes an input, signs it and produces the HTTP response. You only
+//! implement the traits connecting it to your node and wallet.
+
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bitcoin::{OutPoint, Script, Transaction};
+use super::error::{InternalCheckError, InternalContributionError};
+use super::{Metrics, Stage, Headers, RequestMeta, ContributionBudget, InvoiceStatus, InvoiceAction, InvoicePolicy, CheckError, ResponseCache, FallbackScheduler, FallbackDelay, ContributionMonitor, ModeSwitch, ReceiverMode, ProbingGuard, ProposalAnalysis, UnlockedProposal, PaymentRequestStore, PrevoutStatus, ProposalScorer, DefaultScorer, Candidate, ReceiverOptions, StageTimeouts, BumpFeePolicy, OpReturnPolicy, InputScriptType, UncheckedProposal, Proposal, ErrorCode};
+use super::coordination::{self, Coordinator, BoxedCoordinator};
+use crate::time::{Clock, Deadline, SystemClock};
+
+type BoxError = Box<dyn Error + Send + Sync>;
+type Candidates = Vec<(OutPoint, psbt::Input)>;
+type SharedClock = Arc<dyn Clock + Send + Sync>;
+type SharedCoordinator = (Box<dyn Coordinator<Error=BoxError> + Send + Sync>, Duration);
+type Invoices = (Box<dyn Fn(&Script) -> Result<InvoiceStatus, BoxError> + Send + Sync>, InvoicePolicy);
+type LocalSource = Box<dyn Fn() -> Result<Candidates, BoxError> + Send + Sync>;
+type LocalSigner = Box<dyn Fn(&Psbt, usize) -> Result<psbt::Input, BoxError> + Send + Sync>;
+type RemoteFetch = Box<dyn Fn(&Psbt, Deadline) -> Result<Candidates, BoxError> + Send + Sync>;
+type RemoteSign = Box<dyn Fn(&Psbt, &[usize], Deadline) -> Result<Vec<psbt::Input>, BoxError> + Send + Sync>;
+
+/// Checks of the original transaction that need your node.
+pub trait OriginalChecks {
+    type Error: Into<BoxError>;
+
+    /// See `PrevoutStatusProvider`.
+    fn prevout_status(&self, outpoint: &OutPoint) -> Result<PrevoutStatus, Self::Error>;
+
+    /// Returns `true` if the transaction would be accepted to the mempool (`testmempoolaccept`).
+    fn can_broadcast(&self, transaction: &Transaction) -> Result<bool, Self::Error>;
+
+    /// Remembers the inputs of the sender so that they can't be used in another request.
+    ///
+    /// Returns `false` if any of them is already locked. Without this check the sender could
+    /// learn the UTXOs of the receiver by sending the same original transaction repeatedly.
+    fn lock_inputs(&self, outpoints: &[OutPoint]) -> Result<bool, Self::Error>;
+}
+
+/// Provides inputs of the receiver that may be contributed.
+///
+/// The returned inputs must contain UTXO information and shouldn't be contributed to other
+/// proposals at the same time. Closures returning `Result<Vec<(OutPoint, psbt::Input)>, E>`
+/// implement this trait.
+pub trait UtxoSource {
+    type Error: Into<BoxError>;
+
+    fn candidates(&self) -> Result<Vec<(OutPoint, psbt::Input)>, Self::Error>;
+}
+
+impl<E, F> UtxoSource for F where F: Fn() -> Result<Vec<(OutPoint, psbt::Input)>, E>, E: Into<BoxError> {
+    type Error = E;
+
+    fn candidates(&self) -> Result<Vec<(OutPoint, psbt::Input)>, Self::Error> {
+        self()
+    }
+}
+
+/// Where the contributed inputs come from and who signs them.
+enum Wallet {
+    Local(LocalSource, LocalSigner),
+    /// The timeout covers fetching the candidates and signing together.
+    Remote(RemoteFetch, RemoteSign, Duration),
+}
+
+impl Wallet {
+    /// Returns the candidates for the proposal whose contribution started at `started`.
+    ///
+    /// `stage` is the deadline of the stage, remote sources get the earlier of the deadlines.
+    fn candidates(&self, clock: &dyn Clock, original: &Psbt, started: SystemTime, stage: Option<Deadline>) -> Result<Candidates, super::ContributionError> {
+        match self {
+            Wallet::Local(source, _) => source().map_err(|error| InternalContributionError::WalletUnavailable(error).into()),
+            Wallet::Remote(source, _, timeout) => {
+                let deadline = earliest(Deadline::at(started + *timeout), stage);
+                let candidates = source(original, deadline).map_err(InternalContributionError::WalletUnavailable)?;
+                if deadline.is_expired(&clock) {
+                    return Err(InternalContributionError::DeadlineExpired.into());
+                }
+                Ok(candidates)
+            },
+        }
+    }
+
+    fn sign(&self, proposal: &mut Proposal, started: SystemTime, stage: Option<Deadline>) -> Result<(), super::SigningError> {
+        match self {
+            Wallet::Local(_, signer) => proposal.sign_contributed_inputs(signer),
+            Wallet::Remote(_, signer, timeout) => proposal.sign_contributed_inputs_remotely(signer, earliest(Deadline::at(started + *timeout), stage)),
+        }
+    }
+}
+
+fn earliest(deadline: Deadline, other: Option<Deadline>) -> Deadline {
+    other.map_or(deadline, |other| other.min(deadline))
+}
+
+/// Deadlines of the stages of one request, see `StageTimeouts`.
+struct Stages<'a> {
+    clock: &'a dyn Clock,
+    timeouts: StageTimeouts,
+    total: Option<Deadline>,
+}
+
+impl<'a> Stages<'a> {
+    fn start(clock: &'a dyn Clock, timeouts: StageTimeouts) -> Self {
+        Stages {
+            clock,
+            timeouts,
+            total: timeouts.total.map(|total| Deadline::after(&clock, total)),
+        }
+    }
+
+    /// Deadline of a stage starting now, capped by the deadline of the whole request.
+    fn deadline(&self, budget: Option<Duration>) -> Option<Deadline> {
+        match budget.map(|budget| Deadline::after(&self.clock, budget)) {
+            Some(deadline) => Some(earliest(deadline, self.total)),
+            None => self.total,
+        }
+    }
+
+    fn is_expired(&self, deadline: Option<Deadline>) -> bool {
+        matches!(deadline, Some(deadline) if deadline.is_expired(&self.clock))
+    }
+}
+
+/// How the input is contributed.
+pub struct Strategy(InternalStrategy);
+
+enum InternalStrategy {
+    BestInput,
+    Decoy(Box<dyn Fn() -> Result<Script, BoxError> + Send + Sync>),
+}
+
+impl Strategy {
+    /// Contributes the candidate with the best score, see `Proposal::contribute_best_input()`.
+    ///
+    /// The input is merged into the payee output instead of creating change, so the payjoin has
+    /// the classic shape of the original outputs with one more input. The output grows by the
+    /// value of the input minus the part of its fee not covered by the contribution of the
+    /// sender - by exactly the value of the input if the sender covers it, as in the BIP78 test
+    /// vector. This is the default.
+    pub fn best_input() -> Self {
+        Strategy(InternalStrategy::BestInput)
+    }
+
+    /// Contributes the first suitable candidate along with a decoy output.
+    ///
+    /// `new_script` is called for each proposal and should return a fresh script of the receiver.
+    /// See `Proposal::contribute_input_with_decoy()`.
+    pub fn decoy<E: Into<BoxError>>(new_script: impl Fn() -> Result<Script, E> + Send + Sync + 'static) -> Self {
+        Strategy(InternalStrategy::Decoy(Box::new(move || new_script().map_err(Into::into))))
+    }
+}
+
+impl Default for Strategy {
+    fn default() -> Self {
+        Strategy::best_input()
+    }
+}
+
+/// Request received by the HTTP server.
+pub struct Request<'a, H: Headers> {
+    pub body: &'a [u8],
+    /// Query string of the URL without `?`.
+    pub query: &'a str,
+    pub headers: H,
+    /// Script of the URI whose endpoint received the request.
+    ///
+    /// See `UncheckedProposal::check_pays_issued_script()`.
+    pub issued_script: &'a Script,
+    /// Information about the connection, see `PayjoinReceiverBuilder::onion_only()`.
+    pub meta: RequestMeta,
+}
+
+/// Response that should be sent to the sender.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Response {
+    /// HTTP status code.
+    pub status: u16,
+    /// Body of the response - base64-encoded PSBT (`text/plain`) if the status is 200, JSON error
+    /// (`application/json`) otherwise.
+    pub body: Vec<u8>,
+    /// The original transaction if it passed the checks.
+    ///
+    /// Broadcast it if the payjoin transaction doesn't appear in your mempool within a minute or
+    /// so - see `Proposal::export_fallback_package()`.
+    pub fallback: Option<Transaction>,
+    /// How long to wait before sending the response.
+    ///
+    /// Always zero unless `ErrorPolicy::Decoy` is used. Wait without blocking other requests.
+    pub delay: Duration,
+}
+
+impl Response {
+    fn error(error_code: ErrorCode, json: String, fallback: Option<Transaction>) -> Self {
+        Response {
+            status: if error_code == ErrorCode::Unavailable { 503 } else { 400 },
+            body: json.into_bytes(),
+            fallback,
+            delay: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Proposal that `PayjoinReceiver::process()` would send, see `PayjoinReceiver::dry_run()`.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DryRun {
+    /// The proposal with unsigned contributed inputs.
+    pub psbt: Psbt,
+    /// Inputs the receiver would contribute, empty if it wouldn't contribute any.
+    pub contributed: Vec<OutPoint>,
+    pub analysis: ProposalAnalysis,
+}
+
+/// Builder of `PayjoinReceiver`, see `PayjoinReceiver::builder()`.
+///
+/// `build()` is only available after `checks()` was called.
+pub struct PayjoinReceiverBuilder<C> {
+    checks: C,
+    wallet: Option<Wallet>,
+    payment_requests: Option<Box<dyn PaymentRequestStore + Send + Sync>>,
+    invoices: Option<Invoices>,
+    scorer: Box<dyn ProposalScorer + Send + Sync>,
+    strategy: Strategy,
+    options: ReceiverOptions,
+    cache: Option<ResponseCache>,
+    fallback: Option<(Arc<FallbackScheduler>, FallbackDelay)>,
+    monitor: Option<Arc<ContributionMonitor>>,
+    coordinator: Option<SharedCoordinator>,
+    mode: ModeSwitch,
+    guard: Option<ProbingGuard>,
+    check_order: Vec<Stage>,
+    metrics: Box<dyn Metrics + Send + Sync>,
+    clock: SharedClock,
+}
+
+impl<C> PayjoinReceiverBuilder<C> {
+    /// Sets the checks of the original transaction. Required.
+    pub fn checks<D: OriginalChecks>(self, checks: D) -> PayjoinReceiverBuilder<D> {
+        PayjoinReceiverBuilder {
+            checks,
+            wallet: self.wallet,
+            payment_requests: self.payment_requests,
+            invoices: self.invoices,
+            scorer: self.scorer,
+            strategy: self.strategy,
+            options: self.options,
+            cache: self.cache,
+            fallback: self.fallback,
+            monitor: self.monitor,
+            coordinator: self.coordinator,
+            mode: self.mode,
+            guard: self.guard,
+            check_order: self.check_order,
+            metrics: self.metrics,
+            clock: self.clock,
+        }
+    }
+
+    /// Contributes inputs from `source` signed by `signer`.
+    ///
+    /// Without a source the original transaction is sent back unchanged which is still a valid
+    /// (if pointless) response.
+    pub fn utxo_source<S, G>(mut self, source: S, signer: G) -> Self where S: UtxoSource + Send + Sync + 'static, G: super::InputSigner + Send + Sync + 'static {
+        self.wallet = Some(Wallet::Local(
+            Box::new(move || source.candidates().map_err(Into::into)),
+            Box::new(move |psbt: &Psbt, index| signer.sign_input(psbt, index).map_err(Into::into)),
+        ));
+        self
+    }
+
+    /// Contributes inputs fetched from a remote service and signed by it, see `RemoteUtxoSource`.
+    ///
+    /// Fetching the candidates and signing them has to finish within `timeout` after the
+    /// receiver starts contributing, otherwise the request fails with `unavailable`. Choose it
+    /// well below the time senders wait for the response.
+    pub fn remote_utxo_source<S, G>(mut self, source: S, signer: G, timeout: Duration) -> Self where S: super::RemoteUtxoSource + Send + Sync + 'static, G: super::RemoteSigner + Send + Sync + 'static {
+        self.wallet = Some(Wallet::Remote(
+            Box::new(move |original: &Psbt, deadline| source.fetch_candidates(original, deadline).map_err(Into::into)),
+            Box::new(move |psbt: &Psbt, indices: &[usize], deadline| signer.sign_inputs(psbt, indices, deadline).map_err(Into::into)),
+            timeout,
+        ));
+        self
+    }
+
+    /// Checks the amount using `store`, see `UncheckedProposal::check_payment_request()`.
+    pub fn payment_requests(mut self, store: impl PaymentRequestStore + Send + Sync + 'static) -> Self {
+        self.payment_requests = Some(Box::new(store));
+        self
+    }
+
+    /// Decides whether to contribute, respond with the unchanged original or reject based on the
+    /// status of the invoice paid by the issued script.
+    ///
+    /// Without a provider all invoices are treated as fresh.
+    pub fn invoice_status(mut self, provider: impl super::InvoiceStatusProvider + Send + Sync + 'static, policy: InvoicePolicy) -> Self {
+        self.invoices = Some((Box::new(move |script_pubkey: &Script| provider.invoice_status(script_pubkey).map_err(Into::into)), policy));
+        self
+    }
+
+    /// Scores the candidates for `Strategy::best_input()`, defaults to `DefaultScorer`.
+    pub fn scorer(mut self, scorer: impl ProposalScorer + Send + Sync + 'static) -> Self {
+        self.scorer = Box::new(scorer);
+        self
+    }
+
+    /// See `Strategy`.
+    pub fn strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// See `ReceiverOptions::dust_limit()`.
+    pub fn dust_limit(mut self, dust_limit: bitcoin::Amount) -> Self {
+        self.options = self.options.dust_limit(dust_limit);
+        self
+    }
+
+    /// See `ReceiverOptions::bump_fee_policy()`.
+    pub fn bump_policy(mut self, policy: BumpFeePolicy) -> Self {
+        self.options = self.options.bump_fee_policy(policy);
+        self
+    }
+
+    /// Answers repeated requests from `cache` instead of processing them again.
+    ///
+    /// Without a cache a sender retrying the request gets an error because its inputs are
+    /// already locked.
+    pub fn response_cache(mut self, cache: ResponseCache) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Schedules the original transaction of each proposal that passed the checks in `scheduler`
+    /// to be broadcasted after `delay`.
+    ///
+    /// The scheduler can be shared by multiple receivers. `Response::fallback` is still set.
+    pub fn fallback_scheduler(mut self, scheduler: Arc<FallbackScheduler>, delay: FallbackDelay) -> Self {
+        self.fallback = Some((scheduler, delay));
+        self
+    }
+
+    /// Watches the contributed inputs of each signed proposal using `monitor`.
+    pub fn contribution_monitor(mut self, monitor: Arc<ContributionMonitor>) -> Self {
+        self.monitor = Some(monitor);
+        self
+    }
+
+    /// Coordinates with other instances of the receiver using `coordinator`.
+    ///
+    /// Requests, inputs of senders and contributed inputs are claimed for `ttl` so that no two
+    /// instances use them at the same time, and responses are shared for `ttl` so that a retry
+    /// landing on another instance gets the same proposal. `ttl` should be at least as long as
+    /// the time before the original transaction is broadcasted. The local response cache is still
+    /// checked first.
+    pub fn coordinator(mut self, coordinator: impl Coordinator + Send + Sync + 'static, ttl: Duration) -> Self {
+        self.coordinator = Some((Box::new(BoxedCoordinator(coordinator)), ttl));
+        self
+    }
+
+    /// Controls the mode of the receiver with `switch`, e.g. one shared by all receivers.
+    ///
+    /// Each receiver has its own switch by default, see `PayjoinReceiver::mode_switch()`.
+    pub fn mode_switch(mut self, switch: ModeSwitch) -> Self {
+        self.mode = switch;
+        self
+    }
+
+    /// Hides the reasons of rejecting suspicious requests, see `ProbingGuard`.
+    pub fn probing_guard(mut self, guard: ProbingGuard) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    /// See `ReceiverOptions::allowed_sender_input_types()`.
+    pub fn allowed_sender_input_types(mut self, types: impl IntoIterator<Item=InputScriptType>) -> Self {
+        self.options = self.options.allowed_sender_input_types(types);
+        self
+    }
+
+    /// See `ReceiverOptions::op_return_policy()`.
+    pub fn op_return_policy(mut self, policy: OpReturnPolicy) -> Self {
+        self.options = self.options.op_return_policy(policy);
+        self
+    }
+
+    /// See `ReceiverOptions::accept_donations()`.
+    pub fn accept_donations(mut self, minimum: bitcoin::Amount) -> Self {
+        self.options = self.options.accept_donations(minimum);
+        self
+    }
+
+    /// See `ReceiverOptions::max_receiver_fee()`.
+    pub fn max_receiver_fee(mut self, max_receiver_fee: bitcoin::Amount) -> Self {
+        self.options = self.options.max_receiver_fee(max_receiver_fee);
+        self
+    }
+
+    /// See `ReceiverOptions::fee_share()`.
+    pub fn fee_share(mut self, fee_share: crate::FeeShare) -> Self {
+        self.options = self.options.fee_share(fee_share);
+        self
+    }
+
+    /// See `ReceiverOptions::timeouts()`.
+    pub fn timeouts(mut self, timeouts: StageTimeouts) -> Self {
+        self.options = self.options.timeouts(timeouts);
+        self
+    }
+
+    /// See `ReceiverOptions::onion_only()`.
+    pub fn onion_only(mut self, onion_only: bool) -> Self {
+        self.options = self.options.onion_only(onion_only);
+        self
+    }
+
+    /// See `ReceiverOptions::limits()`.
+    pub fn limits(mut self, limits: crate::Limits) -> Self {
+        self.options = self.options.limits(limits);
+        self
+    }
+
+    /// Changes the order of the checks of the original transaction.
+    ///
+    /// `Stage::PaymentRequest`, `Stage::SenderInputTypes`, `Stage::OpReturnOutputs`,
+    /// `Stage::Prevouts` and `Stage::Broadcastability` can be reordered. By default the cheap
+    /// checks run first and the node is called last, put the check rejecting most of your
+    /// requests first to fail faster. Stages missing from `order` run after the listed ones in
+    /// the default order, other stages are ignored. `Stage::IssuedScript` always runs first and
+    /// `Stage::LockInputs` last.
+    pub fn check_order(mut self, order: impl IntoIterator<Item=Stage>) -> Self {
+        let mut check_order = Vec::with_capacity(DEFAULT_CHECK_ORDER.len());
+        for stage in order.into_iter().chain(DEFAULT_CHECK_ORDER.iter().copied()) {
+            if DEFAULT_CHECK_ORDER.contains(&stage) && !check_order.contains(&stage) {
+                check_order.push(stage);
+            }
+        }
+        self.check_order = check_order;
+        self
+    }
+
+    /// Records the duration and the result of each check, see `check_order()`.
+    ///
+    /// Checks that weren't reached because an earlier one failed aren't recorded.
+    pub fn metrics(mut self, metrics: impl Metrics + Send + Sync + 'static) -> Self {
+        self.metrics = Box::new(metrics);
+        self
+    }
+
+    /// Sets the clock the deadlines of `timeouts()` and remote wallets and the durations reported
+    /// to `metrics()` are measured with.
+    ///
+    /// Defaults to `SystemClock`, use `testing::MockClock` in tests.
+    pub fn clock(mut self, clock: impl Clock + Send + Sync + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+}
+
+/// Checks of the original transaction `PayjoinReceiverBuilder::check_order()` can reorder, in
+/// the default order.
+const DEFAULT_CHECK_ORDER: [Stage; 5] = [Stage::PaymentRequest, Stage::SenderInputTypes, Stage::OpReturnOutputs, Stage::Prevouts, Stage::Broadcastability];
+
+impl<C: OriginalChecks> PayjoinReceiverBuilder<C> {
+    pub fn build(self) -> PayjoinReceiver<C> {
+        PayjoinReceiver {
+            checks: self.checks,
+            wallet: self.wallet,
+            payment_requests: self.payment_requests,
+            invoices: self.invoices,
+            scorer: self.scorer,
+            strategy: self.strategy,
+            options: self.options,
+            cache: self.cache,
+            fallback: self.fallback,
+            monitor: self.monitor,
+            coordinator: self.coordinator,
+            mode: self.mode,
+            guard: self.guard,
+            check_order: self.check_order,
+            metrics: self.metrics,
+            clock: self.clock,
+        }
+    }
+}
+
+/// Receiver processing whole requests.
+///
+/// ```
+/// use bip78::receiver::{PayjoinReceiver, OriginalChecks, PrevoutStatus, Request, RequestMeta};
+/// use bip78::bitcoin::{OutPoint, Transaction};
+///
+/// // In real code call your node
+/// struct Node;
+///
+/// impl OriginalChecks for Node {
+///     type Error = std::io::Error;
+///
+///     fn prevout_status(&self, _: &OutPoint) -> Result<PrevoutStatus, Self::Error> {
+///         Ok(PrevoutStatus::Unspent)
+///     }
+///
+///     fn can_broadcast(&self, _: &Transaction) -> Result<bool, Self::Error> {
+///         Ok(true)
+///     }
+///
+///     fn lock_inputs(&self, _: &[OutPoint]) -> Result<bool, Self::Error> {
+///         Ok(true)
+///     }
+/// }
+///
+/// let receiver = PayjoinReceiver::builder().checks(Node).build();
+///
+/// // In real code these come from your HTTP server and invoicing system
+/// let body = bip78::testing::ORIGINAL_PSBT.as_bytes();
+/// let headers = bip78::testing::MockHeaders::new(body.len() as u64);
+/// let issued_script = bip78::testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone();
+/// let meta = RequestMeta::default();
+/// let response = receiver.process(Request { body, query: "v=1", headers, issued_script: &issued_script, meta, });
+/// assert_eq!(response.status, 200);
+/// assert!(response.fallback.is_some());
+/// ```
+pub struct PayjoinReceiver<C> {
+    checks: C,
+    wallet: Option<Wallet>,
+    payment_requests: Option<Box<dyn PaymentRequestStore + Send + Sync>>,
+    invoices: Option<Invoices>,
+    scorer: Box<dyn ProposalScorer + Send + Sync>,
+    strategy: Strategy,
+    options: ReceiverOptions,
+    cache: Option<ResponseCache>,
+    fallback: Option<(Arc<FallbackScheduler>, FallbackDelay)>,
+    monitor: Option<Arc<ContributionMonitor>>,
+    coordinator: Option<SharedCoordinator>,
+    mode: ModeSwitch,
+    guard: Option<ProbingGuard>,
+    check_order: Vec<Stage>,
+    metrics: Box<dyn Metrics + Send + Sync>,
+    clock: SharedClock,
+}
+
+impl PayjoinReceiver<()> {
+    pub fn builder() -> PayjoinReceiverBuilder<()> {
+        PayjoinReceiverBuilder {
+            checks: (),
+            wallet: None,
+            payment_requests: None,
+            invoices: None,
+            scorer: Box::new(DefaultScorer::default()),
+            strategy: Strategy::default(),
+            options: ReceiverOptions::default(),
+            cache: None,
+            fallback: None,
+            monitor: None,
+            coordinator: None,
+            mode: ModeSwitch::new(),
+            guard: None,
+            check_order: DEFAULT_CHECK_ORDER.to_vec(),
+            metrics: Box::new(()),
+            clock: Arc::new(SystemClock),
+        }
+    }
+}
+
+impl<C: OriginalChecks> PayjoinReceiver<C> {
+    /// Processes the request and returns the response.
+    ///
+    /// Send the response after `Response::delay`.
+    pub fn process<H: Headers>(&self, request: Request<'_, H>) -> Response {
+        let deadline = self.guard.as_ref().and_then(ProbingGuard::start);
+        let mut response = self.respond(request);
+        if let (Some(guard), Some(deadline)) = (&self.guard, deadline) {
+            if response.status != 200 {
+                response.delay = guard.delay(&deadline);
+            }
+        }
+        response
+    }
+
+    /// Builds the proposal `process()` would send without locking or signing anything.
+    ///
+    /// All checks except locking the inputs of the sender are performed and a candidate is
+    /// selected the same way, so the result shows whether and what the receiver would contribute
+    /// and at what cost. Claims of the coordinator are not taken either, the actual request may
+    /// select a different input if another instance contributes it meanwhile. Errors are the
+    /// responses `process()` would return, without the `ProbingGuard`.
+    pub fn dry_run<H: Headers>(&self, request: Request<'_, H>) -> Result<DryRun, Response> {
+        let proposal = UncheckedProposal::from_request_bytes_with_limits(request.body, request.query, request.headers, &self.options.limits)
+            .map_err(|error| Response::error(error.error_code(), error.to_json(), None))?;
+        let stages = Stages::start(&*self.clock, self.options.timeouts);
+        let (proposal, status, action) = self.check_unlocked(proposal, request.issued_script, &stages)
+            .map_err(|error| Response::error(error.error_code(), error.to_json(), None))?;
+        if let InvoiceAction::Reject { .. } = action {
+            let error = CheckError::invoice_rejected(status);
+            return Err(Response::error(error.error_code(), error.to_json(), None));
+        }
+        // the proposal is never sent so the inputs don't need to be locked
+        let mut proposal = proposal.assume_locked();
+        if let Some(wallet) = self.wallet.as_ref().filter(|_| self.should_contribute(&proposal, action == InvoiceAction::Contribute) && !stages.is_expired(stages.total)) {
+            let deadline = stages.deadline(stages.timeouts.candidates);
+            let candidates = wallet.candidates(stages.clock, &proposal.psbt, stages.clock.now(), deadline);
+            if !stages.is_expired(deadline) {
+                let candidates = candidates.map_err(|error| Response::error(error.error_code(), error.to_json(), None))?;
+                self.select_candidates(&mut proposal, candidates).map_err(|(error_code, json)| Response::error(error_code, json, None))?;
+            }
+        }
+        Ok(DryRun {
+            contributed: proposal.contributed_outpoints().copied().collect(),
+            analysis: proposal.analyze(),
+            psbt: proposal.psbt,
+        })
+    }
+
+    fn respond<H: Headers>(&self, request: Request<'_, H>) -> Response {
+        // before the cache so that it can't leak responses to rejected connections
+        if let Err(error) = self.options.check_request_meta(&request.meta) {
+            return Response {
+                status: error.http_status(),
+                body: error.to_json().into_bytes(),
+                fallback: None,
+                delay: Duration::from_secs(0),
+            };
+        }
+        if self.mode.mode() == ReceiverMode::Unavailable {
+            return self.check_error(InternalCheckError::Maintenance.into(), None);
+        }
+        let (body, query) = (request.body, request.query);
+        if let Some(response) = self.cache.as_ref().and_then(|cache| cache.get(body, query)) {
+            return response;
+        }
+        let response = match &self.coordinator {
+            Some((coordinator, ttl)) => self.process_coordinated(&**coordinator, *ttl, request),
+            None => self.process_uncached(request),
+        };
+        if let Some(cache) = &self.cache {
+            cache.insert(body, query, &response);
+        }
+        response
+    }
+
+    /// Returns the response shared by another instance or processes the request while holding
+    /// its claim.
+    fn process_coordinated<H: Headers>(&self, coordinator: &(dyn Coordinator<Error=BoxError> + Send + Sync), ttl: Duration, request: Request<'_, H>) -> Response {
+        let unavailable = |error: InternalCheckError| self.check_error(error.into(), None);
+        let hash = super::cache::hash_request(request.body, request.query);
+        let (response_key, request_key) = (format!("response:{}", hash), format!("request:{}", hash));
+        match coordinator.load(&response_key) {
+            Ok(Some(bytes)) => if let Some(response) = coordination::deserialize_response(&bytes) {
+                return response;
+            },
+            Ok(None) => (),
+            Err(error) => return unavailable(InternalCheckError::CoordinatorUnavailable(error)),
+        }
+        match coordinator.claim(&request_key, ttl) {
+            Ok(true) => (),
+            Ok(false) => return unavailable(InternalCheckError::RequestInProgress),
+            Err(error) => return unavailable(InternalCheckError::CoordinatorUnavailable(error)),
+        }
+        let response = self.process_uncached(request);
+        // temporary failures are not shared so that the retry is processed again
+        if response.status != 503 {
+            // if this fails the retry is rejected because the inputs are claimed
+            let _ = coordinator.store(&response_key, &coordination::serialize_response(&response), ttl);
+        }
+        let _ = coordinator.release(&request_key);
+        response
+    }
+
+    /// Returns the handle switching the mode of this receiver without restarting it.
+    ///
+    /// Clone it and pass it to your admin interface.
+    pub fn mode_switch(&self) -> &ModeSwitch {
+        &self.mode
+    }
+
+    /// Returns the cache set by `PayjoinReceiverBuilder::response_cache()`, e.g. to read its
+    /// statistics.
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.cache.as_ref()
+    }
+
+    fn process_uncached<H: Headers>(&self, request: Request<'_, H>) -> Response {
+        let stages = Stages::start(&*self.clock, self.options.timeouts);
+        let (proposal, status, action) = match self.check(request, &stages) {
+            Ok(checked) => checked,
+            Err(response) => return response,
+        };
+        let fallback = proposal.original_tx.clone();
+        if let Some((scheduler, delay)) = &self.fallback {
+            scheduler.schedule_transaction(fallback.clone(), delay);
+        }
+        if let InvoiceAction::Reject { .. } = action {
+            return self.check_error(CheckError::invoice_rejected(status), Some(fallback));
+        }
+        match self.contribute(proposal, action == InvoiceAction::Contribute, &stages) {
+            Ok(psbt) => Response {
+                status: 200,
+                body: base64::encode(bitcoin::consensus::serialize(&psbt)).into_bytes(),
+                fallback: Some(fallback),
+                delay: Duration::from_secs(0),
+            },
+            Err((error_code, json)) => Response::error(error_code, json, Some(fallback)),
+        }
+    }
+
+    fn check<H: Headers>(&self, request: Request<'_, H>, stages: &Stages) -> Result<(Proposal, InvoiceStatus, InvoiceAction), Response> {
+        let proposal = UncheckedProposal::from_request_bytes_with_limits(request.body, request.query, request.headers, &self.options.limits)
+            .map_err(|error| Response::error(error.error_code(), error.to_json(), None))?;
+        self.check_original(proposal, request.issued_script, stages)
+            .map_err(|error| self.check_error(error, None))
+    }
+
+    /// Returns the response to the failed check, disguised by `ProbingGuard` if set.
+    fn check_error(&self, error: CheckError, fallback: Option<Transaction>) -> Response {
+        let error = match &self.guard {
+            Some(guard) => guard.disguise(error),
+            None => error,
+        };
+        Response::error(error.error_code(), error.to_json(), fallback)
+    }
+
+    /// Performs all checks except locking the inputs, giving up after the deadline of the checks.
+    fn check_unlocked(&self, mut proposal: UncheckedProposal, issued_script: &Script, stages: &Stages) -> Result<(UnlockedProposal, InvoiceStatus, InvoiceAction), CheckError> {
+        let deadline = stages.deadline(stages.timeouts.checks);
+        let in_time = || if stages.is_expired(deadline) { Err(CheckError::from(InternalCheckError::ChecksTimedOut)) } else { Ok(()) };
+        proposal = self.measure(Stage::IssuedScript, || proposal.check_pays_issued_script(issued_script))?;
+        let (status, action) = match &self.invoices {
+            Some((provider, policy)) => proposal.invoice_action(&|script_pubkey: &Script| provider(script_pubkey), policy)?,
+            None => (InvoiceStatus::Fresh, InvoiceAction::Contribute),
+        };
+        // there's nothing to check if the original won't be broadcasted
+        if action == (InvoiceAction::Reject { broadcast: false, }) {
+            return Err(CheckError::invoice_rejected(status));
+        }
+        for &stage in &self.check_order {
+            proposal = match stage {
+                Stage::PaymentRequest => self.measure(stage, || self.check_payment(proposal, issued_script))?,
+                Stage::SenderInputTypes => self.measure(stage, || proposal.check_sender_input_types(&self.options))?,
+                Stage::OpReturnOutputs => self.measure(stage, || proposal.check_op_return_outputs(&self.options))?,
+                Stage::Prevouts => {
+                    let proposal = self.measure(stage, || proposal.check_prevouts_unspent(&|outpoint: &OutPoint| self.checks.prevout_status(outpoint)))?;
+                    in_time()?;
+                    proposal
+                },
+                Stage::Broadcastability => {
+                    let proposal = self.measure(stage, || {
+                        let can_broadcast = self.checks
+                            .can_broadcast(&proposal.get_transaction_to_check_broadcast())
+                            .map_err(|error| InternalCheckError::NodeUnavailable(error.into()))?;
+                        if can_broadcast {
+                            Ok(proposal)
+                        } else {
+                            Err(InternalCheckError::NotBroadcastable.into())
+                        }
+                    })?;
+                    in_time()?;
+                    proposal
+                },
+                // filtered by check_order()
+                _ => proposal,
+            };
+        }
+        Ok((proposal.assume_broadcastability_was_verified(), status, action))
+    }
+
+    /// Checks the amount against the registered payment request or the donation minimum.
+    fn check_payment(&self, proposal: UncheckedProposal, issued_script: &Script) -> Result<UncheckedProposal, CheckError> {
+        let registered = self.payment_requests.as_ref().and_then(|store| store.requested_amount(issued_script));
+        match (&self.payment_requests, self.options.donation_minimum) {
+            // donation links have no registered amount, the original decides it
+            (_, Some(minimum)) if registered.is_none() => proposal.check_donation(minimum),
+            (Some(store), _) => proposal.check_payment_request(&**store),
+            (None, _) => Ok(proposal),
+        }
+    }
+
+    /// Runs the check and records it in `metrics`.
+    fn measure<T>(&self, stage: Stage, check: impl FnOnce() -> Result<T, CheckError>) -> Result<T, CheckError> {
+        super::measure(&self.clock, &*self.metrics, stage, check)
+    }
+
+    fn check_original(&self, proposal: UncheckedProposal, issued_script: &Script, stages: &Stages) -> Result<(Proposal, InvoiceStatus, InvoiceAction), CheckError> {
+        let (proposal, status, action) = self.check_unlocked(proposal, issued_script, stages)?;
+        let outpoints = proposal.utxos_to_be_locked().copied().collect::<Vec<_>>();
+        self.measure(Stage::LockInputs, || {
+            let locked = self.checks
+                .lock_inputs(&outpoints)
+                .map_err(|error| InternalCheckError::NodeUnavailable(error.into()))?;
+            if !locked {
+                return Err(InternalCheckError::InputsLocked.into());
+            }
+            if let Some((coordinator, ttl)) = &self.coordinator {
+                let keys = outpoints.iter().map(|outpoint| format!("input:{}", outpoint)).collect::<Vec<_>>();
+                let claimed = claim_all(&**coordinator, &keys, *ttl).map_err(InternalCheckError::CoordinatorUnavailable)?;
+                if !claimed {
+                    return Err(InternalCheckError::InputsLocked.into());
+                }
+            }
+            Ok(())
+        })?;
+        Ok((proposal.assume_locked(), status, action))
+    }
+
+    fn contribute(&self, mut proposal: Proposal, contribute: bool, stages: &Stages) -> Result<Psbt, (ErrorCode, String)> {
+        let without_contribution = |mut proposal: Proposal| {
+            proposal.minimize_response();
+            Ok(proposal.psbt)
+        };
+        let wallet = match &self.wallet {
+            Some(wallet) if self.should_contribute(&proposal, contribute) && !stages.is_expired(stages.total) => wallet,
+            _ => return without_contribution(proposal),
+        };
+        let contribution_error = |error: super::ContributionError| (error.error_code(), error.to_json());
+        let started = stages.clock.now();
+        let deadline = stages.deadline(stages.timeouts.candidates);
+        let candidates = wallet.candidates(stages.clock, &proposal.psbt, started, deadline);
+        if stages.is_expired(deadline) {
+            return without_contribution(proposal);
+        }
+        let mut candidates = candidates.map_err(contribution_error)?;
+        // the sender would reject inputs of other types, the payment works without them
+        candidates.retain(|(outpoint, psbt_input)| proposal.matches_sender_input_type(*outpoint, psbt_input));
+        if candidates.is_empty() {
+            return without_contribution(proposal);
+        }
+        let original = proposal.clone();
+        let mut claimed = Vec::new();
+        if let Some((coordinator, ttl)) = &self.coordinator {
+            candidates = claim_candidates(&**coordinator, candidates, *ttl)
+                .map_err(|error| contribution_error(InternalContributionError::CoordinatorUnavailable(error).into()))?;
+            claimed = candidates.iter().map(|(outpoint, _)| *outpoint).collect();
+        }
+        let result = self.contribute_candidates(&mut proposal, candidates, wallet, started, stages);
+        let contributed = matches!(result, Ok(true));
+        if let Some((coordinator, _)) = &self.coordinator {
+            // the contributed inputs stay claimed until the claims expire
+            let inputs = &proposal.psbt.global.unsigned_tx.input;
+            let unused = claimed.iter().filter(|outpoint| !contributed || !inputs.iter().any(|txin| txin.previous_output == **outpoint));
+            for outpoint in unused {
+                let _ = coordinator.release(&format!("utxo:{}", outpoint));
+            }
+        }
+        result?;
+        if !contributed {
+            return without_contribution(original);
+        }
+        if let Some(monitor) = &self.monitor {
+            monitor.watch(&proposal);
+        }
+        proposal.minimize_response();
+        Ok(proposal.psbt)
+    }
+
+    /// Returns `false` if signing took longer than its budget.
+    fn contribute_candidates(&self, proposal: &mut Proposal, candidates: Candidates, wallet: &Wallet, started: SystemTime, stages: &Stages) -> Result<bool, (ErrorCode, String)> {
+        self.select_candidates(proposal, candidates)?;
+        let deadline = stages.deadline(stages.timeouts.signing);
+        let result = wallet.sign(proposal, started, deadline);
+        if stages.is_expired(deadline) {
+            return Ok(false);
+        }
+        result.map(|()| true).map_err(|error| (error.error_code(), error.to_json()))
+    }
+
+    /// Contributes one of the candidates according to the strategy.
+    fn select_candidates(&self, proposal: &mut Proposal, candidates: Candidates) -> Result<(), (ErrorCode, String)> {
+        let contribution_error = |error: super::ContributionError| (error.error_code(), error.to_json());
+        let receiver_output = proposal.payee.clone().expect("checked by check_pays_issued_script");
+        match &self.strategy.0 {
+            InternalStrategy::BestInput => {
+                let scorer = |candidate: &Candidate<'_>| self.scorer.score(candidate);
+                proposal.contribute_best(candidates, &receiver_output, &scorer, &self.options).map_err(contribution_error)?;
+            },
+            InternalStrategy::Decoy(new_script) => {
+                let decoy_script = new_script().map_err(|error| contribution_error(InternalContributionError::WalletUnavailable(error).into()))?;
+                let mut result = Err(InternalContributionError::NoCandidates.into());
+                for (outpoint, psbt_input) in candidates {
+                    result = proposal.contribute_input_with_decoy(outpoint, psbt_input, &receiver_output, decoy_script.clone(), &self.options);
+                    if result.is_ok() {
+                        break;
+                    }
+                }
+                result.map_err(contribution_error)?;
+            },
+        }
+        Ok(())
+    }
+
+    fn should_contribute(&self, proposal: &Proposal, contribute: bool) -> bool {
+        contribute && self.mode.mode() == ReceiverMode::Normal && self.within_budget(proposal)
+    }
+
+    /// Returns `false` if contributing an input would get the proposal rejected by the sender or
+    /// cost more than allowed.
+    fn within_budget(&self, proposal: &Proposal) -> bool {
+        let params = proposal.sender_params();
+        let input_weight = match params.expected_input_weight() {
+            Some(weight) => weight,
+            // the fee of the input can't be determined
+            None => return false,
+        };
+        let budget = ContributionBudget::compute_with_fee_share(&params, params.original_fee_rate(), self.options.fee_share, std::iter::once(input_weight));
+        match self.options.max_receiver_fee {
+            Some(max_receiver_fee) => budget.max_inputs_within(max_receiver_fee) > 0,
+            None => budget.max_inputs() > 0,
+        }
+    }
+}
+
+/// Claims all `keys` or none of them.
+fn claim_all(coordinator: &dyn Coordinator<Error=BoxError>, keys: &[String], ttl: Duration) -> Result<bool, BoxError> {
+    for (i, key) in keys.iter().enumerate() {
+        let claimed = coordinator.claim(key, ttl);
+        if !matches!(claimed, Ok(true)) {
+            for key in &keys[..i] {
+                let _ = coordinator.release(key);
+            }
+            return claimed;
+        }
+    }
+    Ok(true)
+}
+
+/// Returns the candidates not claimed by another instance, claiming them.
+fn claim_candidates(coordinator: &dyn Coordinator<Error=BoxError>, candidates: Candidates, ttl: Duration) -> Result<Candidates, BoxError> {
+    let mut claimed = Vec::with_capacity(candidates.len());
+    for (outpoint, psbt_input) in candidates {
+        match coordinator.claim(&format!("utxo:{}", outpoint), ttl) {
+            Ok(true) => claimed.push((outpoint, psbt_input)),
+            Ok(false) => (),
+            Err(error) => {
+                for (outpoint, _) in &claimed {
+                    let _ = coordinator.release(&format!("utxo:{}", outpoint));
+                }
+                return Err(error);
+            },
+        }
+    }
+    Ok(claimed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::Mutex;
+    use bitcoin::{OutPoint, Transaction};
+    use crate::testing::{MockClock, MockHeaders};
+    use crate::receiver::Transport;
+    use super::*;
+
+    struct Node {
+        locked: Mutex<HashSet<OutPoint>>,
+        broadcastable: bool,
+    }
+
+    impl OriginalChecks for Node {
+        type Error = std::io::Error;
+
+        fn prevout_status(&self, _: &OutPoint) -> Result<PrevoutStatus, Self::Error> {
+            Ok(PrevoutStatus::Unspent)
+        }
+
+        fn can_broadcast(&self, _: &Transaction) -> Result<bool, Self::Error> {
+            Ok(self.broadcastable)
+        }
+
+        fn lock_inputs(&self, outpoints: &[OutPoint]) -> Result<bool, Self::Error> {
+            let mut locked = self.locked.lock().unwrap();
+            if outpoints.iter().any(|outpoint| locked.contains(outpoint)) {
+                return Ok(false);
+            }
+            locked.extend(outpoints);
+            Ok(true)
+        }
+    }
+
+    fn node(broadcastable: bool) -> Node {
+        Node { locked: Mutex::new(HashSet::new()), broadcastable, }
+    }
+
+    fn process(receiver: &PayjoinReceiver<Node>, issued_script: &Script) -> Response {
+        process_with_meta(receiver, issued_script, RequestMeta::default())
+    }
+
+    fn process_with_meta(receiver: &PayjoinReceiver<Node>, issued_script: &Script, meta: RequestMeta) -> Response {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        receiver.process(Request { body, query: "v=1", headers: MockHeaders::new(body.len() as u64), issued_script, meta, })
+    }
+
+    fn payee() -> Script {
+        crate::testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone()
+    }
+
+    #[test]
+    fn onion_only() {
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .onion_only(true)
+            .response_cache(ResponseCache::new(16, std::time::Duration::from_secs(60)))
+            .build();
+        let onion = RequestMeta { transport: Transport::Onion, remote_addr: None, };
+        let clearnet = RequestMeta { transport: Transport::Clearnet, remote_addr: Some([192, 0, 2, 1].into()), };
+        let response = process_with_meta(&receiver, &payee(), clearnet);
+        assert_eq!(response.status, 403);
+        assert!(String::from_utf8(response.body).unwrap().contains("\"errorCode\":\"unavailable\""));
+        assert_eq!(process(&receiver, &payee()).status, 403);
+        assert_eq!(process_with_meta(&receiver, &payee(), onion.clone()).status, 200);
+        // the cached response isn't returned to clearnet requests
+        assert_eq!(process_with_meta(&receiver, &payee(), onion).status, 200);
+        assert_eq!(process(&receiver, &payee()).status, 403);
+    }
+
+    #[test]
+    fn checks() {
+        let receiver = PayjoinReceiver::builder().checks(node(false)).build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 400);
+        assert!(response.fallback.is_none());
+
+        let receiver = PayjoinReceiver::builder().checks(node(true)).build();
+        let response = process(&receiver, &Script::new());
+        assert_eq!(response.status, 400);
+        assert_eq!(process(&receiver, &payee()).status, 200);
+        // the same inputs again
+        assert_eq!(process(&receiver, &payee()).status, 400);
+    }
+
+    #[test]
+    fn fallback_scheduler() {
+        let scheduler = Arc::new(FallbackScheduler::new(std::time::Duration::from_secs(0)));
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .fallback_scheduler(Arc::clone(&scheduler), FallbackDelay::default())
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+        assert!(scheduler.cancel(&response.fallback.unwrap().txid()));
+        // rejected requests have nothing to broadcast
+        assert_eq!(process(&receiver, &payee()).status, 400);
+        assert!(scheduler.is_empty());
+    }
+
+    #[test]
+    fn contribution_monitor() {
+        use bitcoin::consensus::deserialize;
+        use super::super::SpendEvent;
+
+        let vector = deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let events_clone = Arc::clone(&events);
+        let monitor = Arc::new(ContributionMonitor::new(move |event| events_clone.lock().unwrap().push(event)));
+        let receiver = || PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .contribution_monitor(Arc::clone(&monitor))
+            .build();
+
+        let response = process(&receiver(), &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(monitor.watched_outpoints(), [outpoint]);
+        // signatures of the sender don't matter
+        let mut payjoin = deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().global.unsigned_tx;
+        payjoin.input[0].script_sig = crate::testing::original_psbt().inputs[0].final_script_sig.clone().unwrap();
+        monitor.transaction_seen(&payjoin);
+        assert_eq!(*events.lock().unwrap(), [SpendEvent::Settled { payjoin_txid: payjoin.txid(), outpoints: vec![outpoint], }]);
+        assert!(monitor.watched_outpoints().is_empty());
+
+        process(&receiver(), &payee());
+        let mut other = payjoin.clone();
+        other.output.pop();
+        monitor.transaction_seen(&other);
+        assert_eq!(events.lock().unwrap()[1], SpendEvent::DoubleSpent { spending_txid: other.txid(), outpoints: vec![outpoint], });
+
+        process(&receiver(), &payee());
+        monitor.transaction_seen(&response.fallback.unwrap());
+        assert!(matches!(events.lock().unwrap()[2], SpendEvent::Released { .. }));
+    }
+
+    #[test]
+    fn max_receiver_fee() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = |max_receiver_fee| PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .max_receiver_fee(max_receiver_fee)
+            .build();
+        let inputs = |response: &Response| bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().inputs.len();
+
+        // the sender offered no contribution so the receiver would pay for the whole input
+        let response = process(&receiver(bitcoin::Amount::from_sat(100)), &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(inputs(&response), 1);
+        let response = process(&receiver(bitcoin::Amount::from_sat(1000)), &payee());
+        assert_eq!(inputs(&response), 2);
+    }
+
+    #[test]
+    fn check_order() {
+        struct Recorder(Arc<Mutex<Vec<(Stage, bool)>>>);
+
+        impl Metrics for Recorder {
+            fn record_stage(&self, stage: Stage, _: Duration, passed: bool) {
+                self.0.lock().unwrap().push((stage, passed));
+            }
+        }
+
+        let run = |broadcastable, order: &[Stage]| {
+            let recorded = Arc::new(Mutex::new(Vec::new()));
+            let receiver = PayjoinReceiver::builder()
+                .checks(node(broadcastable))
+                .check_order(order.iter().copied())
+                .metrics(Recorder(recorded.clone()))
+                .build();
+            process(&receiver, &payee());
+            let stages = recorded.lock().unwrap().clone();
+            stages
+        };
+
+        let default = [Stage::IssuedScript, Stage::PaymentRequest, Stage::SenderInputTypes, Stage::OpReturnOutputs, Stage::Prevouts, Stage::Broadcastability, Stage::LockInputs];
+        assert_eq!(run(true, &[]), default.iter().map(|&stage| (stage, true)).collect::<Vec<_>>());
+        // unknown and repeated stages are ignored
+        let order = [Stage::Broadcastability, Stage::LockInputs, Stage::Prevouts, Stage::Broadcastability];
+        assert_eq!(run(true, &order).iter().map(|&(stage, _)| stage).collect::<Vec<_>>(), [
+            Stage::IssuedScript, Stage::Broadcastability, Stage::Prevouts, Stage::PaymentRequest, Stage::SenderInputTypes, Stage::OpReturnOutputs, Stage::LockInputs,
+        ]);
+        // the checks stop at the first failure
+        assert_eq!(run(false, &order), [(Stage::IssuedScript, true), (Stage::Broadcastability, false)]);
+    }
+
+    #[test]
+    fn donations() {
+        let receiver = |minimum: u64, registered: Option<u64>| {
+            let mut store = std::collections::HashMap::new();
+            if let Some(amount) = registered {
+                store.insert(payee(), bitcoin::Amount::from_sat(amount));
+            }
+            PayjoinReceiver::builder()
+                .checks(node(true))
+                .payment_requests(store)
+                .accept_donations(bitcoin::Amount::from_sat(minimum))
+                .build()
+        };
+
+        // the original pays 2_000_000 sat to the issued script
+        assert_eq!(process(&receiver(2_000_000, None), &payee()).status, 200);
+        assert_eq!(process(&receiver(2_000_001, None), &payee()).status, 400);
+        // registered amounts take precedence
+        assert_eq!(process(&receiver(2_000_001, Some(2_000_000)), &payee()).status, 200);
+        assert_eq!(process(&receiver(0, Some(2_000_001)), &payee()).status, 400);
+        let receiver = PayjoinReceiver::builder().checks(node(true)).accept_donations(bitcoin::Amount::from_sat(2_000_001)).build();
+        assert_eq!(process(&receiver, &payee()).status, 400);
+    }
+
+    #[test]
+    fn invoice_status() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = |status, policy| PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .invoice_status(move |_: &Script| Ok::<_, std::io::Error>(status), policy)
+            .build();
+        let inputs = |response: &Response| bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().inputs.len();
+
+        let response = process(&receiver(InvoiceStatus::Fresh, InvoicePolicy::default()), &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(inputs(&response), 2);
+
+        let response = process(&receiver(InvoiceStatus::Expired, InvoicePolicy::default()), &payee());
+        assert_eq!(response.status, 400);
+        assert!(response.fallback.is_some());
+
+        let response = process(&receiver(InvoiceStatus::Unknown, InvoicePolicy::default()), &payee());
+        assert_eq!(response.status, 400);
+        assert!(response.fallback.is_none());
+
+        let policy = InvoicePolicy::default().expired(InvoiceAction::AcceptWithoutContribution);
+        let response = process(&receiver(InvoiceStatus::Expired, policy), &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(inputs(&response), 1);
+    }
+
+    #[test]
+    fn mode_switch() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let switch = ModeSwitch::new();
+        let receiver = || PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .mode_switch(switch.clone())
+            .build();
+        let inputs = |response: &Response| bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().inputs.len();
+
+        assert_eq!(inputs(&process(&receiver(), &payee())), 2);
+
+        let receiver = receiver();
+        assert_eq!(receiver.mode_switch().set(ReceiverMode::NoContributions), ReceiverMode::Normal);
+        assert_eq!(switch.mode(), ReceiverMode::NoContributions);
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(inputs(&response), 1);
+
+        switch.set(ReceiverMode::Unavailable);
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 503);
+        assert!(response.fallback.is_none());
+        assert!(String::from_utf8(response.body).unwrap().contains("\"errorCode\":\"unavailable\""));
+    }
+
+    #[test]
+    fn dry_run() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signer = |_: &Psbt, _: usize| -> Result<psbt::Input, std::io::Error> { panic!("dry run must not sign") };
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates, signer)
+            .build();
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let payee = payee();
+        let request = || Request { body, query: "v=1", headers: MockHeaders::new(body.len() as u64), issued_script: &payee, meta: RequestMeta::default(), };
+        let dry_run = receiver.dry_run(request()).unwrap();
+        assert_eq!(dry_run.contributed, [outpoint]);
+        assert_eq!(dry_run.psbt.inputs.len(), 2);
+        let analysis = &dry_run.analysis;
+        assert_eq!(analysis.contributed_inputs, 1);
+        assert_eq!(analysis.fee, analysis.original_fee + analysis.sender_contribution + analysis.receiver_fee);
+        assert!(analysis.weight > 0 && analysis.fee_rate > 0);
+        assert!(!receiver.checks.locked.lock().unwrap().contains(&crate::testing::original_psbt().global.unsigned_tx.input[0].previous_output));
+
+        // failing checks return the response process() would
+        let other_script = Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let error = receiver.dry_run(Request { issued_script: &other_script, ..request() }).unwrap_err();
+        assert_eq!(error.status, 400);
+    }
+
+    #[test]
+    fn probing_guard() {
+        use crate::receiver::ErrorPolicy;
+
+        let response_time = Duration::from_secs(60);
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .probing_guard(ProbingGuard::new(ErrorPolicy::Decoy { response_time, }))
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(response.delay, Duration::from_secs(0));
+        // reusing the locked inputs gets the same response as a temporary failure
+        let probe = process(&receiver, &payee());
+        receiver.mode_switch().set(ReceiverMode::Unavailable);
+        let unavailable = process(&receiver, &payee());
+        assert_eq!((probe.status, &probe.body), (unavailable.status, &unavailable.body));
+        assert!(probe.delay > Duration::from_secs(59) && probe.delay <= response_time);
+
+        // honest mistakes are reported accurately
+        let other_script = Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let response = process(&receiver, &other_script);
+        assert_eq!(response.status, 503);
+        receiver.mode_switch().set(ReceiverMode::Normal);
+        let response = process(&receiver, &other_script);
+        assert_eq!(response.status, 400);
+        assert!(response.delay > Duration::from_secs(59));
+    }
+
+    #[test]
+    fn response_cache() {
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .response_cache(ResponseCache::new(16, std::time::Duration::from_secs(60)))
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+        // the retry gets the same response although the inputs are locked
+        let retry = process(&receiver, &payee());
+        assert_eq!(retry.status, 200);
+        assert_eq!(retry.body, response.body);
+        let stats = receiver.response_cache().unwrap().stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+    }
+
+    #[test]
+    fn coordinator() {
+        use bitcoin::consensus::deserialize;
+        use super::super::InProcessCoordinator;
+
+        let vector = deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let ttl = Duration::from_secs(60);
+        let coordinator = Arc::new(InProcessCoordinator::new());
+        // separate nodes, only the coordinator is shared
+        let receiver = || PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .coordinator(Arc::clone(&coordinator), ttl)
+            .build();
+        let (first, second) = (receiver(), receiver());
+
+        let response = process(&first, &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(coordinator.claim(&format!("utxo:{}", outpoint), ttl), Ok(false));
+        // the retry landing on the other instance gets the same proposal
+        let retry = process(&second, &payee());
+        assert_eq!(retry.status, 200);
+        assert_eq!(retry.body, response.body);
+        // a different request spending the same inputs is rejected
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let issued_script = payee();
+        let other = second.process(Request { body, query: "v=1&disableoutputsubstitution=1", headers: MockHeaders::new(body.len() as u64), issued_script: &issued_script, meta: RequestMeta::default(), });
+        assert_eq!(other.status, 400);
+    }
+
+    #[cfg(feature = "sender")]
+    #[test]
+    fn contribute() {
+        // the finalized input of the receiver from the official vector
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let mut input = vector.inputs[1].clone();
+        // doesn't cover its fee of 182 sat
+        input.witness_utxo.as_mut().unwrap().value = 100;
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 400);
+        assert!(response.fallback.is_some());
+
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates, signer)
+            .bump_policy(BumpFeePolicy::SubtractOurFeeOutput)
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let params = crate::sender::Params::non_incentivizing();
+        let (_, context) = uri.create_request(crate::testing::original_psbt(), params).unwrap();
+        let proposal = context.process_response_bytes(&response.body).unwrap();
+        assert_eq!(proposal.global.unsigned_tx.input.len(), 2);
+        assert_eq!(proposal.global.unsigned_tx.output[1].value, 2_000_000 - 82);
+    }
+
+    #[test]
+    fn official_vector() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates, signer)
+            .build();
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let query = "v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182";
+        let request = Request { body, query, headers: MockHeaders::new(body.len() as u64), issued_script: &payee(), meta: RequestMeta::default(), };
+        let response = receiver.process(request);
+        assert_eq!(response.status, 200);
+
+        let proposal = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap();
+        let original = crate::testing::original_psbt();
+        // the sender paid the fee of the input so the payee output grew by exactly its value
+        assert_eq!(proposal.global.unsigned_tx.output, vector.global.unsigned_tx.output);
+        assert_eq!(proposal.global.unsigned_tx.output[1].value - original.global.unsigned_tx.output[1].value, vector.inputs[1].witness_utxo.as_ref().unwrap().value);
+        let mut inputs = proposal.global.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect::<Vec<_>>();
+        inputs.sort();
+        let mut expected = vector.global.unsigned_tx.input.iter().map(|txin| txin.previous_output).collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(inputs, expected);
+    }
+
+    #[test]
+    fn remote_utxo_source() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move |original: &Psbt, _: Deadline| {
+            assert_eq!(original.global.unsigned_tx.input.len(), 1);
+            Ok::<_, std::io::Error>(vec![(outpoint, input.clone())])
+        };
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, indices: &[usize], _: Deadline| Ok::<_, std::io::Error>(vec![signed.clone(); indices.len()]);
+
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .remote_utxo_source(candidates.clone(), signer.clone(), Duration::from_secs(10))
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 200);
+        let proposal = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap();
+        assert_eq!(proposal.global.unsigned_tx.input.len(), 2);
+
+        // any answer comes too late
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .remote_utxo_source(candidates, signer, Duration::from_secs(0))
+            .build();
+        let response = process(&receiver, &payee());
+        assert_eq!(response.status, 503);
+    }
+
+    #[test]
+    fn earliest_deadline() {
+        let now = SystemTime::now();
+        let sooner = Deadline::at(now);
+        let later = Deadline::at(now + Duration::from_secs(1));
+        assert_eq!(earliest(later, Some(sooner)).time(), now);
+        assert_eq!(earliest(sooner, Some(later)).time(), now);
+        assert_eq!(earliest(later, None).time(), later.time());
+    }
+
+    #[test]
+    fn stage_timeouts() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = |timeouts| PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .timeouts(timeouts)
+            .build();
+        let inputs = |response: &Response| bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().global.unsigned_tx.input.len();
+
+        let response = process(&receiver(StageTimeouts::default()), &payee());
+        assert_eq!(inputs(&response), 2);
+
+        // the node is never fast enough, the total budget caps the one of the checks
+        let slow_node = StageTimeouts { checks: Some(Duration::from_secs(0)), ..Default::default() };
+        let late = StageTimeouts { checks: Some(Duration::from_secs(60)), total: Some(Duration::from_secs(0)), ..Default::default() };
+        for &timeouts in &[slow_node, late] {
+            let receiver = receiver(timeouts);
+            let response = process(&receiver, &payee());
+            assert_eq!(response.status, 503);
+            assert!(String::from_utf8(response.body).unwrap().contains("checking the original transaction took too long"));
+            assert!(receiver.checks.locked.lock().unwrap().is_empty());
+        }
+
+        // the wallet is never fast enough, the proposal is sent without contribution
+        let slow_wallet = StageTimeouts { candidates: Some(Duration::from_secs(0)), ..Default::default() };
+        let slow_signer = StageTimeouts { signing: Some(Duration::from_secs(0)), ..Default::default() };
+        for &timeouts in &[slow_wallet, slow_signer] {
+            let response = process(&receiver(timeouts), &payee());
+            assert_eq!(response.status, 200);
+            assert_eq!(inputs(&response), 1);
+        }
+    }
+
+    #[test]
+    fn metrics_clock() {
+        struct SlowStore(Arc<MockClock>);
+
+        impl PaymentRequestStore for SlowStore {
+            fn requested_amount(&self, _: &Script) -> Option<bitcoin::Amount> {
+                self.0.advance(Duration::from_secs(1));
+                Some(bitcoin::Amount::from_sat(2_000_000))
+            }
+        }
+
+        struct Recorder(Arc<Mutex<Vec<(Stage, Duration)>>>);
+
+        impl Metrics for Recorder {
+            fn record_stage(&self, stage: Stage, duration: Duration, _: bool) {
+                self.0.lock().unwrap().push((stage, duration));
+            }
+        }
+
+        let clock = Arc::new(MockClock::new());
+        let recorded = Arc::new(Mutex::new(Vec::new()));
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .payment_requests(SlowStore(Arc::clone(&clock)))
+            .metrics(Recorder(Arc::clone(&recorded)))
+            .clock(clock)
+            .build();
+        process(&receiver, &payee());
+        let recorded = recorded.lock().unwrap();
+        assert_eq!(recorded.len(), 7);
+        // only the payment request check took any mock time
+        for &(stage, duration) in recorded.iter() {
+            assert_eq!(duration > Duration::from_secs(0), stage == Stage::PaymentRequest, "{:?}", stage);
+        }
+    }
+
+    #[test]
+    fn clock() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let clock = Arc::new(MockClock::new());
+        // the wallet takes 5 seconds of the mock time to answer
+        let wallet_clock = Arc::clone(&clock);
+        let candidates = move || {
+            wallet_clock.advance(Duration::from_secs(5));
+            Ok::<_, std::io::Error>(vec![(outpoint, input.clone())])
+        };
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = |candidates_budget| PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates.clone(), signer.clone())
+            .timeouts(StageTimeouts { candidates: Some(Duration::from_secs(candidates_budget)), ..Default::default() })
+            .clock(Arc::clone(&clock))
+            .build();
+        let inputs = |response: &Response| bitcoin::consensus::deserialize::<Psbt>(&base64::decode(&response.body).unwrap()).unwrap().global.unsigned_tx.input.len();
+
+        let response = process(&receiver(10), &payee());
+        assert_eq!(inputs(&response), 2);
+        let response = process(&receiver(4), &payee());
+        assert_eq!(response.status, 200);
+        assert_eq!(inputs(&response), 1);
+    }
+
+    #[cfg(feature = "sender")]
+    #[test]
+    fn fee_share() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let half = crate::FeeShare::from_percent(50).unwrap();
+        let original = crate::testing::original_psbt();
+        let respond = |params: crate::sender::Params| {
+            // the receiver refuses to see the same inputs twice
+            let receiver = PayjoinReceiver::builder()
+                .checks(node(true))
+                .utxo_source(candidates.clone(), signer.clone())
+                .fee_share(half)
+                .build();
+            let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+            let (request, context) = uri.create_request(original.clone(), params).unwrap();
+            let query = request.url.split_once('?').unwrap().1;
+            let response = receiver.process(Request { body: &request.body, query, headers: MockHeaders::new(request.body.len() as u64), issued_script: &payee(), meta: RequestMeta::default(), });
+            assert_eq!(response.status, 200);
+            context.process_response_bytes(&response.body)
+        };
+
+        let params = || crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None);
+        let proposal = respond(params().fee_share(half)).unwrap();
+        // the input costs 182 sat, both parties pay 91 sat
+        assert_eq!(original.global.unsigned_tx.output[0].value - proposal.global.unsigned_tx.output[0].value, 91);
+        let error = respond(params().fee_share(crate::FeeShare::from_percent(25).unwrap())).unwrap_err();
+        assert_eq!(error.to_string(), "the receiver took 0.00000091 BTC of our fee contribution but the agreed share is 0.00000045 BTC");
+    }
+
+    #[cfg(feature = "sender")]
+    #[test]
+    fn subtract_fee_without_change() {
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let mut input = vector.inputs[1].clone();
+        input.witness_utxo.as_mut().unwrap().value = 100;
+        let candidates = move || Ok::<_, std::io::Error>(vec![(outpoint, input.clone())]);
+        let signed = vector.inputs[1].clone();
+        let signer = move |_: &Psbt, _: usize| Ok::<_, std::io::Error>(signed.clone());
+        let receiver = PayjoinReceiver::builder()
+            .checks(node(true))
+            .utxo_source(candidates, signer)
+            .bump_policy(BumpFeePolicy::SubtractOurFeeOutput)
+            .build();
+        // the sender pays its whole input without change
+        let mut original = crate::testing::original_psbt();
+        let change = original.global.unsigned_tx.output.remove(0).value;
+        original.global.unsigned_tx.output[0].value += change;
+        original.outputs.remove(0);
+        let amount = bitcoin::Amount::from_sat(2_000_000 + change).to_string_in(bitcoin::Denomination::Bitcoin);
+        let uri = format!("bitcoin:3CZZi7aWFugaCdUCS15dgrUUViupmB8bVM?amount={}&pj=https://example.com/pj", amount);
+        let uri = uri.parse::<crate::Uri>().unwrap();
+        let params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None).clamp_fee_contribution(true);
+        let (request, context) = uri.create_request(original, params).unwrap();
+        let query = request.url.split_once('?').unwrap().1;
+        let response = receiver.process(Request { body: &request.body, query, headers: MockHeaders::new(request.body.len() as u64), issued_script: &payee(), meta: RequestMeta::default(), });
+        assert_eq!(response.status, 200);
+        let proposal = context.process_response_bytes(&response.body).unwrap();
+        assert_eq!(proposal.global.unsigned_tx.output.len(), 1);
+        // the fee rate of the original is higher without the change output so the input costs
+        // 226 sat, the output pays what the input doesn't cover
+        assert_eq!(proposal.global.unsigned_tx.output[0].value, 2_000_000 + change - 126);
+    }
+}
@@ -0,0 +1,216 @@
+//! Caching of responses to repeated requests
+//!
+//! Senders may retry a request after a timeout. Processing it again would fail because the
+//! inputs are already locked (or worse, contribute a different input and reveal another UTXO) so
+//! `PayjoinReceiver` answers duplicates from `ResponseCache` instead.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use crate::time::{Clock, Deadline, SystemClock};
+use super::Response;
+
+/// Counters of cache events, see `ResponseCache::stats()`.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct CacheStats {
+    /// Requests answered from the cache.
+    pub hits: u64,
+    /// Requests that had to be processed.
+    pub misses: u64,
+    /// Responses removed because their TTL passed.
+    pub expired: u64,
+    /// Responses removed because the cache was full.
+    pub evicted: u64,
+}
+
+struct Entry {
+    expiry: Deadline,
+    last_used: u64,
+    response: Response,
+}
+
+#[derive(Default)]
+struct Entries {
+    by_key: HashMap<sha256::Hash, Entry>,
+    // last use -> key, the first item is the least recently used one
+    by_use: BTreeMap<u64, sha256::Hash>,
+    uses: u64,
+    stats: CacheStats,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &sha256::Hash) {
+        if let Some(entry) = self.by_key.remove(key) {
+            self.by_use.remove(&entry.last_used);
+        }
+    }
+
+    fn touch(&mut self, key: sha256::Hash) -> u64 {
+        self.uses += 1;
+        self.by_use.insert(self.uses, key);
+        self.uses
+    }
+}
+
+/// Memory- and time-bounded cache of responses keyed by the hash of the request.
+///
+/// Holds at most `capacity` responses, each for at most `ttl`. When full, the least recently
+/// used response is evicted. Responses with status 503 are never cached since the receiver may
+/// become available on retry.
+pub struct ResponseCache {
+    clock: Arc<dyn Clock + Send + Sync>,
+    capacity: usize,
+    ttl: Duration,
+    entries: Mutex<Entries>,
+}
+
+impl ResponseCache {
+    /// Creates the cache holding at most `capacity` responses for `ttl`.
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self::with_clock(SystemClock, capacity, ttl)
+    }
+
+    /// Creates the cache using a custom clock.
+    pub fn with_clock(clock: impl Clock + Send + Sync + 'static, capacity: usize, ttl: Duration) -> Self {
+        ResponseCache {
+            clock: Arc::new(clock),
+            capacity,
+            ttl,
+            entries: Mutex::new(Entries::default()),
+        }
+    }
+
+    /// Returns the counters of hits, misses and evictions.
+    pub fn stats(&self) -> CacheStats {
+        self.lock().stats
+    }
+
+    /// Returns the number of cached responses, including expired ones not removed yet.
+    pub fn len(&self) -> usize {
+        self.lock().by_key.len()
+    }
+
+    /// Returns `true` if no response is cached.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub(crate) fn get(&self, body: &[u8], query: &str) -> Option<Response> {
+        let key = hash_request(body, query);
+        let mut entries = self.lock();
+        let expired = match entries.by_key.get(&key) {
+            Some(entry) => entry.expiry.is_expired(&self.clock),
+            None => {
+                entries.stats.misses += 1;
+                return None;
+            },
+        };
+        if expired {
+            entries.remove(&key);
+            entries.stats.expired += 1;
+            entries.stats.misses += 1;
+            return None;
+        }
+        let last_used = entries.touch(key);
+        let entry = entries.by_key.get_mut(&key).expect("checked above");
+        let previous_use = std::mem::replace(&mut entry.last_used, last_used);
+        let response = entry.response.clone();
+        entries.by_use.remove(&previous_use);
+        entries.stats.hits += 1;
+        Some(response)
+    }
+
+    pub(crate) fn insert(&self, body: &[u8], query: &str, response: &Response) {
+        if self.capacity == 0 || response.status == 503 {
+            return;
+        }
+        let key = hash_request(body, query);
+        let mut entries = self.lock();
+        entries.remove(&key);
+        if entries.by_key.len() >= self.capacity {
+            let clock = &self.clock;
+            let expired = entries.by_key
+                .iter()
+                .filter(|(_, entry)| entry.expiry.is_expired(clock))
+                .map(|(key, _)| *key)
+                .collect::<Vec<_>>();
+            for key in &expired {
+                entries.remove(key);
+            }
+            entries.stats.expired += expired.len() as u64;
+        }
+        while entries.by_key.len() >= self.capacity {
+            let (_, oldest) = entries.by_use.iter().next().map(|(use_, key)| (*use_, *key)).expect("the cache is full");
+            entries.remove(&oldest);
+            entries.stats.evicted += 1;
+        }
+        let last_used = entries.touch(key);
+        entries.by_key.insert(key, Entry {
+            expiry: Deadline::after(&self.clock, self.ttl),
+            last_used,
+            response: response.clone(),
+        });
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        self.entries.lock().expect("mutex not poisoned")
+    }
+}
+
+pub(super) fn hash_request(body: &[u8], query: &str) -> sha256::Hash {
+    let mut engine = sha256::Hash::engine();
+    // the length prevents ambiguity between the query and the body
+    engine.input(&(query.len() as u64).to_le_bytes());
+    engine.input(query.as_bytes());
+    engine.input(body);
+    sha256::Hash::from_engine(engine)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::testing::MockClock;
+    use super::{ResponseCache, CacheStats, Response};
+
+    fn response(status: u16) -> Response {
+        Response { status, body: status.to_string().into_bytes(), fallback: None, delay: Duration::from_secs(0), }
+    }
+
+    #[test]
+    fn lru() {
+        let cache = ResponseCache::with_clock(MockClock::new(), 2, Duration::from_secs(60));
+        cache.insert(b"a", "v=1", &response(200));
+        cache.insert(b"b", "v=1", &response(400));
+        assert_eq!(cache.get(b"a", "v=1").unwrap().status, 200);
+        assert!(cache.get(b"a", "v=1&disableoutputsubstitution=1").is_none());
+        // evicts b which was used less recently than a
+        cache.insert(b"c", "v=1", &response(200));
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(b"b", "v=1").is_none());
+        assert!(cache.get(b"a", "v=1").is_some());
+        assert!(cache.get(b"c", "v=1").is_some());
+        cache.insert(b"d", "v=1", &response(503));
+        assert!(cache.get(b"d", "v=1").is_none());
+        assert_eq!(cache.stats(), CacheStats { hits: 3, misses: 3, expired: 0, evicted: 1, });
+    }
+
+    #[test]
+    fn ttl() {
+        let clock = Arc::new(MockClock::new());
+        let cache = ResponseCache::with_clock(Arc::clone(&clock), 2, Duration::from_secs(60));
+        cache.insert(b"a", "v=1", &response(200));
+        clock.advance(Duration::from_secs(30));
+        cache.insert(b"b", "v=1", &response(200));
+        clock.advance(Duration::from_secs(30));
+        assert!(cache.get(b"a", "v=1").is_none());
+        assert!(cache.get(b"b", "v=1").is_some());
+        clock.advance(Duration::from_secs(30));
+        // b expired so nothing is evicted
+        cache.insert(b"c", "v=1", &response(200));
+        cache.insert(b"d", "v=1", &response(200));
+        assert_eq!(cache.stats(), CacheStats { hits: 1, misses: 1, expired: 2, evicted: 0, });
+    }
+}
@@ -0,0 +1,363 @@
+//! Coordinating multiple receiver instances
+//!
+//! Receivers behind a load balancer don't see each other's `ResponseCache` and locks. A retried
+//! request landing on another instance would be processed again and contribute a different UTXO
+//! (revealing it to the sender) and two instances may contribute the same UTXO to different
+//! proposals. `PayjoinReceiver` prevents both by claiming requests and UTXOs and sharing responses
+//! through a `Coordinator` common to all instances.
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::time::{Clock, Deadline, SystemClock};
+use super::Response;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Distributed locks and storage shared by receiver instances.
+///
+/// Keys are short ASCII strings chosen by `PayjoinReceiver`. Claims and stored values use
+/// different keys.
+pub trait Coordinator {
+    type Error: Into<BoxError>;
+
+    /// Claims `key` for `ttl` unless it's already claimed by any instance.
+    ///
+    /// Returns `false` if the key is claimed. This must be atomic across all instances.
+    fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Self::Error>;
+
+    /// Releases the claim of this instance, does nothing if it already expired.
+    fn release(&self, key: &str) -> Result<(), Self::Error>;
+
+    /// Stores `value` under `key` for `ttl` replacing the previous one.
+    fn store(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), Self::Error>;
+
+    /// Returns the value under `key` unless it expired.
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error>;
+}
+
+impl<K: Coordinator + ?Sized> Coordinator for Arc<K> {
+    type Error = K::Error;
+
+    fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        (**self).claim(key, ttl)
+    }
+
+    fn release(&self, key: &str) -> Result<(), Self::Error> {
+        (**self).release(key)
+    }
+
+    fn store(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), Self::Error> {
+        (**self).store(key, value, ttl)
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        (**self).load(key)
+    }
+}
+
+/// Erases the error type so that `PayjoinReceiver` can store any coordinator.
+pub(super) struct BoxedCoordinator<K>(pub(super) K);
+
+impl<K: Coordinator> Coordinator for BoxedCoordinator<K> {
+    type Error = BoxError;
+
+    fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        self.0.claim(key, ttl).map_err(Into::into)
+    }
+
+    fn release(&self, key: &str) -> Result<(), Self::Error> {
+        self.0.release(key).map_err(Into::into)
+    }
+
+    fn store(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), Self::Error> {
+        self.0.store(key, value, ttl).map_err(Into::into)
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.0.load(key).map_err(Into::into)
+    }
+}
+
+/// Coordinator of instances running in the same process, e.g. multiple HTTP listeners.
+///
+/// Share it using `Arc`.
+pub struct InProcessCoordinator {
+    clock: Arc<dyn Clock + Send + Sync>,
+    entries: Mutex<HashMap<String, (Deadline, Vec<u8>)>>,
+}
+
+impl InProcessCoordinator {
+    pub fn new() -> Self {
+        Self::with_clock(SystemClock)
+    }
+
+    /// Creates the coordinator using a custom clock.
+    pub fn with_clock(clock: impl Clock + Send + Sync + 'static) -> Self {
+        InProcessCoordinator {
+            clock: Arc::new(clock),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, (Deadline, Vec<u8>)>> {
+        let mut entries = self.entries.lock().expect("mutex not poisoned");
+        let clock = &self.clock;
+        entries.retain(|_, (expiry, _)| !expiry.is_expired(clock));
+        entries
+    }
+}
+
+impl Default for InProcessCoordinator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Coordinator for InProcessCoordinator {
+    type Error = std::convert::Infallible;
+
+    fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let mut entries = self.lock();
+        if entries.contains_key(key) {
+            return Ok(false);
+        }
+        entries.insert(key.to_owned(), (Deadline::after(&self.clock, ttl), Vec::new()));
+        Ok(true)
+    }
+
+    fn release(&self, key: &str) -> Result<(), Self::Error> {
+        self.lock().remove(key);
+        Ok(())
+    }
+
+    fn store(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), Self::Error> {
+        self.lock().insert(key.to_owned(), (Deadline::after(&self.clock, ttl), value.to_owned()));
+        Ok(())
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.lock().get(key).map(|(_, value)| value.clone()))
+    }
+}
+
+/// Reply to a Redis command.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum RedisReply {
+    Nil,
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+}
+
+/// Executes Redis commands, e.g. using the `redis` crate.
+///
+/// The command is given as its arguments, the first one being the name of the command. Closures
+/// taking `&[&[u8]]` and returning `Result<RedisReply, E>` implement this trait.
+pub trait RedisConnection {
+    type Error: Into<BoxError>;
+
+    fn execute(&self, command: &[&[u8]]) -> Result<RedisReply, Self::Error>;
+}
+
+impl<E, F> RedisConnection for F where F: Fn(&[&[u8]]) -> Result<RedisReply, E>, E: Into<BoxError> {
+    type Error = E;
+
+    fn execute(&self, command: &[&[u8]]) -> Result<RedisReply, Self::Error> {
+        self(command)
+    }
+}
+
+/// The server replied with something the command can't return.
+#[derive(Debug)]
+struct UnexpectedReply(RedisReply);
+
+impl fmt::Display for UnexpectedReply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unexpected reply from Redis: {:?}", self.0)
+    }
+}
+
+impl std::error::Error for UnexpectedReply {}
+
+// deletes the key only if this instance still holds the claim
+const RELEASE_SCRIPT: &str = "if redis.call('get', KEYS[1]) == ARGV[1] then return redis.call('del', KEYS[1]) else return 0 end";
+
+/// Coordinator storing claims and values in Redis (or a compatible server).
+///
+/// Claims use `SET NX PX` with a random token of the instance so an instance never releases a
+/// claim that expired and was taken by another one.
+pub struct RedisCoordinator<C> {
+    connection: C,
+    prefix: String,
+    token: String,
+}
+
+impl<C: RedisConnection> RedisCoordinator<C> {
+    /// Creates the coordinator prefixing all keys with `prefix`, e.g. `payjoin:`.
+    pub fn new(connection: C, prefix: impl Into<String>) -> Self {
+        use bitcoin::hashes::hex::ToHex;
+
+        RedisCoordinator {
+            connection,
+            prefix: prefix.into(),
+            token: rand::random::<[u8; 16]>().to_hex(),
+        }
+    }
+
+    fn execute(&self, command: &[&[u8]]) -> Result<RedisReply, BoxError> {
+        self.connection.execute(command).map_err(Into::into)
+    }
+}
+
+impl<C: RedisConnection> Coordinator for RedisCoordinator<C> {
+    type Error = BoxError;
+
+    fn claim(&self, key: &str, ttl: Duration) -> Result<bool, Self::Error> {
+        let key = format!("{}{}", self.prefix, key);
+        let ttl = ttl.as_millis().max(1).to_string();
+        match self.execute(&[b"SET", key.as_bytes(), self.token.as_bytes(), b"NX", b"PX", ttl.as_bytes()])? {
+            RedisReply::Status(status) if status == "OK" => Ok(true),
+            RedisReply::Nil => Ok(false),
+            reply => Err(UnexpectedReply(reply).into()),
+        }
+    }
+
+    fn release(&self, key: &str) -> Result<(), Self::Error> {
+        let key = format!("{}{}", self.prefix, key);
+        match self.execute(&[b"EVAL", RELEASE_SCRIPT.as_bytes(), b"1", key.as_bytes(), self.token.as_bytes()])? {
+            RedisReply::Integer(_) => Ok(()),
+            reply => Err(UnexpectedReply(reply).into()),
+        }
+    }
+
+    fn store(&self, key: &str, value: &[u8], ttl: Duration) -> Result<(), Self::Error> {
+        let key = format!("{}{}", self.prefix, key);
+        let ttl = ttl.as_millis().max(1).to_string();
+        match self.execute(&[b"SET", key.as_bytes(), value, b"PX", ttl.as_bytes()])? {
+            RedisReply::Status(status) if status == "OK" => Ok(()),
+            reply => Err(UnexpectedReply(reply).into()),
+        }
+    }
+
+    fn load(&self, key: &str) -> Result<Option<Vec<u8>>, Self::Error> {
+        let key = format!("{}{}", self.prefix, key);
+        match self.execute(&[b"GET", key.as_bytes()])? {
+            RedisReply::Bulk(value) => Ok(Some(value)),
+            RedisReply::Nil => Ok(None),
+            reply => Err(UnexpectedReply(reply).into()),
+        }
+    }
+}
+
+/// Serializes the response for sharing it with other instances.
+///
+/// Status, length of the fallback transaction (zero if none), the transaction and the body.
+pub(super) fn serialize_response(response: &Response) -> Vec<u8> {
+    let fallback = response.fallback.as_ref().map(bitcoin::consensus::serialize).unwrap_or_default();
+    let mut bytes = Vec::with_capacity(6 + fallback.len() + response.body.len());
+    bytes.extend_from_slice(&response.status.to_le_bytes());
+    bytes.extend_from_slice(&(fallback.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&fallback);
+    bytes.extend_from_slice(&response.body);
+    bytes
+}
+
+/// Returns `None` if the bytes weren't produced by `serialize_response()`.
+pub(super) fn deserialize_response(bytes: &[u8]) -> Option<Response> {
+    let status = u16::from_le_bytes(bytes.get(..2)?.try_into().ok()?);
+    let fallback_len = u32::from_le_bytes(bytes.get(2..6)?.try_into().ok()?) as usize;
+    let fallback_end = 6usize.checked_add(fallback_len)?;
+    let fallback = match fallback_len {
+        0 => None,
+        _ => Some(bitcoin::consensus::deserialize(bytes.get(6..fallback_end)?).ok()?),
+    };
+    Some(Response { status, body: bytes.get(fallback_end..)?.to_owned(), fallback, delay: Duration::from_secs(0), })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::testing::MockClock;
+    use super::*;
+
+    #[test]
+    fn in_process() {
+        let clock = Arc::new(MockClock::new());
+        let coordinator = InProcessCoordinator::with_clock(Arc::clone(&clock));
+        let ttl = Duration::from_secs(60);
+        assert_eq!(coordinator.claim("a", ttl), Ok(true));
+        assert_eq!(coordinator.claim("a", ttl), Ok(false));
+        coordinator.release("a").unwrap();
+        assert_eq!(coordinator.claim("a", ttl), Ok(true));
+        coordinator.store("b", b"value", ttl).unwrap();
+        assert_eq!(coordinator.load("b"), Ok(Some(b"value".to_vec())));
+        clock.advance(ttl);
+        assert_eq!(coordinator.load("b"), Ok(None));
+        assert_eq!(coordinator.claim("a", ttl), Ok(true));
+    }
+
+    /// Implements the commands used by `RedisCoordinator` ignoring expiration.
+    #[derive(Default)]
+    struct MockRedis(RefCell<HashMap<Vec<u8>, Vec<u8>>>);
+
+    impl MockRedis {
+        fn execute(&self, command: &[&[u8]]) -> Result<RedisReply, &'static str> {
+            let mut values = self.0.borrow_mut();
+            let ok = RedisReply::Status("OK".to_owned());
+            match command {
+                [b"SET", key, value, b"NX", b"PX", _] if values.contains_key(*key) => Ok(RedisReply::Nil),
+                [b"SET", key, value, b"NX", b"PX", _] | [b"SET", key, value, b"PX", _] => {
+                    values.insert(key.to_vec(), value.to_vec());
+                    Ok(ok)
+                },
+                [b"GET", key] => Ok(values.get(*key).cloned().map_or(RedisReply::Nil, RedisReply::Bulk)),
+                [b"EVAL", script, b"1", key, token] if *script == RELEASE_SCRIPT.as_bytes() => {
+                    if values.get(*key).map(Vec::as_slice) == Some(*token) {
+                        values.remove(*key);
+                        Ok(RedisReply::Integer(1))
+                    } else {
+                        Ok(RedisReply::Integer(0))
+                    }
+                },
+                _ => Err("unknown command"),
+            }
+        }
+    }
+
+    #[test]
+    fn redis() {
+        let redis = MockRedis::default();
+        let ttl = Duration::from_secs(60);
+        let first = RedisCoordinator::new(|command: &[&[u8]]| redis.execute(command), "payjoin:");
+        let second = RedisCoordinator::new(|command: &[&[u8]]| redis.execute(command), "payjoin:");
+        assert!(first.claim("a", ttl).unwrap());
+        assert!(!second.claim("a", ttl).unwrap());
+        // only the owner can release the claim
+        second.release("a").unwrap();
+        assert!(!second.claim("a", ttl).unwrap());
+        first.release("a").unwrap();
+        assert!(second.claim("a", ttl).unwrap());
+        first.store("b", b"value", ttl).unwrap();
+        assert_eq!(second.load("b").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(second.load("c").unwrap(), None);
+        assert!(redis.0.borrow().contains_key(&b"payjoin:b"[..]));
+    }
+
+    #[test]
+    fn response_roundtrip() {
+        let fallback = crate::testing::original_psbt().extract_tx();
+        let response = Response { status: 200, body: b"cHNidP8=".to_vec(), fallback: Some(fallback.clone()), delay: Duration::from_secs(0), };
+        let deserialized = deserialize_response(&serialize_response(&response)).unwrap();
+        assert_eq!((deserialized.status, deserialized.body, deserialized.fallback), (200, response.body, Some(fallback)));
+        let response = Response { status: 400, body: Vec::new(), fallback: None, delay: Duration::from_secs(0), };
+        let bytes = serialize_response(&response);
+        assert!(deserialize_response(&bytes).unwrap().fallback.is_none());
+        assert!(deserialize_response(&bytes[..5]).is_none());
+    }
+}
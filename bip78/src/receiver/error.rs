@@ -1,11 +1,123 @@
+use std::fmt;
+
+pub use crate::ErrorCode;
+
+/// Serializes the error into JSON body of the response as defined in BIP78.
+fn to_json(code: ErrorCode, message: &impl fmt::Display) -> String {
+    use std::fmt::Write;
+
+    let mut json = String::new();
+    write!(json, "{{\"errorCode\":\"{}\",", code).expect("writing to string doesn't fail");
+    if code == ErrorCode::VersionUnsupported {
+        json.push_str("\"supported\":[");
+        for (i, version) in crate::ProtocolVersion::SUPPORTED.iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            write!(json, "{}", version).expect("writing to string doesn't fail");
+        }
+        json.push_str("],");
+    }
+    json.push_str("\"message\":\"");
+    for c in message.to_string().chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            c if c.is_control() => write!(json, "\\u{:04x}", c as u32).expect("writing to string doesn't fail"),
+            c => json.push(c),
+        }
+    }
+    json.push_str("\"}");
+    json
+}
+
+/// Error returned when the request is malformed.
+///
+/// Respond with `http_status()` and `to_json()` as the body.
+#[derive(Debug)]
 pub struct RequestError(InternalRequestError);
 
+#[derive(Debug)]
 pub(crate) enum InternalRequestError {
     Decode(bitcoin::consensus::encode::Error),
     MissingHeader(&'static str),
     InvalidContentType(String),
     InvalidContentLength(std::num::ParseIntError),
     ContentLengthTooLarge(u64),
+    LimitExceeded(crate::limits::LimitExceeded),
+    InvalidDisableOutputSubstitution(String),
+    InvalidOriginalInput(crate::psbt::PsbtInputsError),
+    /// A value exceeds `MAX_MONEY` or the outputs exceed the inputs.
+    InvalidAmounts,
+    VersionUnsupported(String),
+    OnionRequired(super::Transport),
+    InvalidFeeParam(&'static str, String),
+}
+
+impl RequestError {
+    pub fn error_code(&self) -> ErrorCode {
+        match &self.0 {
+            InternalRequestError::VersionUnsupported(_) => ErrorCode::VersionUnsupported,
+            InternalRequestError::OnionRequired(_) => ErrorCode::Unavailable,
+            _ => ErrorCode::OriginalPsbtRejected,
+        }
+    }
+
+    /// HTTP status of the response - 403 if the transport was rejected, 400 otherwise.
+    pub fn http_status(&self) -> u16 {
+        match &self.0 {
+            InternalRequestError::OnionRequired(_) => 403,
+            _ => 400,
+        }
+    }
+
+    /// Returns the body of the response that should be sent to the sender.
+    pub fn to_json(&self) -> String {
+        to_json(self.error_code(), self)
+    }
+}
+
+impl fmt::Display for RequestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalRequestError::*;
+
+        match &self.0 {
+            Decode(_) => write!(f, "couldn't decode PSBT"),
+            MissingHeader(header) => write!(f, "missing header {}", header),
+            InvalidContentType(content_type) => write!(f, "invalid content type {}", content_type),
+            InvalidContentLength(_) => write!(f, "invalid content length"),
+            ContentLengthTooLarge(length) => write!(f, "content length {} is too large", length),
+            LimitExceeded(error) => write!(f, "{}", error),
+            InvalidDisableOutputSubstitution(value) => write!(f, "invalid value of disableoutputsubstitution: {}", value),
+            InvalidOriginalInput(_) => write!(f, "an input in the original transaction is invalid"),
+            InvalidAmounts => write!(f, "the amounts of the original transaction are out of range"),
+            VersionUnsupported(version) => write!(f, "version {} of payjoin is not supported", version),
+            OnionRequired(super::Transport::Clearnet) => write!(f, "clearnet requests are rejected, use the onion service"),
+            OnionRequired(_) => write!(f, "requests not coming through the onion service are rejected"),
+            InvalidFeeParam(name, value) => write!(f, "invalid value of {}: {}", name, value),
+        }
+    }
+}
+
+impl std::error::Error for RequestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalRequestError::*;
+
+        match &self.0 {
+            Decode(error) => Some(error),
+            MissingHeader(_) => None,
+            InvalidContentType(_) => None,
+            InvalidContentLength(error) => Some(error),
+            ContentLengthTooLarge(_) => None,
+            LimitExceeded(_) => None,
+            InvalidDisableOutputSubstitution(_) => None,
+            InvalidOriginalInput(error) => Some(error),
+            InvalidAmounts => None,
+            VersionUnsupported(_) => None,
+            OnionRequired(_) => None,
+            InvalidFeeParam(_, _) => None,
+        }
+    }
 }
 
 impl From<InternalRequestError> for RequestError {
@@ -13,3 +125,387 @@ impl From<InternalRequestError> for RequestError {
         RequestError(value)
     }
 }
+
+/// Error returned when a check of the original PSBT failed.
+///
+/// Respond with HTTP status 400 (or 503 if the code is `Unavailable`) and `to_json()` as the
+/// body.
+#[derive(Debug)]
+pub struct CheckError(InternalCheckError);
+
+#[derive(Debug)]
+pub(crate) enum InternalCheckError {
+    UnknownPaymentRequest,
+    AmountTooLow { expected: bitcoin::Amount, actual: bitcoin::Amount, },
+    PrevoutSpent { outpoint: bitcoin::OutPoint, status: super::PrevoutStatus, },
+    PrevoutStatusUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    DisallowedInputType { index: usize, input_type: Option<super::InputScriptType>, },
+    OpReturnRejected { index: usize, },
+    IssuedScriptNotPaid,
+    NodeUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    ChecksTimedOut,
+    NotBroadcastable,
+    InputsLocked,
+    InvoiceRejected(super::InvoiceStatus),
+    InvoiceStatusUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    CoordinatorUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    RequestInProgress,
+    Maintenance,
+    Disguised(Box<CheckError>),
+}
+
+impl CheckError {
+    /// Creates the error rejecting the original transaction because of the invoice status.
+    pub fn invoice_rejected(status: super::InvoiceStatus) -> Self {
+        InternalCheckError::InvoiceRejected(status).into()
+    }
+
+    pub fn error_code(&self) -> ErrorCode {
+        use InternalCheckError::*;
+
+        match &self.0 {
+            UnknownPaymentRequest => ErrorCode::OriginalPsbtRejected,
+            AmountTooLow { .. } => ErrorCode::OriginalPsbtRejected,
+            PrevoutSpent { .. } => ErrorCode::OriginalPsbtRejected,
+            PrevoutStatusUnavailable(_) => ErrorCode::Unavailable,
+            DisallowedInputType { .. } => ErrorCode::OriginalPsbtRejected,
+            OpReturnRejected { .. } => ErrorCode::OriginalPsbtRejected,
+            IssuedScriptNotPaid => ErrorCode::OriginalPsbtRejected,
+            NodeUnavailable(_) => ErrorCode::Unavailable,
+            ChecksTimedOut => ErrorCode::Unavailable,
+            NotBroadcastable => ErrorCode::OriginalPsbtRejected,
+            InputsLocked => ErrorCode::OriginalPsbtRejected,
+            InvoiceRejected(_) => ErrorCode::OriginalPsbtRejected,
+            InvoiceStatusUnavailable(_) => ErrorCode::Unavailable,
+            CoordinatorUnavailable(_) => ErrorCode::Unavailable,
+            RequestInProgress => ErrorCode::Unavailable,
+            Maintenance => ErrorCode::Unavailable,
+            Disguised(_) => ErrorCode::Unavailable,
+        }
+    }
+
+    /// Returns `true` if the request looks like probing the receiver.
+    ///
+    /// Original transactions with inputs that are already locked, spent or otherwise can't be
+    /// broadcasted are unlikely to come from honest senders. See `ProbingGuard`.
+    pub fn is_suspicious(&self) -> bool {
+        use InternalCheckError::*;
+
+        match &self.0 {
+            PrevoutSpent { .. } | NotBroadcastable | InputsLocked => true,
+            Disguised(error) => error.is_suspicious(),
+            _ => false,
+        }
+    }
+
+    /// Returns the body of the response that should be sent to the sender.
+    pub fn to_json(&self) -> String {
+        to_json(self.error_code(), self)
+    }
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalCheckError::*;
+
+        match &self.0 {
+            UnknownPaymentRequest => write!(f, "the original transaction doesn't pay any known payment request"),
+            AmountTooLow { expected, actual, } => write!(f, "the original transaction pays {} but {} was requested", actual, expected),
+            PrevoutSpent { outpoint, status: super::PrevoutStatus::SpentInMempool, } => write!(f, "the input {} of the original transaction conflicts with a mempool transaction", outpoint),
+            PrevoutSpent { outpoint, .. } => write!(f, "the input {} of the original transaction is already spent", outpoint),
+            PrevoutStatusUnavailable(_) => write!(f, "failed to check the inputs of the original transaction"),
+            DisallowedInputType { index, input_type: Some(input_type), } => write!(f, "the input {} of the original transaction has disallowed type {:?}", index, input_type),
+            DisallowedInputType { index, input_type: None, } => write!(f, "the input {} of the original transaction has unknown type", index),
+            OpReturnRejected { index, } => write!(f, "the output {} of the original transaction is an OP_RETURN output", index),
+            IssuedScriptNotPaid => write!(f, "the original transaction doesn't pay the address of this payment exactly once"),
+            NodeUnavailable(_) => write!(f, "failed to check the original transaction"),
+            ChecksTimedOut => write!(f, "checking the original transaction took too long"),
+            NotBroadcastable => write!(f, "the original transaction can't be broadcasted"),
+            InputsLocked => write!(f, "the inputs of the original transaction are used in another payjoin"),
+            InvoiceRejected(super::InvoiceStatus::Fresh) => write!(f, "payjoin is not available for this payment request"),
+            InvoiceRejected(super::InvoiceStatus::Expired) => write!(f, "the payment request has expired"),
+            InvoiceRejected(super::InvoiceStatus::Unknown) => write!(f, "the original transaction doesn't pay any known payment request"),
+            InvoiceStatusUnavailable(_) => write!(f, "failed to check the payment request"),
+            CoordinatorUnavailable(_) => write!(f, "failed to coordinate with other instances of the receiver"),
+            RequestInProgress => write!(f, "the same request is being processed by another instance of the receiver"),
+            Maintenance => write!(f, "the receiver is temporarily not accepting payjoin requests"),
+            Disguised(_) => write!(f, "the receiver is temporarily unavailable"),
+        }
+    }
+}
+
+impl std::error::Error for CheckError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalCheckError::*;
+
+        match &self.0 {
+            UnknownPaymentRequest => None,
+            AmountTooLow { .. } => None,
+            PrevoutSpent { .. } => None,
+            PrevoutStatusUnavailable(error) => Some(&**error),
+            DisallowedInputType { .. } => None,
+            OpReturnRejected { .. } => None,
+            IssuedScriptNotPaid => None,
+            NodeUnavailable(error) => Some(&**error),
+            ChecksTimedOut => None,
+            NotBroadcastable => None,
+            InputsLocked => None,
+            InvoiceRejected(_) => None,
+            InvoiceStatusUnavailable(error) => Some(&**error),
+            CoordinatorUnavailable(error) => Some(&**error),
+            RequestInProgress => None,
+            Maintenance => None,
+            Disguised(error) => Some(&**error),
+        }
+    }
+}
+
+impl From<InternalCheckError> for CheckError {
+    fn from(value: InternalCheckError) -> Self {
+        CheckError(value)
+    }
+}
+
+/// Error that may occur when substituting the output.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it.
+#[derive(Debug)]
+pub struct OutputSubstitutionError(InternalOutputSubstitutionError);
+
+#[derive(Debug)]
+pub(crate) enum InternalOutputSubstitutionError {
+    Disabled,
+    NonStandardScript(bitcoin::Script),
+    OutputNotFound,
+}
+
+impl fmt::Display for OutputSubstitutionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalOutputSubstitutionError::*;
+
+        match &self.0 {
+            Disabled => write!(f, "the sender disabled output substitution"),
+            NonStandardScript(script) => write!(f, "the script {} is not standard", script),
+            OutputNotFound => write!(f, "the output to be substituted is not present in the transaction"),
+        }
+    }
+}
+
+impl std::error::Error for OutputSubstitutionError {}
+
+impl From<InternalOutputSubstitutionError> for OutputSubstitutionError {
+    fn from(value: InternalOutputSubstitutionError) -> Self {
+        OutputSubstitutionError(value)
+    }
+}
+
+/// Error that may occur when contributing inputs to the proposal.
+///
+/// Respond with HTTP status 503 (or 400 if the code is not `Unavailable`) and `to_json()` as
+/// the body.
+#[derive(Debug)]
+pub struct ContributionError(InternalContributionError);
+
+#[derive(Debug)]
+pub(crate) enum InternalContributionError {
+    InsufficientValue { available: bitcoin::Amount, required_fee: bitcoin::Amount, },
+    MissingUtxoInfo(crate::psbt::PrevTxOutError),
+    UnsupportedInputType,
+    MismatchedInputType,
+    OutputNotFound,
+    NoCandidates,
+    UnverifiedReceiverOutput,
+    NonStandardDecoyScript(bitcoin::Script),
+    NoDecoyTarget,
+    InsufficientValueForDecoy { decoy: bitcoin::Amount, missing: bitcoin::Amount, },
+    WalletUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    CoordinatorUnavailable(Box<dyn std::error::Error + Send + Sync>),
+    DeadlineExpired,
+}
+
+impl ContributionError {
+    pub fn error_code(&self) -> ErrorCode {
+        use InternalContributionError::*;
+
+        match &self.0 {
+            InsufficientValue { .. } => ErrorCode::NotEnoughMoney,
+            MissingUtxoInfo(_) => ErrorCode::Unavailable,
+            UnsupportedInputType => ErrorCode::Unavailable,
+            MismatchedInputType => ErrorCode::Unavailable,
+            OutputNotFound => ErrorCode::Unavailable,
+            NoCandidates => ErrorCode::Unavailable,
+            UnverifiedReceiverOutput => ErrorCode::Unavailable,
+            NonStandardDecoyScript(_) => ErrorCode::Unavailable,
+            NoDecoyTarget => ErrorCode::Unavailable,
+            InsufficientValueForDecoy { .. } => ErrorCode::NotEnoughMoney,
+            WalletUnavailable(_) => ErrorCode::Unavailable,
+            CoordinatorUnavailable(_) => ErrorCode::Unavailable,
+            DeadlineExpired => ErrorCode::Unavailable,
+        }
+    }
+
+    /// Returns the body of the response that should be sent to the sender.
+    ///
+    /// Internal problems of the receiver are not disclosed to the sender.
+    pub fn to_json(&self) -> String {
+        to_json(self.error_code(), &self.error_code().description())
+    }
+}
+
+impl fmt::Display for ContributionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalContributionError::*;
+
+        match &self.0 {
+            InsufficientValue { available, required_fee, } => write!(f, "the contributed input has value {} which doesn't cover its fee {}", available, required_fee),
+            MissingUtxoInfo(_) => write!(f, "the contributed input is missing UTXO information"),
+            UnsupportedInputType => write!(f, "can not determine the fee for the input type used by the sender"),
+            MismatchedInputType => write!(f, "the contributed input is of a different type than the inputs of the sender"),
+            OutputNotFound => write!(f, "the output receiving the contribution is not present in the transaction"),
+            NoCandidates => write!(f, "no inputs to contribute were provided"),
+            UnverifiedReceiverOutput => write!(f, "the output receiving the contribution wasn't verified by check_pays_issued_script()"),
+            NonStandardDecoyScript(script) => write!(f, "the decoy script {} is not standard", script),
+            NoDecoyTarget => write!(f, "the original transaction has no output of the sender to mimic"),
+            InsufficientValueForDecoy { decoy, missing, } => write!(f, "the contributed input is {} short of funding the decoy output of {}", missing, decoy),
+            WalletUnavailable(_) => write!(f, "failed to get inputs or scripts from the wallet"),
+            CoordinatorUnavailable(_) => write!(f, "failed to claim the inputs to contribute"),
+            DeadlineExpired => write!(f, "the remote UTXO source didn't provide the inputs in time"),
+        }
+    }
+}
+
+impl std::error::Error for ContributionError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalContributionError::*;
+
+        match &self.0 {
+            InsufficientValue { .. } => None,
+            MissingUtxoInfo(error) => Some(error),
+            UnsupportedInputType => None,
+            MismatchedInputType => None,
+            OutputNotFound => None,
+            NoCandidates => None,
+            UnverifiedReceiverOutput => None,
+            NonStandardDecoyScript(_) => None,
+            NoDecoyTarget => None,
+            InsufficientValueForDecoy { .. } => None,
+            WalletUnavailable(error) => Some(&**error),
+            CoordinatorUnavailable(error) => Some(&**error),
+            DeadlineExpired => None,
+        }
+    }
+}
+
+impl From<InternalContributionError> for ContributionError {
+    fn from(value: InternalContributionError) -> Self {
+        ContributionError(value)
+    }
+}
+
+/// Error that may occur when signing the contributed inputs.
+///
+/// This is always a problem of the receiver. Respond with HTTP status 503 and `to_json()` as the
+/// body.
+#[derive(Debug)]
+pub struct SigningError(InternalSigningError);
+
+#[derive(Debug)]
+pub(crate) enum InternalSigningError {
+    Signer { index: usize, error: Box<dyn std::error::Error + Send + Sync>, },
+    NotSigned { index: usize, },
+    UnsafeSighashFlag { index: usize, flag: u8, },
+    DeadlineExpired,
+}
+
+impl SigningError {
+    pub fn error_code(&self) -> ErrorCode {
+        ErrorCode::Unavailable
+    }
+
+    /// Returns the body of the response that should be sent to the sender.
+    ///
+    /// Internal problems of the receiver are not disclosed to the sender.
+    pub fn to_json(&self) -> String {
+        to_json(self.error_code(), &self.error_code().description())
+    }
+}
+
+impl fmt::Display for SigningError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalSigningError::*;
+
+        match &self.0 {
+            Signer { index, .. } => write!(f, "failed to sign the input #{}", index),
+            NotSigned { index, } => write!(f, "the signer didn't finalize the input #{}", index),
+            UnsafeSighashFlag { index, flag, } => write!(f, "the input #{} was signed with sighash flag {:#04x} instead of SIGHASH_ALL", index, flag),
+            DeadlineExpired => write!(f, "the remote signer didn't sign the inputs in time"),
+        }
+    }
+}
+
+impl std::error::Error for SigningError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalSigningError::*;
+
+        match &self.0 {
+            Signer { error, .. } => Some(&**error),
+            NotSigned { .. } => None,
+            UnsafeSighashFlag { .. } => None,
+            DeadlineExpired => None,
+        }
+    }
+}
+
+impl From<InternalSigningError> for SigningError {
+    fn from(value: InternalSigningError) -> Self {
+        SigningError(value)
+    }
+}
+
+/// Error returned when a snapshot of a proposal can't be resumed.
+#[derive(Debug)]
+pub struct SnapshotError(InternalSnapshotError);
+
+#[derive(Debug)]
+pub(crate) enum InternalSnapshotError {
+    InvalidMac,
+    UnsupportedVersion(u8),
+    UnknownStage(u8),
+    UnsupportedProtocolVersion(u32),
+    Decode(bitcoin::consensus::encode::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalSnapshotError::*;
+
+        match &self.0 {
+            InvalidMac => write!(f, "the snapshot was modified or created with a different key"),
+            UnsupportedVersion(version) => write!(f, "unsupported version {} of the snapshot format", version),
+            UnknownStage(stage) => write!(f, "unknown stage {} of the snapshot", stage),
+            UnsupportedProtocolVersion(version) => write!(f, "the snapshot uses unsupported payjoin version {}", version),
+            Decode(_) => write!(f, "failed to decode the snapshot"),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        use InternalSnapshotError::*;
+
+        match &self.0 {
+            InvalidMac => None,
+            UnsupportedVersion(_) => None,
+            UnknownStage(_) => None,
+            UnsupportedProtocolVersion(_) => None,
+            Decode(error) => Some(error),
+        }
+    }
+}
+
+impl From<InternalSnapshotError> for SnapshotError {
+    fn from(value: InternalSnapshotError) -> Self {
+        SnapshotError(value)
+    }
+}
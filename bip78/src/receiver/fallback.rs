@@ -0,0 +1,229 @@
+//! Delegating the broadcast of the original transaction
+//!
+//! If the sender never broadcasts the payjoin transaction the receiver must broadcast the original
+//! one. Receivers that are not online all the time can hand this off to a third-party service
+//! (watchtower) by giving it the `FallbackPackage`. The package contains only the signed original
+//! transaction so the service can't do anything else than broadcast it.
+//!
+//! Receivers broadcasting by themselves can use `FallbackScheduler`. Broadcasting the original
+//! also punishes senders that probe the receiver for its UTXOs without paying.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+use bitcoin::consensus::encode::{self, Encodable, Decodable};
+use rand::Rng;
+use crate::time::{Clock, Deadline, SystemClock};
+
+/// Version of the serialization format.
+const FORMAT_VERSION: u8 = 0;
+
+/// The original transaction and the time after which it should be broadcasted.
+///
+/// Use `bitcoin::consensus::serialize()` and `bitcoin::consensus::deserialize()` to convert it
+/// to/from bytes. The format is: version byte (currently 0), earliest broadcast time as UNIX
+/// timestamp in seconds (`u64`, little endian) and the consensus-encoded transaction.
+///
+/// The service should broadcast the transaction once `earliest_broadcast` expires unless the
+/// payjoin transaction (which conflicts with it) was already confirmed or is in mempool.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FallbackPackage {
+    pub transaction: bitcoin::Transaction,
+    pub earliest_broadcast: Deadline,
+}
+
+impl Encodable for FallbackPackage {
+    fn consensus_encode<W: std::io::Write>(&self, mut writer: W) -> Result<usize, std::io::Error> {
+        let timestamp = self.earliest_broadcast
+            .time()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or(Duration::from_secs(0))
+            .as_secs();
+        let mut len = FORMAT_VERSION.consensus_encode(&mut writer)?;
+        len += timestamp.consensus_encode(&mut writer)?;
+        len += self.transaction.consensus_encode(&mut writer)?;
+        Ok(len)
+    }
+}
+
+impl Decodable for FallbackPackage {
+    fn consensus_decode<D: std::io::Read>(mut decoder: D) -> Result<Self, encode::Error> {
+        if u8::consensus_decode(&mut decoder)? != FORMAT_VERSION {
+            return Err(encode::Error::ParseFailed("unsupported version of fallback package"));
+        }
+        let timestamp = u64::consensus_decode(&mut decoder)?;
+        let time = UNIX_EPOCH
+            .checked_add(Duration::from_secs(timestamp))
+            .ok_or(encode::Error::ParseFailed("earliest broadcast time out of range"))?;
+        let transaction = bitcoin::Transaction::consensus_decode(&mut decoder)?;
+
+        Ok(FallbackPackage {
+            transaction,
+            earliest_broadcast: Deadline::at(time),
+        })
+    }
+}
+
+/// Randomized delay before the original transaction is broadcasted.
+///
+/// A fixed delay would reveal which implementation the receiver uses to anyone watching the
+/// mempool so the delay is chosen uniformly from a range. Defaults to one to three minutes.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct FallbackDelay {
+    min: Duration,
+    max: Duration,
+}
+
+impl FallbackDelay {
+    /// Delay chosen from `min..=max`, the bounds are swapped if `min > max`.
+    pub fn between(min: Duration, max: Duration) -> Self {
+        if min <= max {
+            FallbackDelay { min, max, }
+        } else {
+            FallbackDelay { min: max, max: min, }
+        }
+    }
+
+    /// Always the same delay. Not recommended, see above.
+    pub fn fixed(delay: Duration) -> Self {
+        FallbackDelay { min: delay, max: delay, }
+    }
+
+    /// Picks a random deadline.
+    pub fn deadline(&self, clock: &impl Clock) -> Deadline {
+        let jitter = self.max - self.min;
+        let jitter_ms = rand::thread_rng().gen_range(0..=jitter.as_millis() as u64);
+        Deadline::after(clock, self.min + Duration::from_millis(jitter_ms))
+    }
+}
+
+impl Default for FallbackDelay {
+    fn default() -> Self {
+        FallbackDelay::between(Duration::from_secs(60), Duration::from_secs(180))
+    }
+}
+
+/// Collects fallback transactions of all proposals and releases them for broadcasting in batches.
+///
+/// Call `take_due()` periodically (see `next_deadline()`) and broadcast the returned transactions.
+/// Once one transaction is due, all transactions due within `batch_window` are released with it
+/// so that the broadcasts can't be correlated with individual requests. Cancel the transaction
+/// when the payjoin transaction appears in your mempool.
+pub struct FallbackScheduler {
+    clock: Arc<dyn Clock + Send + Sync>,
+    batch_window: Duration,
+    pending: Mutex<Vec<FallbackPackage>>,
+}
+
+impl FallbackScheduler {
+    /// Creates the scheduler releasing transactions due within `batch_window` together.
+    pub fn new(batch_window: Duration) -> Self {
+        Self::with_clock(SystemClock, batch_window)
+    }
+
+    /// Creates the scheduler using a custom clock.
+    pub fn with_clock(clock: impl Clock + Send + Sync + 'static, batch_window: Duration) -> Self {
+        FallbackScheduler {
+            clock: Arc::new(clock),
+            batch_window,
+            pending: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Schedules the package for broadcasting.
+    pub fn schedule(&self, package: FallbackPackage) {
+        self.lock().push(package);
+    }
+
+    /// Schedules the transaction for broadcasting after `delay`.
+    pub fn schedule_transaction(&self, transaction: bitcoin::Transaction, delay: &FallbackDelay) {
+        let earliest_broadcast = delay.deadline(&self.clock);
+        self.schedule(FallbackPackage { transaction, earliest_broadcast, });
+    }
+
+    /// Removes the transaction with given ID, returns `false` if it wasn't scheduled.
+    pub fn cancel(&self, txid: &bitcoin::Txid) -> bool {
+        let mut pending = self.lock();
+        let len = pending.len();
+        pending.retain(|package| package.transaction.txid() != *txid);
+        pending.len() != len
+    }
+
+    /// Returns the earliest deadline of scheduled transactions.
+    pub fn next_deadline(&self) -> Option<Deadline> {
+        self.lock().iter().map(|package| package.earliest_broadcast).min()
+    }
+
+    /// Removes and returns the transactions that should be broadcasted now.
+    pub fn take_due(&self) -> Vec<bitcoin::Transaction> {
+        let mut pending = self.lock();
+        let any_due = pending.iter().any(|package| package.earliest_broadcast.is_expired(&self.clock));
+        if !any_due {
+            return Vec::new();
+        }
+        let batch_end = Deadline::after(&self.clock, self.batch_window);
+        let (due, not_due) = std::mem::take(&mut *pending)
+            .into_iter()
+            .partition::<Vec<_>, _>(|package| package.earliest_broadcast <= batch_end);
+        *pending = not_due;
+        due.into_iter().map(|package| package.transaction).collect()
+    }
+
+    /// Returns the number of scheduled transactions.
+    pub fn len(&self) -> usize {
+        self.lock().len()
+    }
+
+    /// Returns `true` if no transaction is scheduled.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<FallbackPackage>> {
+        self.pending.lock().expect("mutex not poisoned")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::testing::MockClock;
+    use crate::time::Deadline;
+    use super::{FallbackDelay, FallbackScheduler};
+
+    fn transaction(lock_time: u32) -> bitcoin::Transaction {
+        bitcoin::Transaction { version: 2, lock_time, input: Vec::new(), output: Vec::new(), }
+    }
+
+    #[test]
+    fn delay() {
+        let clock = MockClock::new();
+        let delay = FallbackDelay::between(Duration::from_secs(120), Duration::from_secs(60));
+        for _ in 0..100 {
+            let remaining = delay.deadline(&clock).remaining(&clock);
+            assert!(remaining >= Duration::from_secs(60) && remaining <= Duration::from_secs(120));
+        }
+        assert_eq!(FallbackDelay::fixed(Duration::from_secs(1)).deadline(&clock), Deadline::after(&clock, Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn batches() {
+        let clock = Arc::new(MockClock::new());
+        let scheduler = FallbackScheduler::with_clock(Arc::clone(&clock), Duration::from_secs(30));
+        for (lock_time, delay) in [(1, 60), (2, 80), (3, 100), (4, 200)].iter() {
+            scheduler.schedule_transaction(transaction(*lock_time), &FallbackDelay::fixed(Duration::from_secs(*delay)));
+        }
+        assert!(scheduler.cancel(&transaction(3).txid()));
+        assert!(!scheduler.cancel(&transaction(3).txid()));
+        assert!(scheduler.take_due().is_empty());
+        assert_eq!(scheduler.next_deadline(), Some(Deadline::after(&*clock, Duration::from_secs(60))));
+
+        clock.advance(Duration::from_secs(60));
+        // 2 is due within the batch window
+        assert_eq!(scheduler.take_due(), [transaction(1), transaction(2)]);
+        assert_eq!(scheduler.len(), 1);
+        clock.advance(Duration::from_secs(140));
+        assert_eq!(scheduler.take_due(), [transaction(4)]);
+        assert!(scheduler.is_empty());
+    }
+}
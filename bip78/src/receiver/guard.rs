@@ -0,0 +1,109 @@
+//! Hiding the reason of rejecting suspicious requests
+//!
+//! A sender probing the receiver sends original transactions whose inputs are already locked,
+//! spent or otherwise can't be broadcasted and learns from the accurate error (and from how long
+//! it took to produce it) which checks the receiver performs and how far the request got.
+//! `ProbingGuard` flags such requests (see `CheckError::is_suspicious()`) and, with
+//! `ErrorPolicy::Decoy`, answers them with the same generic `unavailable` error as any temporary
+//! failure, sent after the same delay.
+
+use std::sync::Arc;
+use std::time::Duration;
+use crate::time::{Clock, Deadline, SystemClock};
+use super::error::InternalCheckError;
+use super::CheckError;
+
+/// How `PayjoinReceiver` responds to requests rejected by the checks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ErrorPolicy {
+    /// The actual error is returned.
+    Accurate,
+    /// Suspicious requests and temporary failures get the same generic `unavailable` error.
+    ///
+    /// `Response::delay` of all errors is set so that they are sent `response_time` after the
+    /// request arrived. Choose it longer than the checks usually take, responses that took
+    /// longer are sent immediately.
+    Decoy { response_time: Duration, },
+}
+
+/// Guard applying `ErrorPolicy` to the responses of `PayjoinReceiver`.
+///
+/// The original errors are still available as `source()` of the generic one for logging, the
+/// sender only sees the generic message.
+pub struct ProbingGuard {
+    clock: Arc<dyn Clock + Send + Sync>,
+    policy: ErrorPolicy,
+}
+
+impl ProbingGuard {
+    pub fn new(policy: ErrorPolicy) -> Self {
+        Self::with_clock(SystemClock, policy)
+    }
+
+    /// Creates the guard measuring the response time using a custom clock.
+    pub fn with_clock(clock: impl Clock + Send + Sync + 'static, policy: ErrorPolicy) -> Self {
+        ProbingGuard {
+            clock: Arc::new(clock),
+            policy,
+        }
+    }
+
+    pub fn policy(&self) -> ErrorPolicy {
+        self.policy
+    }
+
+    /// Returns the error that should be sent to the sender instead of `error`.
+    pub fn disguise(&self, error: CheckError) -> CheckError {
+        match self.policy {
+            ErrorPolicy::Decoy { .. } if error.is_suspicious() || error.error_code() == super::ErrorCode::Unavailable => {
+                InternalCheckError::Disguised(Box::new(error)).into()
+            },
+            _ => error,
+        }
+    }
+
+    /// Called when the request arrives, returns when the error response should be sent.
+    pub(crate) fn start(&self) -> Option<Deadline> {
+        match self.policy {
+            ErrorPolicy::Accurate => None,
+            ErrorPolicy::Decoy { response_time, } => Some(Deadline::after(&self.clock, response_time)),
+        }
+    }
+
+    /// Returns how long the error response should be delayed.
+    pub(crate) fn delay(&self, deadline: &Deadline) -> Duration {
+        deadline.remaining(&self.clock)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+    use crate::testing::MockClock;
+    use super::*;
+
+    #[test]
+    fn decoy() {
+        let clock = Arc::new(MockClock::new());
+        let guard = ProbingGuard::with_clock(Arc::clone(&clock), ErrorPolicy::Decoy { response_time: Duration::from_secs(2), });
+        let suspicious = guard.disguise(InternalCheckError::InputsLocked.into());
+        let unavailable = guard.disguise(InternalCheckError::RequestInProgress.into());
+        assert_eq!(suspicious.to_json(), unavailable.to_json());
+        assert_eq!(suspicious.error_code(), super::super::ErrorCode::Unavailable);
+        assert!(std::error::Error::source(&suspicious).is_some());
+        // errors of honest senders stay accurate
+        let error = guard.disguise(InternalCheckError::IssuedScriptNotPaid.into());
+        assert_eq!(error.error_code(), super::super::ErrorCode::OriginalPsbtRejected);
+
+        let deadline = guard.start().unwrap();
+        clock.advance(Duration::from_millis(500));
+        assert_eq!(guard.delay(&deadline), Duration::from_millis(1500));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(guard.delay(&deadline), Duration::ZERO);
+
+        let guard = ProbingGuard::new(ErrorPolicy::Accurate);
+        assert!(guard.start().is_none());
+        assert!(guard.disguise(InternalCheckError::InputsLocked.into()).is_suspicious());
+    }
+}
@@ -0,0 +1,127 @@
+//! Labeling outputs received through payjoin
+//!
+//! Once the payjoin settles, the receiver's output holds the payment and the value of the
+//! inputs the receiver contributed. Wallets showing it as "received X" misreport the history, so
+//! `PayjoinLabels` records where the value of each receiver output came from and passes the
+//! records to a `LabelSink` when the payjoin transaction is seen.
+
+use bitcoin::{Amount, OutPoint, Transaction, Txid};
+use bitcoin::hashes::sha256d;
+use super::Proposal;
+
+/// Provenance of a receiver output of a settled payjoin.
+///
+/// `payment + recycled == value`. The fee paid by the receiver for its inputs is deducted from
+/// the payment so `payment` is what the receiver actually gained.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct OutputLabel {
+    pub payjoin_txid: Txid,
+    pub original_txid: Txid,
+    pub outpoint: OutPoint,
+    pub value: Amount,
+    /// Part of the value paid by the sender.
+    pub payment: Amount,
+    /// Part of the value coming from inputs contributed by the receiver.
+    pub recycled: Amount,
+}
+
+/// Stores labels of settled payjoins, e.g. as BIP329 records in the wallet.
+///
+/// Closures taking `OutputLabel` and returning `Result<(), E>` implement this trait.
+pub trait LabelSink {
+    type Error: Into<Box<dyn std::error::Error + Send + Sync>>;
+
+    fn record_label(&self, label: OutputLabel) -> Result<(), Self::Error>;
+}
+
+impl<E, F> LabelSink for F where F: Fn(OutputLabel) -> Result<(), E>, E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    type Error = E;
+
+    fn record_label(&self, label: OutputLabel) -> Result<(), Self::Error> {
+        self(label)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct PendingLabel {
+    index: usize,
+    value: Amount,
+    payment: Amount,
+}
+
+/// Labels of the receiver outputs of a proposal waiting for the payjoin to settle.
+///
+/// Created by `Proposal::labels()`. Pass transactions seen in your mempool or in blocks (or the
+/// payjoin transaction reported by `SpendEvent::Settled`) to `settled()`.
+#[derive(Debug, Clone)]
+pub struct PayjoinLabels {
+    original_txid: Txid,
+    // doesn't change when the sender signs its inputs
+    expected_ntxid: sha256d::Hash,
+    outputs: Vec<PendingLabel>,
+}
+
+impl PayjoinLabels {
+    pub(super) fn new(proposal: &Proposal) -> Self {
+        let tx = &proposal.psbt.global.unsigned_tx;
+        let recycled = tx.input
+            .iter()
+            .zip(&proposal.psbt.inputs)
+            .filter(|(txin, _)| !proposal.sender_inputs.contains(&txin.previous_output))
+            .filter_map(|(txin, psbtin)| crate::psbt::InputPair { txin, psbtin, }.previous_txout().ok().map(|txout| Amount::from_sat(txout.value)))
+            .fold(Amount::ZERO, |sum, value| sum.checked_add(value).unwrap_or(sum));
+        // decoys are funded by the contributed inputs, the payee output gets the rest
+        let is_payee = |output: &bitcoin::TxOut| proposal.payee.as_ref() == Some(&output.script_pubkey);
+        let decoys = tx.output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| !is_payee(output))
+            .filter(|(_, output)| !proposal.original_tx.output.iter().any(|original| original.script_pubkey == output.script_pubkey));
+        let payee = tx.output
+            .iter()
+            .enumerate()
+            .filter(|(_, output)| is_payee(output));
+        let mut remaining = recycled;
+        let outputs = decoys
+            .chain(payee)
+            .map(|(index, output)| {
+                let value = Amount::from_sat(output.value);
+                let recycled = value.min(remaining);
+                remaining -= recycled;
+                PendingLabel { index, value, payment: value - recycled, }
+            })
+            .collect();
+        PayjoinLabels {
+            original_txid: proposal.original_txid(),
+            expected_ntxid: tx.ntxid(),
+            outputs,
+        }
+    }
+
+    /// Returns the ID of the original transaction.
+    pub fn original_txid(&self) -> Txid {
+        self.original_txid
+    }
+
+    /// Records the labels if `transaction` is the payjoin transaction.
+    ///
+    /// Returns `false` without recording anything for other transactions. If the sink fails the
+    /// labels recorded before the failure are not rolled back so the sink should be idempotent.
+    pub fn settled<S: LabelSink>(&self, transaction: &Transaction, sink: &S) -> Result<bool, S::Error> {
+        if transaction.ntxid() != self.expected_ntxid {
+            return Ok(false);
+        }
+        let payjoin_txid = transaction.txid();
+        for output in &self.outputs {
+            sink.record_label(OutputLabel {
+                payjoin_txid,
+                original_txid: self.original_txid,
+                outpoint: OutPoint { txid: payjoin_txid, vout: output.index as u32, },
+                value: output.value,
+                payment: output.payment,
+                recycled: output.value - output.payment,
+            })?;
+        }
+        Ok(true)
+    }
+}
@@ -0,0 +1,94 @@
+//! Instrumentation of request processing
+
+use std::time::Duration;
+use crate::time::Clock;
+
+/// Stage of processing the request.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum Stage {
+    /// Decoding the request - `UncheckedProposal::from_request_bytes()`.
+    DecodeRequest,
+    /// `UncheckedProposal::check_pays_expected_script()`
+    ExpectedScripts,
+    /// `UncheckedProposal::check_payment_request()` or `check_donation()`
+    PaymentRequest,
+    /// `UncheckedProposal::check_pays_issued_script()`
+    IssuedScript,
+    /// `UncheckedProposal::check_sender_input_types()`
+    SenderInputTypes,
+    /// `UncheckedProposal::check_op_return_outputs()`
+    OpReturnOutputs,
+    /// `UncheckedProposal::check_prevouts_unspent()`
+    Prevouts,
+    /// Checking that the original transaction can be broadcasted, usually by calling the node.
+    Broadcastability,
+    /// Locking the inputs of the original transaction in the wallet.
+    LockInputs,
+    /// Stage defined by the application.
+    Custom(&'static str),
+}
+
+/// Receives measurements of processing stages.
+///
+/// Implement this to forward the data to your monitoring system. `()` discards everything.
+pub trait Metrics {
+    /// Called after a stage completed (successfully or not).
+    fn record_stage(&self, stage: Stage, duration: Duration, passed: bool);
+}
+
+impl Metrics for () {
+    fn record_stage(&self, _stage: Stage, _duration: Duration, _passed: bool) {}
+}
+
+impl<M: Metrics + ?Sized> Metrics for &M {
+    fn record_stage(&self, stage: Stage, duration: Duration, passed: bool) {
+        (*self).record_stage(stage, duration, passed)
+    }
+}
+
+/// Runs `f` and records how long it took and whether it succeeded.
+///
+/// The result is returned unchanged.
+///
+/// ```
+/// use bip78::receiver::{UncheckedProposal, Stage, measure};
+///
+/// let body = bip78::testing::ORIGINAL_PSBT.as_bytes();
+/// let headers = bip78::testing::MockHeaders::new(body.len() as u64);
+/// let clock = bip78::time::SystemClock;
+/// let proposal = measure(&clock, &(), Stage::DecodeRequest, || UncheckedProposal::from_request_bytes(body, "v=1", headers)).unwrap();
+/// ```
+pub fn measure<T, E>(clock: &impl Clock, metrics: &(impl Metrics + ?Sized), stage: Stage, f: impl FnOnce() -> Result<T, E>) -> Result<T, E> {
+    let start = clock.now();
+    let result = f();
+    // the clock may go backwards
+    let duration = clock.now().duration_since(start).unwrap_or(Duration::from_secs(0));
+    metrics.record_stage(stage, duration, result.is_ok());
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockClock;
+    use std::cell::RefCell;
+
+    impl Metrics for RefCell<Vec<(Stage, Duration, bool)>> {
+        fn record_stage(&self, stage: Stage, duration: Duration, passed: bool) {
+            self.borrow_mut().push((stage, duration, passed));
+        }
+    }
+
+    #[test]
+    fn measure_stages() {
+        let clock = MockClock::new();
+        let metrics = RefCell::new(Vec::new());
+        measure(&clock, &metrics, Stage::ExpectedScripts, || Ok::<_, ()>(())).unwrap();
+        measure(&clock, &metrics, Stage::Broadcastability, || { clock.advance(Duration::from_millis(30)); Err::<(), _>(()) }).unwrap_err();
+        assert_eq!(*metrics.borrow(), [
+            (Stage::ExpectedScripts, Duration::from_secs(0), true),
+            (Stage::Broadcastability, Duration::from_millis(30), false),
+        ]);
+    }
+}
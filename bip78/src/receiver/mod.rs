@@ -1,67 +1,659 @@
+//! Receiver side of BIP78
+//!
+//! **Important: the receiver implementation is incomplete!**
+//!
+//! The receiver processes the request in stages represented by types. Each stage requires you to
+//! perform a check (e.g. whether the original transaction can be broadcasted) before moving to the
+//! next one.
+//!
+//! ## Order of checks
+//!
+//! The types only enforce the order of checks that must happen in sequence (broadcastability
+//! before locking inputs). The cheap checks on `UncheckedProposal` can be called in any order
+//! and you should call them before anything that hits your node, so that invalid requests are
+//! rejected as soon as possible:
+//!
+//! 1. `check_pays_expected_script()`
+//! 2. `check_payment_request()` (`check_donation()` for links without amount)
+//! 3. `check_pays_issued_script()` (required if you contribute inputs)
+//! 4. `check_sender_input_types()` (if you restrict them)
+//! 5. `check_prevouts_unspent()`
+//! 6. `get_transaction_to_check_broadcast()` + `testmempoolaccept`
+//!
+//! If you only accept requests through your onion service (`ReceiverOptions::onion_only()`)
+//! call `ReceiverOptions::check_request_meta()` even before parsing the request.
+//!
+//! Wrap the stages in `measure()` to find out which ones are slow or reject most requests.
+//! If you don't need to customize the stages use `PayjoinReceiver` which performs all of them,
+//! `PayjoinReceiverBuilder::check_order()` changes their order and `metrics()` records them.
+//! To continue processing in another service convert the stage into `ProposalSnapshot`.
+//!
+//! ## Example
+//!
+//! ```
+//! use bip78::receiver::UncheckedProposal;
+//!
+//! // In real code these come from your HTTP server
+//! let body = bip78::testing::ORIGINAL_PSBT.as_bytes();
+//! let headers = bip78::testing::MockHeaders::new(body.len() as u64);
+//! let proposal = UncheckedProposal::from_request_bytes(body, "v=1", headers).unwrap();
+//!
+//! // Mock check - in real code use `testmempoolaccept` RPC call of your node
+//! let tx = proposal.get_transaction_to_check_broadcast();
+//! assert_eq!(tx.input.len(), 1);
+//! let proposal = proposal.assume_broadcastability_was_verified();
+//!
+//! // Mock locking - in real code lock these in your wallet
+//! assert_eq!(proposal.utxos_to_be_locked().count(), 1);
+//! let proposal = proposal.assume_locked();
+//! ```
+
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
 use bitcoin::{Script, TxOut};
-use crate::psbt::PsbtExt;
+use crate::psbt::{PsbtExt, MAX_MONEY};
+use crate::output_type::OutputType;
+use crate::{FeeShare, Limits, ProtocolVersion};
 
+mod analysis;
+mod budget;
+mod builder;
+mod cache;
+mod coordination;
 mod error;
+mod fallback;
+mod guard;
+mod labels;
+mod metrics;
+mod mode;
+mod monitor;
+mod remote;
+mod scoring;
+mod snapshot;
+mod uri_factory;
 
-pub use error::RequestError;
-use error::InternalRequestError;
+pub use analysis::{ProposalAnalysis, Accounting, PartyAmounts};
+pub use budget::{ContributionBudget, SenderParams};
+pub use builder::{PayjoinReceiver, PayjoinReceiverBuilder, OriginalChecks, UtxoSource, Strategy, Request, Response, DryRun};
+pub use cache::{ResponseCache, CacheStats};
+pub use coordination::{Coordinator, InProcessCoordinator, RedisCoordinator, RedisConnection, RedisReply};
+pub use error::{RequestError, CheckError, ErrorCode, OutputSubstitutionError, ContributionError, SigningError, SnapshotError};
+pub use fallback::{FallbackPackage, FallbackDelay, FallbackScheduler};
+pub use guard::{ProbingGuard, ErrorPolicy};
+pub use labels::{LabelSink, OutputLabel, PayjoinLabels};
+pub use metrics::{Metrics, Stage, measure};
+pub use mode::{ModeSwitch, ReceiverMode};
+pub use monitor::{ContributionMonitor, SpendEvent};
+pub use remote::{RemoteUtxoSource, RemoteSigner};
+pub use scoring::{Candidate, ProposalScorer, DefaultScorer, CandidateScore};
+pub use snapshot::ProposalSnapshot;
+pub use uri_factory::{UriFactory, AddressSource, XpubAddressSource};
+use error::{InternalRequestError, InternalCheckError, InternalOutputSubstitutionError, InternalContributionError, InternalSigningError};
 
 pub trait Headers {
     fn get_header(&self, key: &str) -> Option<&str>;
 }
 
+/// How the request reached the HTTP server.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Transport {
+    /// Through a Tor onion service.
+    Onion,
+    /// Directly over the internet.
+    Clearnet,
+    /// The server can't tell, e.g. because it sits behind a reverse proxy.
+    Unknown,
+}
+
+/// Information about the connection that delivered the request.
+///
+/// Only the HTTP server knows these so it has to fill them in. Checked by
+/// `ReceiverOptions::check_request_meta()` before the PSBT is parsed.
+#[derive(Debug, Clone)]
+pub struct RequestMeta {
+    pub transport: Transport,
+    /// Address of the peer if known.
+    ///
+    /// Requests arriving through an onion service come from the local Tor daemon so don't
+    /// rely on it to identify senders.
+    pub remote_addr: Option<std::net::IpAddr>,
+}
+
+impl Default for RequestMeta {
+    fn default() -> Self {
+        RequestMeta {
+            transport: Transport::Unknown,
+            remote_addr: None,
+        }
+    }
+}
+
+/// Outstanding payment requests (invoices) known to the receiver.
+///
+/// This is usually backed by the invoicing system of the receiver. It's used to reject
+/// original PSBTs paying unknown or stale addresses before contributing any inputs.
+pub trait PaymentRequestStore {
+    /// Returns the requested amount if the script belongs to an outstanding payment request.
+    fn requested_amount(&self, script_pubkey: &Script) -> Option<bitcoin::Amount>;
+}
+
+impl PaymentRequestStore for std::collections::HashMap<Script, bitcoin::Amount> {
+    fn requested_amount(&self, script_pubkey: &Script) -> Option<bitcoin::Amount> {
+        self.get(script_pubkey).copied()
+    }
+}
+
+/// State of the invoice paid by the original transaction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum InvoiceStatus {
+    /// The invoice is outstanding.
+    Fresh,
+    /// The invoice expired. The payment is still valid on-chain but the sender may have used
+    /// stale exchange rates.
+    Expired,
+    /// The script doesn't belong to any invoice known to the receiver.
+    Unknown,
+}
+
+/// Provides the status of the invoice paid by the issued script.
+///
+/// Closures returning `Result<InvoiceStatus, E>` implement this trait.
+pub trait InvoiceStatusProvider {
+    type Error: Into<Box<dyn std::error::Error + Send + Sync>>;
+
+    fn invoice_status(&self, script_pubkey: &Script) -> Result<InvoiceStatus, Self::Error>;
+}
+
+impl<E, F> InvoiceStatusProvider for F where F: Fn(&Script) -> Result<InvoiceStatus, E>, E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    type Error = E;
+
+    fn invoice_status(&self, script_pubkey: &Script) -> Result<InvoiceStatus, Self::Error> {
+        self(script_pubkey)
+    }
+}
+
+/// What to do with the original transaction, see `InvoicePolicy`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum InvoiceAction {
+    /// Contribute inputs as usual.
+    Contribute,
+    /// Send the original transaction back unchanged so that no UTXO is revealed.
+    AcceptWithoutContribution,
+    /// Respond with an error.
+    ///
+    /// If `broadcast` is `true` the original transaction is still offered for broadcasting
+    /// (`Response::fallback`) since the payment is valid on-chain.
+    Reject { broadcast: bool, },
+}
+
+/// Maps each `InvoiceStatus` to an `InvoiceAction`.
+///
+/// By default only fresh invoices get contributions, expired ones are rejected but broadcasted
+/// and unknown ones are rejected.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct InvoicePolicy {
+    fresh: InvoiceAction,
+    expired: InvoiceAction,
+    unknown: InvoiceAction,
+}
+
+impl InvoicePolicy {
+    /// Sets the action for `InvoiceStatus::Fresh`.
+    pub fn fresh(mut self, action: InvoiceAction) -> Self {
+        self.fresh = action;
+        self
+    }
+
+    /// Sets the action for `InvoiceStatus::Expired`.
+    pub fn expired(mut self, action: InvoiceAction) -> Self {
+        self.expired = action;
+        self
+    }
+
+    /// Sets the action for `InvoiceStatus::Unknown`.
+    pub fn unknown(mut self, action: InvoiceAction) -> Self {
+        self.unknown = action;
+        self
+    }
+
+    /// Returns the action for `status`.
+    pub fn action(&self, status: InvoiceStatus) -> InvoiceAction {
+        match status {
+            InvoiceStatus::Fresh => self.fresh,
+            InvoiceStatus::Expired => self.expired,
+            InvoiceStatus::Unknown => self.unknown,
+        }
+    }
+}
+
+impl Default for InvoicePolicy {
+    fn default() -> Self {
+        InvoicePolicy {
+            fresh: InvoiceAction::Contribute,
+            expired: InvoiceAction::Reject { broadcast: true, },
+            unknown: InvoiceAction::Reject { broadcast: false, },
+        }
+    }
+}
+
+/// State of an output spent by the original transaction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum PrevoutStatus {
+    /// The output exists and is not spent by any confirmed or mempool transaction.
+    Unspent,
+    /// The output is spent by a confirmed transaction or it doesn't exist at all.
+    Spent,
+    /// The output is spent by a transaction in the mempool.
+    SpentInMempool,
+}
+
+/// Provides the state of outputs spent by the original transaction.
+///
+/// This library does no IO so you need to implement it using your node or block explorer:
+///
+/// * Bitcoin Core: `gettxout <txid> <vout> true` returns `null` if the output is spent (also by
+///   a mempool transaction), calling it with `false` distinguishes `SpentInMempool`
+/// * Esplora: `GET /tx/<txid>/outspend/<vout>` returns `spent` and `status.confirmed`
+///
+/// Closures returning `Result<PrevoutStatus, E>` implement this trait.
+pub trait PrevoutStatusProvider {
+    type Error: Into<Box<dyn std::error::Error + Send + Sync>>;
+
+    fn prevout_status(&self, outpoint: &bitcoin::OutPoint) -> Result<PrevoutStatus, Self::Error>;
+}
+
+impl<E, F> PrevoutStatusProvider for F where F: Fn(&bitcoin::OutPoint) -> Result<PrevoutStatus, E>, E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    type Error = E;
+
+    fn prevout_status(&self, outpoint: &bitcoin::OutPoint) -> Result<PrevoutStatus, Self::Error> {
+        self(outpoint)
+    }
+}
+
+/// Signs inputs contributed by the receiver.
+///
+/// The implementation gets the whole proposal and the index of the input to sign and returns the
+/// finalized input - only `final_script_sig` and `final_script_witness` are used. The
+/// `sighash_type` of the input is set to `SIGHASH_ALL` so that PSBT signers honor it.
+///
+/// Closures taking `&Psbt` and `usize` and returning `Result<psbt::Input, E>` implement this trait.
+pub trait InputSigner {
+    type Error: Into<Box<dyn std::error::Error + Send + Sync>>;
+
+    fn sign_input(&self, psbt: &Psbt, index: usize) -> Result<bitcoin::util::psbt::Input, Self::Error>;
+}
+
+impl<E, F> InputSigner for F where F: Fn(&Psbt, usize) -> Result<bitcoin::util::psbt::Input, E>, E: Into<Box<dyn std::error::Error + Send + Sync>> {
+    type Error = E;
+
+    fn sign_input(&self, psbt: &Psbt, index: usize) -> Result<bitcoin::util::psbt::Input, Self::Error> {
+        self(psbt, index)
+    }
+}
+
+/// Fast membership test for scripts the receiver currently expects to be paid.
+///
+/// This is meant as a cheap pre-filter performed before any node RPC calls so that attackers
+/// can't cause expensive work by sending PSBTs paying random addresses. False positives are
+/// fine (the full checks follow), so a bloom filter can be plugged in using a closure.
+pub trait ExpectedScripts {
+    fn contains_script(&self, script_pubkey: &Script) -> bool;
+}
+
+impl<F: Fn(&Script) -> bool> ExpectedScripts for F {
+    fn contains_script(&self, script_pubkey: &Script) -> bool {
+        self(script_pubkey)
+    }
+}
+
+impl ExpectedScripts for std::collections::HashSet<Script> {
+    fn contains_script(&self, script_pubkey: &Script) -> bool {
+        self.contains(script_pubkey)
+    }
+}
+
+impl ExpectedScripts for std::collections::BTreeSet<Script> {
+    fn contains_script(&self, script_pubkey: &Script) -> bool {
+        self.contains(script_pubkey)
+    }
+}
+
+impl<V> ExpectedScripts for std::collections::HashMap<Script, V> {
+    fn contains_script(&self, script_pubkey: &Script) -> bool {
+        self.contains_key(script_pubkey)
+    }
+}
+
 pub struct UncheckedProposal {
     psbt: Psbt,
+    params: Params,
+    payee: Option<Script>,
+}
+
+/// Optional parameters sent by the sender in the query string.
+#[derive(Clone)]
+struct Params {
+    version: ProtocolVersion,
+    disable_output_substitution: bool,
+    /// Maximum contribution and index of the output it's deducted from.
+    fee_contribution: Option<(bitcoin::Amount, usize)>,
+    min_fee_rate: Option<crate::fee_rate::FeeRate>,
+}
+
+impl Params {
+    fn from_query(query: &str) -> Result<Self, RequestError> {
+        // BIP78 doesn't say what to do if the version is missing, assume the first one
+        let mut version = ProtocolVersion::V1;
+        let mut disable_output_substitution = false;
+        let mut max_fee_contribution = None;
+        let mut fee_output_index = None;
+        let mut min_fee_rate = None;
+        let invalid = |name, value: &str| RequestError::from(InternalRequestError::InvalidFeeParam(name, value.to_owned()));
+
+        for kv in query.split('&').filter(|kv| !kv.is_empty()) {
+            let mut kv = kv.splitn(2, '=');
+            let key = kv.next().expect("split always returns at least one item");
+            let value = kv.next().unwrap_or("");
+            // Unknown parameters must be ignored according to BIP78
+            match key {
+                "v" => {
+                    version = value
+                        .parse()
+                        .ok()
+                        .and_then(ProtocolVersion::from_number)
+                        .ok_or_else(|| InternalRequestError::VersionUnsupported(value.to_owned()))?;
+                },
+                "disableoutputsubstitution" => {
+                    disable_output_substitution = match value {
+                        "1" | "true" => true,
+                        "0" | "false" => false,
+                        _ => return Err(InternalRequestError::InvalidDisableOutputSubstitution(value.to_owned()).into()),
+                    };
+                },
+                "maxadditionalfeecontribution" => {
+                    let amount = value.parse::<u64>().map_err(|_| invalid("maxadditionalfeecontribution", value))?;
+                    max_fee_contribution = Some(bitcoin::Amount::from_sat(amount));
+                },
+                "additionalfeeoutputindex" => {
+                    fee_output_index = Some(value.parse::<usize>().map_err(|_| invalid("additionalfeeoutputindex", value))?);
+                },
+                "minfeerate" => {
+                    // sat/vB, fractions are allowed, higher rates than FeeRate::MAX can't be paid
+                    let rate = value
+                        .parse::<f64>()
+                        .ok()
+                        .filter(|rate| rate.is_finite() && *rate >= 0.0 && *rate <= MAX_MONEY as f64)
+                        .ok_or_else(|| invalid("minfeerate", value))?;
+                    min_fee_rate = Some(crate::fee_rate::FeeRate::from_sat_per_kwu((rate * 250.0) as u64));
+                },
+                _ => (),
+            }
+        }
+
+        Ok(Params {
+            version,
+            disable_output_substitution,
+            // the contribution is only meaningful with both parameters
+            fee_contribution: max_fee_contribution.zip(fee_output_index),
+            min_fee_rate,
+        })
+    }
+}
+
+/// Validates the headers and returns the content length.
+fn check_headers(headers: &impl Headers, limits: &Limits) -> Result<u64, RequestError> {
+    let content_type = headers.get_header("content-type").ok_or(InternalRequestError::MissingHeader("Content-Type"))?;
+    if content_type != "text/plain" {
+        return Err(InternalRequestError::InvalidContentType(content_type.to_owned()).into());
+    }
+    let content_length = headers
+        .get_header("content-length")
+        .ok_or(InternalRequestError::MissingHeader("Content-Length"))?
+        .parse::<u64>()
+        .map_err(InternalRequestError::InvalidContentLength)?;
+    if limits.check_psbt_size(content_length).is_err() {
+        return Err(InternalRequestError::ContentLengthTooLarge(content_length).into());
+    }
+    Ok(content_length)
+}
+
+/// Sums the values of the inputs and the outputs of the PSBT.
+///
+/// Returns `None` if a sum exceeds `MAX_MONEY` or an input lacks UTXO information.
+fn value_totals(psbt: &Psbt) -> Option<(u64, u64)> {
+    let add = |sum: u64, value: u64| sum.checked_add(value).filter(|sum| *sum <= MAX_MONEY);
+    let inputs = psbt.input_pairs().try_fold(0, |sum, input| add(sum, input.previous_txout().ok()?.value))?;
+    let outputs = psbt.global.unsigned_tx.output.iter().try_fold(0, |sum, output| add(sum, output.value))?;
+    Some((inputs, outputs))
 }
 
 impl UncheckedProposal {
+    /// Reads and decodes the request.
+    ///
+    /// This is a convenience adapter for blocking readers. Only `Content-Length` bytes are read
+    /// and only after the headers were validated. See `from_request_bytes()`.
     pub fn from_request(body: impl std::io::Read, query: &str, headers: impl Headers) -> Result<Self, RequestError> {
-        use crate::bitcoin::consensus::Decodable;
+        Self::from_request_with_limits(body, query, headers, &Limits::default())
+    }
 
-        let content_type = headers.get_header("content-type").ok_or(InternalRequestError::MissingHeader("Content-Type"))?;
-        if content_type != "text/plain" {
-            return Err(InternalRequestError::InvalidContentType(content_type.to_owned()).into());
-        }
-        let content_length = headers
-            .get_header("content-length")
-            .ok_or(InternalRequestError::MissingHeader("Content-Length"))?
-            .parse::<u64>()
-            .map_err(InternalRequestError::InvalidContentLength)?;
-        // 4M block size limit with base64 encoding overhead => maximum reasonable size of content-length
-        if content_length > 4_000_000 * 4 / 3 {
-            return Err(InternalRequestError::ContentLengthTooLarge(content_length).into());
-        }
+    /// Same as `from_request()` but enforces custom `limits`.
+    pub fn from_request_with_limits(body: impl std::io::Read, query: &str, headers: impl Headers, limits: &Limits) -> Result<Self, RequestError> {
+        use std::io::Read;
+
+        let content_length = check_headers(&headers, limits)?;
+
+        let mut bytes = Vec::new();
+        body.take(content_length)
+            .read_to_end(&mut bytes)
+            .map_err(|error| InternalRequestError::Decode(error.into()))?;
+
+        Self::from_request_bytes_with_limits(&bytes, query, headers, limits)
+    }
+
+    /// Decodes the request body.
+    ///
+    /// This doesn't perform any IO so you can use it with any HTTP library, async runtime or
+    /// across FFI. Bytes beyond `Content-Length` are ignored.
+    pub fn from_request_bytes(body: &[u8], query: &str, headers: impl Headers) -> Result<Self, RequestError> {
+        Self::from_request_bytes_with_limits(body, query, headers, &Limits::default())
+    }
+
+    /// Same as `from_request_bytes()` but enforces custom `limits`.
+    ///
+    /// Besides `Content-Length` the length of the query and the numbers of inputs and outputs
+    /// are checked.
+    pub fn from_request_bytes_with_limits(body: &[u8], query: &str, headers: impl Headers, limits: &Limits) -> Result<Self, RequestError> {
+        use crate::bitcoin::consensus::Decodable;
 
+        limits.check_url_length(query.len()).map_err(InternalRequestError::LimitExceeded)?;
+        let content_length = check_headers(&headers, limits)?;
+        // cheap, and senders probing the supported versions send no PSBT
+        let params = Params::from_query(query)?;
         // enforce the limit
-        let mut limited = body.take(content_length);
-        let reader = base64::read::DecoderReader::new(&mut limited, base64::STANDARD);
+        let mut body = &body[..body.len().min(content_length as usize)];
+        let reader = base64::read::DecoderReader::new(&mut body, base64::STANDARD);
         let psbt = Psbt::consensus_decode(reader).map_err(InternalRequestError::Decode)?;
+        limits.check_transaction(&psbt.global.unsigned_tx).map_err(InternalRequestError::LimitExceeded)?;
+        // Both witness and non-witness UTXOs are supported; non-witness ones are checked against
+        // the txid so that the sender can't lie about the amounts
+        psbt.validate_input_utxos(true).map_err(InternalRequestError::InvalidOriginalInput)?;
+        // the values are controlled by the sender, later computations rely on them being sane
+        match value_totals(&psbt) {
+            Some((inputs, outputs)) if outputs <= inputs => (),
+            _ => return Err(InternalRequestError::InvalidAmounts.into()),
+        }
 
         Ok(UncheckedProposal {
             psbt,
+            params,
+            payee: None,
         })
     }
 
+    /// Rejects the original PSBT early if it doesn't pay any expected script.
+    ///
+    /// This check is cheap and should be performed before anything that requires node RPC
+    /// calls. It doesn't replace `check_payment_request()`.
+    pub fn check_pays_expected_script(self, expected: &impl ExpectedScripts) -> Result<Self, CheckError> {
+        if self.psbt.global.unsigned_tx.output.iter().any(|output| expected.contains_script(&output.script_pubkey)) {
+            Ok(self)
+        } else {
+            Err(InternalCheckError::UnknownPaymentRequest.into())
+        }
+    }
+
+    /// Returns the version of the protocol used by the sender.
+    pub fn version(&self) -> ProtocolVersion {
+        self.params.version
+    }
+
+    /// Rejects the original PSBT if it spends a script type not allowed by `options`.
+    ///
+    /// This is a policy check - e.g. custodians may not be allowed to co-sign transactions with
+    /// certain script types. If the types are restricted, inputs of unknown type are rejected as
+    /// well.
+    pub fn check_sender_input_types(self, options: &ReceiverOptions) -> Result<Self, CheckError> {
+        use crate::input_type::InputType;
+
+        let allowed = match &options.allowed_sender_input_types {
+            Some(allowed) => allowed,
+            None => return Ok(self),
+        };
+        for (index, input) in self.psbt.input_pairs().enumerate() {
+            let input_type = input
+                .previous_txout()
+                .ok()
+                .and_then(|txout| InputType::from_spent_input(txout, input.psbtin).ok())
+                .map(InputScriptType::from_input_type);
+            match input_type {
+                Some(input_type) if allowed.contains(&input_type) => (),
+                _ => return Err(InternalCheckError::DisallowedInputType { index, input_type, }.into()),
+            }
+        }
+        Ok(self)
+    }
+
+    /// Rejects the original PSBT if it contains an `OP_RETURN` output and `options` veto them.
+    ///
+    /// See `ReceiverOptions::op_return_policy()`.
+    pub fn check_op_return_outputs(self, options: &ReceiverOptions) -> Result<Self, CheckError> {
+        if options.op_return_policy == OpReturnPolicy::Preserve {
+            return Ok(self);
+        }
+        match self.psbt.global.unsigned_tx.output.iter().position(|output| output.script_pubkey.is_op_return()) {
+            Some(index) => Err(InternalCheckError::OpReturnRejected { index, }.into()),
+            None => Ok(self),
+        }
+    }
+
+    /// Checks that none of the inputs of the original transaction was already spent.
+    ///
+    /// Do this before locking your UTXOs so that you don't waste them on a proposal that can
+    /// never be broadcasted. Failing provider results in `ErrorCode::Unavailable`.
+    pub fn check_prevouts_unspent(self, provider: &impl PrevoutStatusProvider) -> Result<Self, CheckError> {
+        for input in &self.psbt.global.unsigned_tx.input {
+            let status = provider
+                .prevout_status(&input.previous_output)
+                .map_err(|error| InternalCheckError::PrevoutStatusUnavailable(error.into()))?;
+            if status != PrevoutStatus::Unspent {
+                return Err(InternalCheckError::PrevoutSpent { outpoint: input.previous_output, status, }.into());
+            }
+        }
+        Ok(self)
+    }
+
+    /// Checks that the original PSBT pays an outstanding payment request.
+    ///
+    /// The first output paying a script known to the store must pay at least the requested
+    /// amount. Overpayment is accepted.
+    pub fn check_payment_request(self, store: &(impl PaymentRequestStore + ?Sized)) -> Result<Self, CheckError> {
+        let (requested, actual) = self.psbt.global.unsigned_tx.output
+            .iter()
+            .find_map(|output| store.requested_amount(&output.script_pubkey).map(|requested| (requested, output.value)))
+            .ok_or(InternalCheckError::UnknownPaymentRequest)?;
+        let actual = bitcoin::Amount::from_sat(actual);
+        if actual < requested {
+            return Err(InternalCheckError::AmountTooLow { expected: requested, actual, }.into());
+        }
+        Ok(self)
+    }
+
+    /// Checks that the original PSBT pays at least `minimum` to the issued script.
+    ///
+    /// Use this instead of `check_payment_request()` for donation links which don't specify the
+    /// amount, the payment is whatever the original transaction pays. Must be called after
+    /// `check_pays_issued_script()`.
+    pub fn check_donation(self, minimum: bitcoin::Amount) -> Result<Self, CheckError> {
+        let script_pubkey = self.payee.as_ref().ok_or(InternalCheckError::IssuedScriptNotPaid)?;
+        let actual = self.psbt.global.unsigned_tx.output
+            .iter()
+            .find(|output| output.script_pubkey == *script_pubkey)
+            .map(|output| bitcoin::Amount::from_sat(output.value))
+            .ok_or(InternalCheckError::IssuedScriptNotPaid)?;
+        if actual < minimum {
+            return Err(InternalCheckError::AmountTooLow { expected: minimum, actual, }.into());
+        }
+        Ok(self)
+    }
+
+    /// Returns the action `policy` assigns to the status of the invoice paid by the issued script.
+    ///
+    /// Must be called after `check_pays_issued_script()`. The rejection is up to you since you
+    /// may still want to check and broadcast the original transaction, see
+    /// `CheckError::invoice_rejected()`.
+    pub fn invoice_action(&self, provider: &impl InvoiceStatusProvider, policy: &InvoicePolicy) -> Result<(InvoiceStatus, InvoiceAction), CheckError> {
+        let script_pubkey = self.payee.as_ref().ok_or(InternalCheckError::IssuedScriptNotPaid)?;
+        let status = provider
+            .invoice_status(script_pubkey)
+            .map_err(|error| InternalCheckError::InvoiceStatusUnavailable(error.into()))?;
+        Ok((status, policy.action(status)))
+    }
+
+    /// Checks that the original PSBT pays the script from the URI issued for this request.
+    ///
+    /// Unlike the other checks this one doesn't accept any script of yours: an attacker could
+    /// relay a PSBT paying your other invoice (or another receiver using the same endpoint) to
+    /// make you contribute inputs to a payment you didn't negotiate. Pass the script of the URI
+    /// whose `pj` endpoint received the request - it must be paid by exactly one output.
+    ///
+    /// Inputs can only be contributed to the output verified by this check.
+    pub fn check_pays_issued_script(mut self, script_pubkey: &Script) -> Result<Self, CheckError> {
+        let count = self.psbt.global.unsigned_tx.output
+            .iter()
+            .filter(|output| output.script_pubkey == *script_pubkey)
+            .count();
+        if count != 1 {
+            return Err(InternalCheckError::IssuedScriptNotPaid.into());
+        }
+        self.payee = Some(script_pubkey.clone());
+        Ok(self)
+    }
+
     pub fn get_transaction_to_check_broadcast(&self) -> bitcoin::Transaction {
         self.psbt.clone().extract_tx()
     }
 
     pub fn assume_broadcastability_was_verified(self) -> UnlockedProposal {
-        UnlockedProposal {
-            psbt: self.psbt,
-        }
+        self.into_unlocked()
     }
 
     pub fn this_is_purely_interactive_wallet(self) -> UnlockedProposal {
+        self.into_unlocked()
+    }
+
+    /// The only way to get to the next stage - the public methods document why it's OK.
+    fn into_unlocked(self) -> UnlockedProposal {
         UnlockedProposal {
             psbt: self.psbt,
+            params: self.params,
+            payee: self.payee,
         }
     }
 }
 
 pub struct UnlockedProposal {
     psbt: Psbt,
+    params: Params,
+    payee: Option<Script>,
 }
 
 impl UnlockedProposal {
@@ -70,8 +662,36 @@ impl UnlockedProposal {
     }
 
     pub fn assume_locked(self) -> Proposal {
+        use crate::input_type::InputType;
+        use crate::weight::ComputeWeight;
+
+        let sender_inputs = self.utxos_to_be_locked().copied().collect();
+        let sender_input_weight = {
+            let first = self.psbt.input_pairs().next().expect("validated in from_request");
+            let first_txout = first.previous_txout().expect("validated in from_request");
+            InputType::from_spent_input(first_txout, first.psbtin)
+                .ok()
+                .and_then(|input_type| input_type.expected_input_weight())
+        };
+        let (input_value, output_value) = value_totals(&self.psbt).expect("validated in from_request");
+        let original_tx = self.psbt.clone().extract_tx();
+        // the original PSBT is finalized so the weight is accurate
+        let original_fee = bitcoin::Amount::from_sat(input_value.checked_sub(output_value).expect("validated in from_request"));
+        let original_fee_rate = original_fee / original_tx.weight();
+        // BIP78 says to ignore the contribution if the index is out of bounds
+        let fee_contribution = self.params.fee_contribution.and_then(|(amount, index)| {
+            Some((amount, self.psbt.global.unsigned_tx.output.get(index)?.script_pubkey.clone()))
+        });
         Proposal {
             psbt: self.psbt,
+            original_tx,
+            params: self.params,
+            payee: self.payee,
+            sender_inputs,
+            original_fee,
+            original_fee_rate,
+            sender_input_weight,
+            fee_contribution,
         }
     }
 }
@@ -80,8 +700,479 @@ impl UnlockedProposal {
 #[must_use = "The transaction must be broadcasted to prevent abuse"]
 pub struct MustBroadcast(pub bitcoin::Transaction);
 
+#[derive(Clone)]
 pub struct Proposal {
     psbt: Psbt,
+    original_tx: bitcoin::Transaction,
+    params: Params,
+    /// Output verified by `check_pays_issued_script()`.
+    payee: Option<Script>,
+    sender_inputs: Vec<bitcoin::OutPoint>,
+    original_fee: bitcoin::Amount,
+    original_fee_rate: crate::fee_rate::FeeRate,
+    sender_input_weight: Option<crate::weight::Weight>,
+    /// Remaining fee contribution of the sender and the script of the output paying it.
+    fee_contribution: Option<(bitcoin::Amount, Script)>,
+}
+
+impl Proposal {
+    /// Replaces the script of our output with a different one.
+    ///
+    /// This allows the receiver to e.g. forward the payment to a different wallet. The new
+    /// script must be standard. Witness programs of all versions are accepted so Taproot and
+    /// future SegWit versions work without updating this library.
+    ///
+    /// Fails if the sender disabled output substitution.
+    pub fn substitute_output_script(&mut self, original: &Script, new: Script) -> Result<(), OutputSubstitutionError> {
+        if self.params.disable_output_substitution {
+            return Err(InternalOutputSubstitutionError::Disabled.into());
+        }
+        if OutputType::from_script(&new).is_none() {
+            return Err(InternalOutputSubstitutionError::NonStandardScript(new).into());
+        }
+        let index = self.psbt.global.unsigned_tx.output
+            .iter()
+            .position(|output| output.script_pubkey == *original)
+            .ok_or(InternalOutputSubstitutionError::OutputNotFound)?;
+        if self.payee.as_ref() == Some(original) {
+            self.payee = Some(new.clone());
+        }
+        self.psbt.global.unsigned_tx.output[index].script_pubkey = new;
+        // metadata belonged to the old script
+        self.psbt.outputs[index] = Default::default();
+        Ok(())
+    }
+
+    /// Returns `true` if a script of the original transaction is missing in the proposal.
+    ///
+    /// Send `SUBSTITUTED_HEADER` with the response if this returns `true`.
+    pub fn output_substituted(&self) -> bool {
+        let outputs = &self.psbt.global.unsigned_tx.output;
+        self.original_tx.output.iter().any(|original| outputs.iter().all(|output| output.script_pubkey != original.script_pubkey))
+    }
+
+    /// Adds an input of the receiver to the proposal.
+    ///
+    /// The fee for the input is computed using the fee rate of the original transaction and the
+    /// weight of a typical input of the type used by the sender (BIP78 requires the types to be
+    /// the same, inputs of other types are refused). The fee is paid from the contribution offered
+    /// by the sender (if any), the rest is deducted from the value of the input and the remaining
+    /// value is added to `receiver_output`. The input is inserted at a random position.
+    ///
+    /// `psbt_input` must contain the UTXO information so that the input can be signed. Fails with
+    /// `ErrorCode::NotEnoughMoney` if the value of the input doesn't cover its fee.
+    /// `receiver_output` must have been verified by `UncheckedProposal::check_pays_issued_script()`
+    /// (or be its substitute).
+    pub fn contribute_input(&mut self, outpoint: bitcoin::OutPoint, psbt_input: bitcoin::util::psbt::Input, receiver_output: &Script) -> Result<(), ContributionError> {
+        self.add_input(outpoint, psbt_input, receiver_output, &ReceiverOptions::default()).map(drop)
+    }
+
+    /// Contributes the best of `candidates` according to `scorer`.
+    ///
+    /// Each candidate input is contributed to a copy of the proposal as in `contribute_input()`
+    /// and the copy with highest score replaces this proposal. Candidates that can't be
+    /// contributed are skipped. The returned scores of all candidates can be logged to audit the
+    /// decision. If no candidate could be contributed the error of the last one is returned.
+    pub fn contribute_best_input(&mut self, candidates: impl IntoIterator<Item=(bitcoin::OutPoint, bitcoin::util::psbt::Input)>, receiver_output: &Script, scorer: &impl ProposalScorer) -> Result<Vec<CandidateScore>, ContributionError> {
+        self.contribute_best(candidates, receiver_output, scorer, &ReceiverOptions::default())
+    }
+
+    fn contribute_best(&mut self, candidates: impl IntoIterator<Item=(bitcoin::OutPoint, bitcoin::util::psbt::Input)>, receiver_output: &Script, scorer: &(impl ProposalScorer + ?Sized), options: &ReceiverOptions) -> Result<Vec<CandidateScore>, ContributionError> {
+        let mut scores = Vec::new();
+        let mut best: Option<(i64, usize, Proposal)> = None;
+        let mut last_error = None;
+
+        for (outpoint, psbt_input) in candidates {
+            let mut proposal = self.clone();
+            match proposal.add_input(outpoint, psbt_input, receiver_output, options) {
+                Ok((value, fee)) => {
+                    let score = scorer.score(&Candidate { outpoint, value, fee, psbt: &proposal.psbt, });
+                    let is_better = match &best {
+                        Some((best_score, _, _)) => score > *best_score,
+                        None => true,
+                    };
+                    if is_better {
+                        best = Some((score, scores.len(), proposal));
+                    }
+                    scores.push(CandidateScore { outpoint, score: Some(score), selected: false, });
+                },
+                Err(error) => {
+                    scores.push(CandidateScore { outpoint, score: None, selected: false, });
+                    last_error = Some(error);
+                },
+            }
+        }
+
+        match best {
+            Some((_, index, proposal)) => {
+                *self = proposal;
+                scores[index].selected = true;
+                Ok(scores)
+            },
+            None => Err(last_error.unwrap_or_else(|| InternalContributionError::NoCandidates.into())),
+        }
+    }
+
+    /// Contributes an input and adds an output mimicking the change of the sender.
+    ///
+    /// Same as `contribute_input()` but part of the contributed value goes to a new output paying
+    /// `decoy_script` the same amount as the first output of the original transaction not paying
+    /// `receiver_output` (usually the change of the sender). Chain analysis then sees two equal
+    /// outputs and can't tell which one is the change of the sender nor which output is the
+    /// payment. The receiver pays the fee for the new output.
+    ///
+    /// Fails with `ErrorCode::NotEnoughMoney` if the contributed input can't fund the decoy while
+    /// keeping the receiver output above the dust limit (or not decreasing it if the sender
+    /// disabled output substitution). Note that senders forbidding additional outputs will reject
+    /// the proposal.
+    pub fn contribute_input_with_decoy(&mut self, outpoint: bitcoin::OutPoint, psbt_input: bitcoin::util::psbt::Input, receiver_output: &Script, decoy_script: Script, options: &ReceiverOptions) -> Result<(), ContributionError> {
+        use crate::weight::ComputeWeight;
+        use rand::Rng;
+
+        if OutputType::from_script(&decoy_script).is_none() {
+            return Err(InternalContributionError::NonStandardDecoyScript(decoy_script).into());
+        }
+        let original_amount = self.original_tx.output
+            .iter()
+            .find(|output| output.script_pubkey == *receiver_output)
+            .ok_or(InternalContributionError::OutputNotFound)?
+            .value;
+        let decoy_value = self.original_tx.output
+            .iter()
+            .find(|output| output.script_pubkey != *receiver_output && !output.script_pubkey.is_op_return())
+            .ok_or(InternalContributionError::NoDecoyTarget)?
+            .value;
+        let decoy = TxOut { value: decoy_value, script_pubkey: decoy_script, };
+
+        let mut proposal = self.clone();
+        proposal.add_input(outpoint, psbt_input, receiver_output, options)?;
+        let output = proposal.psbt.global.unsigned_tx.output
+            .iter_mut()
+            .find(|output| output.script_pubkey == *receiver_output)
+            .expect("add_input checked the output exists");
+        let min_remaining = if self.params.disable_output_substitution {
+            bitcoin::Amount::from_sat(original_amount)
+        } else {
+            options.dust_limit
+        };
+        let required = bitcoin::Amount::from_sat(decoy_value) + self.original_fee_rate * decoy.weight() + min_remaining;
+        let available = bitcoin::Amount::from_sat(output.value);
+        if available < required {
+            return Err(InternalContributionError::InsufficientValueForDecoy { decoy: bitcoin::Amount::from_sat(decoy_value), missing: required - available, }.into());
+        }
+        output.value -= (required - min_remaining).as_sat();
+
+        let index = rand::thread_rng().gen_range(0..=proposal.psbt.outputs.len());
+        proposal.psbt.global.unsigned_tx.output.insert(index, decoy);
+        proposal.psbt.outputs.insert(index, Default::default());
+        *self = proposal;
+        Ok(())
+    }
+
+    /// Returns the value of the contributed input and the fee paid for it.
+    fn add_input(&mut self, outpoint: bitcoin::OutPoint, psbt_input: bitcoin::util::psbt::Input, receiver_output: &Script, options: &ReceiverOptions) -> Result<(bitcoin::Amount, bitcoin::Amount), ContributionError> {
+        use bitcoin::util::psbt::Input;
+        use rand::Rng;
+
+        if self.payee.as_ref() != Some(receiver_output) {
+            return Err(InternalContributionError::UnverifiedReceiverOutput.into());
+        }
+        let output_index = self.psbt.global.unsigned_tx.output
+            .iter()
+            .position(|output| output.script_pubkey == *receiver_output)
+            .ok_or(InternalContributionError::OutputNotFound)?;
+        let input_weight = self.sender_input_weight.ok_or(InternalContributionError::UnsupportedInputType)?;
+        // the sender requires all inputs to have the same sequence
+        let sequence = self.psbt.global.unsigned_tx.input[0].sequence;
+        let txin = bitcoin::TxIn {
+            previous_output: outpoint,
+            script_sig: Script::new(),
+            sequence,
+            witness: Vec::new(),
+        };
+        let value = crate::psbt::InputPair { txin: &txin, psbtin: &psbt_input, }
+            .previous_txout()
+            .map_err(InternalContributionError::MissingUtxoInfo)?
+            .value;
+        if !self.matches_sender_input_type(outpoint, &psbt_input) {
+            return Err(InternalContributionError::MismatchedInputType.into());
+        }
+        let available = bitcoin::Amount::from_sat(value);
+        let (contribution, fee_output_index) = match self.available_contribution(receiver_output, options) {
+            Some((amount, index)) => (amount.min(options.fee_share.sender_part(self.original_fee_rate * input_weight)), Some(index)),
+            None => (bitcoin::Amount::ZERO, None),
+        };
+        let required_fee = self.original_fee_rate * input_weight - contribution;
+        let output_value = bitcoin::Amount::from_sat(self.psbt.global.unsigned_tx.output[output_index].value);
+        let new_value = if available > required_fee {
+            output_value + (available - required_fee)
+        } else {
+            // Decreasing the output is a substitution of sorts so the sender must allow it
+            let shortfall = required_fee - available;
+            match options.bump_fee_policy {
+                BumpFeePolicy::SubtractOurFeeOutput if !self.params.disable_output_substitution && output_value >= shortfall + options.dust_limit => output_value - shortfall,
+                _ => return Err(InternalContributionError::InsufficientValue { available, required_fee, }.into()),
+            }
+        };
+
+        self.psbt.global.unsigned_tx.output[output_index].value = new_value.as_sat();
+        if let (Some(index), Some((remaining, _))) = (fee_output_index, &mut self.fee_contribution) {
+            self.psbt.global.unsigned_tx.output[index].value -= contribution.as_sat();
+            *remaining -= contribution;
+        }
+        let index = rand::thread_rng().gen_range(0..=self.psbt.inputs.len());
+        self.psbt.global.unsigned_tx.input.insert(index, txin);
+        self.psbt.inputs.insert(index, Input { partial_sigs: Default::default(), final_script_sig: None, final_script_witness: None, ..psbt_input });
+        Ok((available, required_fee))
+    }
+
+    /// Returns `true` if the input spends the same type of script as the inputs of the sender.
+    ///
+    /// The sender rejects proposals mixing input types. Nested SegWit can't be told apart from
+    /// other P2SH scripts without the redeem script so all P2SH inputs are considered the same.
+    pub(crate) fn matches_sender_input_type(&self, outpoint: bitcoin::OutPoint, psbt_input: &bitcoin::util::psbt::Input) -> bool {
+        let txin = bitcoin::TxIn { previous_output: outpoint, ..Default::default() };
+        match (crate::psbt::InputPair { txin: &txin, psbtin: psbt_input, }).previous_txout() {
+            Ok(txout) => OutputType::from_script(&txout.script_pubkey) == self.sender_script_type(),
+            Err(_) => false,
+        }
+    }
+
+    fn sender_script_type(&self) -> Option<OutputType> {
+        let outpoint = self.sender_inputs.first()?;
+        let input = self.psbt.input_pairs().find(|input| input.txin.previous_output == *outpoint)?;
+        OutputType::from_script(&input.previous_txout().ok()?.script_pubkey)
+    }
+
+    /// Returns the remaining contribution of the sender and the current index of its output.
+    ///
+    /// The output stays above the dust limit.
+    fn available_contribution(&self, receiver_output: &Script, options: &ReceiverOptions) -> Option<(bitcoin::Amount, usize)> {
+        let (remaining, script_pubkey) = self.fee_contribution.as_ref()?;
+        // BIP78 says to ignore the contribution if it should be paid by our output, OP_RETURN
+        // outputs are preserved
+        if script_pubkey == receiver_output || self.payee.as_ref() == Some(script_pubkey) || script_pubkey.is_op_return() {
+            return None;
+        }
+        let index = self.psbt.global.unsigned_tx.output.iter().position(|output| output.script_pubkey == *script_pubkey)?;
+        let spare = bitcoin::Amount::from_sat(self.psbt.global.unsigned_tx.output[index].value)
+            .checked_sub(options.dust_limit)
+            .unwrap_or(bitcoin::Amount::ZERO);
+        Some(((*remaining).min(spare), index))
+    }
+
+    /// Fee parameters of the sender, see `ContributionBudget::compute()`.
+    ///
+    /// The maximum contribution is what remains after inputs contributed so far.
+    pub fn sender_params(&self) -> SenderParams {
+        use crate::weight::ComputeWeight;
+
+        let max_fee_contribution = match &self.payee {
+            Some(payee) => self.available_contribution(payee, &ReceiverOptions::default().dust_limit(bitcoin::Amount::ZERO)).map(|(amount, _)| amount),
+            None => None,
+        };
+        SenderParams {
+            max_fee_contribution: max_fee_contribution.unwrap_or(bitcoin::Amount::ZERO),
+            min_fee_rate: self.params.min_fee_rate,
+            original_fee: self.original_fee,
+            original_fee_rate: self.original_fee_rate,
+            original_weight: self.original_tx.weight(),
+            input_weight: self.sender_input_weight,
+        }
+    }
+
+    /// Estimates the fee, size and privacy of the proposal once it's signed.
+    ///
+    /// Call this after contributing inputs but before signing them, e.g. to decide whether the
+    /// payjoin is worth it.
+    pub fn analyze(&self) -> ProposalAnalysis {
+        use crate::weight::{ComputeWeight, Weight};
+
+        // contributed inputs and changed outputs aren't validated, don't let them overflow
+        let input_value = self.psbt
+            .input_pairs()
+            .map(|input| input.previous_txout().map(|txout| txout.value).unwrap_or(0))
+            .fold(0u64, u64::saturating_add);
+        let output_value = self.psbt.global.unsigned_tx.output.iter().map(|output| output.value).fold(0u64, u64::saturating_add);
+        let fee = bitcoin::Amount::from_sat(input_value.saturating_sub(output_value));
+        let contributed = self.psbt.global.unsigned_tx.input
+            .iter()
+            .filter(|txin| !self.sender_inputs.contains(&txin.previous_output))
+            .collect::<Vec<_>>();
+        let original_outputs_weight = self.original_tx.output.iter().fold(Weight::ZERO, |sum, output| sum + output.weight());
+        let outputs_weight = self.psbt.global.unsigned_tx.output.iter().fold(Weight::ZERO, |sum, output| sum + output.weight());
+        let inputs_weight = contributed.iter().fold(Weight::ZERO, |sum, txin| sum + self.sender_input_weight.unwrap_or_else(|| txin.weight()));
+        let weight = self.original_tx.weight() - original_outputs_weight + outputs_weight + inputs_weight;
+        let sender_contribution = match (self.params.fee_contribution, &self.fee_contribution) {
+            (Some((max, _)), Some((remaining, _))) => max - *remaining,
+            _ => bitcoin::Amount::ZERO,
+        };
+        let fee_increase = fee.checked_sub(self.original_fee).unwrap_or(bitcoin::Amount::ZERO);
+        ProposalAnalysis {
+            contributed_inputs: contributed.len(),
+            fee,
+            original_fee: self.original_fee,
+            sender_contribution,
+            receiver_fee: fee_increase.checked_sub(sender_contribution).unwrap_or(bitcoin::Amount::ZERO),
+            weight: weight.into(),
+            fee_rate: (fee / weight).to_sat_per_kwu() * 4,
+            has_unnecessary_input: scoring::has_unnecessary_input(&self.psbt),
+        }
+    }
+
+    /// Splits the value moved by the proposal between the sender and the receiver.
+    ///
+    /// Outputs of the original transaction other than the payee are attributed to the sender,
+    /// everything else to the receiver. Returns `None` if the paid script wasn't verified by
+    /// `UncheckedProposal::check_pays_issued_script()` - the payment can't be told apart then -
+    /// or if the values of contributed inputs or outputs exceed `MAX_MONEY`.
+    pub fn accounting(&self) -> Option<Accounting> {
+        use bitcoin::{Amount, SignedAmount};
+
+        let payee = self.payee.as_ref()?;
+        let add = |sum: u64, value: u64| sum.checked_add(value).filter(|sum| *sum <= MAX_MONEY);
+        // validated in from_request
+        let original_outputs = self.original_tx.output.iter().map(|output| output.value).sum::<u64>();
+        let sender_inputs = original_outputs + self.original_fee.as_sat();
+        let receiver_inputs = self.psbt
+            .input_pairs()
+            .filter(|input| !self.sender_inputs.contains(&input.txin.previous_output))
+            .map(|input| input.previous_txout().map(|txout| txout.value).unwrap_or(0))
+            .try_fold(0, add)?;
+        let (sender_outputs, receiver_outputs) = self.psbt.global.unsigned_tx.output
+            .iter()
+            .try_fold((0, 0), |(sender, receiver), output| {
+                let is_sender = output.script_pubkey != *payee && self.original_tx.output.iter().any(|original| original.script_pubkey == output.script_pubkey);
+                if is_sender {
+                    Some((add(sender, output.value)?, receiver))
+                } else {
+                    Some((sender, add(receiver, output.value)?))
+                }
+            })?;
+        let fee = add(sender_inputs, receiver_inputs)?.saturating_sub(add(sender_outputs, receiver_outputs)?);
+        let sender_contribution = match (self.params.fee_contribution, &self.fee_contribution) {
+            (Some((max, _)), Some((remaining, _))) => max - *remaining,
+            _ => Amount::ZERO,
+        };
+        let sender_fee = (self.original_fee + sender_contribution).as_sat().min(fee);
+        let net_sent = |inputs: u64, outputs: u64| SignedAmount::from_sat(inputs as i64 - outputs as i64);
+        Some(Accounting {
+            sender: PartyAmounts {
+                gross_sent: Amount::from_sat(sender_inputs),
+                net_sent: net_sent(sender_inputs, sender_outputs),
+                counterparty_contribution: Amount::from_sat(receiver_inputs),
+                fee_paid: Amount::from_sat(sender_fee),
+            },
+            receiver: PartyAmounts {
+                gross_sent: Amount::from_sat(receiver_inputs),
+                net_sent: net_sent(receiver_inputs, receiver_outputs),
+                counterparty_contribution: Amount::from_sat(sender_inputs),
+                fee_paid: Amount::from_sat(fee - sender_fee),
+            },
+        })
+    }
+
+    /// Signs the contributed inputs using `signer` and checks they commit to the whole transaction.
+    ///
+    /// Call this after all other changes to the proposal. Each input returned by the signer must
+    /// be finalized and contain only signatures with `SIGHASH_ALL`, other flags would keep the
+    /// signatures valid even if the sender (or anyone else) changed the transaction. Taproot
+    /// fields (BIP371) of the signed inputs are removed. The proposal is modified only if all
+    /// inputs were signed successfully.
+    pub fn sign_contributed_inputs(&mut self, signer: &impl InputSigner) -> Result<(), SigningError> {
+        use bitcoin::blockdata::transaction::SigHashType;
+
+        let receiver_inputs = self.receiver_input_indices();
+        let mut psbt = self.psbt.clone();
+        for &index in &receiver_inputs {
+            psbt.inputs[index].sighash_type = Some(SigHashType::All);
+        }
+        for &index in &receiver_inputs {
+            let signed = signer.sign_input(&psbt, index)
+                .map_err(|error| InternalSigningError::Signer { index, error: error.into(), })?;
+            let flags = crate::psbt::InputPair { txin: &psbt.global.unsigned_tx.input[index], psbtin: &signed, }.final_sighash_flags();
+            if flags.is_empty() {
+                return Err(InternalSigningError::NotSigned { index, }.into());
+            }
+            if let Some(&flag) = flags.iter().find(|&&flag| flag != SigHashType::All as u8) {
+                return Err(InternalSigningError::UnsafeSighashFlag { index, flag, }.into());
+            }
+            psbt.inputs[index].final_script_sig = signed.final_script_sig;
+            psbt.inputs[index].final_script_witness = signed.final_script_witness;
+            // the input is final, the internal key and merkle paths would only leak information
+            crate::psbt::strip_input_tap_fields(&mut psbt.inputs[index]);
+        }
+        self.psbt = psbt;
+        Ok(())
+    }
+
+    fn receiver_input_indices(&self) -> Vec<usize> {
+        let sender_inputs = &self.sender_inputs;
+        self.psbt.global.unsigned_tx.input
+            .iter()
+            .enumerate()
+            .filter(|(_, txin)| !sender_inputs.contains(&txin.previous_output))
+            .map(|(index, _)| index)
+            .collect()
+    }
+
+    /// Strips all PSBT fields not required by BIP78.
+    ///
+    /// This removes UTXO information and signatures of sender inputs (as required by the
+    /// specification), key paths, scripts of finalized inputs, taproot fields and unknown and
+    /// proprietary fields (senders may reject proposals containing them). Call this after finalizing your inputs to
+    /// make the response as small as possible - this matters especially over Tor.
+    pub fn minimize_response(&mut self) {
+        let sender_inputs = &self.sender_inputs;
+        crate::psbt::minimize_proposal(&mut self.psbt, |outpoint| sender_inputs.contains(outpoint));
+    }
+
+    /// Returns the proposal PSBT.
+    pub fn psbt(&self) -> &Psbt {
+        &self.psbt
+    }
+
+    /// Returns the outpoints of inputs contributed by the receiver.
+    pub fn contributed_outpoints(&self) -> impl '_ + Iterator<Item=&bitcoin::OutPoint> {
+        let sender_inputs = &self.sender_inputs;
+        self.psbt.global.unsigned_tx.input
+            .iter()
+            .map(|txin| &txin.previous_output)
+            .filter(move |outpoint| !sender_inputs.contains(outpoint))
+    }
+
+    /// Returns the ID of the original transaction.
+    pub fn original_txid(&self) -> bitcoin::Txid {
+        self.original_tx.txid()
+    }
+
+    /// Returns the conventions of the wallet of the sender.
+    ///
+    /// If your wallet creates transactions spending the contributed inputs (e.g. a consolidation
+    /// funding the receiver output), use the same lock time and RBF signaling.
+    /// `contribute_input()` already copies the sequence number.
+    pub fn tx_conventions(&self) -> crate::TxConventions {
+        crate::TxConventions::from_transaction(&self.original_tx).expect("validated in from_request")
+    }
+
+    /// Describes the value of the receiver outputs for labeling them once the payjoin settles.
+    ///
+    /// Call this after signing the contributed inputs. Only outputs added by the receiver and the
+    /// output verified by `UncheckedProposal::check_pays_issued_script()` are labeled.
+    pub fn labels(&self) -> PayjoinLabels {
+        PayjoinLabels::new(self)
+    }
+
+    /// Exports the original transaction so that its broadcast can be delegated to a third party.
+    ///
+    /// `earliest_broadcast` should give the sender enough time to broadcast the payjoin
+    /// transaction. The package contains no keys nor the proposal.
+    pub fn export_fallback_package(&self, earliest_broadcast: crate::time::Deadline) -> FallbackPackage {
+        FallbackPackage {
+            transaction: self.original_tx.clone(),
+            earliest_broadcast,
+        }
+    }
 }
 
 /*
@@ -100,17 +1191,774 @@ impl Proposal {
 }
 */
 
+/// Script type of an input.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum InputScriptType {
+    P2Pk,
+    P2Pkh,
+    P2Sh,
+    P2ShP2Wpkh,
+    P2ShP2Wsh,
+    P2Wpkh,
+    P2Wsh,
+    Taproot,
+}
+
+impl InputScriptType {
+    fn from_input_type(input_type: crate::input_type::InputType) -> Self {
+        use crate::input_type::{InputType, SegWitV0Type};
+
+        match input_type {
+            InputType::P2Pk => InputScriptType::P2Pk,
+            InputType::P2Pkh => InputScriptType::P2Pkh,
+            InputType::P2Sh => InputScriptType::P2Sh,
+            InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: true, } => InputScriptType::P2ShP2Wpkh,
+            InputType::SegWitV0 { ty: SegWitV0Type::Script | SegWitV0Type::Multisig { .. }, nested: true, } => InputScriptType::P2ShP2Wsh,
+            InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: false, } => InputScriptType::P2Wpkh,
+            InputType::SegWitV0 { ty: SegWitV0Type::Script | SegWitV0Type::Multisig { .. }, nested: false, } => InputScriptType::P2Wsh,
+            InputType::Taproot => InputScriptType::Taproot,
+        }
+    }
+}
+
+/// Policy of the receiver.
 pub struct ReceiverOptions {
     dust_limit: bitcoin::Amount,
+    allowed_sender_input_types: Option<Vec<InputScriptType>>,
+    bump_fee_policy: BumpFeePolicy,
+    op_return_policy: OpReturnPolicy,
+    onion_only: bool,
+    max_receiver_fee: Option<bitcoin::Amount>,
+    donation_minimum: Option<bitcoin::Amount>,
+    fee_share: FeeShare,
+    timeouts: StageTimeouts,
+    limits: Limits,
 }
 
+impl Default for ReceiverOptions {
+    fn default() -> Self {
+        ReceiverOptions {
+            dust_limit: bitcoin::Amount::from_sat(546),
+            allowed_sender_input_types: None,
+            bump_fee_policy: BumpFeePolicy::FailOnInsufficient,
+            op_return_policy: OpReturnPolicy::Preserve,
+            onion_only: false,
+            max_receiver_fee: None,
+            donation_minimum: None,
+            fee_share: FeeShare::default(),
+            timeouts: StageTimeouts::default(),
+            limits: Limits::default(),
+        }
+    }
+}
+
+impl ReceiverOptions {
+    /// Only accepts original PSBTs spending inputs of given types.
+    ///
+    /// All types are allowed by default. Enforced by `UncheckedProposal::check_sender_input_types()`.
+    pub fn allowed_sender_input_types(mut self, types: impl IntoIterator<Item=InputScriptType>) -> Self {
+        self.allowed_sender_input_types = Some(types.into_iter().collect());
+        self
+    }
+
+    /// Minimum value of outputs of the receiver.
+    ///
+    /// Defaults to 546 satoshis which is the dust limit of P2PKH outputs.
+    pub fn dust_limit(mut self, dust_limit: bitcoin::Amount) -> Self {
+        self.dust_limit = dust_limit;
+        self
+    }
+
+    /// What to do if a contributed input doesn't cover its own fee.
+    ///
+    /// Defaults to `BumpFeePolicy::FailOnInsufficient`. Applies to
+    /// `Proposal::contribute_input_with_decoy()` and `PayjoinReceiver`.
+    pub fn bump_fee_policy(mut self, policy: BumpFeePolicy) -> Self {
+        self.bump_fee_policy = policy;
+        self
+    }
+
+    /// What to do with `OP_RETURN` outputs of the original transaction.
+    ///
+    /// Defaults to `OpReturnPolicy::Preserve`. Enforced by
+    /// `UncheckedProposal::check_op_return_outputs()`.
+    pub fn op_return_policy(mut self, policy: OpReturnPolicy) -> Self {
+        self.op_return_policy = policy;
+        self
+    }
+
+    /// Maximum fee the receiver pays for its contributed inputs in one payjoin.
+    ///
+    /// The part paid by the sender doesn't count. Unlimited by default. `PayjoinReceiver` doesn't
+    /// contribute if it would pay more, see `ContributionBudget`.
+    pub fn max_receiver_fee(mut self, max_receiver_fee: bitcoin::Amount) -> Self {
+        self.max_receiver_fee = Some(max_receiver_fee);
+        self
+    }
+
+    /// Accepts payments of at least `minimum` to scripts without a requested amount.
+    ///
+    /// Disabled by default. `PayjoinReceiver` calls `UncheckedProposal::check_donation()` if the
+    /// payment request store has no amount for the issued script (or there's no store) instead
+    /// of rejecting the original transaction.
+    pub fn accept_donations(mut self, minimum: bitcoin::Amount) -> Self {
+        self.donation_minimum = Some(minimum);
+        self
+    }
+
+    /// Part of the fee of each contributed input taken from the contribution of the sender.
+    ///
+    /// Defaults to `FeeShare::SENDER_PAYS`: as much as the sender allows. The rest is paid from
+    /// the value of the input. Senders checking the share with `Params::fee_share()` must be
+    /// configured with the same value.
+    pub fn fee_share(mut self, fee_share: FeeShare) -> Self {
+        self.fee_share = fee_share;
+        self
+    }
+
+    /// Rejects requests that didn't arrive through a Tor onion service.
+    ///
+    /// Disabled by default. Requests with unknown transport are rejected too.
+    pub fn onion_only(mut self, onion_only: bool) -> Self {
+        self.onion_only = onion_only;
+        self
+    }
+
+    /// Time budgets of the stages of `PayjoinReceiver`.
+    ///
+    /// Unlimited by default. See `StageTimeouts` for what happens when a budget is exceeded.
+    pub fn timeouts(mut self, timeouts: StageTimeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Bounds of requests processed by `PayjoinReceiver`.
+    ///
+    /// See `UncheckedProposal::from_request_bytes_with_limits()`.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Checks the connection that delivered the request.
+    ///
+    /// Call this before `UncheckedProposal::from_request()` so that rejected requests don't
+    /// cost parsing. Respond with `RequestError::http_status()`.
+    pub fn check_request_meta(&self, meta: &RequestMeta) -> Result<(), RequestError> {
+        if self.onion_only && meta.transport != Transport::Onion {
+            return Err(InternalRequestError::OnionRequired(meta.transport).into());
+        }
+        Ok(())
+    }
+}
+
+/// Time budgets of the stages of `PayjoinReceiver`, see `ReceiverOptions::timeouts()`.
+///
+/// The sender broadcasts the original transaction if the response doesn't arrive in time so a
+/// slow node or signer shouldn't keep the request busy past that. The calls are blocking so a
+/// stage can't be interrupted - the budget is checked after each call and the remaining stages
+/// are skipped once it's exceeded. Checks running out of time produce an `Unavailable` error
+/// before the inputs of the sender are locked, contribution stages running out of time produce
+/// the proposal without contributed inputs. Remote UTXO sources and signers also get the
+/// deadline of their stage. `None` means unlimited.
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct StageTimeouts {
+    /// Checks of the original transaction needing the node (prevouts, broadcastability).
+    pub checks: Option<std::time::Duration>,
+    /// Getting the candidates to contribute from the wallet.
+    pub candidates: Option<std::time::Duration>,
+    /// Signing the contributed inputs.
+    pub signing: Option<std::time::Duration>,
+    /// Whole request, it caps the budgets of the stages.
+    pub total: Option<std::time::Duration>,
+}
+
+/// Handling of contributed inputs that don't cover their own fee.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum BumpFeePolicy {
+    /// Reject the input with `ErrorCode::NotEnoughMoney`.
     FailOnInsufficient,
+    /// Pay the rest of the fee from the output of the receiver.
+    ///
+    /// Only if the sender didn't disable output substitution and the output stays above the
+    /// dust limit. Useful for consolidating small UTXOs and for originals without change (or
+    /// without fee contribution) where nobody else can pay for the input. The output is decreased
+    /// by exactly the fee of the input at the original fee rate minus the value of the input and
+    /// the contribution of the sender, so the sender sees the fee rate preserved.
     SubtractOurFeeOutput,
 }
 
+/// Handling of `OP_RETURN` outputs in the original transaction.
+///
+/// Senders sometimes attach metadata such as order ids. The outputs belong to the sender like its
+/// change but they can't pay any fee: proposals keep them at their position with unchanged script
+/// and value and the sender rejects proposals that modify them.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum OpReturnPolicy {
+    /// Keep the outputs untouched in the proposal.
+    Preserve,
+    /// Reject originals containing them with `ErrorCode::OriginalPsbtRejected`.
+    ///
+    /// For receivers that don't want to co-sign data they can't interpret.
+    Reject,
+}
+
 pub struct NewOutputOptions {
     set_as_fee_output: bool,
     subtract_fees_from_this: bool,
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockHeaders;
+
+    fn get_proposal_from_test_vector(query: &str) -> Result<UncheckedProposal, RequestError> {
+        let original_psbt = crate::testing::ORIGINAL_PSBT.as_bytes();
+        UncheckedProposal::from_request(original_psbt, query, MockHeaders::new(original_psbt.len() as u64))
+    }
+
+    /// Proposal paying the script of `crate::testing::URI` so that inputs can be contributed.
+    fn get_verified_proposal(query: &str) -> Proposal {
+        let payee = crate::testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone();
+        get_proposal_from_test_vector(query).unwrap().check_pays_issued_script(&payee).unwrap().this_is_purely_interactive_wallet().assume_locked()
+    }
+
+    fn payee_script(proposal: &Proposal) -> Script {
+        proposal.psbt.global.unsigned_tx.output[1].script_pubkey.clone()
+    }
+
+    fn taproot_script() -> Script {
+        Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(1).unwrap(), &[42; 32])
+    }
+
+    #[test]
+    fn substitute_taproot() {
+        let mut proposal = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        let payee = payee_script(&proposal);
+        assert!(!proposal.output_substituted());
+        proposal.substitute_output_script(&payee, taproot_script()).unwrap();
+        assert_eq!(proposal.psbt.global.unsigned_tx.output[1].script_pubkey, taproot_script());
+        assert!(proposal.output_substituted());
+    }
+
+    #[test]
+    fn substitute_non_standard() {
+        let mut proposal = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        let payee = payee_script(&proposal);
+        proposal.substitute_output_script(&payee, Script::new_op_return(&[42])).unwrap_err();
+    }
+
+    #[test]
+    fn substitute_disabled() {
+        let mut proposal = get_proposal_from_test_vector("v=1&disableoutputsubstitution=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        let payee = payee_script(&proposal);
+        proposal.substitute_output_script(&payee, taproot_script()).unwrap_err();
+    }
+
+    #[test]
+    fn payment_request() {
+        let proposal = get_proposal_from_test_vector("v=1").unwrap();
+        let payee = proposal.psbt.global.unsigned_tx.output[1].script_pubkey.clone();
+        let mut store = std::collections::HashMap::new();
+        let error = get_proposal_from_test_vector("v=1").unwrap().check_payment_request(&store).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+        store.insert(payee.clone(), bitcoin::Amount::from_sat(2_000_001));
+        get_proposal_from_test_vector("v=1").unwrap().check_payment_request(&store).err().unwrap();
+        store.insert(payee, bitcoin::Amount::from_sat(2_000_000));
+        proposal.check_payment_request(&store).unwrap();
+    }
+
+    #[test]
+    fn donation() {
+        let proposal = || get_proposal_from_test_vector("v=1").unwrap();
+        let payee = proposal().psbt.global.unsigned_tx.output[1].script_pubkey.clone();
+        let error = proposal().check_donation(bitcoin::Amount::ZERO).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+        let proposal = || get_proposal_from_test_vector("v=1").unwrap().check_pays_issued_script(&payee).unwrap();
+        let error = proposal().check_donation(bitcoin::Amount::from_sat(2_000_001)).err().unwrap();
+        assert_eq!(error.to_string(), "the original transaction pays 0.02000000 BTC but 0.02000001 BTC was requested");
+        proposal().check_donation(bitcoin::Amount::from_sat(2_000_000)).unwrap();
+    }
+
+    #[test]
+    fn error_json() {
+        let error = get_proposal_from_test_vector("v=1&disableoutputsubstitution=\"").err().unwrap();
+        assert_eq!(error.to_json(), r#"{"errorCode":"original-psbt-rejected","message":"invalid value of disableoutputsubstitution: \""}"#);
+    }
+
+    #[test]
+    fn invalid_disable_output_substitution() {
+        assert!(get_proposal_from_test_vector("v=1&disableoutputsubstitution=yes").is_err());
+    }
+
+    #[test]
+    fn request_bytes_beyond_content_length() {
+        let mut body = crate::testing::ORIGINAL_PSBT.as_bytes().to_vec();
+        let content_length = body.len() as u64;
+        body.extend_from_slice(b"garbage");
+        UncheckedProposal::from_request_bytes(&body, "v=1", MockHeaders::new(content_length)).unwrap();
+    }
+
+    #[test]
+    fn limits() {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        let headers = || MockHeaders::new(body.len() as u64);
+        let from_request = |limits: crate::Limits| UncheckedProposal::from_request_bytes_with_limits(body, "v=1", headers(), &limits);
+        from_request(crate::Limits::default()).unwrap();
+        assert!(from_request(crate::Limits { max_psbt_size: body.len() as u64 - 1, ..Default::default() }).is_err());
+        assert!(from_request(crate::Limits { max_url_length: 2, ..Default::default() }).is_err());
+        assert!(from_request(crate::Limits { max_outputs: 1, ..Default::default() }).is_err());
+        let error = from_request(crate::Limits { max_inputs: 0, ..Default::default() }).err().unwrap();
+        assert_eq!(error.to_string(), "the number of inputs (1) exceeds the limit of 0");
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+        assert!(UncheckedProposal::from_request_bytes(body, "v=1", MockHeaders::new(4_000_000)).is_ok());
+        assert!(UncheckedProposal::from_request_bytes(body, "v=1", MockHeaders::new(6_000_000)).is_err());
+    }
+
+    #[test]
+    fn expected_scripts() {
+        let proposal = get_proposal_from_test_vector("v=1").unwrap();
+        let payee = proposal.psbt.global.unsigned_tx.output[1].script_pubkey.clone();
+        let mut expected = std::collections::HashSet::new();
+        expected.insert(taproot_script());
+        let error = proposal.check_pays_expected_script(&expected).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+
+        expected.insert(payee);
+        let proposal = get_proposal_from_test_vector("v=1").unwrap();
+        proposal.check_pays_expected_script(&expected).unwrap();
+    }
+
+    #[test]
+    fn minimize_response() {
+        let mut proposal = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        proposal.minimize_response();
+        let input = &proposal.psbt().inputs[0];
+        assert!(input.witness_utxo.is_none());
+        assert!(input.final_script_sig.is_none());
+        assert!(input.final_script_witness.is_none());
+    }
+
+    #[test]
+    fn non_witness_utxo() {
+        let mut psbt = crate::testing::original_psbt();
+        let txout = psbt.inputs[0].witness_utxo.take().unwrap();
+        let prev_tx = bitcoin::Transaction { version: 2, lock_time: 0, input: Vec::new(), output: vec![txout], };
+        psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+
+        // txid doesn't match the previous transaction
+        let body = base64::encode(bitcoin::consensus::serialize(&psbt));
+        UncheckedProposal::from_request_bytes(body.as_bytes(), "v=1", MockHeaders::new(body.len() as u64)).err().unwrap();
+
+        psbt.global.unsigned_tx.input[0].previous_output = bitcoin::OutPoint::new(prev_tx.txid(), 0);
+        let body = base64::encode(bitcoin::consensus::serialize(&psbt));
+        UncheckedProposal::from_request_bytes(body.as_bytes(), "v=1", MockHeaders::new(body.len() as u64)).unwrap();
+    }
+
+    #[test]
+    fn invalid_amounts() {
+        let request = |psbt: &Psbt| {
+            let body = base64::encode(bitcoin::consensus::serialize(psbt));
+            UncheckedProposal::from_request_bytes(body.as_bytes(), "v=1", MockHeaders::new(body.len() as u64))
+        };
+        let mut psbt = crate::testing::original_psbt();
+        for value in [u64::MAX, MAX_MONEY + 1] {
+            psbt.inputs[0].witness_utxo.as_mut().unwrap().value = value;
+            let error = request(&psbt).err().unwrap();
+            assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+            assert_eq!(error.to_string(), "the amounts of the original transaction are out of range");
+        }
+        // the outputs exceed the inputs
+        let mut psbt = crate::testing::original_psbt();
+        psbt.global.unsigned_tx.output[0].value += 1_000_000;
+        request(&psbt).err().unwrap();
+        let mut psbt = crate::testing::original_psbt();
+        psbt.global.unsigned_tx.output[0].value = u64::MAX;
+        request(&psbt).err().unwrap();
+    }
+
+    #[test]
+    fn prevouts() {
+        let spent = |_: &bitcoin::OutPoint| Ok::<_, std::io::Error>(PrevoutStatus::SpentInMempool);
+        let error = get_proposal_from_test_vector("v=1").unwrap().check_prevouts_unspent(&spent).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+
+        let unavailable = |_: &bitcoin::OutPoint| Err::<PrevoutStatus, _>(std::io::Error::other("connection refused"));
+        let error = get_proposal_from_test_vector("v=1").unwrap().check_prevouts_unspent(&unavailable).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::Unavailable);
+
+        let unspent = |_: &bitcoin::OutPoint| Ok::<_, std::io::Error>(PrevoutStatus::Unspent);
+        get_proposal_from_test_vector("v=1").unwrap().check_prevouts_unspent(&unspent).unwrap();
+    }
+
+    #[test]
+    fn version_unsupported() {
+        assert_eq!(get_proposal_from_test_vector("").unwrap().version(), ProtocolVersion::V1);
+        let error = get_proposal_from_test_vector("v=2").err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::VersionUnsupported);
+        assert_eq!(error.to_json(), r#"{"errorCode":"version-unsupported","supported":[1],"message":"version 2 of payjoin is not supported"}"#);
+    }
+
+    #[test]
+    fn contribute_input() {
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let outpoint = bitcoin::OutPoint { txid: Default::default(), vout: 1, };
+        let input = |value| bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+
+        // 2 sat/vB * 91 vB
+        let error = proposal.contribute_input(outpoint, input(182), &payee).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::NotEnoughMoney);
+        assert_eq!(error.to_json(), r#"{"errorCode":"not-enough-money","message":"the receiver added some inputs but could not bump the fee of the payjoin proposal"}"#);
+
+        proposal.contribute_input(outpoint, input(1_000), &payee).unwrap();
+        assert_eq!(proposal.psbt.inputs.len(), 2);
+        assert_eq!(proposal.psbt.global.unsigned_tx.output[1].value, 2_000_000 + 1_000 - 182);
+    }
+
+    #[test]
+    fn issued_script() {
+        let original = crate::testing::original_psbt();
+        let payee = original.global.unsigned_tx.output[1].script_pubkey.clone();
+        let error = get_proposal_from_test_vector("v=1").unwrap().check_pays_issued_script(&taproot_script()).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+
+        // a script of ours but not the one issued for this payment
+        let mut proposal = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        let outpoint = bitcoin::OutPoint { txid: Default::default(), vout: 1, };
+        let input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value: 1_000, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+        let error = proposal.contribute_input(outpoint, input.clone(), &payee).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::Unavailable);
+
+        // the verified output can be substituted
+        let mut proposal = get_verified_proposal("v=1");
+        proposal.substitute_output_script(&payee, taproot_script()).unwrap();
+        proposal.contribute_input(outpoint, input, &taproot_script()).unwrap();
+    }
+
+    #[test]
+    fn sign_contributed_inputs() {
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let outpoint = bitcoin::OutPoint { txid: Default::default(), vout: 1, };
+        let input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value: 1_000, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+        proposal.contribute_input(outpoint, input, &payee).unwrap();
+        let receiver_index = proposal.psbt.global.unsigned_tx.input.iter().position(|txin| txin.previous_output == outpoint).unwrap();
+        // not a valid signature but it has the shape of one
+        fn signer(flag: u8, receiver_index: usize) -> impl Fn(&Psbt, usize) -> Result<bitcoin::util::psbt::Input, std::io::Error> {
+            move |psbt, index| {
+                assert_eq!(index, receiver_index);
+                assert_eq!(psbt.inputs[index].sighash_type, Some(bitcoin::SigHashType::All));
+                let mut signature = vec![0x30, 0x44];
+                signature.extend_from_slice(&[0x42; 0x44]);
+                signature.push(flag);
+                Ok(bitcoin::util::psbt::Input {
+                    final_script_witness: Some(vec![signature, vec![0x02; 33]]),
+                    ..Default::default()
+                })
+            }
+        }
+
+        let error = proposal.sign_contributed_inputs(&signer(0x83, receiver_index)).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::Unavailable);
+        assert!(proposal.psbt.inputs[receiver_index].final_script_witness.is_none());
+
+        // internal key of the contributed input
+        let tap_internal_key = bitcoin::util::psbt::raw::Key { type_value: 0x17, key: Vec::new(), };
+        proposal.psbt.inputs[receiver_index].unknown.insert(tap_internal_key, vec![0x42; 32]);
+        proposal.sign_contributed_inputs(&signer(0x01, receiver_index)).unwrap();
+        assert_eq!(proposal.psbt.inputs[receiver_index].final_script_witness.as_ref().unwrap().len(), 2);
+        assert!(proposal.psbt.inputs[receiver_index].unknown.is_empty());
+        assert!(proposal.psbt.inputs[1 - receiver_index].sighash_type.is_none());
+    }
+
+    #[test]
+    fn accounting() {
+        let mut proposal = get_verified_proposal("v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182");
+        let payee = payee_script(&proposal);
+        let outpoint = bitcoin::OutPoint { txid: Default::default(), vout: 1, };
+        let input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value: 1_000, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+        proposal.contribute_input(outpoint, input, &payee).unwrap();
+        let analysis = proposal.analyze();
+        let accounting = proposal.accounting().unwrap();
+        let payment = bitcoin::SignedAmount::from_sat(2_000_000);
+
+        assert_eq!(accounting.sender.gross_sent, bitcoin::Amount::from_sat(97_983_400));
+        assert_eq!(accounting.sender.counterparty_contribution, bitcoin::Amount::from_sat(1_000));
+        assert_eq!(accounting.receiver.gross_sent, bitcoin::Amount::from_sat(1_000));
+        assert_eq!(accounting.receiver.counterparty_contribution, accounting.sender.gross_sent);
+        assert_eq!(accounting.sender.fee_paid, analysis.original_fee + analysis.sender_contribution);
+        assert_eq!(accounting.receiver.fee_paid, analysis.receiver_fee);
+        assert_eq!(accounting.sender.net_sent, payment + accounting.sender.fee_paid.to_signed().unwrap());
+        assert_eq!(accounting.receiver.net_sent, accounting.receiver.fee_paid.to_signed().unwrap() - payment);
+
+        let unverified = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        assert!(unverified.accounting().is_none());
+    }
+
+    #[test]
+    fn sign_contributed_inputs_remotely() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::time::{Deadline, SystemClock};
+
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let outpoint = bitcoin::OutPoint { txid: Default::default(), vout: 1, };
+        let input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value: 1_000, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+        proposal.contribute_input(outpoint, input, &payee).unwrap();
+        let receiver_index = proposal.psbt.global.unsigned_tx.input.iter().position(|txin| txin.previous_output == outpoint).unwrap();
+        let mut signature = vec![0x30, 0x44];
+        signature.extend_from_slice(&[0x42; 0x44]);
+        signature.push(0x01);
+        let signed = bitcoin::util::psbt::Input {
+            final_script_witness: Some(vec![signature, vec![0x02; 33]]),
+            ..Default::default()
+        };
+        let signer = |psbt: &Psbt, indices: &[usize], _: Deadline| {
+            assert_eq!(indices, [receiver_index]);
+            assert_eq!(psbt.inputs[receiver_index].sighash_type, Some(bitcoin::SigHashType::All));
+            Ok::<_, std::io::Error>(vec![signed.clone()])
+        };
+
+        let error = proposal.clone().sign_contributed_inputs_remotely(&signer, Deadline::at(UNIX_EPOCH)).unwrap_err();
+        assert_eq!(error.to_string(), "the remote signer didn't sign the inputs in time");
+        let deadline = Deadline::after(&SystemClock, Duration::from_secs(60));
+        let nothing = |_: &Psbt, _: &[usize], _: Deadline| Ok::<_, std::io::Error>(Vec::new());
+        let error = proposal.clone().sign_contributed_inputs_remotely(&nothing, deadline).unwrap_err();
+        assert_eq!(error.to_string(), format!("the signer didn't finalize the input #{}", receiver_index));
+        proposal.sign_contributed_inputs_remotely(&signer, deadline).unwrap();
+        assert_eq!(proposal.psbt.inputs[receiver_index].final_script_witness, signed.final_script_witness);
+    }
+
+    #[cfg(feature = "sender")]
+    #[test]
+    fn contribute_input_with_decoy() {
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let sender_change = proposal.psbt.global.unsigned_tx.output[0].value;
+        let options = ReceiverOptions::default();
+        // the finalized input of the receiver from the official vector
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let mut input = vector.inputs[1].clone();
+
+        let error = proposal.contribute_input_with_decoy(outpoint, input.clone(), &payee, taproot_script(), &options).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::NotEnoughMoney);
+        assert_eq!(proposal.psbt.inputs.len(), 1);
+
+        input.witness_utxo.as_mut().unwrap().value = 100_000_000;
+        proposal.contribute_input_with_decoy(outpoint, input.clone(), &payee, taproot_script(), &options).unwrap();
+        let outputs = &proposal.psbt.global.unsigned_tx.output;
+        assert_eq!(outputs.len(), 3);
+        assert_eq!(outputs.iter().filter(|output| output.value == sender_change).count(), 2);
+
+        // the sender accepts the proposal
+        proposal.sign_contributed_inputs(&|_: &Psbt, _: usize| Ok::<_, std::io::Error>(input.clone())).unwrap();
+        proposal.minimize_response();
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None);
+        let (_, context) = uri.create_request(crate::testing::original_psbt(), params).unwrap();
+        let response = base64::encode(bitcoin::consensus::serialize(proposal.psbt()));
+        context.process_response_bytes(response.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn sender_fee_contribution() {
+        let query = "v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182";
+        let mut proposal = get_verified_proposal(query);
+        let payee = payee_script(&proposal);
+        let sender_change = proposal.psbt.global.unsigned_tx.output[0].value;
+        let params = proposal.sender_params();
+        assert_eq!(params.max_fee_contribution(), bitcoin::Amount::from_sat(182));
+        assert_eq!(params.expected_input_weight(), Some(364));
+        let budget = ContributionBudget::compute(&params, params.original_fee_rate(), std::iter::once(364));
+        assert_eq!(budget.receiver_fee(1), Some(bitcoin::Amount::ZERO));
+
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let outpoint = vector.global.unsigned_tx.input[1].previous_output;
+        let input = vector.inputs[1].clone();
+        let receiver_value = proposal.psbt.global.unsigned_tx.output[1].value;
+        proposal.contribute_input(outpoint, input.clone(), &payee).unwrap();
+        let fee = sender_change - proposal.psbt.global.unsigned_tx.output[0].value;
+        assert!(fee > 0 && fee <= 182);
+        // the receiver gets the whole value of its input
+        assert_eq!(proposal.psbt.global.unsigned_tx.output[1].value, receiver_value + input.witness_utxo.as_ref().unwrap().value);
+        assert_eq!(proposal.sender_params().max_fee_contribution(), bitcoin::Amount::from_sat(182 - fee));
+
+        proposal.sign_contributed_inputs(&|_: &Psbt, _: usize| Ok::<_, std::io::Error>(input.clone())).unwrap();
+        proposal.minimize_response();
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), Some(0));
+        let (_, context) = uri.create_request(crate::testing::original_psbt(), params).unwrap();
+        let response = base64::encode(bitcoin::consensus::serialize(proposal.psbt()));
+        context.process_response_bytes(response.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn invalid_fee_params() {
+        assert!(get_proposal_from_test_vector("v=1&maxadditionalfeecontribution=-1").is_err());
+        assert!(get_proposal_from_test_vector("v=1&minfeerate=nan").is_err());
+        assert!(get_proposal_from_test_vector("v=1&minfeerate=1e300").is_err());
+        assert!(get_proposal_from_test_vector("v=1&minfeerate=2100000000000001").is_err());
+        let proposal = get_proposal_from_test_vector("v=1&minfeerate=2100000000000000").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        assert_eq!(proposal.sender_params().min_fee_rate(), Some(MAX_MONEY * 1000));
+        get_proposal_from_test_vector("v=1&minfeerate=1.5&additionalfeeoutputindex=5&maxadditionalfeecontribution=100").unwrap();
+    }
+
+    #[test]
+    fn fallback_package() {
+        use std::time::{Duration, UNIX_EPOCH};
+        use crate::time::Deadline;
+
+        let mut proposal = get_proposal_from_test_vector("v=1").unwrap().this_is_purely_interactive_wallet().assume_locked();
+        let original_tx = proposal.psbt.clone().extract_tx();
+        proposal.substitute_output_script(&payee_script(&proposal), taproot_script()).unwrap();
+        let earliest_broadcast = Deadline::at(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let package = proposal.export_fallback_package(earliest_broadcast);
+        assert_eq!(package.transaction, original_tx);
+
+        let bytes = bitcoin::consensus::serialize(&package);
+        assert_eq!(bytes[..9], [0, 0x00, 0x10, 0x5e, 0x5f, 0, 0, 0, 0]);
+        assert_eq!(bitcoin::consensus::deserialize::<FallbackPackage>(&bytes).unwrap(), package);
+
+        let mut bytes = bytes;
+        bytes[0] = 1;
+        assert!(bitcoin::consensus::deserialize::<FallbackPackage>(&bytes).is_err());
+    }
+
+    #[test]
+    fn tx_conventions() {
+        let proposal = get_verified_proposal("v=1");
+        let conventions = proposal.tx_conventions();
+        assert_eq!(conventions.sequence, 0xfffffffe);
+        assert!(!conventions.signals_rbf());
+        assert!(conventions.preserved_by(&proposal.original_tx, &proposal.psbt.global.unsigned_tx));
+    }
+
+    #[test]
+    fn labels() {
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let input = bitcoin::util::psbt::Input {
+            witness_utxo: Some(TxOut { value: 1_000, script_pubkey: payee.clone(), }),
+            ..Default::default()
+        };
+        proposal.contribute_input(bitcoin::OutPoint { txid: Default::default(), vout: 1, }, input, &payee).unwrap();
+        let labels = proposal.labels();
+        let original_tx = proposal.original_tx.clone();
+        let payjoin_tx = proposal.psbt.global.unsigned_tx.clone();
+
+        let recorded = std::cell::RefCell::new(Vec::new());
+        let sink = |label| {
+            recorded.borrow_mut().push(label);
+            Ok::<_, std::io::Error>(())
+        };
+        assert!(!labels.settled(&original_tx, &sink).unwrap());
+        assert!(recorded.borrow().is_empty());
+        assert!(labels.settled(&payjoin_tx, &sink).unwrap());
+        assert_eq!(*recorded.borrow(), [OutputLabel {
+            payjoin_txid: payjoin_tx.txid(),
+            original_txid: original_tx.txid(),
+            outpoint: bitcoin::OutPoint { txid: payjoin_tx.txid(), vout: 1, },
+            value: bitcoin::Amount::from_sat(2_000_000 + 1_000 - 182),
+            // the receiver paid 182 sats for its input
+            payment: bitcoin::Amount::from_sat(2_000_000 - 182),
+            recycled: bitcoin::Amount::from_sat(1_000),
+        }]);
+    }
+
+    #[test]
+    fn contribute_best_input() {
+        let mut proposal = get_verified_proposal("v=1");
+        let payee = payee_script(&proposal);
+        let candidate = |vout, value| {
+            let outpoint = bitcoin::OutPoint { txid: Default::default(), vout, };
+            let input = bitcoin::util::psbt::Input {
+                witness_utxo: Some(TxOut { value, script_pubkey: payee.clone(), }),
+                ..Default::default()
+            };
+            (outpoint, input)
+        };
+        // the input of the sender is larger than any output unless the receiver contributes
+        // enough to make their output larger
+        let candidates = vec![candidate(0, 100), candidate(1, 50_000), candidate(2, 300_000_000)];
+
+        let scores = proposal.contribute_best_input(candidates.clone(), &payee, &DefaultScorer::default()).unwrap();
+        assert_eq!(scores.iter().map(|score| score.score).collect::<Vec<_>>(), [None, Some(-10_182), Some(-182)]);
+        assert_eq!(scores.iter().map(|score| score.selected).collect::<Vec<_>>(), [false, false, true]);
+        assert!(proposal.psbt.global.unsigned_tx.input.iter().any(|input| input.previous_output.vout == 2));
+
+        let mut proposal = get_verified_proposal("v=1");
+        let smallest = |candidate: &Candidate| -(candidate.value.as_sat() as i64);
+        let scores = proposal.contribute_best_input(candidates, &payee, &smallest).unwrap();
+        assert!(scores[1].selected);
+
+        let error = proposal.contribute_best_input(vec![candidate(3, 100)], &payee, &smallest).unwrap_err();
+        assert_eq!(error.error_code(), ErrorCode::NotEnoughMoney);
+    }
+
+    #[test]
+    fn op_return_outputs() {
+        let mut psbt = crate::testing::original_psbt();
+        psbt.global.unsigned_tx.output.insert(0, TxOut { value: 0, script_pubkey: Script::new_op_return(b"order 42"), });
+        psbt.outputs.insert(0, Default::default());
+        let body = base64::encode(bitcoin::consensus::serialize(&psbt));
+        // the sender asks to pay the fee from the OP_RETURN output
+        let request = || UncheckedProposal::from_request_bytes(body.as_bytes(), "v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182", MockHeaders::new(body.len() as u64)).unwrap();
+
+        let options = ReceiverOptions::default().op_return_policy(OpReturnPolicy::Reject);
+        let error = request().check_op_return_outputs(&options).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+        assert_eq!(error.to_string(), "the output 0 of the original transaction is an OP_RETURN output");
+
+        let options = ReceiverOptions::default();
+        let payee = psbt.global.unsigned_tx.output[2].script_pubkey.clone();
+        let mut proposal = request()
+            .check_op_return_outputs(&options)
+            .unwrap()
+            .check_pays_issued_script(&payee)
+            .unwrap()
+            .this_is_purely_interactive_wallet()
+            .assume_locked();
+        assert_eq!(proposal.sender_params().max_fee_contribution(), bitcoin::Amount::ZERO);
+        let vector = bitcoin::consensus::deserialize::<Psbt>(&base64::decode(crate::testing::PROPOSAL_PSBT).unwrap()).unwrap();
+        let mut input = vector.inputs[1].clone();
+        input.witness_utxo.as_mut().unwrap().value = 100_000_000;
+        proposal.contribute_input_with_decoy(vector.global.unsigned_tx.input[1].previous_output, input, &payee, taproot_script(), &options).unwrap();
+        let outputs = &proposal.psbt.global.unsigned_tx.output;
+        // the decoy mimics the change and the OP_RETURN output is untouched
+        assert_eq!(outputs.iter().filter(|output| output.value == psbt.global.unsigned_tx.output[1].value).count(), 2);
+        assert!(outputs.contains(&psbt.global.unsigned_tx.output[0]));
+    }
+
+    #[test]
+    fn sender_input_types() {
+        let options = ReceiverOptions::default();
+        get_proposal_from_test_vector("v=1").unwrap().check_sender_input_types(&options).unwrap();
+        let options = options.allowed_sender_input_types(vec![InputScriptType::P2Wpkh, InputScriptType::P2ShP2Wpkh]);
+        get_proposal_from_test_vector("v=1").unwrap().check_sender_input_types(&options).unwrap();
+
+        let options = ReceiverOptions::default().allowed_sender_input_types(vec![InputScriptType::P2Wpkh]);
+        let error = get_proposal_from_test_vector("v=1").unwrap().check_sender_input_types(&options).err().unwrap();
+        assert_eq!(error.error_code(), ErrorCode::OriginalPsbtRejected);
+        assert_eq!(error.to_string(), "the input 0 of the original transaction has disallowed type P2ShP2Wpkh");
+    }
+}
@@ -0,0 +1,79 @@
+//! Switching the receiver between modes at runtime
+//!
+//! If the keys of the hot wallet may be compromised or fees spike, contributing inputs has to stop
+//! immediately, but the payments should still be accepted. `ModeSwitch` is a handle shared with
+//! `PayjoinReceiver` through which an admin endpoint or a signal handler changes the mode of
+//! a running receiver.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+/// How `PayjoinReceiver` handles requests.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum ReceiverMode {
+    /// Requests are processed as configured.
+    Normal,
+    /// Proposals are checked but the original transaction is sent back without contributing.
+    ///
+    /// The payment is still acknowledged and the sender may sign the unchanged transaction.
+    NoContributions,
+    /// All requests are rejected with `ErrorCode::Unavailable`, senders broadcast the original.
+    Unavailable,
+}
+
+impl ReceiverMode {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => ReceiverMode::Normal,
+            1 => ReceiverMode::NoContributions,
+            _ => ReceiverMode::Unavailable,
+        }
+    }
+
+    fn to_u8(self) -> u8 {
+        match self {
+            ReceiverMode::Normal => 0,
+            ReceiverMode::NoContributions => 1,
+            ReceiverMode::Unavailable => 2,
+        }
+    }
+}
+
+/// Handle changing the mode of a running receiver.
+///
+/// Clones control the same receiver. Requests already being processed finish in the mode they
+/// started in, except that contributing is skipped if the mode changed before it.
+///
+/// ```
+/// use bip78::receiver::{ModeSwitch, ReceiverMode};
+///
+/// let switch = ModeSwitch::new();
+/// let handle = switch.clone();
+/// handle.set(ReceiverMode::NoContributions);
+/// assert_eq!(switch.mode(), ReceiverMode::NoContributions);
+/// ```
+#[derive(Debug, Clone)]
+pub struct ModeSwitch(Arc<AtomicU8>);
+
+impl ModeSwitch {
+    /// Creates a switch in `ReceiverMode::Normal`.
+    pub fn new() -> Self {
+        ModeSwitch(Arc::new(AtomicU8::new(ReceiverMode::Normal.to_u8())))
+    }
+
+    /// Returns the current mode.
+    pub fn mode(&self) -> ReceiverMode {
+        ReceiverMode::from_u8(self.0.load(Ordering::SeqCst))
+    }
+
+    /// Changes the mode of all receivers using this switch, returns the previous mode.
+    pub fn set(&self, mode: ReceiverMode) -> ReceiverMode {
+        ReceiverMode::from_u8(self.0.swap(mode.to_u8(), Ordering::SeqCst))
+    }
+}
+
+impl Default for ModeSwitch {
+    fn default() -> Self {
+        ModeSwitch::new()
+    }
+}
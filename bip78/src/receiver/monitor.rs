@@ -0,0 +1,111 @@
+//! Watching contributed UTXOs until the payjoin settles
+//!
+//! After the response is sent the contributed UTXOs still belong to the receiver. If something
+//! else spends them (a concurrent system using the same wallet, a compromised key) the sender
+//! broadcasts an invalid transaction and blames the receiver. `ContributionMonitor` detects this.
+
+use std::sync::Mutex;
+use bitcoin::{OutPoint, Transaction, Txid};
+use bitcoin::hashes::sha256d;
+use super::Proposal;
+
+/// What happened to the contributed UTXOs of a proposal.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum SpendEvent {
+    /// The payjoin transaction spent them as expected.
+    Settled { payjoin_txid: Txid, outpoints: Vec<OutPoint>, },
+    /// The original transaction was broadcasted instead so they are still unspent and can be
+    /// contributed to another proposal.
+    Released { original_txid: Txid, outpoints: Vec<OutPoint>, },
+    /// An unexpected transaction spent them.
+    DoubleSpent { spending_txid: Txid, outpoints: Vec<OutPoint>, },
+}
+
+struct Watch {
+    original_txid: Txid,
+    // doesn't change when the sender signs its inputs
+    expected_ntxid: sha256d::Hash,
+    outpoints: Vec<OutPoint>,
+}
+
+/// Watches contributed UTXOs and reports how they were spent.
+///
+/// Call `watch()` after signing the proposal and feed every transaction from your mempool and
+/// blocks (e.g. from ZMQ `rawtx`) to `transaction_seen()`. Each proposal produces exactly one
+/// event after which it's no longer watched.
+pub struct ContributionMonitor {
+    handler: Box<dyn Fn(SpendEvent) + Send + Sync>,
+    watches: Mutex<Vec<Watch>>,
+}
+
+impl ContributionMonitor {
+    /// Creates the monitor calling `handler` for each event.
+    ///
+    /// The handler is called without holding any locks so it may call the monitor.
+    pub fn new(handler: impl Fn(SpendEvent) + Send + Sync + 'static) -> Self {
+        ContributionMonitor {
+            handler: Box::new(handler),
+            watches: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Starts watching the contributed inputs of the proposal.
+    ///
+    /// Does nothing if no input was contributed.
+    pub fn watch(&self, proposal: &Proposal) {
+        let outpoints = proposal.contributed_outpoints().copied().collect::<Vec<_>>();
+        if outpoints.is_empty() {
+            return;
+        }
+        self.lock().push(Watch {
+            original_txid: proposal.original_txid(),
+            expected_ntxid: proposal.psbt.global.unsigned_tx.ntxid(),
+            outpoints,
+        });
+    }
+
+    /// Stops watching the proposal with given original transaction without an event.
+    pub fn unwatch(&self, original_txid: &Txid) {
+        self.lock().retain(|watch| watch.original_txid != *original_txid);
+    }
+
+    /// Returns all watched outpoints, e.g. to subscribe to their spends.
+    pub fn watched_outpoints(&self) -> Vec<OutPoint> {
+        self.lock().iter().flat_map(|watch| watch.outpoints.iter().copied()).collect()
+    }
+
+    /// Processes a transaction seen in the mempool or in a block.
+    pub fn transaction_seen(&self, transaction: &Transaction) {
+        let txid = transaction.txid();
+        let ntxid = transaction.ntxid();
+        let mut events = Vec::new();
+        self.lock().retain(|watch| {
+            if watch.original_txid == txid {
+                events.push(SpendEvent::Released { original_txid: txid, outpoints: watch.outpoints.clone(), });
+                return false;
+            }
+            let spent = transaction.input
+                .iter()
+                .map(|txin| txin.previous_output)
+                .filter(|outpoint| watch.outpoints.contains(outpoint))
+                .collect::<Vec<_>>();
+            if spent.is_empty() {
+                return true;
+            }
+            if watch.expected_ntxid == ntxid {
+                events.push(SpendEvent::Settled { payjoin_txid: txid, outpoints: spent, });
+            } else {
+                events.push(SpendEvent::DoubleSpent { spending_txid: txid, outpoints: spent, });
+            }
+            false
+        });
+        for event in events {
+            (self.handler)(event);
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Watch>> {
+        self.watches.lock().expect("mutex not poisoned")
+    }
+}
@@ -0,0 +1,88 @@
+//! Contributing inputs held by a remote service
+//!
+//! Large receivers often keep their UTXOs with a custodian or a co-signing service exposing a
+//! PSBT-funding API instead of a local wallet. `RemoteUtxoSource` fetches the inputs to
+//! contribute and `RemoteSigner` gets them signed in a single round trip each. Both get the
+//! deadline by which they have to answer - the sender waits only so long for the proposal.
+//! `PayjoinReceiverBuilder::remote_utxo_source()` uses them, `payjoin-client` contains an HTTP
+//! implementation.
+
+use bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bitcoin::OutPoint;
+use crate::time::{Deadline, SystemClock};
+use super::error::InternalSigningError;
+use super::{Proposal, SigningError};
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Remote service providing inputs of the receiver that may be contributed.
+///
+/// The returned inputs must contain UTXO information. The service is responsible for not
+/// offering the same inputs to concurrent proposals. Closures taking `&Psbt` and `Deadline`
+/// and returning `Result<Vec<(OutPoint, psbt::Input)>, E>` implement this trait.
+pub trait RemoteUtxoSource {
+    type Error: Into<BoxError>;
+
+    /// Returns the candidates for `original`, the PSBT of the sender, before `deadline`.
+    fn fetch_candidates(&self, original: &Psbt, deadline: Deadline) -> Result<Vec<(OutPoint, psbt::Input)>, Self::Error>;
+}
+
+impl<E, F> RemoteUtxoSource for F where F: Fn(&Psbt, Deadline) -> Result<Vec<(OutPoint, psbt::Input)>, E>, E: Into<BoxError> {
+    type Error = E;
+
+    fn fetch_candidates(&self, original: &Psbt, deadline: Deadline) -> Result<Vec<(OutPoint, psbt::Input)>, Self::Error> {
+        self(original, deadline)
+    }
+}
+
+/// Remote service signing the inputs it provided.
+///
+/// Gets the whole proposal and the indices of the contributed inputs and returns the finalized
+/// inputs in the same order, see `InputSigner` for the requirements. Missing inputs are
+/// reported as not signed. Closures taking `&Psbt`, `&[usize]` and `Deadline` and returning
+/// `Result<Vec<psbt::Input>, E>` implement this trait.
+pub trait RemoteSigner {
+    type Error: Into<BoxError>;
+
+    fn sign_inputs(&self, proposal: &Psbt, indices: &[usize], deadline: Deadline) -> Result<Vec<psbt::Input>, Self::Error>;
+}
+
+impl<E, F> RemoteSigner for F where F: Fn(&Psbt, &[usize], Deadline) -> Result<Vec<psbt::Input>, E>, E: Into<BoxError> {
+    type Error = E;
+
+    fn sign_inputs(&self, proposal: &Psbt, indices: &[usize], deadline: Deadline) -> Result<Vec<psbt::Input>, Self::Error> {
+        self(proposal, indices, deadline)
+    }
+}
+
+impl Proposal {
+    /// Signs the contributed inputs using a single call of `signer`.
+    ///
+    /// Performs the same checks as `sign_contributed_inputs()`. Signatures returned after
+    /// `deadline` are discarded because the sender has most likely given up by then.
+    pub fn sign_contributed_inputs_remotely(&mut self, signer: &impl RemoteSigner, deadline: Deadline) -> Result<(), SigningError> {
+        use bitcoin::blockdata::transaction::SigHashType;
+
+        let indices = self.receiver_input_indices();
+        if indices.is_empty() {
+            return Ok(());
+        }
+        if deadline.is_expired(&SystemClock) {
+            return Err(InternalSigningError::DeadlineExpired.into());
+        }
+        // the same PSBT sign_contributed_inputs() passes to local signers
+        let mut psbt = self.psbt.clone();
+        for &index in &indices {
+            psbt.inputs[index].sighash_type = Some(SigHashType::All);
+        }
+        let signed = signer.sign_inputs(&psbt, &indices, deadline)
+            .map_err(|error| InternalSigningError::Signer { index: indices[0], error: error.into(), })?;
+        if deadline.is_expired(&SystemClock) {
+            return Err(InternalSigningError::DeadlineExpired.into());
+        }
+        self.sign_contributed_inputs(&|_: &Psbt, index: usize| {
+            let position = indices.iter().position(|&signed| signed == index).expect("indices of the same proposal");
+            Ok::<_, BoxError>(signed.get(position).cloned().unwrap_or_default())
+        })
+    }
+}
@@ -0,0 +1,95 @@
+//! Choosing the best input to contribute
+//!
+//! The receiver usually has several UTXOs that could be contributed. Each of them produces a
+//! different proposal with different fee cost and privacy. `Proposal::contribute_best_input()`
+//! builds all the candidates, lets a `ProposalScorer` score them and keeps the best one.
+
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{Amount, OutPoint};
+use crate::psbt::PsbtExt;
+
+/// Proposal built by contributing one of the candidate inputs.
+#[non_exhaustive]
+pub struct Candidate<'a> {
+    /// The contributed input.
+    pub outpoint: OutPoint,
+    /// Value of the contributed input.
+    pub value: Amount,
+    /// Fee paid by the receiver for the input.
+    pub fee: Amount,
+    /// The resulting proposal.
+    pub psbt: &'a Psbt,
+}
+
+impl Candidate<'_> {
+    /// Returns `true` if one input is larger than any output.
+    ///
+    /// This suggests that not all inputs belong to the same wallet since a wallet wouldn't add
+    /// inputs it doesn't need - BIP78 calls this unnecessary input heuristic (UIH2) and
+    /// recommends avoiding it. Contributing a larger input usually helps because its value is
+    /// added to the output of the receiver.
+    pub fn has_unnecessary_input(&self) -> bool {
+        has_unnecessary_input(self.psbt)
+    }
+}
+
+pub(crate) fn has_unnecessary_input(psbt: &Psbt) -> bool {
+    let mut largest_in = 0;
+    for input in psbt.input_pairs() {
+        match input.previous_txout() {
+            Ok(txout) => largest_in = largest_in.max(txout.value),
+            // can't say anything without the values
+            Err(_) => return false,
+        }
+    }
+    let largest_out = psbt.global.unsigned_tx.output.iter().map(|output| output.value).max().unwrap_or(0);
+    largest_in > largest_out
+}
+
+/// Scores candidate proposals.
+///
+/// Higher score is better. Closures taking `&Candidate` and returning `i64` implement this trait.
+pub trait ProposalScorer {
+    fn score(&self, candidate: &Candidate<'_>) -> i64;
+}
+
+impl<F: Fn(&Candidate<'_>) -> i64> ProposalScorer for F {
+    fn score(&self, candidate: &Candidate<'_>) -> i64 {
+        self(candidate)
+    }
+}
+
+/// Scorer minimizing the fee and avoiding unnecessary input heuristic.
+///
+/// The score is the negated fee in satoshis minus `uih_penalty` if the candidate has an
+/// unnecessary input.
+#[derive(Debug, Copy, Clone)]
+pub struct DefaultScorer {
+    /// How much you are willing to pay to avoid unnecessary input heuristic.
+    pub uih_penalty: Amount,
+}
+
+impl Default for DefaultScorer {
+    fn default() -> Self {
+        DefaultScorer {
+            uih_penalty: Amount::from_sat(10_000),
+        }
+    }
+}
+
+impl ProposalScorer for DefaultScorer {
+    fn score(&self, candidate: &Candidate<'_>) -> i64 {
+        let penalty = if candidate.has_unnecessary_input() { self.uih_penalty } else { Amount::ZERO };
+        -((candidate.fee + penalty).as_sat() as i64)
+    }
+}
+
+/// Score of one candidate input, useful for auditing the decision.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct CandidateScore {
+    pub outpoint: OutPoint,
+    /// `None` if the input couldn't be contributed (e.g. its value doesn't cover the fee).
+    pub score: Option<i64>,
+    /// `true` if this candidate was chosen.
+    pub selected: bool,
+}
@@ -0,0 +1,269 @@
+//! Suspending and resuming the processing of a request
+//!
+//! Receivers split into services (e.g. one accepting requests and one holding the keys) pass the
+//! proposal between them through a queue. `ProposalSnapshot` serializes each stage together with
+//! the results of the checks performed so far. Resuming a snapshot skips those checks, so the
+//! snapshot is authenticated with HMAC-SHA256 using a key known only to the services - a modified
+//! snapshot could otherwise claim that an arbitrary transaction was already verified.
+
+use bitcoin::consensus::encode::{self, Encodable, Decodable, VarInt};
+use bitcoin::hashes::{sha256, Hash, HashEngine, Hmac, HmacEngine};
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use bitcoin::{Amount, OutPoint, Script, Transaction};
+use crate::fee_rate::FeeRate;
+use crate::weight::Weight;
+use crate::ProtocolVersion;
+use super::error::InternalSnapshotError;
+use super::{Params, UncheckedProposal, UnlockedProposal, Proposal, SnapshotError};
+
+/// Version of the serialization format.
+const FORMAT_VERSION: u8 = 0;
+const MAC_LEN: usize = 32;
+
+const STAGE_UNCHECKED: u8 = 0;
+const STAGE_UNLOCKED: u8 = 1;
+const STAGE_LOCKED: u8 = 2;
+
+/// The proposal at any stage of processing.
+///
+/// The format is: version byte (currently 0), stage byte, the parameters of the sender, the
+/// verified receiver output, the PSBT, for `Locked` the data computed by `assume_locked()` and
+/// finally the HMAC of all the preceding bytes. The key should be at least 32 random bytes.
+pub enum ProposalSnapshot {
+    Unchecked(UncheckedProposal),
+    /// Broadcastability was verified.
+    Unlocked(UnlockedProposal),
+    /// The inputs of the sender were locked, contributions are preserved.
+    Locked(Proposal),
+}
+
+impl ProposalSnapshot {
+    /// Serializes the proposal and authenticates it with `key`.
+    pub fn encode(&self, key: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let (stage, psbt, params, payee) = match self {
+            ProposalSnapshot::Unchecked(proposal) => (STAGE_UNCHECKED, &proposal.psbt, &proposal.params, &proposal.payee),
+            ProposalSnapshot::Unlocked(proposal) => (STAGE_UNLOCKED, &proposal.psbt, &proposal.params, &proposal.payee),
+            ProposalSnapshot::Locked(proposal) => (STAGE_LOCKED, &proposal.psbt, &proposal.params, &proposal.payee),
+        };
+        write(&mut bytes, &FORMAT_VERSION);
+        write(&mut bytes, &stage);
+        write_params(&mut bytes, params);
+        write_option(&mut bytes, payee.as_ref());
+        write(&mut bytes, psbt);
+        if let ProposalSnapshot::Locked(proposal) = self {
+            write(&mut bytes, &proposal.original_tx);
+            write(&mut bytes, &VarInt(proposal.sender_inputs.len() as u64));
+            for outpoint in &proposal.sender_inputs {
+                write(&mut bytes, outpoint);
+            }
+            write(&mut bytes, &proposal.original_fee.as_sat());
+            write(&mut bytes, &proposal.original_fee_rate.to_sat_per_kwu());
+            write_option(&mut bytes, proposal.sender_input_weight.map(u64::from).as_ref());
+            write(&mut bytes, &proposal.fee_contribution.is_some());
+            if let Some((amount, script_pubkey)) = &proposal.fee_contribution {
+                write(&mut bytes, &amount.as_sat());
+                write(&mut bytes, script_pubkey);
+            }
+        }
+        let mac = mac(key, &bytes);
+        bytes.extend_from_slice(&mac[..]);
+        bytes
+    }
+
+    /// Verifies the snapshot using `key` and deserializes it.
+    pub fn decode(bytes: &[u8], key: &[u8]) -> Result<Self, SnapshotError> {
+        if bytes.len() < MAC_LEN {
+            return Err(InternalSnapshotError::InvalidMac.into());
+        }
+        let (mut data, expected_mac) = bytes.split_at(bytes.len() - MAC_LEN);
+        let mac = mac(key, data);
+        // constant time so that the MAC can't be guessed byte by byte
+        if mac[..].iter().zip(expected_mac).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+            return Err(InternalSnapshotError::InvalidMac.into());
+        }
+        let version = read::<u8>(&mut data)?;
+        if version != FORMAT_VERSION {
+            return Err(InternalSnapshotError::UnsupportedVersion(version).into());
+        }
+        let stage = read::<u8>(&mut data)?;
+        let params = read_params(&mut data)?;
+        let payee = read_option::<Script>(&mut data)?;
+        let psbt = read::<Psbt>(&mut data)?;
+        let snapshot = match stage {
+            STAGE_UNCHECKED => ProposalSnapshot::Unchecked(UncheckedProposal { psbt, params, payee, }),
+            STAGE_UNLOCKED => ProposalSnapshot::Unlocked(UnlockedProposal { psbt, params, payee, }),
+            STAGE_LOCKED => {
+                let original_tx = read::<Transaction>(&mut data)?;
+                let input_count = read::<VarInt>(&mut data)?.0;
+                // each outpoint takes 36 bytes, don't preallocate more than the data can hold
+                let mut sender_inputs = Vec::with_capacity(input_count.min(data.len() as u64 / 36) as usize);
+                for _ in 0..input_count {
+                    sender_inputs.push(read::<OutPoint>(&mut data)?);
+                }
+                let original_fee = Amount::from_sat(read(&mut data)?);
+                let original_fee_rate = FeeRate::from_sat_per_kwu(read(&mut data)?);
+                let sender_input_weight = read_option::<u64>(&mut data)?.map(Weight::manual_from_u64);
+                let fee_contribution = match read::<bool>(&mut data)? {
+                    true => Some((Amount::from_sat(read(&mut data)?), read::<Script>(&mut data)?)),
+                    false => None,
+                };
+                ProposalSnapshot::Locked(Proposal {
+                    psbt,
+                    original_tx,
+                    params,
+                    payee,
+                    sender_inputs,
+                    original_fee,
+                    original_fee_rate,
+                    sender_input_weight,
+                    fee_contribution,
+                })
+            },
+            stage => return Err(InternalSnapshotError::UnknownStage(stage).into()),
+        };
+        if !data.is_empty() {
+            return Err(InternalSnapshotError::Decode(encode::Error::ParseFailed("data after the end of the snapshot")).into());
+        }
+        Ok(snapshot)
+    }
+}
+
+impl From<UncheckedProposal> for ProposalSnapshot {
+    fn from(value: UncheckedProposal) -> Self {
+        ProposalSnapshot::Unchecked(value)
+    }
+}
+
+impl From<UnlockedProposal> for ProposalSnapshot {
+    fn from(value: UnlockedProposal) -> Self {
+        ProposalSnapshot::Unlocked(value)
+    }
+}
+
+impl From<Proposal> for ProposalSnapshot {
+    fn from(value: Proposal) -> Self {
+        ProposalSnapshot::Locked(value)
+    }
+}
+
+fn mac(key: &[u8], data: &[u8]) -> Hmac<sha256::Hash> {
+    let mut engine = HmacEngine::<sha256::Hash>::new(key);
+    engine.input(data);
+    Hmac::from_engine(engine)
+}
+
+fn write<T: Encodable + ?Sized>(bytes: &mut Vec<u8>, value: &T) {
+    value.consensus_encode(bytes).expect("writing to vec doesn't fail");
+}
+
+fn write_option<T: Encodable>(bytes: &mut Vec<u8>, value: Option<&T>) {
+    write(bytes, &value.is_some());
+    if let Some(value) = value {
+        write(bytes, value);
+    }
+}
+
+fn write_params(bytes: &mut Vec<u8>, params: &Params) {
+    write(bytes, &params.version.number());
+    write(bytes, &params.disable_output_substitution);
+    write(bytes, &params.fee_contribution.is_some());
+    if let Some((amount, index)) = params.fee_contribution {
+        write(bytes, &amount.as_sat());
+        write(bytes, &(index as u64));
+    }
+    write_option(bytes, params.min_fee_rate.map(FeeRate::to_sat_per_kwu).as_ref());
+}
+
+fn read<T: Decodable>(data: &mut &[u8]) -> Result<T, SnapshotError> {
+    T::consensus_decode(data).map_err(|error| InternalSnapshotError::Decode(error).into())
+}
+
+fn read_option<T: Decodable>(data: &mut &[u8]) -> Result<Option<T>, SnapshotError> {
+    match read::<bool>(data)? {
+        true => read(data).map(Some),
+        false => Ok(None),
+    }
+}
+
+fn read_params(data: &mut &[u8]) -> Result<Params, SnapshotError> {
+    let number = read::<u32>(data)?;
+    let version = ProtocolVersion::from_number(number).ok_or(InternalSnapshotError::UnsupportedProtocolVersion(number))?;
+    let disable_output_substitution = read(data)?;
+    let fee_contribution = match read::<bool>(data)? {
+        true => Some((Amount::from_sat(read(data)?), read::<u64>(data)? as usize)),
+        false => None,
+    };
+    let min_fee_rate = read_option::<u64>(data)?.map(FeeRate::from_sat_per_kwu);
+    Ok(Params { version, disable_output_substitution, fee_contribution, min_fee_rate, })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::MockHeaders;
+    use super::*;
+
+    const KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn unchecked() -> UncheckedProposal {
+        let body = crate::testing::ORIGINAL_PSBT.as_bytes();
+        UncheckedProposal::from_request_bytes(body, "v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182&minfeerate=1", MockHeaders::new(body.len() as u64)).unwrap()
+    }
+
+    fn roundtrip(snapshot: ProposalSnapshot) -> ProposalSnapshot {
+        let bytes = snapshot.encode(KEY);
+        let decoded = ProposalSnapshot::decode(&bytes, KEY).unwrap();
+        assert_eq!(decoded.encode(KEY), bytes);
+        decoded
+    }
+
+    #[test]
+    fn stages() {
+        let payee = crate::testing::original_psbt().global.unsigned_tx.output[1].script_pubkey.clone();
+        let proposal = unchecked().check_pays_issued_script(&payee).unwrap();
+        let proposal = match roundtrip(proposal.into()) {
+            ProposalSnapshot::Unchecked(proposal) => proposal.assume_broadcastability_was_verified(),
+            _ => panic!("wrong stage"),
+        };
+        let proposal = match roundtrip(proposal.into()) {
+            ProposalSnapshot::Unlocked(proposal) => proposal.assume_locked(),
+            _ => panic!("wrong stage"),
+        };
+        let original_txid = proposal.original_txid();
+        let params = proposal.sender_params();
+        match roundtrip(proposal.into()) {
+            ProposalSnapshot::Locked(proposal) => {
+                assert_eq!(proposal.original_txid(), original_txid);
+                assert_eq!(proposal.payee, Some(payee));
+                assert_eq!(proposal.sender_params().max_fee_contribution(), params.max_fee_contribution());
+                assert_eq!(proposal.sender_params().min_fee_rate(), params.min_fee_rate());
+            },
+            _ => panic!("wrong stage"),
+        }
+    }
+
+    #[test]
+    fn tampering() {
+        let bytes = ProposalSnapshot::from(unchecked().assume_broadcastability_was_verified()).encode(KEY);
+        assert!(ProposalSnapshot::decode(&bytes, b"other key").is_err());
+        for i in 0..bytes.len() {
+            let mut tampered = bytes.clone();
+            tampered[i] ^= 1;
+            assert!(ProposalSnapshot::decode(&tampered, KEY).is_err());
+        }
+        for len in 0..bytes.len() {
+            assert!(ProposalSnapshot::decode(&bytes[..len], KEY).is_err());
+        }
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut bytes = ProposalSnapshot::from(unchecked()).encode(KEY);
+        bytes.truncate(bytes.len() - MAC_LEN);
+        bytes[0] = FORMAT_VERSION + 1;
+        let mac = mac(KEY, &bytes);
+        bytes.extend_from_slice(&mac[..]);
+        let error = ProposalSnapshot::decode(&bytes, KEY).err().unwrap();
+        assert!(error.to_string().contains("version"));
+    }
+}
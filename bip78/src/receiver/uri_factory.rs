@@ -0,0 +1,155 @@
+//! Creation of payment links with fresh addresses
+
+use bitcoin::util::bip32::{ExtendedPubKey, ChildNumber};
+use bitcoin::secp256k1::{Secp256k1, VerifyOnly};
+use crate::{Uri, PjParseError};
+
+/// Source of fresh receiving addresses.
+///
+/// Closures returning `Result<Address, E>` implement this trait so you can plug in e.g. Bitcoin
+/// Core `getnewaddress` RPC call easily.
+pub trait AddressSource {
+    type Error;
+
+    /// Returns an address that was not given out before.
+    fn next_address(&mut self) -> Result<bitcoin::Address, Self::Error>;
+}
+
+impl<E, F: FnMut() -> Result<bitcoin::Address, E>> AddressSource for F {
+    type Error = E;
+
+    fn next_address(&mut self) -> Result<bitcoin::Address, Self::Error> {
+        self()
+    }
+}
+
+/// Derives P2WPKH addresses from an extended public key.
+///
+/// This is equivalent to `wpkh(xpub/*)` descriptor. Make sure your wallet watches the derived
+/// addresses and that the index doesn't get reused after restart.
+pub struct XpubAddressSource {
+    xpub: ExtendedPubKey,
+    next_index: u32,
+    secp: Secp256k1<VerifyOnly>,
+}
+
+impl XpubAddressSource {
+    /// Creates the source which will derive child number `next_index` first.
+    pub fn new(xpub: ExtendedPubKey, next_index: u32) -> Self {
+        XpubAddressSource {
+            xpub,
+            next_index,
+            secp: Secp256k1::verification_only(),
+        }
+    }
+
+    /// Index of the address that will be derived next.
+    ///
+    /// Store this to avoid reusing addresses after restart.
+    pub fn next_index(&self) -> u32 {
+        self.next_index
+    }
+}
+
+impl AddressSource for XpubAddressSource {
+    type Error = bitcoin::util::bip32::Error;
+
+    fn next_address(&mut self) -> Result<bitcoin::Address, Self::Error> {
+        let child_number = ChildNumber::from_normal_idx(self.next_index)?;
+        let public_key = self.xpub.ckd_pub(&self.secp, child_number)?.public_key;
+        self.next_index += 1;
+        Ok(bitcoin::Address::p2wpkh(&public_key, self.xpub.network).expect("derived keys are compressed"))
+    }
+}
+
+/// Creates payment links with PayJoin endpoint and fresh addresses.
+///
+/// Use `{:#}` when formatting the links for QR codes: the scheme and bech32 addresses are
+/// uppercased which allows more compact encoding.
+///
+/// ```
+/// use bip78::receiver::UriFactory;
+///
+/// // In real code this would call `getnewaddress` RPC
+/// let get_new_address = || "bc1qar0srrr7xfkvy5l643lydnw9re59gtzzwf5mdq".parse::<bip78::bitcoin::Address>();
+/// let mut factory = UriFactory::new(get_new_address, "https://example.com/pj").unwrap();
+/// let uri = factory.create_uri(bip78::bitcoin::Amount::from_sat(100_000)).unwrap();
+/// assert_eq!(format!("{:#}", uri), "BITCOIN:BC1QAR0SRRR7XFKVY5L643LYDNW9RE59GTZZWF5MDQ?amount=0.001&pj=https://example.com/pj");
+/// ```
+pub struct UriFactory<S> {
+    source: S,
+    endpoint: String,
+    disable_output_substitution: bool,
+}
+
+impl<S: AddressSource> UriFactory<S> {
+    /// Creates the factory producing links with given endpoint.
+    ///
+    /// The endpoint must be an HTTP(S) URL. If you publish the endpoint as an onion service the
+    /// URL should be `http://<address>.onion/...` - TLS is redundant with Tor.
+    pub fn new(source: S, endpoint: impl Into<String>) -> Result<Self, PjParseError> {
+        let endpoint = endpoint.into();
+        crate::uri::check_endpoint(&endpoint)?;
+
+        Ok(UriFactory {
+            source,
+            endpoint,
+            disable_output_substitution: false,
+        })
+    }
+
+    /// Forbids the senders from substituting outputs (sets `pjos=0`).
+    pub fn disable_output_substitution(mut self, disable: bool) -> Self {
+        self.disable_output_substitution = disable;
+        self
+    }
+
+    /// Creates a payment link requesting `amount` to a fresh address.
+    pub fn create_uri(&mut self, amount: bitcoin::Amount) -> Result<Uri<'static>, S::Error> {
+        self.create(Some(amount))
+    }
+
+    /// Creates a payment link without amount to a fresh address, see
+    /// `ReceiverOptions::accept_donations()`.
+    pub fn create_donation_uri(&mut self) -> Result<Uri<'static>, S::Error> {
+        self.create(None)
+    }
+
+    fn create(&mut self, amount: Option<bitcoin::Amount>) -> Result<Uri<'static>, S::Error> {
+        let address = self.source.next_address()?;
+        let uri = Uri::new(address, amount, self.endpoint.clone())
+            .expect("endpoint validated in constructor")
+            .disable_output_substitution(self.disable_output_substitution);
+        Ok(uri)
+    }
+
+    /// Returns the address source.
+    pub fn address_source(&self) -> &S {
+        &self.source
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn xpub_source() {
+        // BIP84 test vector: zpub of account 0
+        let mut account = bitcoin::util::base58::from_check("zpub6rFR7y4Q2AijBEqTUquhVz398htDFrtymD9xYYfG1m4wAcvPhXNfE3EfH1r1ADqtfSdVCToUG868RvUUkgDKf31mGDtKsAYz2oz2AGutZYs").unwrap();
+        // xpub version bytes
+        account[..4].copy_from_slice(&[0x04, 0x88, 0xB2, 0x1E]);
+        let account = ExtendedPubKey::decode(&account).unwrap();
+        let receiving = account.ckd_pub(&Secp256k1::verification_only(), ChildNumber::from_normal_idx(0).unwrap()).unwrap();
+        let mut source = XpubAddressSource::new(receiving, 0);
+        assert_eq!(source.next_address().unwrap().to_string(), "bc1qcr8te4kr609gcawutmrza0j4xv80jy8z306fyu");
+        assert_eq!(source.next_address().unwrap().to_string(), "bc1qnjg0jd8228aq7egyzacy8cys3knf9xvrerkf9g");
+        assert_eq!(source.next_index(), 2);
+    }
+
+    #[test]
+    fn bad_endpoint() {
+        let source = || Err::<bitcoin::Address, ()>(());
+        assert!(UriFactory::new(source, "example.com/pj").is_err());
+    }
+}
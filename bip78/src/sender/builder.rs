@@ -0,0 +1,282 @@
+//! Single entry point for creating requests
+//!
+//! `PayjoinSender` collects the URI, the original PSBT and all options in one place and creates
+//! `Request` and `Context` the same way `Uri::create_request()` does. Unlike `Params` it can
+//! compute the fee contribution from a fee rate because it knows the inputs of the PSBT.
+
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use crate::fee_rate::FeeRate;
+use crate::input_type::InputType;
+use crate::psbt::PsbtExt;
+use crate::{ProtocolVersion, Uri};
+use super::error::InternalCreateRequestError;
+use super::{Params, Quirks, UnknownFields, Request, Context, CreateRequestError};
+
+enum Contribution {
+    Amount(bitcoin::Amount),
+    Rate(FeeRate),
+}
+
+/// Builder of payjoin requests.
+///
+/// ```
+/// let uri = bip78::testing::URI.parse::<bip78::Uri>().unwrap();
+/// let (request, context) = bip78::sender::PayjoinSender::new(uri)
+///     .psbt(bip78::testing::original_psbt())
+///     .fee_contribution_rate(2)
+///     .min_fee_rate(1)
+///     .build()
+///     .unwrap();
+/// assert_eq!(request.url, "https://example.com/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182&minfeerate=1");
+/// # let _ = context;
+/// ```
+pub struct PayjoinSender<'a> {
+    uri: Uri<'a>,
+    psbt: Option<Psbt>,
+    contribution: Option<Contribution>,
+    change_index: Option<usize>,
+    params: Params,
+}
+
+impl<'a> PayjoinSender<'a> {
+    /// Starts building a request paying `uri`.
+    ///
+    /// No fee contribution is offered unless you set it.
+    pub fn new(uri: Uri<'a>) -> Self {
+        PayjoinSender {
+            uri,
+            psbt: None,
+            contribution: None,
+            change_index: None,
+            params: Params::default(),
+        }
+    }
+
+    /// Sets the signed and finalized original PSBT. Required.
+    pub fn psbt(mut self, psbt: Psbt) -> Self {
+        self.psbt = Some(psbt);
+        self
+    }
+
+    /// Offers the receiver to pay for one additional input at `sat_per_vb`.
+    ///
+    /// The input is assumed to have the same type as the first input of the PSBT since the
+    /// receiver must use the same type. Building fails if its weight can't be estimated or the
+    /// rate exceeds `MAX_MONEY` per vB.
+    pub fn fee_contribution_rate(mut self, sat_per_vb: u64) -> Self {
+        self.contribution = Some(Contribution::Rate(FeeRate::from_sat_per_vb(sat_per_vb)));
+        self
+    }
+
+    /// Offers the receiver to pay at most `max_fee_contribution`.
+    ///
+    /// See `Params::with_fee_contribution()`.
+    pub fn max_fee_contribution(mut self, max_fee_contribution: bitcoin::Amount) -> Self {
+        self.contribution = Some(Contribution::Amount(max_fee_contribution));
+        self
+    }
+
+    /// Index of the change output paying the contribution, auto-detected by default.
+    pub fn change_index(mut self, index: usize) -> Self {
+        self.change_index = Some(index);
+        self
+    }
+
+    /// See `Params::clamp_fee_contribution()`.
+    pub fn clamp_fee_contribution(mut self, clamp: bool) -> Self {
+        self.params = self.params.clamp_fee_contribution(clamp);
+        self
+    }
+
+    /// See `Params::min_fee_rate()`.
+    pub fn min_fee_rate(mut self, sat_per_vb: u64) -> Self {
+        self.params = self.params.min_fee_rate(sat_per_vb);
+        self
+    }
+
+    /// See `Params::fee_share()`.
+    pub fn fee_share(mut self, fee_share: crate::FeeShare) -> Self {
+        self.params = self.params.fee_share(fee_share);
+        self
+    }
+
+    /// See `Params::check_change_privacy()`.
+    pub fn check_change_privacy(mut self, check: bool) -> Self {
+        self.params = self.params.check_change_privacy(check);
+        self
+    }
+
+    /// See `Params::verify_receiver_signatures()`.
+    pub fn verify_receiver_signatures(mut self, verify: bool) -> Self {
+        self.params = self.params.verify_receiver_signatures(verify);
+        self
+    }
+
+    /// See `Params::always_disable_output_substitution()`.
+    pub fn always_disable_output_substitution(mut self, disable: bool) -> Self {
+        self.params = self.params.always_disable_output_substitution(disable);
+        self
+    }
+
+    /// See `Params::allow_additional_outputs()`.
+    pub fn allow_additional_outputs(mut self, allow: bool) -> Self {
+        self.params = self.params.allow_additional_outputs(allow);
+        self
+    }
+
+    /// See `Params::additional_inputs()`.
+    pub fn additional_inputs(mut self, range: std::ops::RangeInclusive<usize>) -> Self {
+        self.params = self.params.additional_inputs(range);
+        self
+    }
+
+    /// See `Params::require_matching_rbf_signaling()`.
+    pub fn require_matching_rbf_signaling(mut self, require: bool) -> Self {
+        self.params = self.params.require_matching_rbf_signaling(require);
+        self
+    }
+
+    /// See `Params::substitution_script_policy()`.
+    pub fn substitution_script_policy(mut self, allowlist: super::Allowlist) -> Self {
+        self.params = self.params.substitution_script_policy(allowlist);
+        self
+    }
+
+    /// See `Params::sequence_policy()`.
+    pub fn sequence_policy(mut self, policy: super::SequencePolicy) -> Self {
+        self.params = self.params.sequence_policy(policy);
+        self
+    }
+
+    /// See `Params::unknown_fields()`.
+    pub fn unknown_fields(mut self, policy: UnknownFields) -> Self {
+        self.params = self.params.unknown_fields(policy);
+        self
+    }
+
+    /// See `Params::strict()`.
+    pub fn strict(mut self) -> Self {
+        self.params = self.params.strict();
+        self
+    }
+
+    /// See `Params::compat()`.
+    pub fn compat(mut self) -> Self {
+        self.params = self.params.compat();
+        self
+    }
+
+    /// See `Params::quirks()`.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.params = self.params.quirks(quirks);
+        self
+    }
+
+    /// See `Params::version()`.
+    pub fn version(mut self, version: ProtocolVersion) -> Self {
+        self.params = self.params.version(version);
+        self
+    }
+
+    /// See `Params::fallback_endpoint()`.
+    pub fn fallback_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.params = self.params.fallback_endpoint(endpoint);
+        self
+    }
+
+    /// See `Params::max_latency()`.
+    pub fn max_latency(mut self, max_latency: std::time::Duration) -> Self {
+        self.params = self.params.max_latency(max_latency);
+        self
+    }
+
+    /// See `Params::on_event()`.
+    pub fn on_event(mut self, callback: impl Fn(&super::PayjoinEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.params = self.params.on_event(callback);
+        self
+    }
+
+    /// See `Params::limits()`.
+    pub fn limits(mut self, limits: crate::Limits) -> Self {
+        self.params = self.params.limits(limits);
+        self
+    }
+
+    /// Validates the PSBT and creates the request.
+    pub fn build(self) -> Result<(Request, Context), CreateRequestError> {
+        let psbt = self.psbt.ok_or(InternalCreateRequestError::MissingPsbt)?;
+        let max_fee_contribution = match self.contribution {
+            Some(Contribution::Amount(amount)) => Some(amount),
+            Some(Contribution::Rate(rate)) => {
+                if rate > FeeRate::MAX {
+                    return Err(InternalCreateRequestError::FeeRateOutOfRange.into());
+                }
+                let zeroth_input = psbt.input_pairs().next().ok_or(InternalCreateRequestError::NoInputs)?;
+                let txout = zeroth_input.previous_txout().map_err(InternalCreateRequestError::InvalidFirstInput)?;
+                let weight = InputType::from_spent_input(txout, zeroth_input.psbtin)
+                    .map_err(InternalCreateRequestError::InvalidInputType)?
+                    .expected_input_weight()
+                    .ok_or(InternalCreateRequestError::UnknownInputWeight)?;
+                Some(rate * weight)
+            },
+            None => None,
+        };
+        let change_index = self.change_index;
+        let params = Params {
+            fee_contribution: max_fee_contribution.map(|amount| (amount, change_index)),
+            ..self.params
+        };
+        super::from_psbt_and_uri(psbt, self.uri, params)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PayjoinSender;
+
+    #[test]
+    fn fee_rate_out_of_range() {
+        let sender = || PayjoinSender::new(crate::testing::URI.parse::<crate::Uri>().unwrap()).psbt(crate::testing::original_psbt());
+        let max = crate::psbt::MAX_MONEY;
+        assert!(sender().min_fee_rate(max).build().is_ok());
+        for rate in [max + 1, u64::MAX / 250 + 1, u64::MAX] {
+            let error = sender().min_fee_rate(rate).build().err().unwrap();
+            assert_eq!(error.to_string(), "the fee rate exceeds the maximum of 2100000000000000 sat/vB");
+            sender().fee_contribution_rate(rate).build().err().unwrap();
+        }
+    }
+
+    #[test]
+    fn missing_psbt() {
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        PayjoinSender::new(uri).build().err().unwrap();
+    }
+
+    #[test]
+    fn strict() {
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let (request, context) = PayjoinSender::new(uri)
+            .psbt(crate::testing::original_psbt())
+            .max_fee_contribution(bitcoin::Amount::from_sat(182))
+            .change_index(0)
+            .strict()
+            .build()
+            .unwrap();
+        assert_eq!(request.url, "https://example.com/pj?v=1&disableoutputsubstitution=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182");
+        // the official proposal has no extra outputs, a single input and doesn't substitute
+        context.process_response_bytes(crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn min_fee_rate() {
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let (_, context) = PayjoinSender::new(uri)
+            .psbt(crate::testing::original_psbt())
+            .fee_contribution_rate(2)
+            .min_fee_rate(3)
+            .build()
+            .unwrap();
+        let error = context.process_response_bytes(crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap_err();
+        assert!(error.is_protocol_violation());
+    }
+}
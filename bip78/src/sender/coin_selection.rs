@@ -0,0 +1,206 @@
+//! Selecting inputs of the original transaction
+//!
+//! Wallets funding the original PSBT with their general-purpose coin selection often produce
+//! transactions the receiver can't do much with: mixed input types (which BIP78 forbids the
+//! receiver to continue), change too small to pay the fee contribution or unconfirmed inputs the
+//! receiver may refuse. `select_original_inputs()` picks inputs avoiding these problems. Pass the
+//! result to your wallet as the only allowed inputs (e.g. `inputs` and `add_inputs=false` of
+//! `walletcreatefundedpsbt`).
+
+use bitcoin::{Amount, OutPoint, Script, TxOut};
+use crate::fee_rate::FeeRate;
+use crate::input_type::{InputType, SegWitV0Type};
+use crate::weight::{ComputeWeight, Weight};
+
+/// Change below this would be dust for all standard scripts.
+const DUST_LIMIT: u64 = 546;
+/// Version, lock time, counts of inputs and outputs and the segwit marker.
+const BASE_WEIGHT: u64 = 4 * (4 + 4 + 1 + 1) + 2;
+/// Key path spend.
+const TAPROOT_INPUT_WEIGHT: u64 = 230;
+
+/// Unspent output of the wallet.
+#[derive(Debug, Clone)]
+pub struct WalletUtxo {
+    pub outpoint: OutPoint,
+    pub txout: TxOut,
+    pub confirmations: u32,
+}
+
+/// Inputs selected for the original transaction.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Selection {
+    pub inputs: Vec<OutPoint>,
+    /// Fee of the original transaction at the requested fee rate.
+    pub fee: Amount,
+    /// Value of the change output, at least the maximum fee contribution plus dust limit.
+    pub change: Amount,
+}
+
+/// What the sender wants to pay.
+#[derive(Debug, Clone)]
+pub struct SelectionTarget<'a> {
+    pub payee: &'a Script,
+    pub amount: Amount,
+    pub change_script: &'a Script,
+    /// Fee rate of the original transaction in sat/vB.
+    pub fee_rate: u64,
+    /// Fee contribution offered to the receiver, it's deducted from the change.
+    pub max_fee_contribution: Amount,
+}
+
+/// Selects confirmed inputs of a single type funding the payment with change large enough to pay
+/// the fee contribution.
+///
+/// Types for which the fee of an additional input is known (P2PKH, P2WPKH and P2SH which is
+/// assumed to wrap P2WPKH) are preferred because they allow the receiver to contribute within
+/// the fee contribution. Within them the selection with the fewest inputs wins. Returns `None`
+/// if no single type can fund the payment or the fee rate exceeds `MAX_MONEY` per vB.
+pub fn select_original_inputs(utxos: &[WalletUtxo], target: &SelectionTarget<'_>) -> Option<Selection> {
+    let fee_rate = Some(FeeRate::from_sat_per_vb(target.fee_rate)).filter(|rate| *rate <= FeeRate::MAX)?;
+    let outputs_weight = TxOut { value: 0, script_pubkey: target.payee.clone(), }.weight()
+        + TxOut { value: 0, script_pubkey: target.change_script.clone(), }.weight();
+    let mut groups = Vec::<(InputType, Vec<&WalletUtxo>)>::new();
+    for utxo in utxos.iter().filter(|utxo| utxo.confirmations > 0) {
+        let input_type = match assumed_input_type(&utxo.txout.script_pubkey) {
+            Some(input_type) => input_type,
+            None => continue,
+        };
+        match groups.iter_mut().find(|(group_type, _)| *group_type == input_type) {
+            Some((_, group)) => group.push(utxo),
+            None => groups.push((input_type, vec![utxo])),
+        }
+    }
+    groups
+        .into_iter()
+        .filter_map(|(input_type, mut group)| {
+            let input_weight = input_weight(input_type);
+            // largest first minimizes the number of inputs
+            group.sort_by_key(|utxo| std::cmp::Reverse(utxo.txout.value));
+            let mut weight = Weight::manual_from_u64(BASE_WEIGHT) + outputs_weight;
+            let mut total = Amount::ZERO;
+            for (count, utxo) in group.iter().enumerate() {
+                weight += input_weight;
+                total = total.checked_add(Amount::from_sat(utxo.txout.value))?;
+                let fee = fee_rate * weight;
+                let required = target.amount
+                    .checked_add(fee)?
+                    .checked_add(target.max_fee_contribution)?
+                    .checked_add(Amount::from_sat(DUST_LIMIT))?;
+                if total >= required {
+                    let selection = Selection {
+                        inputs: group[..=count].iter().map(|utxo| utxo.outpoint).collect(),
+                        fee,
+                        change: total - target.amount - fee,
+                    };
+                    let unknown_weight = input_type.expected_input_weight().is_none();
+                    return Some(((unknown_weight, count, fee), selection));
+                }
+            }
+            None
+        })
+        .min_by_key(|(key, _)| *key)
+        .map(|(_, selection)| selection)
+}
+
+/// Type of the input spending `script_pubkey` if it's spendable by a single signature.
+fn assumed_input_type(script_pubkey: &Script) -> Option<InputType> {
+    if script_pubkey.is_p2pkh() {
+        Some(InputType::P2Pkh)
+    } else if script_pubkey.is_v0_p2wpkh() {
+        Some(InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: false, })
+    } else if script_pubkey.is_p2sh() {
+        Some(InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: true, })
+    } else if is_p2tr(script_pubkey) {
+        Some(InputType::Taproot)
+    } else {
+        None
+    }
+}
+
+fn is_p2tr(script_pubkey: &Script) -> bool {
+    let bytes = script_pubkey.as_bytes();
+    bytes.len() == 34 && bytes[0] == bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1.into_u8() && bytes[1] == 32
+}
+
+fn input_weight(input_type: InputType) -> Weight {
+    input_type.expected_input_weight().unwrap_or(Weight::manual_from_u64(TAPROOT_INPUT_WEIGHT))
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::hashes::Hash;
+    use super::*;
+
+    fn utxo(vout: u32, value: u64, script_pubkey: Script, confirmations: u32) -> WalletUtxo {
+        WalletUtxo {
+            outpoint: OutPoint { txid: bitcoin::Txid::from_inner([0; 32]), vout, },
+            txout: TxOut { value, script_pubkey, },
+            confirmations,
+        }
+    }
+
+    fn p2wpkh() -> Script {
+        Script::new_v0_wpkh(&bitcoin::WPubkeyHash::from_inner([0; 20]))
+    }
+
+    fn p2pkh() -> Script {
+        Script::new_p2pkh(&bitcoin::PubkeyHash::from_inner([0; 20]))
+    }
+
+    fn target<'a>(scripts: &'a (Script, Script), amount: u64) -> SelectionTarget<'a> {
+        SelectionTarget {
+            payee: &scripts.0,
+            amount: Amount::from_sat(amount),
+            change_script: &scripts.1,
+            fee_rate: 2,
+            max_fee_contribution: Amount::from_sat(182),
+        }
+    }
+
+    #[test]
+    fn single_type() {
+        let scripts = (p2wpkh(), p2wpkh());
+        let utxos = [
+            utxo(0, 60_000, p2wpkh(), 1),
+            utxo(1, 50_000, p2pkh(), 1),
+            utxo(2, 50_000, p2wpkh(), 1),
+            // unconfirmed
+            utxo(3, 1_000_000, p2wpkh(), 0),
+        ];
+        let selection = select_original_inputs(&utxos, &target(&scripts, 100_000)).unwrap();
+        assert_eq!(selection.inputs, [utxos[0].outpoint, utxos[2].outpoint]);
+        // two P2WPKH inputs and two P2WPKH outputs
+        assert_eq!(selection.fee, Amount::from_sat((42 + 2 * 272 + 2 * 124) / 2));
+        assert_eq!(selection.change, Amount::from_sat(110_000 - 100_000) - selection.fee);
+        // mixing types would be needed
+        assert_eq!(select_original_inputs(&utxos, &target(&scripts, 150_000)), None);
+    }
+
+    #[test]
+    fn change_headroom() {
+        let scripts = (p2wpkh(), p2wpkh());
+        let utxos = [utxo(0, 100_500, p2wpkh(), 1), utxo(1, 10_000, p2wpkh(), 1)];
+        // the first input alone would leave too small change for the fee contribution
+        let selection = select_original_inputs(&utxos, &target(&scripts, 100_000)).unwrap();
+        assert_eq!(selection.inputs.len(), 2);
+        assert!(selection.change >= Amount::from_sat(182 + DUST_LIMIT));
+    }
+
+    #[test]
+    fn prefers_known_weight() {
+        let scripts = (p2wpkh(), p2wpkh());
+        let p2tr = Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(1).unwrap(), &[42; 32]);
+        let utxos = [utxo(0, 200_000, p2tr, 1), utxo(1, 60_000, p2pkh(), 1), utxo(2, 60_000, p2pkh(), 1)];
+        let selection = select_original_inputs(&utxos, &target(&scripts, 100_000)).unwrap();
+        assert_eq!(selection.inputs, [utxos[1].outpoint, utxos[2].outpoint]);
+    }
+
+    #[test]
+    fn fee_rate_out_of_range() {
+        let scripts = (p2wpkh(), p2wpkh());
+        let utxos = [utxo(0, 200_000, p2wpkh(), 1)];
+        let target = SelectionTarget { fee_rate: u64::MAX, ..target(&scripts, 100_000) };
+        assert_eq!(select_original_inputs(&utxos, &target), None);
+    }
+}
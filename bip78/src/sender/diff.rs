@@ -0,0 +1,158 @@
+//! Human-readable difference between the original PSBT and the proposal
+//!
+//! Meant for debugging failed payjoins, see `Context::audit_response()`.
+
+use std::fmt;
+use bitcoin::{Amount, OutPoint, Script};
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use crate::psbt::PsbtExt;
+
+#[derive(Debug, Clone)]
+enum InputDiff {
+    Kept { index: usize, outpoint: OutPoint, },
+    Added { index: usize, outpoint: OutPoint, value: Option<u64>, },
+    Removed { outpoint: OutPoint, },
+}
+
+#[derive(Debug, Clone)]
+enum OutputDiff {
+    Kept { index: usize, script: Script, original: u64, proposed: u64, },
+    Added { index: usize, script: Script, value: u64, },
+    Removed { script: Script, value: u64, },
+}
+
+/// Changes the receiver made to the original PSBT.
+///
+/// Inputs are matched by outpoint and outputs by script so a substituted output shows up as
+/// removed and added. Display it to get one change per line.
+#[derive(Debug, Clone)]
+pub struct ProposalDiff {
+    inputs: Vec<InputDiff>,
+    outputs: Vec<OutputDiff>,
+    original_fee: Option<Amount>,
+    proposed_fee: Option<Amount>,
+}
+
+impl ProposalDiff {
+    /// Compares the proposal with the original PSBT.
+    ///
+    /// The proposal doesn't have to be valid. Values of sender inputs are taken from the original.
+    pub fn new(original: &Psbt, proposal: &Psbt) -> Self {
+        let original_value = |outpoint: &OutPoint| original
+            .input_pairs()
+            .find(|input| input.txin.previous_output == *outpoint)
+            .and_then(|input| input.previous_txout().ok().map(|txout| txout.value));
+
+        let mut inputs = Vec::new();
+        let mut input_values = Some(0u64);
+        for (index, input) in proposal.input_pairs().enumerate() {
+            let outpoint = input.txin.previous_output;
+            let value = match original_value(&outpoint) {
+                Some(value) => {
+                    inputs.push(InputDiff::Kept { index, outpoint, });
+                    Some(value)
+                },
+                None => {
+                    let value = input.previous_txout().ok().map(|txout| txout.value);
+                    inputs.push(InputDiff::Added { index, outpoint, value, });
+                    value
+                },
+            };
+            input_values = input_values.and_then(|sum| sum.checked_add(value?));
+        }
+        for txin in &original.global.unsigned_tx.input {
+            if !proposal.global.unsigned_tx.input.iter().any(|proposed| proposed.previous_output == txin.previous_output) {
+                inputs.push(InputDiff::Removed { outpoint: txin.previous_output, });
+            }
+        }
+
+        let mut unmatched = original.global.unsigned_tx.output.iter().map(Some).collect::<Vec<_>>();
+        let mut outputs = Vec::new();
+        for (index, txout) in proposal.global.unsigned_tx.output.iter().enumerate() {
+            let original = unmatched
+                .iter_mut()
+                .find(|original| matches!(original, Some(original) if original.script_pubkey == txout.script_pubkey))
+                .and_then(Option::take);
+            match original {
+                Some(original) => outputs.push(OutputDiff::Kept { index, script: txout.script_pubkey.clone(), original: original.value, proposed: txout.value, }),
+                None => outputs.push(OutputDiff::Added { index, script: txout.script_pubkey.clone(), value: txout.value, }),
+            }
+        }
+        for txout in unmatched.into_iter().flatten() {
+            outputs.push(OutputDiff::Removed { script: txout.script_pubkey.clone(), value: txout.value, });
+        }
+
+        let output_values = proposal.global.unsigned_tx.output.iter().try_fold(0u64, |sum, txout| sum.checked_add(txout.value));
+        let proposed_fee = match (input_values, output_values) {
+            (Some(inputs), Some(outputs)) if inputs <= super::MAX_MONEY => inputs.checked_sub(outputs).map(Amount::from_sat),
+            _ => None,
+        };
+
+        ProposalDiff {
+            inputs,
+            outputs,
+            original_fee: super::calculate_psbt_fee(original),
+            proposed_fee,
+        }
+    }
+
+    /// Fee of the original transaction, `None` if it can't be computed.
+    pub fn original_fee(&self) -> Option<Amount> {
+        self.original_fee
+    }
+
+    /// Fee of the proposal, `None` if some input values are missing or outputs exceed inputs.
+    pub fn proposed_fee(&self) -> Option<Amount> {
+        self.proposed_fee
+    }
+}
+
+struct MaybeAmount(Option<Amount>);
+
+impl fmt::Display for MaybeAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Some(amount) => write!(f, "{}", amount),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+impl fmt::Display for ProposalDiff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for input in &self.inputs {
+            match input {
+                InputDiff::Kept { index, outpoint, } => writeln!(f, "  input {}: {} (sender)", index, outpoint)?,
+                InputDiff::Added { index, outpoint, value, } => writeln!(f, "+ input {}: {} {}", index, outpoint, MaybeAmount(value.map(Amount::from_sat)))?,
+                InputDiff::Removed { outpoint, } => writeln!(f, "- input: {}", outpoint)?,
+            }
+        }
+        for output in &self.outputs {
+            match output {
+                OutputDiff::Kept { index, script, original, proposed, } if original == proposed => writeln!(f, "  output {}: {:x} {}", index, script, Amount::from_sat(*original))?,
+                OutputDiff::Kept { index, script, original, proposed, } => writeln!(f, "~ output {}: {:x} {} -> {} ({:+} sat)", index, script, Amount::from_sat(*original), Amount::from_sat(*proposed), i128::from(*proposed) - i128::from(*original))?,
+                OutputDiff::Added { index, script, value, } => writeln!(f, "+ output {}: {:x} {}", index, script, Amount::from_sat(*value))?,
+                OutputDiff::Removed { script, value, } => writeln!(f, "- output: {:x} {}", script, Amount::from_sat(*value))?,
+            }
+        }
+        write!(f, "  fee: {} -> {}", MaybeAmount(self.original_fee), MaybeAmount(self.proposed_fee))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProposalDiff;
+
+    #[test]
+    fn official_vector() {
+        let original = crate::testing::original_psbt();
+        let proposal = super::super::load_psbt_from_base64(crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap();
+        let diff = ProposalDiff::new(&original, &proposal);
+        assert_eq!(diff.original_fee(), Some(bitcoin::Amount::from_sat(332)));
+        assert_eq!(diff.proposed_fee(), Some(bitcoin::Amount::from_sat(514)));
+        let text = diff.to_string();
+        assert_eq!(text.lines().filter(|line| line.starts_with('+')).count(), 1);
+        assert_eq!(text.lines().filter(|line| line.starts_with('~')).count(), 2);
+        assert!(text.ends_with("fee: 0.00000332 BTC -> 0.00000514 BTC"));
+    }
+}
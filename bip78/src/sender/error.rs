@@ -1,4 +1,7 @@
+use bitcoin::blockdata::transaction::SigHashType;
 use crate::input_type::{InputType, InputTypeError};
+use crate::ErrorCode;
+use super::privacy::{ChangeInference, Severity};
 use std::fmt;
 
 /// Error that may occur when the response from receiver is malformed.
@@ -17,27 +20,122 @@ pub(crate) enum InternalValidationError {
     InvalidProposedInput(crate::psbt::PrevTxOutError),
     VersionsDontMatch { proposed: i32, original: i32, },
     LockTimesDontMatch { proposed: u32, original: u32, },
+    ConventionsChanged,
     SenderTxinSequenceChanged { proposed: u32, original: u32, },
+    SenderTxinSighashTypeChanged { proposed: Option<SigHashType>, original: Option<SigHashType>, },
+    SenderTxinUnsafeSighashType { sighash_type: SigHashType, },
+    RbfSignalingChanged { proposed: bool, original: bool, },
     SenderTxinContainsNonWitnessUtxo,
     SenderTxinContainsWitnessUtxo,
     SenderTxinContainsFinalScriptSig,
     SenderTxinContainsFinalScriptWitness,
+    SenderTxinContainsTapFields,
+    SenderTxinContainsPreimages,
+    SenderTxinScriptMismatch,
     TxInContainsKeyPaths,
     ContainsPartialSigs,
     ReceiverTxinNotFinalized,
     ReceiverTxinMissingUtxoInfo,
+    ReceiverTxinUnsafeSighashFlag { index: usize, flag: u8, },
+    ReceiverTxinInvalidSignature { index: usize, },
     MixedSequence,
+    ReceiverTxinSequenceRejected { index: usize, sequence: u32, },
     MixedInputTypes { proposed: InputType, original: InputType, },
     MissingOrShuffledInputs,
+    InputValueOverflow,
     TxOutContainsKeyPaths,
-    FeeContributionExceedsMaximum,
+    FeeContributionExceedsMaximum { contributed: bitcoin::Amount, maximum: bitcoin::Amount, },
     DisallowedOutputSubstitution,
+    SubstitutionScriptNotAllowed(super::ScriptType),
     OutputValueDecreased,
-    MissingOrShuffledOutputs,
+    OpReturnChanged,
+    MissingOutputs,
+    OutputValueOverflow,
     Inflation,
     AbsoluteFeeDecreased,
-    PayeeTookContributedFee,
+    PayeeTookContributedFee { contributed: bitcoin::Amount, fee_increase: bitcoin::Amount, },
     FeeContributionPaysOutputSizeIncrease,
+    FeeRateBelowMinimum { proposed: u64, minimum: u64, },
+    FeeShareExceeded { contributed: bitcoin::Amount, agreed: bitcoin::Amount, },
+    DisallowedAdditionalOutput,
+    UnexpectedAdditionalInputCount { count: usize, allowed: std::ops::RangeInclusive<usize>, },
+    ProposalContainsUnknownFields,
+    LimitExceeded(crate::limits::LimitExceeded),
+    ReceiverError { code: Option<ErrorCode>, },
+}
+
+impl ValidationError {
+    /// Returns the error code if the receiver responded with a well-known error.
+    ///
+    /// `None` is returned both for unknown codes and when the receiver didn't report an error.
+    pub fn receiver_error_code(&self) -> Option<ErrorCode> {
+        match &self.internal {
+            InternalValidationError::ReceiverError { code, .. } => *code,
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the receiver deliberately broke the rules of the protocol.
+    ///
+    /// These errors can't be explained by a buggy or outdated implementation - the receiver
+    /// attempted to take sender's money, to change the payment or to fingerprint the
+    /// transaction. You may want to warn the user and avoid the endpoint in the future.
+    ///
+    /// If this returns `false` the proposal was malformed or used features we don't support.
+    /// It's still unsafe to sign it but the receiver is likely just incompatible.
+    pub fn is_protocol_violation(&self) -> bool {
+        use InternalValidationError::*;
+
+        match &self.internal {
+            Decode(_) => false,
+            InvalidInputType(_) => false,
+            InvalidProposedInput(_) => false,
+            VersionsDontMatch { .. } => true,
+            LockTimesDontMatch { .. } => true,
+            ConventionsChanged => true,
+            SenderTxinSequenceChanged { .. } => true,
+            SenderTxinSighashTypeChanged { .. } => true,
+            SenderTxinUnsafeSighashType { .. } => true,
+            RbfSignalingChanged { .. } => true,
+            SenderTxinContainsNonWitnessUtxo => false,
+            SenderTxinContainsWitnessUtxo => false,
+            SenderTxinContainsFinalScriptSig => false,
+            SenderTxinContainsFinalScriptWitness => false,
+            SenderTxinContainsTapFields => false,
+            SenderTxinContainsPreimages => false,
+            SenderTxinScriptMismatch => true,
+            TxInContainsKeyPaths => false,
+            ContainsPartialSigs => false,
+            ReceiverTxinNotFinalized => true,
+            ReceiverTxinMissingUtxoInfo => false,
+            ReceiverTxinUnsafeSighashFlag { .. } => true,
+            ReceiverTxinInvalidSignature { .. } => true,
+            MixedSequence => true,
+            ReceiverTxinSequenceRejected { .. } => true,
+            MixedInputTypes { .. } => true,
+            MissingOrShuffledInputs => true,
+            InputValueOverflow => true,
+            TxOutContainsKeyPaths => false,
+            FeeContributionExceedsMaximum { .. } => true,
+            DisallowedOutputSubstitution => true,
+            SubstitutionScriptNotAllowed(_) => false,
+            OutputValueDecreased => true,
+            OpReturnChanged => true,
+            MissingOutputs => true,
+            OutputValueOverflow => true,
+            Inflation => true,
+            AbsoluteFeeDecreased => true,
+            PayeeTookContributedFee { .. } => true,
+            FeeContributionPaysOutputSizeIncrease => true,
+            FeeRateBelowMinimum { .. } => true,
+            FeeShareExceeded { .. } => false,
+            DisallowedAdditionalOutput => false,
+            UnexpectedAdditionalInputCount { .. } => false,
+            ProposalContainsUnknownFields => false,
+            LimitExceeded(_) => false,
+            ReceiverError { .. } => false,
+        }
+    }
 }
 
 impl From<InternalValidationError> for ValidationError {
@@ -63,27 +161,50 @@ impl fmt::Display for ValidationError {
             InvalidProposedInput(_) => write!(f, "invalid proposed transaction input"),
             VersionsDontMatch { proposed, original, } => write!(f, "proposed transaction version {} doesn't match the original {}", proposed, original),
             LockTimesDontMatch { proposed, original, } => write!(f, "proposed transaction lock time {} doesn't match the original {}", proposed, original),
+            ConventionsChanged => write!(f, "proposed transaction doesn't follow the RBF and lock time conventions of the original"),
             SenderTxinSequenceChanged { proposed, original, } => write!(f, "proposed transaction sequence number {} doesn't match the original {}", proposed, original),
+            SenderTxinSighashTypeChanged { proposed, original, } => write!(f, "proposed transaction requests sighash type {:?} for an input of the sender but the original requested {:?}", proposed, original),
+            SenderTxinUnsafeSighashType { sighash_type, } => write!(f, "proposed transaction requests sighash type {} for an input of the sender", sighash_type),
+            RbfSignalingChanged { proposed, original, } => write!(f, "proposed transaction {} RBF but the original {}", if *proposed { "signals" } else { "doesn't signal" }, if *original { "does" } else { "doesn't" }),
             SenderTxinContainsNonWitnessUtxo => write!(f, "an input in proposed transaction belonging to the sender contains non-witness UTXO information"),
             SenderTxinContainsWitnessUtxo => write!(f, "an input in proposed transaction belonging to the sender contains witness UTXO information"),
             SenderTxinContainsFinalScriptSig => write!(f, "an input in proposed transaction belonging to the sender contains finalized non-witness signature"),
             SenderTxinContainsFinalScriptWitness => write!(f, "an input in proposed transaction belonging to the sender contains finalized witness signature"),
+            SenderTxinContainsTapFields => write!(f, "an input in proposed transaction belonging to the sender contains taproot fields"),
+            SenderTxinContainsPreimages => write!(f, "an input in proposed transaction belonging to the sender contains hash preimages"),
+            SenderTxinScriptMismatch => write!(f, "an input in proposed transaction belonging to the sender contains a script that doesn't belong to the spent output"),
             TxInContainsKeyPaths => write!(f, "proposed transaction inputs contain key paths"),
             ContainsPartialSigs => write!(f, "an input in proposed transaction belonging to the sender contains partial signatures"),
             ReceiverTxinNotFinalized => write!(f, "an input in proposed transaction belonging to the receiver is not finalized"),
             ReceiverTxinMissingUtxoInfo => write!(f, "an input in proposed transaction belonging to the receiver is missing UTXO information"),
+            ReceiverTxinUnsafeSighashFlag { index, flag, } => write!(f, "input #{} in proposed transaction belonging to the receiver is signed with sighash flag {:#04x}", index, flag),
+            ReceiverTxinInvalidSignature { index, } => write!(f, "input #{} in proposed transaction belonging to the receiver has an invalid signature", index),
             MixedSequence => write!(f, "inputs of proposed transaction contain mixed sequence numbers"),
+            ReceiverTxinSequenceRejected { index, sequence, } => write!(f, "input #{} in proposed transaction belonging to the receiver has disallowed sequence number {:#010x}", index, sequence),
             MixedInputTypes { proposed, original, } => write!(f, "proposed transaction contains input of type {:?} while original contains inputs of type {:?}", proposed, original),
             MissingOrShuffledInputs => write!(f, "proposed transaction is missing inputs of the sender or they are shuffled"),
+            InputValueOverflow => write!(f, "total value of proposed inputs exceeds 21 million bitcoins"),
             TxOutContainsKeyPaths => write!(f, "proposed transaction outputs contain key paths"),
-            FeeContributionExceedsMaximum => write!(f, "fee contribution exceeds allowed maximum"),
+            FeeContributionExceedsMaximum { contributed, maximum, } => write!(f, "fee contribution {} exceeds allowed maximum {}", contributed, maximum),
             DisallowedOutputSubstitution => write!(f, "the receiver change output despite it being disallowed"),
+            SubstitutionScriptNotAllowed(script_type) => write!(f, "the receiver substituted its output with a script of type {:?} which is not allowed", script_type),
             OutputValueDecreased => write!(f, "the amount in our non-fee output was decreased"),
-            MissingOrShuffledOutputs => write!(f, "proposed transaction is missing outputs of the sender or they are shuffled"),
+            OpReturnChanged => write!(f, "the OP_RETURN output was changed"),
+            MissingOutputs => write!(f, "proposed transaction is missing outputs of the original transaction"),
+            OutputValueOverflow => write!(f, "total value of proposed outputs exceeds 21 million bitcoins"),
             Inflation => write!(f, "proposed transaction is attempting inflation"),
             AbsoluteFeeDecreased => write!(f, "abslute fee of proposed transaction is lower than original"),
-            PayeeTookContributedFee => write!(f, "payee tried to take fee contribution for himself"),
+            PayeeTookContributedFee { contributed, fee_increase, } => write!(f, "payee tried to take fee contribution for himself: {} was contributed but the fee only increased by {}", contributed, fee_increase),
             FeeContributionPaysOutputSizeIncrease => write!(f, "fee contribution pays for additional outputs"),
+            FeeRateBelowMinimum { proposed, minimum, } => write!(f, "proposed transaction pays {} sat/vB which is below the requested minimum of {} sat/vB", proposed, minimum),
+            FeeShareExceeded { contributed, agreed, } => write!(f, "the receiver took {} of our fee contribution but the agreed share is {}", contributed, agreed),
+            DisallowedAdditionalOutput => write!(f, "the receiver added an output despite it being disallowed"),
+            UnexpectedAdditionalInputCount { count, allowed, } => write!(f, "the receiver added {} inputs but the allowed number is between {} and {}", count, allowed.start(), allowed.end()),
+            ProposalContainsUnknownFields => write!(f, "proposed transaction contains unknown or proprietary PSBT fields"),
+            LimitExceeded(error) => write!(f, "the response is too large: {}", error),
+            // The message is not displayed because a malicious receiver could use it to trick the user
+            ReceiverError { code: Some(code), .. } => write!(f, "the receiver responded with an error: {}", code.description()),
+            ReceiverError { code: None, .. } => write!(f, "the receiver responded with an unknown error"),
         }
     }
 }
@@ -98,42 +219,118 @@ impl std::error::Error for ValidationError {
             InvalidProposedInput(error) => Some(error),
             VersionsDontMatch { proposed: _, original: _, } => None,
             LockTimesDontMatch { proposed: _, original: _, } => None,
+            ConventionsChanged => None,
             SenderTxinSequenceChanged { proposed: _, original: _, } => None,
+            SenderTxinSighashTypeChanged { .. } => None,
+            SenderTxinUnsafeSighashType { .. } => None,
+            RbfSignalingChanged { .. } => None,
             SenderTxinContainsNonWitnessUtxo => None,
             SenderTxinContainsWitnessUtxo => None,
             SenderTxinContainsFinalScriptSig => None,
             SenderTxinContainsFinalScriptWitness => None,
+            SenderTxinContainsTapFields => None,
+            SenderTxinContainsPreimages => None,
+            SenderTxinScriptMismatch => None,
             TxInContainsKeyPaths => None,
             ContainsPartialSigs => None,
             ReceiverTxinNotFinalized => None,
             ReceiverTxinMissingUtxoInfo => None,
+            ReceiverTxinUnsafeSighashFlag { .. } => None,
+            ReceiverTxinInvalidSignature { .. } => None,
             MixedSequence => None,
+            ReceiverTxinSequenceRejected { .. } => None,
             MixedInputTypes { .. } => None,
             MissingOrShuffledInputs => None,
+            InputValueOverflow => None,
             TxOutContainsKeyPaths => None,
-            FeeContributionExceedsMaximum => None,
+            FeeContributionExceedsMaximum { .. } => None,
             DisallowedOutputSubstitution => None,
+            SubstitutionScriptNotAllowed(_) => None,
             OutputValueDecreased => None,
-            MissingOrShuffledOutputs => None,
+            OpReturnChanged => None,
+            MissingOutputs => None,
+            OutputValueOverflow => None,
             Inflation => None,
             AbsoluteFeeDecreased => None,
-            PayeeTookContributedFee => None,
+            PayeeTookContributedFee { .. } => None,
             FeeContributionPaysOutputSizeIncrease => None,
+            FeeRateBelowMinimum { .. } => None,
+            FeeShareExceeded { .. } => None,
+            DisallowedAdditionalOutput => None,
+            UnexpectedAdditionalInputCount { .. } => None,
+            ProposalContainsUnknownFields => None,
+            LimitExceeded(_) => None,
+            ReceiverError { .. } => None,
         }
     }
 }
 
+/// Deviation from BIP78 that was tolerated because of `Params::compat()` or a finding of
+/// `Params::check_change_privacy()`.
+///
+/// This is currently opaque type because we aren't sure which variants will stay.
+/// You can only display it and get its severity.
+#[derive(Debug)]
+pub struct ValidationWarning(InternalValidationWarning);
+
+impl ValidationWarning {
+    /// Tolerated deviations are `Severity::Low`, privacy findings are higher.
+    pub fn severity(&self) -> Severity {
+        match &self.0 {
+            InternalValidationWarning::ChangeIdentifiable { inference, .. } => inference.severity(),
+            _ => Severity::Low,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum InternalValidationWarning {
+    SenderTxinContainsWitnessUtxo { index: usize, },
+    TxOutContainsKeyPaths { index: usize, },
+    EmptyTxinField { index: usize, field: &'static str, },
+    SenderTxinContainsNonWitnessUtxo { index: usize, },
+    TxInContainsKeyPaths { index: usize, },
+    FinalizedTxinContainsPartialSigs { index: usize, },
+    ChangeIdentifiable { index: usize, inference: ChangeInference, },
+}
+
+impl fmt::Display for ValidationWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use InternalValidationWarning::*;
+
+        match &self.0 {
+            SenderTxinContainsWitnessUtxo { index, } => write!(f, "input {} belonging to the sender contains witness UTXO information", index),
+            TxOutContainsKeyPaths { index, } => write!(f, "output {} contains key paths (removed)", index),
+            EmptyTxinField { index, field, } => write!(f, "input {} contains empty {} (removed)", index, field),
+            SenderTxinContainsNonWitnessUtxo { index, } => write!(f, "input {} belonging to the sender contains non-witness UTXO information (removed)", index),
+            TxInContainsKeyPaths { index, } => write!(f, "input {} contains key paths (removed)", index),
+            FinalizedTxinContainsPartialSigs { index, } => write!(f, "finalized input {} contains partial signatures (removed)", index),
+            ChangeIdentifiable { index, inference: ChangeInference::RoundPayment, } => write!(f, "output {} is identifiable as our change: the payment is a round amount but the change isn't", index),
+            ChangeIdentifiable { index, inference: ChangeInference::PaymentAmountPreserved, } => write!(f, "output {} is identifiable as our change: the receiver kept the amount of the payment", index),
+        }
+    }
+}
+
+impl From<InternalValidationWarning> for ValidationWarning {
+    fn from(value: InternalValidationWarning) -> Self {
+        ValidationWarning(value)
+    }
+}
+
 /// Error returned when request could not be created.
 ///
-/// This error can currently only happen due to programmer mistake.
-/// `unwrap()`ing it is thus considered OK in Rust but you may achieve nicer message by displaying
-/// it.
+/// This error happens due to programmer mistake or if the PSBT uses features that are not
+/// supported (e.g. exotic input scripts). You may achieve nicer message by displaying it.
 #[derive(Debug)]
 pub struct CreateRequestError(InternalCreateRequestError);
 
 #[derive(Debug)]
 pub(crate) enum InternalCreateRequestError {
+    InconsistentPsbt,
     InvalidOriginalInput(crate::psbt::PsbtInputsError),
+    InvalidFirstInput(crate::psbt::PrevTxOutError),
+    InvalidInputType(InputTypeError),
+    InvalidFee,
     NoInputs,
     PayeeValueNotEqual,
     NoOutputs,
@@ -143,6 +340,13 @@ pub(crate) enum InternalCreateRequestError {
     AmbiguousChangeOutput,
     ChangeIndexOutOfBounds,
     ChangeIndexPointsAtPayee,
+    ChangeIndexPointsAtOpReturn,
+    EndpointContainsFragment,
+    InvalidEndpoint(crate::uri::PjParseError),
+    MissingPsbt,
+    UnknownInputWeight,
+    LimitExceeded(crate::limits::LimitExceeded),
+    FeeRateOutOfRange,
 }
 
 impl fmt::Display for CreateRequestError {
@@ -150,7 +354,11 @@ impl fmt::Display for CreateRequestError {
         use InternalCreateRequestError::*;
 
         match &self.0 {
+            InconsistentPsbt => write!(f, "the number of inputs or outputs in the PSBT doesn't match the transaction"),
             InvalidOriginalInput(_) => write!(f, "an input in the original transaction is invalid"),
+            InvalidFirstInput(_) => write!(f, "the first input of the original transaction is invalid"),
+            InvalidInputType(_) => write!(f, "the type of the first input of the original transaction is not supported"),
+            InvalidFee => write!(f, "the amounts in the original transaction are out of range or the outputs exceed the inputs"),
             NoInputs => write!(f, "the original transaction has no inputs"),
             PayeeValueNotEqual => write!(f, "the value in original transaction doesn't equal value requested in the payment link"),
             NoOutputs => write!(f, "the original transaction has no outputs"),
@@ -160,6 +368,13 @@ impl fmt::Display for CreateRequestError {
             AmbiguousChangeOutput => write!(f, "can not determine which output is change because there's more than two outputs"),
             ChangeIndexOutOfBounds => write!(f, "fee output index is points out of bounds"),
             ChangeIndexPointsAtPayee => write!(f, "fee output index is points at output belonging to the payee"),
+            ChangeIndexPointsAtOpReturn => write!(f, "fee output index points at an OP_RETURN output"),
+            EndpointContainsFragment => write!(f, "the payjoin endpoint contains a fragment"),
+            InvalidEndpoint(_) => write!(f, "a fallback endpoint is invalid"),
+            MissingPsbt => write!(f, "no original PSBT was provided"),
+            UnknownInputWeight => write!(f, "fee contribution rate can't be used because the weight of the inputs is unknown"),
+            LimitExceeded(error) => write!(f, "the request is too large: {}", error),
+            FeeRateOutOfRange => write!(f, "the fee rate exceeds the maximum of {} sat/vB", crate::psbt::MAX_MONEY),
         }
     }
 }
@@ -169,7 +384,11 @@ impl std::error::Error for CreateRequestError {
         use InternalCreateRequestError::*;
 
         match &self.0 {
+            InconsistentPsbt => None,
             InvalidOriginalInput(error) => Some(error),
+            InvalidFirstInput(error) => Some(error),
+            InvalidInputType(error) => Some(error),
+            InvalidFee => None,
             NoInputs => None,
             PayeeValueNotEqual => None,
             NoOutputs => None,
@@ -179,6 +398,13 @@ impl std::error::Error for CreateRequestError {
             AmbiguousChangeOutput => None,
             ChangeIndexOutOfBounds => None,
             ChangeIndexPointsAtPayee => None,
+            ChangeIndexPointsAtOpReturn => None,
+            EndpointContainsFragment => None,
+            InvalidEndpoint(error) => Some(error),
+            MissingPsbt => None,
+            UnknownInputWeight => None,
+            LimitExceeded(_) => None,
+            FeeRateOutOfRange => None,
         }
     }
 }
@@ -4,39 +4,115 @@
 //! Usage is prety simple:
 //!
 //! 1. Parse BIP21 as `bip78::Uri`
-//! 2. Create a finalized PSBT paying `.amount()` to `.address()`
+//! 2. Create a finalized PSBT paying `.amount()` to `.address()` (the amount chosen by the user
+//!    if the link has none)
 //! 3. Spawn a thread or async task that will broadcast the transaction after one minute unless
 //!    canceled
-//! 4. Call `.create_request()` with the PSBT and your parameters
-//! 5. Send the request and receive response
-//! 6. Feed the response to `.process_response()`
-//! 7. Sign resulting PSBT
+//! 4. Call `.create_request()` with the PSBT and your parameters (or use `PayjoinSender` which
+//!    can also compute the fee contribution from a fee rate)
+//! 5. Send the request and receive response (in async code `await_response()` can do this and
+//!    the next step while enforcing `Params::max_latency()`, `await_response_with_retries()`
+//!    also retries if the receiver is temporarily unavailable, `send_with_failover()` tries other
+//!    endpoints if the URI lists more of them)
+//! 6. Feed the response body to `.process_response_bytes()`
+//! 7. Sign resulting PSBT (call `restore_metadata()` first if your signer needs key origins or
+//!    UTXOs of your inputs, e.g. a hardware wallet)
 //! 8. Cancel the one-minute deadline and broadcast the resulting PSBT
 //!
+//! ## Example
+//!
+//! ```
+//! // Mock transport - in real code you would POST `request.body` to `request.url`
+//! fn send(request: &bip78::sender::Request) -> Vec<u8> {
+//!     assert_eq!(request.url, "https://example.com/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182");
+//!     bip78::testing::PROPOSAL_PSBT.as_bytes().to_vec()
+//! }
+//!
+//! let link = bip78::testing::URI.parse::<bip78::Uri>().unwrap();
+//! // In real code this PSBT would be created and signed by your wallet
+//! let psbt = bip78::testing::original_psbt();
+//! // Pay for one additional input at 2 sat/vB
+//! let params = bip78::sender::Params::with_fee_contribution(bip78::bitcoin::Amount::from_sat(182), None);
+//! let (request, context) = link.create_request(psbt, params).unwrap();
+//! // broadcast this if anything below fails or takes too long
+//! let fallback = context.fallback_tx();
+//! let response = send(&request);
+//! match context.process_response_bytes(&response) {
+//!     Ok(proposal) => {
+//!         // sign and broadcast the proposal
+//!         assert_eq!(proposal.global.unsigned_tx.input.len(), 2);
+//!     },
+//!     Err(error) => {
+//!         eprintln!("PayJoin failed: {}", error);
+//!         // broadcast `fallback`
+//!         # let _ = fallback;
+//!         # panic!("the test vector is valid");
+//!     },
+//! }
+//! ```
 
 use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
 use crate::input_type::InputType;
 use bitcoin::{TxOut, Script};
-use error::{InternalValidationError, InternalCreateRequestError};
+use bitcoin::blockdata::transaction::SigHashType;
+use error::{InternalValidationError, InternalValidationWarning, InternalCreateRequestError};
 use crate::weight::{Weight, ComputeWeight};
-use crate::psbt::PsbtExt;
-pub use error::{ValidationError, CreateRequestError};
+use crate::fee_rate::FeeRate;
+use crate::psbt::{PsbtExt, InputPair, MAX_MONEY};
+use crate::TxConventions;
+use crate::{FeeShare, Limits, ProtocolVersion};
+pub use error::{ValidationError, ValidationWarning, CreateRequestError};
+pub use outcome::{Outcome, PayjoinEvent, await_response, await_response_with_retries, await_response_with_failover, send_with_failover, Failover, Response, RetryPolicy, parse_retry_after};
+pub use probe::{probe_endpoint, Transport, EndpointInfo, ProbeError, ProbeCache};
+pub use builder::PayjoinSender;
+pub use diff::ProposalDiff;
+pub use coin_selection::{select_original_inputs, WalletUtxo, SelectionTarget, Selection};
+pub use quirks::{Quirks, Implementation};
+pub use substitution::{Allowlist, ScriptType};
+pub use privacy::Severity;
 
 // See usize casts
 #[cfg(not(any(target_pointer_width = "32", target_pointer_width = "64")))]
 compile_error!("This crate currently only supports 32 bit and 64 bit architectures");
 
+mod builder;
+mod coin_selection;
+mod diff;
 mod error;
+mod outcome;
+mod probe;
+mod privacy;
+mod quirks;
+mod signature;
+mod substitution;
 
 type InternalResult<T> = Result<T, InternalValidationError>;
+type EventCallback = std::sync::Arc<dyn Fn(&PayjoinEvent<'_>) + Send + Sync>;
 
 /// Builder for sender-side payjoin parameters
 ///
-/// These parameters define how client wants to handle PayJoin.
+/// These parameters define how client wants to handle PayJoin. Start with `Params::default()`
+/// (or one of the constructors) and chain the setters.
 pub struct Params {
     disable_output_substitution: bool,
+    substitution_scripts: Allowlist,
     fee_contribution: Option<(bitcoin::Amount, Option<usize>)>,
     clamp_fee_contribution: bool,
+    allow_additional_outputs: bool,
+    additional_inputs: std::ops::RangeInclusive<usize>,
+    quirks: Quirks,
+    require_matching_rbf: bool,
+    check_change_privacy: bool,
+    verify_receiver_signatures: bool,
+    sequence_policy: SequencePolicy,
+    unknown_fields: UnknownFields,
+    version: ProtocolVersion,
+    max_latency: Option<std::time::Duration>,
+    min_fee_rate: Option<FeeRate>,
+    fee_share: Option<FeeShare>,
+    fallback_endpoints: Vec<String>,
+    limits: Limits,
+    events: Option<EventCallback>,
 }
 
 impl Params {
@@ -48,23 +124,29 @@ impl Params {
     /// `change_index` specifies which output can be used to pay fee. I `None` is provided, then
     /// the output is auto-detected unless the supplied transaction has more than two outputs.
     pub fn with_fee_contribution(max_fee_contribution: bitcoin::Amount, change_index: Option<usize>) -> Self {
-        Params {
-            disable_output_substitution: false,
-            fee_contribution: Some((max_fee_contribution, change_index)),
-            clamp_fee_contribution: false,
-        }
+        Params::default().fee_contribution(max_fee_contribution, change_index)
     }
 
     /// Perform PayJoin without incentivizing the payee to cooperate.
     ///
     /// While it's generally better to offer some contribution some users may wish not to.
-    /// This function disables contribution.
+    /// This function disables contribution. Same as `Params::default()`.
     pub fn non_incentivizing() -> Self {
-        Params {
-            disable_output_substitution: false,
-            fee_contribution: None,
-            clamp_fee_contribution: false,
-        }
+        Params::default()
+    }
+
+    /// Offer the receiver contribution to pay for his input.
+    ///
+    /// See `Params::with_fee_contribution()`, this is the same for params built incrementally.
+    pub fn fee_contribution(mut self, max_fee_contribution: bitcoin::Amount, change_index: Option<usize>) -> Self {
+        self.fee_contribution = Some((max_fee_contribution, change_index));
+        self
+    }
+
+    /// Withdraw the fee contribution offered previously.
+    pub fn no_fee_contribution(mut self) -> Self {
+        self.fee_contribution = None;
+        self
     }
 
     /// Disable output substitution even if the receiver didn't.
@@ -87,6 +169,257 @@ impl Params {
         self.clamp_fee_contribution = clamp;
         self
     }
+
+    /// Allow or forbid the receiver adding outputs.
+    ///
+    /// Additional outputs are allowed by BIP78 and the receiver pays for them. Forbidding them
+    /// prevents the receiver from e.g. batching its own payments.
+    pub fn allow_additional_outputs(mut self, allow: bool) -> Self {
+        self.allow_additional_outputs = allow;
+        self
+    }
+
+    /// Limit the number of inputs the receiver may add.
+    ///
+    /// By default any number (including zero) is accepted.
+    pub fn additional_inputs(mut self, range: std::ops::RangeInclusive<usize>) -> Self {
+        self.additional_inputs = range;
+        self
+    }
+
+    /// Accept only the narrowest possible proposals.
+    ///
+    /// This disables output substitution, forbids additional outputs, requires exactly one
+    /// additional input and matching RBF signaling and rejects unknown PSBT fields. Fee
+    /// contribution (if any) is already checked exactly: it may pay at most
+    /// for the weight of the additional input at the original fee rate. Inputs of the receiver
+    /// must have the same type as the inputs of the sender regardless of this setting.
+    ///
+    /// Note that this may reduce the chance of successful PayJoin.
+    pub fn strict(self) -> Self {
+        self
+            .require_matching_rbf_signaling(true)
+            .unknown_fields(UnknownFields::Reject)
+            .always_disable_output_substitution(true)
+            .allow_additional_outputs(false)
+            .additional_inputs(1..=1)
+    }
+
+    /// Reject proposals which signal RBF differently than the original transaction.
+    ///
+    /// Inputs of the receiver must always have the same sequence number as the first input of
+    /// the sender. This additionally checks the whole transaction so that the receiver can't
+    /// fingerprint it by changing whether it's replaceable, regardless of how the sequence
+    /// numbers of the original transaction were chosen.
+    pub fn require_matching_rbf_signaling(mut self, require: bool) -> Self {
+        self.require_matching_rbf = require;
+        self
+    }
+
+    /// Restrict the scripts the receiver may substitute its output with.
+    ///
+    /// Defaults to `Allowlist::standard()`. Has no effect if output substitution is disabled.
+    pub fn substitution_script_policy(mut self, allowlist: Allowlist) -> Self {
+        self.substitution_scripts = allowlist;
+        self
+    }
+
+    /// Warn if the amounts of the proposal make our change identifiable.
+    ///
+    /// Disabled by default. The proposal is not rejected, the findings are reported as
+    /// `ValidationReport::warnings` with `Severity::Medium` or `Severity::High` so that you can
+    /// decide whether to broadcast the original transaction instead.
+    pub fn check_change_privacy(mut self, check: bool) -> Self {
+        self.check_change_privacy = check;
+        self
+    }
+
+    /// Verify the signatures of the inputs of the receiver.
+    ///
+    /// Disabled by default. A receiver contributing an invalid input would make the payjoin
+    /// transaction fail to broadcast after we signed it. Only P2PKH, P2WPKH and P2SH-P2WPKH
+    /// inputs are verified, inputs of other types are accepted without verification.
+    pub fn verify_receiver_signatures(mut self, verify: bool) -> Self {
+        self.verify_receiver_signatures = verify;
+        self
+    }
+
+    /// Choose how the sequence numbers of the inputs of the receiver are checked.
+    ///
+    /// Defaults to `SequencePolicy::ExactMatch` as required by BIP78. Sequence numbers of the
+    /// inputs of the sender must never change.
+    pub fn sequence_policy(mut self, policy: SequencePolicy) -> Self {
+        self.sequence_policy = policy;
+        self
+    }
+
+    /// Choose what to do with unknown and proprietary fields in the proposal.
+    ///
+    /// Defaults to `UnknownFields::Strip`.
+    pub fn unknown_fields(mut self, policy: UnknownFields) -> Self {
+        self.unknown_fields = policy;
+        self
+    }
+
+    /// Tolerate harmless deviations from BIP78 seen in the wild.
+    ///
+    /// Currently these are tolerated:
+    ///
+    /// * key paths in outputs - they are removed from the resulting PSBT so that they can't
+    ///   confuse your wallet
+    /// * witness UTXO in sender inputs - only if it's equal to the one in the original PSBT
+    /// * present but empty final script sig or witness - treated as missing
+    /// * non-witness UTXO in sender inputs - only if it's equal to the one in the original PSBT,
+    ///   it's removed
+    /// * key paths in inputs - removed
+    /// * partial signatures in finalized inputs - removed
+    ///
+    /// Each tolerated deviation is reported as a warning in `ValidationReport`. This is the same
+    /// as `quirks(Quirks::ALL)`.
+    pub fn compat(self) -> Self {
+        self.quirks(Quirks::ALL)
+    }
+
+    /// Tolerate only the given deviations from BIP78.
+    ///
+    /// Use `Quirks::of()` if you know the implementation of the receiver. More quirks can be
+    /// tolerated after receiving the response with `Context::tolerate()`.
+    pub fn quirks(mut self, quirks: Quirks) -> Self {
+        self.quirks = quirks;
+        self
+    }
+
+    /// Use given version of the protocol.
+    ///
+    /// Defaults to `ProtocolVersion::V1`.
+    pub fn version(mut self, version: ProtocolVersion) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Give up on PayJoin if the receiver doesn't respond within `max_latency`.
+    ///
+    /// The budget is applied by `await_response()` and exposed as `Request::timeout` for
+    /// blocking HTTP clients.
+    pub fn max_latency(mut self, max_latency: std::time::Duration) -> Self {
+        self.max_latency = Some(max_latency);
+        self
+    }
+
+    /// Bounds of the request and of the response.
+    ///
+    /// Requests with URLs longer than `Limits::max_url_length` are not created and responses
+    /// exceeding the other limits are rejected before (size) or right after (counts) decoding.
+    pub fn limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// Require the proposal to pay at least `sat_per_vb`.
+    ///
+    /// The rate is sent to the receiver as `minfeerate` and the proposal is rejected if its
+    /// estimated fee rate is lower. Useful if the original transaction pays barely enough to be
+    /// relayed and the receiver may contribute without increasing the fee.
+    ///
+    /// Only whole sat/vB rates can be required, they are sent exactly. Creating the request fails
+    /// if the rate exceeds `MAX_MONEY` per vB.
+    pub fn min_fee_rate(mut self, sat_per_vb: u64) -> Self {
+        self.min_fee_rate = Some(FeeRate::from_sat_per_vb(sat_per_vb));
+        self
+    }
+
+    /// Require the receiver to take at most `fee_share` of the fee of its inputs from our
+    /// contribution.
+    ///
+    /// The share isn't sent to the receiver - it must be agreed on beforehand, see
+    /// `ReceiverOptions::fee_share()`. Without it the receiver may take the fee at the original
+    /// fee rate up to the maximum contribution.
+    pub fn fee_share(mut self, fee_share: FeeShare) -> Self {
+        self.fee_share = Some(fee_share);
+        self
+    }
+
+    /// Adds an endpoint to try after the endpoints of the URI.
+    ///
+    /// Useful if you know the receiver is also reachable elsewhere (e.g. over Tor) although its
+    /// URI doesn't say so. The URLs are in `Request::fallback_urls`, see
+    /// `send_with_failover()`.
+    pub fn fallback_endpoint(mut self, endpoint: impl Into<String>) -> Self {
+        self.fallback_endpoints.push(endpoint.into());
+        self
+    }
+
+    /// Reports the progress of `send_with_failover()` and the `await_response*()` helpers to
+    /// `callback`, e.g. to update a progress bar.
+    ///
+    /// The callback is called synchronously so it should return quickly.
+    pub fn on_event(mut self, callback: impl Fn(&PayjoinEvent<'_>) + Send + Sync + 'static) -> Self {
+        self.events = Some(std::sync::Arc::new(callback));
+        self
+    }
+
+    /// Runs the checks of `Uri::create_request()` and returns only the request.
+    ///
+    /// Use it to find out whether the original PSBT and the parameters are acceptable, or what
+    /// the receiver would get (see `Request::preview()`), without sending anything.
+    pub fn dry_run(self, psbt: Psbt, uri: crate::Uri<'_>) -> Result<Request, CreateRequestError> {
+        from_psbt_and_uri(psbt, uri, self).map(|(request, _)| request)
+    }
+}
+
+/// Non-incentivizing parameters with the defaults of all options.
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            disable_output_substitution: false,
+            substitution_scripts: Allowlist::default(),
+            fee_contribution: None,
+            clamp_fee_contribution: false,
+            allow_additional_outputs: true,
+            additional_inputs: 0..=usize::MAX,
+            quirks: Quirks::NONE,
+            require_matching_rbf: false,
+            check_change_privacy: false,
+            verify_receiver_signatures: false,
+            sequence_policy: SequencePolicy::ExactMatch,
+            unknown_fields: UnknownFields::Strip,
+            version: ProtocolVersion::V1,
+            max_latency: None,
+            min_fee_rate: None,
+            fee_share: None,
+            fallback_endpoints: Vec::new(),
+            limits: Limits::default(),
+            events: None,
+        }
+    }
+}
+
+/// Handling of unknown and proprietary PSBT fields in the proposal.
+///
+/// The receiver could use these fields to tag the PSBT you store with data that identifies you or
+/// the payment. They are never needed to sign the transaction.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum UnknownFields {
+    /// Remove the fields from the PSBT returned by `Context::process_response()`.
+    Strip,
+    /// Reject the proposal if it contains any such field.
+    Reject,
+}
+
+/// Accepted sequence numbers of the inputs added by the receiver.
+///
+/// Some wallets always sign with a specific sequence number and can't follow the sender. The
+/// relaxed policies accept them at the cost of a small fingerprint but never accept a sequence
+/// number enabling a relative lock time (BIP68) different from the original, which could delay
+/// the confirmation of the payjoin.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SequencePolicy {
+    /// The same sequence number as the first input of the sender.
+    ExactMatch,
+    /// Any sequence number signaling RBF the same way as the first input of the sender.
+    RequireRbfSignaling,
+    /// Any sequence number.
+    Any,
 }
 
 /// Represents data that needs to be transmitted to the receiver.
@@ -105,19 +438,113 @@ pub struct Request {
     /// is `text/plain` and `Content-Length` is `body.len()` (most libraries do the latter
     /// automatically).
     pub body: Vec<u8>,
+
+    /// Version of the protocol used by this request.
+    pub version: ProtocolVersion,
+
+    /// Time after which the request should be abandoned, see `Params::max_latency()`.
+    pub timeout: Option<std::time::Duration>,
+
+    /// URLs of other endpoints in order of preference, to be tried if `url` fails.
+    ///
+    /// They contain the same query as `url`, see `send_with_failover()`.
+    pub fallback_urls: Vec<String>,
+
+    /// Keys the TLS certificates of HTTPS endpoints must use, empty if the link didn't pin any.
+    ///
+    /// If not empty the request must not be sent unless the certificate presented by the
+    /// endpoint matches one of them, see `CertificatePin::matches_certificate()`.
+    pub certificate_pins: Vec<crate::CertificatePin>,
+
+    /// Maximum size of the response body, see `Params::limits()`.
+    ///
+    /// Stop reading the response once it's exceeded, `process_response_bytes()` would reject it
+    /// anyway.
+    pub max_response_size: u64,
+}
+
+impl Request {
+    /// Returns `url` followed by `fallback_urls`.
+    pub fn urls(&self) -> impl '_ + Iterator<Item=&str> {
+        std::iter::once(&*self.url).chain(self.fallback_urls.iter().map(String::as_str))
+    }
+
+    /// Returns what the receiver gets in human-readable form.
+    ///
+    /// Meant for debugging integrations: the URL with its decoded query parameters, the fallback
+    /// URLs and the body decoded as JSON (see `psbt::to_json()`). The format is not stable.
+    pub fn preview(&self) -> String {
+        use std::fmt::Write;
+
+        let mut preview = String::new();
+        // writing to String never fails
+        let _ = writeln!(preview, "POST {}", self.url);
+        if let Some((_, query)) = self.url.split_once('?') {
+            for (key, value) in query.split('&').map(|pair| pair.split_once('=').unwrap_or((pair, ""))) {
+                let _ = writeln!(preview, "  {}: {}", key, value);
+            }
+        }
+        for url in &self.fallback_urls {
+            let _ = writeln!(preview, "fallback: {}", url);
+        }
+        let _ = writeln!(preview, "Content-Type: text/plain\nContent-Length: {}\n", self.body.len());
+        match load_psbt_from_base64(&*self.body) {
+            Ok(psbt) => preview.push_str(&crate::psbt::to_json(&psbt)),
+            Err(_) => preview.push_str(&String::from_utf8_lossy(&self.body)),
+        }
+        preview
+    }
 }
 
 /// Data required for validation of response.
 ///
 /// This type is used to process the response. It is returned from `Uri::create_request()` method
-/// and you only need to call `process_response()` on it to continue BIP78 flow.
+/// and you only need to call `process_response_bytes()` (or `process_response()`) on it to continue BIP78 flow.
 pub struct Context {
     original_psbt: Psbt,
     disable_output_substitution: bool,
+    substitution_scripts: Allowlist,
     fee_contribution: Option<(bitcoin::Amount, usize)>,
     input_type: InputType,
     sequence: u32,
     payee: Script,
+    allow_additional_outputs: bool,
+    additional_inputs: std::ops::RangeInclusive<usize>,
+    quirks: Quirks,
+    require_matching_rbf: bool,
+    check_change_privacy: bool,
+    verify_receiver_signatures: bool,
+    sequence_policy: SequencePolicy,
+    unknown_fields: UnknownFields,
+    max_latency: Option<std::time::Duration>,
+    min_fee_rate: Option<FeeRate>,
+    fee_share: Option<FeeShare>,
+    limits: Limits,
+    events: Option<EventCallback>,
+}
+
+/// Result of validation with information for debugging failures, see `Context::audit_response()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Audit {
+    /// Same as the result of `Context::process_response_with_report()`.
+    pub result: Result<ValidationReport, ValidationError>,
+    /// Changes made by the receiver, `None` if the response is not a PSBT.
+    pub diff: Option<ProposalDiff>,
+}
+
+/// Successfully validated proposal.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct ValidationReport {
+    /// The PSBT that you should sign and broadcast.
+    pub psbt: Psbt,
+
+    /// Tolerated deviations from BIP78 and privacy findings.
+    ///
+    /// This is always empty unless `Params::compat()` or `Params::check_change_privacy()` was
+    /// used.
+    pub warnings: Vec<ValidationWarning>,
 }
 
 macro_rules! check_eq {
@@ -137,162 +564,526 @@ macro_rules! ensure {
     }
 }
 
+/// Returns `true` if the transaction signals replaceability as defined in BIP125.
+fn signals_rbf(tx: &bitcoin::Transaction) -> bool {
+    tx.input.iter().any(|input| sequence_signals_rbf(input.sequence))
+}
+
+fn sequence_signals_rbf(sequence: u32) -> bool {
+    sequence < 0xfffffffe
+}
+
+/// Returns `true` if the sequence number enables a relative lock time (BIP68).
+fn sequence_enables_lock_time(sequence: u32) -> bool {
+    sequence & (1 << 31) == 0
+}
+
 fn load_psbt_from_base64(mut input: impl std::io::Read) -> Result<Psbt, bitcoin::consensus::encode::Error> {
     use bitcoin::consensus::Decodable;
     let reader = base64::read::DecoderReader::new(&mut input, base64::STANDARD);
     Psbt::consensus_decode(reader)
 }
 
-fn calculate_psbt_fee(psbt: &Psbt) -> bitcoin::Amount {
+/// Parses the JSON error response defined in BIP78.
+///
+/// Returns `None` if the response doesn't look like an error.
+fn parse_error_response(response: &[u8]) -> Option<InternalValidationError> {
+    // base64 doesn't contain '{'
+    if response.iter().find(|byte| !byte.is_ascii_whitespace()) != Some(&b'{') {
+        return None;
+    }
+    let fields = serde_json::from_slice::<serde_json::Value>(response).ok();
+    // The message is ignored on purpose - see Display impl of the error
+    let code = fields
+        .as_ref()
+        .and_then(|fields| fields.get("errorCode"))
+        .and_then(serde_json::Value::as_str)
+        .and_then(crate::ErrorCode::parse);
+    Some(InternalValidationError::ReceiverError { code, })
+}
+
+/// Adds `value` to `sum`, `None` if the result exceeds `MAX_MONEY`.
+///
+/// Values in proposals are controlled by the receiver so they can't be summed unchecked.
+fn add_value(sum: bitcoin::Amount, value: u64) -> Option<bitcoin::Amount> {
+    sum.as_sat().checked_add(value).filter(|sum| *sum <= MAX_MONEY).map(bitcoin::Amount::from_sat)
+}
+
+/// Returns `None` if the fee is negative, any amount is out of range or UTXO information is
+/// missing.
+fn calculate_psbt_fee(psbt: &Psbt) -> Option<bitcoin::Amount> {
+    // amounts are signed so larger values would wrap
+    let to_amount = |value| if value <= MAX_MONEY { Some(bitcoin::Amount::from_sat(value)) } else { None };
     let mut total_outputs = bitcoin::Amount::ZERO;
     let mut total_inputs = bitcoin::Amount::ZERO;
 
     for output in &psbt.global.unsigned_tx.output {
-        total_outputs += bitcoin::Amount::from_sat(output.value);
+        total_outputs = total_outputs.checked_add(to_amount(output.value)?)?;
     }
 
     for input in psbt.input_pairs() {
-        total_inputs += bitcoin::Amount::from_sat(input.previous_txout().unwrap().value);
+        total_inputs = total_inputs.checked_add(to_amount(input.previous_txout().ok()?.value)?)?;
     }
 
-    total_inputs - total_outputs
+    total_inputs.checked_sub(total_outputs)
 }
 
 impl Context {
+    /// Returns the ID of the original (fallback) transaction.
+    ///
+    /// Use this to watch mempool and detect whether the receiver broadcasted the original
+    /// transaction instead of signing the PayJoin proposal.
+    pub fn original_txid(&self) -> bitcoin::Txid {
+        // Can't use unsigned_tx because script_sig of P2SH inputs affects txid
+        self.fallback_tx().txid()
+    }
+
+    /// Extracts the finalized original transaction.
+    ///
+    /// This is the transaction you should broadcast if PayJoin fails for any reason (timeout,
+    /// invalid response...).
+    pub fn fallback_tx(&self) -> bitcoin::Transaction {
+        self.original_psbt.clone().extract_tx()
+    }
+
+    /// Additionally tolerates `quirks`, usually those returned by `Quirks::detect()` for the
+    /// response about to be processed.
+    pub fn tolerate(&mut self, quirks: Quirks) {
+        self.quirks = self.quirks.union(&quirks);
+    }
+
     /// Decodes and validates the response.
     ///
     /// Call this method with response from receiver to continue BIP78 flow. If the response is
     /// valid you will get appropriate PSBT that you should sign and broadcast.
+    ///
+    /// This is a convenience adapter for blocking readers, it reads the whole response (but not
+    /// more than `Limits::max_psbt_size`) and calls `process_response_bytes()`.
     #[inline]
     pub fn process_response(self, response: impl std::io::Read) -> Result<Psbt, ValidationError> {
+        use std::io::Read;
+
+        let mut bytes = Vec::new();
+        // one more byte to detect exceeding the limit
+        response.take(self.limits.max_psbt_size.saturating_add(1)).read_to_end(&mut bytes)
+            .map_err(|error| InternalValidationError::Decode(error.into()))?;
+
+        self.process_response_bytes(&bytes)
+    }
+
+    /// Decodes and validates the response body.
+    ///
+    /// This doesn't perform any IO so you can use it with any HTTP library, async runtime or
+    /// across FFI. The body must already be decompressed if the receiver used `Content-Encoding`,
+    /// stop decompressing at `Request::max_response_size` bytes to avoid compression bombs.
+    pub fn process_response_bytes(self, response: &[u8]) -> Result<Psbt, ValidationError> {
+        self.process_response_with_report(response).map(|report| report.psbt)
+    }
+
+    /// Decodes and validates the response body, reporting tolerated deviations.
+    ///
+    /// Same as `process_response_bytes()` but also returns warnings. See `Params::compat()`.
+    pub fn process_response_with_report(self, response: &[u8]) -> Result<ValidationReport, ValidationError> {
+        self.limits.check_psbt_size(response.len() as u64).map_err(InternalValidationError::LimitExceeded)?;
+        if let Some(error) = parse_error_response(response) {
+            return Err(error.into());
+        }
         let proposal = load_psbt_from_base64(response)
             .map_err(InternalValidationError::Decode)?;
+        self.limits.check_transaction(&proposal.global.unsigned_tx).map_err(InternalValidationError::LimitExceeded)?;
 
-        // process in non-generic function
         self.process_proposal(proposal).map_err(Into::into)
     }
 
-    fn process_proposal(self, proposal: Psbt) -> InternalResult<Psbt> {
+    /// Validates the response body and compares the proposal with the original.
+    ///
+    /// Same as `process_response_with_report()` but the returned `Audit` also contains the
+    /// changes the receiver made, which is useful for logging and debugging invalid proposals.
+    pub fn audit_response(self, response: &[u8]) -> Audit {
+        let diff = match parse_error_response(response) {
+            Some(_) => None,
+            None => load_psbt_from_base64(response).ok().map(|proposal| ProposalDiff::new(&self.original_psbt, &proposal)),
+        };
+        Audit {
+            result: self.process_response_with_report(response),
+            diff,
+        }
+    }
+
+    fn process_proposal(self, mut proposal: Psbt) -> InternalResult<ValidationReport> {
+        let mut warnings = Vec::new();
+        self.normalize(&mut proposal, &mut warnings);
         self.basic_checks(&proposal)?;
-        let in_stats = self.check_inputs(&proposal)?;
-        let out_stats = self.check_outputs(&proposal)?;
-        self.check_fees(&proposal, in_stats, out_stats)?;
-        Ok(proposal)
+        let in_stats = self.check_inputs(&proposal, &mut warnings)?;
+        self.check_conventions(&proposal)?;
+        let out_stats = self.check_outputs(&proposal, &mut warnings)?;
+        if self.check_change_privacy {
+            self.check_change_privacy(&proposal, &out_stats, &mut warnings);
+        }
+        self.check_fees(in_stats, out_stats)?;
+        if has_unknown_fields(&proposal) {
+            ensure!(self.unknown_fields == UnknownFields::Strip, ProposalContainsUnknownFields);
+            strip_unknown_fields(&mut proposal);
+        }
+        if self.quirks.output_key_paths {
+            // The receiver is not allowed to tell us which outputs are ours
+            for output in &mut proposal.outputs {
+                output.bip32_derivation.clear();
+            }
+        }
+        Ok(ValidationReport {
+            psbt: proposal,
+            warnings: warnings.into_iter().map(Into::into).collect(),
+        })
     }
 
-    fn check_fees(&self, proposal: &Psbt, in_stats: InputStats, out_stats: OutputStats) -> InternalResult<()> {
+    fn check_fees(&self, in_stats: InputStats, out_stats: OutputStats) -> InternalResult<()> {
         if out_stats.total_value > in_stats.total_value {
             return Err(InternalValidationError::Inflation);
         }
         let proposed_psbt_fee = in_stats.total_value - out_stats.total_value;
-        let original_fee = calculate_psbt_fee(&self.original_psbt);
+        let original_fee = calculate_psbt_fee(&self.original_psbt).expect("checked in from_psbt_and_uri");
         ensure!(original_fee <= proposed_psbt_fee, AbsoluteFeeDecreased);
-        ensure!(out_stats.contributed_fee <= proposed_psbt_fee - original_fee, PayeeTookContributedFee);
-        let original_weight = self.original_psbt.global.unsigned_tx.weight();
+        let fee_increase = proposed_psbt_fee - original_fee;
+        if out_stats.contributed_fee > fee_increase {
+            return Err(InternalValidationError::PayeeTookContributedFee { contributed: out_stats.contributed_fee, fee_increase, });
+        }
+        // unsigned_tx lacks signatures so its weight would overestimate the fee rate
+        let original_weight = self.fallback_tx().weight();
         let original_fee_rate = original_fee / original_weight;
-        ensure!(out_stats.contributed_fee <= original_fee_rate * self.input_type.expected_input_weight() * (proposal.inputs.len() - self.original_psbt.inputs.len()) as u64, FeeContributionPaysOutputSizeIncrease);
+        // We can't tell how much the receiver should pay for inputs of unknown size so we don't
+        // allow any contribution
+        let max_contribution = in_stats.contributable_weight
+            .map(|weight| original_fee_rate * weight)
+            .unwrap_or(bitcoin::Amount::ZERO);
+        ensure!(out_stats.contributed_fee <= max_contribution, FeeContributionPaysOutputSizeIncrease);
+        if let Some(fee_share) = self.fee_share {
+            let agreed = fee_share.sender_part(max_contribution);
+            if out_stats.contributed_fee > agreed {
+                return Err(InternalValidationError::FeeShareExceeded { contributed: out_stats.contributed_fee, agreed, });
+            }
+        }
+        if let Some(min_fee_rate) = self.min_fee_rate {
+            let original_outputs_weight = self.original_psbt.global.unsigned_tx.output
+                .iter()
+                .fold(Weight::ZERO, |sum, output| sum + output.weight());
+            let proposed_weight = original_weight - original_outputs_weight + out_stats.total_weight + in_stats.added_weight;
+            let proposed_fee_rate = proposed_psbt_fee / proposed_weight;
+            if proposed_fee_rate < min_fee_rate {
+                return Err(InternalValidationError::FeeRateBelowMinimum { proposed: proposed_fee_rate.to_sat_per_vb(), minimum: min_fee_rate.to_sat_per_vb(), });
+            }
+        }
         Ok(())
     }
 
     // version and lock time
+    /// Removes empty and redundant fields that some receivers produce when re-serializing.
+    ///
+    /// Only fields that would be ignored or stripped anyway are removed and only if tolerated by
+    /// the quirks, see `Params::compat()`.
+    fn normalize(&self, proposal: &mut Psbt, warnings: &mut Vec<InternalValidationWarning>) {
+        let original = &self.original_psbt;
+        let quirks = &self.quirks;
+        for (index, (txin, input)) in proposal.global.unsigned_tx.input.iter().zip(&mut proposal.inputs).enumerate() {
+            if quirks.empty_final_fields && matches!(&input.final_script_sig, Some(script) if script.is_empty()) {
+                input.final_script_sig = None;
+                warnings.push(InternalValidationWarning::EmptyTxinField { index, field: "final script sig", });
+            }
+            if quirks.empty_final_fields && matches!(&input.final_script_witness, Some(witness) if witness.is_empty()) {
+                input.final_script_witness = None;
+                warnings.push(InternalValidationWarning::EmptyTxinField { index, field: "final script witness", });
+            }
+            if quirks.input_key_paths && !input.bip32_derivation.is_empty() {
+                input.bip32_derivation.clear();
+                warnings.push(InternalValidationWarning::TxInContainsKeyPaths { index, });
+            }
+            let finalized = input.final_script_sig.is_some() || input.final_script_witness.is_some();
+            if quirks.partial_sigs_in_finalized_inputs && finalized && !input.partial_sigs.is_empty() {
+                input.partial_sigs.clear();
+                warnings.push(InternalValidationWarning::FinalizedTxinContainsPartialSigs { index, });
+            }
+            let original_input = original.global.unsigned_tx.input
+                .iter()
+                .position(|original| original.previous_output == txin.previous_output)
+                .map(|index| &original.inputs[index]);
+            if let Some(original_input) = original_input {
+                if quirks.non_witness_utxo_in_sender_inputs && input.non_witness_utxo.is_some() && input.non_witness_utxo == original_input.non_witness_utxo {
+                    input.non_witness_utxo = None;
+                    warnings.push(InternalValidationWarning::SenderTxinContainsNonWitnessUtxo { index, });
+                }
+            }
+        }
+    }
+
     fn basic_checks(&self, proposal: &Psbt) -> InternalResult<()> {
         check_eq!(proposal.global.unsigned_tx.version, self.original_psbt.global.unsigned_tx.version, VersionsDontMatch);
         check_eq!(proposal.global.unsigned_tx.lock_time, self.original_psbt.global.unsigned_tx.lock_time, LockTimesDontMatch);
+        if self.require_matching_rbf {
+            check_eq!(signals_rbf(&proposal.global.unsigned_tx), signals_rbf(&self.original_psbt.global.unsigned_tx), RbfSignalingChanged);
+        }
+        Ok(())
+    }
+
+    /// Checks the conventions as a whole after the individual inputs were checked.
+    ///
+    /// `SequencePolicy::Any` accepts different RBF signaling of the inputs of the receiver.
+    fn check_conventions(&self, proposal: &Psbt) -> InternalResult<()> {
+        if self.sequence_policy == SequencePolicy::Any {
+            return Ok(());
+        }
+        let conventions = TxConventions::from_transaction(&self.original_psbt.global.unsigned_tx).expect("the original has inputs");
+        ensure!(conventions.preserved_by(&self.original_psbt.global.unsigned_tx, &proposal.global.unsigned_tx), ConventionsChanged);
         Ok(())
     }
 
-    fn check_inputs(&self, proposal: &Psbt) -> InternalResult<InputStats> {
+    fn check_inputs(&self, proposal: &Psbt, warnings: &mut Vec<InternalValidationWarning>) -> InternalResult<InputStats> {
         let mut original_inputs = self.original_psbt.input_pairs().peekable();
         let mut total_value = bitcoin::Amount::ZERO;
-        let mut total_weight = Weight::ZERO;
+        let mut added_weight = Weight::ZERO;
+        let mut contributable_weight = Some(Weight::ZERO);
+        let secp = if self.verify_receiver_signatures { Some(bitcoin::secp256k1::Secp256k1::verification_only()) } else { None };
 
-        for proposed in proposal.input_pairs() {
-            ensure!(proposed.psbtin.bip32_derivation.is_empty(), TxInContainsKeyPaths);
+        for (index, proposed) in proposal.input_pairs().enumerate() {
+            ensure!(proposed.psbtin.bip32_derivation.is_empty() && !crate::psbt::input_has_tap_key_paths(proposed.psbtin), TxInContainsKeyPaths);
             ensure!(proposed.psbtin.partial_sigs.is_empty(), ContainsPartialSigs);
             match original_inputs.peek() {
                 // our (sender)
                 Some(original) if proposed.txin.previous_output == original.txin.previous_output => {
-                    check_eq!(proposed.txin.sequence, original.txin.sequence, SenderTxinSequenceChanged);
-                    ensure!(proposed.psbtin.non_witness_utxo.is_none(), SenderTxinContainsNonWitnessUtxo);
-                    ensure!(proposed.psbtin.witness_utxo.is_none(), SenderTxinContainsWitnessUtxo);
-                    ensure!(proposed.psbtin.final_script_sig.is_none(), SenderTxinContainsFinalScriptSig);
-                    ensure!(proposed.psbtin.final_script_witness.is_none(), SenderTxinContainsFinalScriptWitness);
+                    self.check_sender_input(index, &proposed, original, warnings)?;
                     let prevout = original.previous_txout().expect("We've validated this before");
-                    total_value += bitcoin::Amount::from_sat(prevout.value);
-                    // We assume the signture will be the same size
-                    // I know sigs can be slightly different size but there isn't much to do about
-                    // it other than prefer Taproot.
-                    total_weight += original.txin.weight();
+                    total_value = add_value(total_value, prevout.value).ok_or(InternalValidationError::InputValueOverflow)?;
 
                     original_inputs.next();
                 },
                 // theirs (receiver)
                 None | Some(_) => {
-                    /* this seems to be wrong but not sure why/how
-                    match (&proposed.psbtin.final_script_sig, &proposed.psbtin.final_script_witness) {
-                        // TODO: use to compute weight correctly
-                        (Some(sig), Some(witness)) => (),
-                        _ => return Err(InternalValidationError::ReceiverTxinNotFinalized)
-                    }
-                    */
+                    // native SegWit inputs have no script sig so either field is enough
+                    ensure!(proposed.psbtin.final_script_sig.is_some() || proposed.psbtin.final_script_witness.is_some(), ReceiverTxinNotFinalized);
                     ensure!(proposed.psbtin.witness_utxo.is_some() || proposed.psbtin.non_witness_utxo.is_some(), ReceiverTxinMissingUtxoInfo);
-                    ensure!(proposed.txin.sequence == self.sequence, MixedSequence);
+                    self.check_receiver_sequence(index, proposed.txin.sequence)?;
+                    // Other flags are unusual (thus fingerprintable) and would allow changing
+                    // the transaction without invalidating the signature
+                    if let Some(&flag) = proposed.final_sighash_flags().iter().find(|&&flag| flag != SigHashType::All as u8) {
+                        return Err(InternalValidationError::ReceiverTxinUnsafeSighashFlag { index, flag, });
+                    }
                     let txout = proposed.previous_txout()
                         .map_err(InternalValidationError::InvalidProposedInput)?;
-                    total_value += bitcoin::Amount::from_sat(txout.value);
-                    // TODO: THIS IS INCORRECT, but we don't use it yet
-                    total_weight += proposed.txin.weight();
-                    check_eq!(InputType::from_spent_input(txout, proposed.psbtin)?, self.input_type, MixedInputTypes);
+                    total_value = add_value(total_value, txout.value).ok_or(InternalValidationError::InputValueOverflow)?;
+                    if let Some(secp) = &secp {
+                        if signature::verify_input(secp, &proposal.global.unsigned_tx, index, txout, proposed.psbtin) == Some(false) {
+                            return Err(InternalValidationError::ReceiverTxinInvalidSignature { index, });
+                        }
+                    }
+                    let input_type = InputType::from_spent_input(txout, proposed.psbtin)?;
+                    check_eq!(input_type.script_type(), self.input_type.script_type(), MixedInputTypes);
+                    let mut signed_txin = proposed.txin.clone();
+                    signed_txin.script_sig = proposed.psbtin.final_script_sig.clone().unwrap_or_default();
+                    signed_txin.witness = proposed.psbtin.final_script_witness.clone().unwrap_or_default();
+                    let actual_weight = signed_txin.weight();
+                    added_weight += actual_weight;
+                    // The receiver computes the contribution from the expected weight of the
+                    // type. The actual weight is controlled by the receiver so it never raises
+                    // the contribution - inputs without typical size allow none.
+                    contributable_weight = contributable_weight.and_then(|sum| Some(sum + input_type.expected_input_weight()?));
                 },
             }
         }
         ensure!(original_inputs.peek().is_none(), MissingOrShuffledInputs);
+        let additional_inputs = proposal.inputs.len() - self.original_psbt.inputs.len();
+        if !self.additional_inputs.contains(&additional_inputs) {
+            return Err(InternalValidationError::UnexpectedAdditionalInputCount { count: additional_inputs, allowed: self.additional_inputs.clone(), });
+        }
         Ok(InputStats {
             total_value,
-            total_weight,
+            added_weight,
+            contributable_weight,
         })
     }
 
-    fn check_outputs(&self, proposal: &Psbt) -> InternalResult<OutputStats> {
-        let mut original_outputs = proposal.global.unsigned_tx.output.iter().enumerate().peekable();
+    /// Checks an input of the sender in the proposal against the original one.
+    ///
+    /// The receiver may strip any field of our inputs, we restore them from the original before
+    /// signing (see `restore_metadata()`). The fields the receiver left in are checked as follows:
+    ///
+    /// | Field                                  | If present in the proposal                     |
+    /// |----------------------------------------|------------------------------------------------|
+    /// | sequence (can't be stripped)           | must equal the original                        |
+    /// | sighash type                           | must equal the original and be `ALL`           |
+    /// | non-witness UTXO                       | forbidden (quirk: equal to the original)       |
+    /// | witness UTXO                           | forbidden (quirk: equal to the original)       |
+    /// | redeem script, witness script          | must belong to the spent output                |
+    /// | final script sig, final script witness | forbidden                                      |
+    /// | partial signatures                     | forbidden (checked for all inputs)             |
+    /// | BIP32 and taproot key paths            | forbidden (checked for all inputs, quirk)      |
+    /// | other taproot fields                   | forbidden                                      |
+    /// | hash preimages                         | forbidden                                      |
+    /// | proprietary and unknown fields         | according to `UnknownFields`                   |
+    ///
+    /// Fields tolerated by a quirk are removed or reported by `normalize()` and here.
+    fn check_sender_input(&self, index: usize, proposed: &InputPair, original: &InputPair, warnings: &mut Vec<InternalValidationWarning>) -> InternalResult<()> {
+        check_eq!(proposed.txin.sequence, original.txin.sequence, SenderTxinSequenceChanged);
+        // Other sighash types than ALL would allow the receiver to modify the transaction after
+        // we sign.
+        if let Some(sighash_type) = proposed.psbtin.sighash_type {
+            if sighash_type != SigHashType::All {
+                return Err(InternalValidationError::SenderTxinUnsafeSighashType { sighash_type, });
+            }
+            check_eq!(proposed.psbtin.sighash_type, original.psbtin.sighash_type, SenderTxinSighashTypeChanged);
+        }
+        let prevout = original.previous_txout().expect("We've validated this before");
+        ensure!(proposed.psbtin.non_witness_utxo.is_none(), SenderTxinContainsNonWitnessUtxo);
+        if let Some(witness_utxo) = &proposed.psbtin.witness_utxo {
+            ensure!(self.quirks.witness_utxo_in_sender_inputs && witness_utxo == prevout, SenderTxinContainsWitnessUtxo);
+            warnings.push(InternalValidationWarning::SenderTxinContainsWitnessUtxo { index, });
+        }
+        // The original is usually finalized and doesn't contain the scripts anymore so they are
+        // compared with the spent output instead.
+        if let Some(redeem_script) = &proposed.psbtin.redeem_script {
+            ensure!(redeem_script.to_p2sh() == prevout.script_pubkey, SenderTxinScriptMismatch);
+        }
+        if let Some(witness_script) = &proposed.psbtin.witness_script {
+            let program = witness_script.to_v0_p2wsh();
+            ensure!(program == prevout.script_pubkey || program.to_p2sh() == prevout.script_pubkey, SenderTxinScriptMismatch);
+        }
+        ensure!(proposed.psbtin.final_script_sig.is_none(), SenderTxinContainsFinalScriptSig);
+        ensure!(proposed.psbtin.final_script_witness.is_none(), SenderTxinContainsFinalScriptWitness);
+        // Taproot signatures, internal keys or scripts of our inputs can only come from
+        // a receiver tampering with the signing of our wallet.
+        ensure!(!crate::psbt::input_has_tap_fields(proposed.psbtin), SenderTxinContainsTapFields);
+        let has_preimages = !proposed.psbtin.ripemd160_preimages.is_empty()
+            || !proposed.psbtin.sha256_preimages.is_empty()
+            || !proposed.psbtin.hash160_preimages.is_empty()
+            || !proposed.psbtin.hash256_preimages.is_empty();
+        ensure!(!has_preimages, SenderTxinContainsPreimages);
+        Ok(())
+    }
+
+    fn check_receiver_sequence(&self, index: usize, sequence: u32) -> InternalResult<()> {
+        if sequence == self.sequence {
+            return Ok(());
+        }
+        let allowed = match self.sequence_policy {
+            SequencePolicy::ExactMatch => return Err(InternalValidationError::MixedSequence),
+            SequencePolicy::RequireRbfSignaling => sequence_signals_rbf(sequence) == sequence_signals_rbf(self.sequence),
+            SequencePolicy::Any => true,
+        };
+        if !allowed || sequence_enables_lock_time(sequence) {
+            return Err(InternalValidationError::ReceiverTxinSequenceRejected { index, sequence, });
+        }
+        Ok(())
+    }
+
+    /// Warns about each of our outputs the amounts give away, see `privacy`.
+    fn check_change_privacy(&self, proposal: &Psbt, out_stats: &OutputStats, warnings: &mut Vec<InternalValidationWarning>) {
+        let (payee_index, original_payment) = match out_stats.payee {
+            Some(payee) => payee,
+            None => return,
+        };
+        if proposal.global.unsigned_tx.input.len() <= self.original_psbt.global.unsigned_tx.input.len() {
+            return;
+        }
+        let outputs = &proposal.global.unsigned_tx.output;
+        let payment = bitcoin::Amount::from_sat(outputs[payee_index].value);
+        for &index in &out_stats.own_outputs {
+            if let Some(inference) = privacy::change_inference(payment, original_payment, bitcoin::Amount::from_sat(outputs[index].value)) {
+                warnings.push(InternalValidationWarning::ChangeIdentifiable { index, inference, });
+            }
+        }
+    }
+
+    /// Pairs the proposed outputs with the original ones and checks them.
+    ///
+    /// The receiver may reorder the outputs so they are paired by script, not by position. Each
+    /// proposed output is paired with the first unpaired original output with the same script,
+    /// the fee output is then the one paired with the original output at the fee contribution
+    /// index. The only original output allowed to change its script is the payee output, it's
+    /// paired with the first unpaired proposed output.
+    fn check_outputs(&self, proposal: &Psbt, warnings: &mut Vec<InternalValidationWarning>) -> InternalResult<OutputStats> {
+        let original_outputs = &self.original_psbt.global.unsigned_tx.output;
+        let proposed_outputs = &proposal.global.unsigned_tx.output;
         let mut total_value = bitcoin::Amount::ZERO;
         let mut contributed_fee = bitcoin::Amount::ZERO;
         let mut total_weight = Weight::ZERO;
+        let mut payee = None;
+        let mut own_outputs = Vec::new();
+        // index of the proposed output paired with each original output
+        let mut pairs = vec![None; original_outputs.len()];
+        let mut unpaired = Vec::new();
 
-        for (proposed_txout, proposed_psbtout) in proposal.global.unsigned_tx.output.iter().zip(&proposal.outputs) {
-            ensure!(proposed_psbtout.bip32_derivation.is_empty(), TxOutContainsKeyPaths);
-            total_value += bitcoin::Amount::from_sat(proposed_txout.value);
+        for (index, (proposed_txout, proposed_psbtout)) in proposed_outputs.iter().zip(&proposal.outputs).enumerate() {
+            if !proposed_psbtout.bip32_derivation.is_empty() || crate::psbt::output_has_tap_key_paths(proposed_psbtout) {
+                ensure!(self.quirks.output_key_paths, TxOutContainsKeyPaths);
+                warnings.push(InternalValidationWarning::TxOutContainsKeyPaths { index, });
+            }
+            total_value = add_value(total_value, proposed_txout.value).ok_or(InternalValidationError::OutputValueOverflow)?;
             total_weight += proposed_txout.weight();
-            match (original_outputs.peek(), self.fee_contribution) {
+            let original_index = original_outputs
+                .iter()
+                .enumerate()
+                .position(|(original_index, original_output)| pairs[original_index].is_none() && original_output.script_pubkey == proposed_txout.script_pubkey);
+            match original_index {
+                Some(original_index) => pairs[original_index] = Some(index),
+                None => unpaired.push(index),
+            }
+        }
+
+        for (original_index, original_output) in original_outputs.iter().enumerate() {
+            let index = match pairs[original_index] {
+                Some(index) => index,
+                // OP_RETURN outputs of the original must survive exactly
+                None if original_output.script_pubkey.is_op_return() => {
+                    ensure!(!unpaired.iter().any(|&index| proposed_outputs[index].script_pubkey.is_op_return()), OpReturnChanged);
+                    return Err(InternalValidationError::MissingOutputs);
+                },
+                // substituted payee output
+                None if original_output.script_pubkey == self.payee && !unpaired.is_empty() => {
+                    ensure!(!self.disable_output_substitution, DisallowedOutputSubstitution);
+                    let index = unpaired.remove(0);
+                    let script_pubkey = &proposed_outputs[index].script_pubkey;
+                    if !self.substitution_scripts.allows(script_pubkey) {
+                        return Err(InternalValidationError::SubstitutionScriptNotAllowed(ScriptType::of(script_pubkey)));
+                    }
+                    payee = Some((index, bitcoin::Amount::from_sat(original_output.value)));
+                    continue;
+                },
+                None => return Err(InternalValidationError::MissingOutputs),
+            };
+            let proposed_txout = &proposed_outputs[index];
+            match self.fee_contribution {
+                _ if original_output.script_pubkey.is_op_return() => ensure!(proposed_txout == original_output, OpReturnChanged),
                 // fee output
-                (Some((original_output_index, original_output)), Some((max_fee_contrib, fee_contrib_idx))) if proposed_txout.script_pubkey == original_output.script_pubkey && *original_output_index == fee_contrib_idx => {
+                Some((max_fee_contrib, fee_contrib_idx)) if original_index == fee_contrib_idx => {
                     if proposed_txout.value < original_output.value {
                         contributed_fee = bitcoin::Amount::from_sat(original_output.value - proposed_txout.value);
-                        ensure!(contributed_fee < max_fee_contrib, FeeContributionExceedsMaximum);
+                        if contributed_fee > max_fee_contrib {
+                            return Err(InternalValidationError::FeeContributionExceedsMaximum { contributed: contributed_fee, maximum: max_fee_contrib, });
+                        }
                         //The remaining fee checks are done in the caller
                     }
-                    original_outputs.next();
+                    own_outputs.push(index);
                 },
                 // payee output
-                (Some((_original_output_index, original_output)), _) if original_output.script_pubkey == self.payee => {
-                    ensure!(!self.disable_output_substitution || (proposed_txout.script_pubkey == original_output.script_pubkey && proposed_txout.value >= original_output.value), DisallowedOutputSubstitution);
-                    original_outputs.next();
-                }
+                _ if original_output.script_pubkey == self.payee => {
+                    ensure!(!self.disable_output_substitution || proposed_txout.value >= original_output.value, DisallowedOutputSubstitution);
+                    payee = Some((index, bitcoin::Amount::from_sat(original_output.value)));
+                },
                 // our output
-                (Some((_original_output_index, original_output)), _) if proposed_txout.script_pubkey == original_output.script_pubkey => {
+                _ => {
                     ensure!(proposed_txout.value >= original_output.value, OutputValueDecreased);
-                    original_outputs.next();
+                    own_outputs.push(index);
                 },
-                // all original outputs processed, only additional outputs remain
-                _ => (),
             }
         }
 
-        ensure!(original_outputs.peek().is_none(), MissingOrShuffledOutputs);
+        // only additional outputs remain
+        ensure!(unpaired.is_empty() || self.allow_additional_outputs, DisallowedAdditionalOutput);
+        own_outputs.sort_unstable();
         Ok(OutputStats {
             total_value,
             contributed_fee,
             total_weight,
+            payee,
+            own_outputs,
         })
     }
 }
@@ -301,18 +1092,27 @@ struct OutputStats {
     total_value: bitcoin::Amount,
     contributed_fee: bitcoin::Amount,
     total_weight: Weight,
+    /// Index of the output of the receiver and its original value.
+    payee: Option<(usize, bitcoin::Amount)>,
+    /// Indices of the original outputs of the sender.
+    own_outputs: Vec<usize>,
 }
 
 struct InputStats {
     total_value: bitcoin::Amount,
-    total_weight: Weight,
+    /// Weight of the finalized inputs added by the receiver.
+    added_weight: Weight,
+    /// Expected weight of the inputs added by the receiver the fee contribution may pay for,
+    /// `None` if the weight of some input can't be estimated.
+    contributable_weight: Option<Weight>,
 }
 
-fn check_single_payee(psbt: &Psbt, script_pubkey: &Script, amount: bitcoin::Amount) -> Result<(), InternalCreateRequestError> {
+fn check_single_payee(psbt: &Psbt, script_pubkey: &Script, amount: Option<bitcoin::Amount>) -> Result<(), InternalCreateRequestError> {
     let mut payee_found = false;
     for output in &psbt.global.unsigned_tx.output {
         if output.script_pubkey == *script_pubkey {
-            if output.value != amount.as_sat() {
+            // donation links leave the amount up to the sender
+            if matches!(amount, Some(amount) if output.value != amount.as_sat()) {
                 return Err(InternalCreateRequestError::PayeeValueNotEqual)
             }
             if payee_found {
@@ -329,16 +1129,69 @@ fn check_single_payee(psbt: &Psbt, script_pubkey: &Script, amount: bitcoin::Amou
 }
 
 fn clear_unneeded_fields(psbt: &mut Psbt) {
+    strip_unknown_fields(psbt);
     psbt.global.xpub.clear();
+    for input in &mut psbt.inputs {
+        input.bip32_derivation.clear();
+    }
+    for output in &mut psbt.outputs {
+        output.bip32_derivation.clear();
+    }
+}
+
+/// Restores information about the sender inputs and outputs that was removed from the proposal.
+///
+/// Receivers strip UTXOs and scripts of sender inputs and senders strip key origins and taproot
+/// fields (internal keys, tap trees) before sending the request, but hardware wallets and other external signers need them to sign and
+/// to recognize change. This copies them from `original`, the full PSBT the wallet created,
+/// matching inputs by outpoint and outputs by script. Call it on the validated proposal only,
+/// receiver inputs and outputs are never touched.
+pub fn restore_metadata(proposal: &mut Psbt, original: &Psbt) {
+    for (txin, input) in proposal.global.unsigned_tx.input.iter().zip(&mut proposal.inputs) {
+        let original_input = original.global.unsigned_tx.input
+            .iter()
+            .position(|original| original.previous_output == txin.previous_output)
+            .map(|index| &original.inputs[index]);
+        if let Some(original_input) = original_input {
+            input.non_witness_utxo = original_input.non_witness_utxo.clone();
+            input.witness_utxo = original_input.witness_utxo.clone();
+            input.sighash_type = original_input.sighash_type;
+            input.redeem_script = original_input.redeem_script.clone();
+            input.witness_script = original_input.witness_script.clone();
+            input.bip32_derivation = original_input.bip32_derivation.clone();
+            crate::psbt::restore_input_tap_fields(input, original_input);
+        }
+    }
+    for (txout, output) in proposal.global.unsigned_tx.output.iter().zip(&mut proposal.outputs) {
+        let original_output = original.global.unsigned_tx.output
+            .iter()
+            .position(|original| original.script_pubkey == txout.script_pubkey)
+            .map(|index| &original.outputs[index]);
+        if let Some(original_output) = original_output {
+            output.redeem_script = original_output.redeem_script.clone();
+            output.witness_script = original_output.witness_script.clone();
+            output.bip32_derivation = original_output.bip32_derivation.clone();
+            crate::psbt::restore_output_tap_fields(output, original_output);
+        }
+    }
+    proposal.global.xpub = original.global.xpub.clone();
+}
+
+fn has_unknown_fields(psbt: &Psbt) -> bool {
+    !psbt.global.proprietary.is_empty()
+        || !psbt.global.unknown.is_empty()
+        || psbt.inputs.iter().any(|input| !input.proprietary.is_empty() || !input.unknown.is_empty())
+        || psbt.outputs.iter().any(|output| !output.proprietary.is_empty() || !output.unknown.is_empty())
+}
+
+fn strip_unknown_fields(psbt: &mut Psbt) {
     psbt.global.proprietary.clear();
     psbt.global.unknown.clear();
     for input in &mut psbt.inputs {
-        input.bip32_derivation.clear();
         input.proprietary.clear();
         input.unknown.clear();
     }
     for output in &mut psbt.outputs {
-        output.bip32_derivation.clear();
         output.proprietary.clear();
         output.unknown.clear();
     }
@@ -357,17 +1210,22 @@ fn check_fee_output_amount(output: &TxOut, amount: bitcoin::Amount, clamp_fee_co
 }
 
 fn find_change_index(psbt: &Psbt, payee: &Script, amount: bitcoin::Amount, clamp_fee_contribution: bool) -> Result<Option<(bitcoin::Amount, usize)>, InternalCreateRequestError> {
-    match (psbt.global.unsigned_tx.output.len(), clamp_fee_contribution) {
+    // OP_RETURN outputs can't pay the fee
+    let outputs = psbt.global.unsigned_tx.output
+        .iter()
+        .enumerate()
+        .filter(|(_, output)| !output.script_pubkey.is_op_return())
+        .collect::<Vec<_>>();
+    match (outputs.len(), clamp_fee_contribution) {
         (0, _) => return Err(InternalCreateRequestError::NoOutputs),
-        (1, false) if psbt.global.unsigned_tx.output[0].script_pubkey == *payee => return Err(InternalCreateRequestError::FeeOutputValueLowerThanFeeContribution),
-        (1, true) if psbt.global.unsigned_tx.output[0].script_pubkey == *payee => return Ok(None),
+        (1, false) if outputs[0].1.script_pubkey == *payee => return Err(InternalCreateRequestError::FeeOutputValueLowerThanFeeContribution),
+        (1, true) if outputs[0].1.script_pubkey == *payee => return Ok(None),
         (1, _) => return Err(InternalCreateRequestError::MissingPayeeOutput),
         (2, _) => (),
         _ => return Err(InternalCreateRequestError::AmbiguousChangeOutput),
     }
-    let (index, output) = psbt.global.unsigned_tx.output
-        .iter()
-        .enumerate()
+    let (index, output) = outputs
+        .into_iter()
         .find(|(_, output)| output.script_pubkey != *payee)
         .ok_or(InternalCreateRequestError::MultiplePayeeOutputs)?;
 
@@ -381,6 +1239,9 @@ fn check_change_index(psbt: &Psbt, payee: &Script, amount: bitcoin::Amount, inde
     if output.script_pubkey == *payee {
         return Err(InternalCreateRequestError::ChangeIndexPointsAtPayee);
     }
+    if output.script_pubkey.is_op_return() {
+        return Err(InternalCreateRequestError::ChangeIndexPointsAtOpReturn);
+    }
     Ok((check_fee_output_amount(output, amount, clamp_fee_contribution)?, index))
 }
 
@@ -392,19 +1253,37 @@ fn determine_fee_contribution(psbt: &Psbt, payee: &Script, params: &Params) -> R
     })
 }
 
-fn serialize_url(endpoint: String, disable_output_substitution: bool, fee_contribution: Option<(bitcoin::Amount, usize)>) -> String {
+/// Prepares the endpoint for appending query parameters.
+fn start_query(endpoint: String) -> Result<String, InternalCreateRequestError> {
+    // Fragments are not sent to the server so parameters appended after them would get lost
+    if endpoint.contains('#') {
+        return Err(InternalCreateRequestError::EndpointContainsFragment);
+    }
+    let mut url = endpoint;
+    // The endpoint may already contain a query, e.g. BTCPay Server uses invoice ID
+    if url.contains('?') && !url.ends_with('?') && !url.ends_with('&') {
+        url.push('&');
+    } else if !url.contains('?') {
+        url.push('?');
+    }
+    Ok(url)
+}
+
+fn serialize_url(endpoint: String, version: ProtocolVersion, disable_output_substitution: bool, fee_contribution: Option<(bitcoin::Amount, usize)>, min_fee_rate: Option<FeeRate>) -> Result<String, InternalCreateRequestError> {
     use std::fmt::Write;
 
-    let mut url = endpoint;
-    url.push_str("?v=1");
+    let mut url = start_query(endpoint)?;
+    write!(url, "v={}", version).expect("writing to string doesn't fail");
     if disable_output_substitution {
         url.push_str("&disableoutputsubstitution=1");
     }
     if let Some((amount, index)) = fee_contribution {
         write!(url, "&additionalfeeoutputindex={}&maxadditionalfeecontribution={}", index, amount.as_sat()).expect("writing to string doesn't fail");
     }
-    // TODO: min feerate
-    url
+    if let Some(min_fee_rate) = min_fee_rate {
+        write!(url, "&minfeerate={}", min_fee_rate.to_sat_per_vb()).expect("writing to string doesn't fail");
+    }
+    Ok(url)
 }
 
 fn serialize_psbt(psbt: &Psbt) -> Vec<u8> {
@@ -418,10 +1297,17 @@ fn serialize_psbt(psbt: &Psbt) -> Vec<u8> {
 }
 
 pub(crate) fn from_psbt_and_uri(mut psbt: Psbt, uri: crate::Uri, params: Params) -> Result<(Request, Context), CreateRequestError> {
+    if psbt.inputs.len() != psbt.global.unsigned_tx.input.len() || psbt.outputs.len() != psbt.global.unsigned_tx.output.len() {
+        return Err(InternalCreateRequestError::InconsistentPsbt.into());
+    }
     psbt
         .validate_input_utxos(true)
         .map_err(InternalCreateRequestError::InvalidOriginalInput)?;
-    let disable_output_substitution = uri.disable_output_substitution || params.disable_output_substitution;
+    calculate_psbt_fee(&psbt).ok_or(InternalCreateRequestError::InvalidFee)?;
+    if matches!(params.min_fee_rate, Some(rate) if rate > FeeRate::MAX) {
+        return Err(InternalCreateRequestError::FeeRateOutOfRange.into());
+    }
+    let disable_output_substitution = uri.extras.disable_output_substitution || params.disable_output_substitution;
     let payee = uri.address.script_pubkey();
     check_single_payee(&psbt, &payee, uri.amount)?;
     let fee_contribution = determine_fee_contribution(&psbt, &payee, &params)?;
@@ -430,29 +1316,94 @@ pub(crate) fn from_psbt_and_uri(mut psbt: Psbt, uri: crate::Uri, params: Params)
     let zeroth_input = psbt.input_pairs().next().ok_or(InternalCreateRequestError::NoInputs)?;
 
     let sequence = zeroth_input.txin.sequence;
-    let txout = zeroth_input.previous_txout().expect("We already checked this above");
-    let input_type = InputType::from_spent_input(txout, &zeroth_input.psbtin).unwrap();
-    let url = serialize_url(uri.endpoint.into(), disable_output_substitution, fee_contribution);
+    let txout = zeroth_input.previous_txout().map_err(InternalCreateRequestError::InvalidFirstInput)?;
+    let input_type = InputType::from_spent_input(txout, zeroth_input.psbtin)
+        .map_err(InternalCreateRequestError::InvalidInputType)?;
+    let url = serialize_url(uri.endpoint.into(), params.version, disable_output_substitution, fee_contribution, params.min_fee_rate)?;
+    for endpoint in &params.fallback_endpoints {
+        crate::uri::check_endpoint(endpoint).map_err(InternalCreateRequestError::InvalidEndpoint)?;
+    }
+    let (version, min_fee_rate) = (params.version, params.min_fee_rate);
+    let fallback_urls = uri.fallback_endpoints
+        .into_iter()
+        .map(String::from)
+        .chain(params.fallback_endpoints)
+        .map(|endpoint| serialize_url(endpoint, version, disable_output_substitution, fee_contribution, min_fee_rate))
+        .collect::<Result<Vec<_>, _>>()?;
+    for url in std::iter::once(&url).chain(&fallback_urls) {
+        params.limits.check_url_length(url.len()).map_err(InternalCreateRequestError::LimitExceeded)?;
+    }
     let body = serialize_psbt(&psbt);
     Ok((Request {
         url,
         body,
+        version: params.version,
+        timeout: params.max_latency,
+        fallback_urls,
+        certificate_pins: uri.extras.certificate_pins,
+        max_response_size: params.limits.max_psbt_size,
     }, Context {
         original_psbt: psbt,
         disable_output_substitution,
+        substitution_scripts: params.substitution_scripts,
         fee_contribution,
         payee,
         input_type,
         sequence,
+        allow_additional_outputs: params.allow_additional_outputs,
+        additional_inputs: params.additional_inputs,
+        quirks: params.quirks,
+        require_matching_rbf: params.require_matching_rbf,
+        check_change_privacy: params.check_change_privacy,
+        verify_receiver_signatures: params.verify_receiver_signatures,
+        sequence_policy: params.sequence_policy,
+        unknown_fields: params.unknown_fields,
+        max_latency: params.max_latency,
+        min_fee_rate: params.min_fee_rate,
+        fee_share: params.fee_share,
+        limits: params.limits,
+        events: params.events,
     }))
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::input_type::{InputType, SegWitV0Type};
+
+    fn create_context(fee_contribution: Option<(bitcoin::Amount, usize)>) -> super::Context {
+        let original_psbt = crate::testing::original_psbt();
+        let payee = original_psbt.global.unsigned_tx.output[1].script_pubkey.clone();
+        let sequence = original_psbt.global.unsigned_tx.input[0].sequence;
+        super::Context {
+            original_psbt,
+            disable_output_substitution: false,
+            substitution_scripts: super::Allowlist::default(),
+            fee_contribution,
+            payee,
+            input_type: InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: true, },
+            sequence,
+            allow_additional_outputs: true,
+            additional_inputs: 0..=usize::MAX,
+            quirks: super::Quirks::NONE,
+            require_matching_rbf: false,
+            check_change_privacy: false,
+            verify_receiver_signatures: false,
+            sequence_policy: super::SequencePolicy::ExactMatch,
+            unknown_fields: super::UnknownFields::Strip,
+            max_latency: None,
+            min_fee_rate: None,
+            fee_share: None,
+            limits: crate::Limits::default(),
+            events: None,
+        }
+    }
+
+    fn load_proposal() -> super::Psbt {
+        super::load_psbt_from_base64(crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap()
+    }
+
     #[test]
     fn official_vectors() {
-        use crate::input_type::{InputType, SegWitV0Type};
-
         let mut original_psbt = "cHNidP8BAHMCAAAAAY8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////AtyVuAUAAAAAF6kUHehJ8GnSdBUOOv6ujXLrWmsJRDCHgIQeAAAAAAAXqRR3QJbbz0hnQ8IvQ0fptGn+votneofTAAAAAAEBIKgb1wUAAAAAF6kU3k4ekGHKWRNbA1rV5tR5kEVDVNCHAQcXFgAUx4pFclNVgo1WWAdN1SYNX8tphTABCGsCRzBEAiB8Q+A6dep+Rz92vhy26lT0AjZn4PRLi8Bf9qoB/CMk0wIgP/Rj2PWZ3gEjUkTlhDRNAQ0gXwTO7t9n+V14pZ6oljUBIQMVmsAaoNWHVMS02LfTSe0e388LNitPa1UQZyOihY+FFgABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUAAA=".as_bytes();
 
         let mut proposal = "cHNidP8BAJwCAAAAAo8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////jye60aAl3JgZdaIERvjkeh72VYZuTGH/ps2I4l0IO4MBAAAAAP7///8CJpW4BQAAAAAXqRQd6EnwadJ0FQ46/q6NcutaawlEMIcACT0AAAAAABepFHdAltvPSGdDwi9DR+m0af6+i2d6h9MAAAAAAQEgqBvXBQAAAAAXqRTeTh6QYcpZE1sDWtXm1HmQRUNU0IcBBBYAFMeKRXJTVYKNVlgHTdUmDV/LaYUwIgYDFZrAGqDVh1TEtNi300ntHt/PCzYrT2tVEGcjooWPhRYYSFzWUDEAAIABAACAAAAAgAEAAAAAAAAAAAEBIICEHgAAAAAAF6kUyPLL+cphRyyI5GTUazV0hF2R2NWHAQcXFgAUX4BmVeWSTJIEwtUb5TlPS/ntohABCGsCRzBEAiBnu3tA3yWlT0WBClsXXS9j69Bt+waCs9JcjWtNjtv7VgIge2VYAaBeLPDB6HGFlpqOENXMldsJezF9Gs5amvDQRDQBIQJl1jz1tBt8hNx2owTm+4Du4isx0pmdKNMNIjjaMHFfrQABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUIgICygvBWB5prpfx61y1HDAwo37kYP3YRJBvAjtunBAur3wYSFzWUDEAAIABAACAAAAAgAEAAAABAAAAAAA=".as_bytes();
@@ -461,13 +1412,28 @@ mod tests {
         eprintln!("original: {:#?}", original_psbt);
         let payee = original_psbt.global.unsigned_tx.output[1].script_pubkey.clone();
         let sequence = original_psbt.global.unsigned_tx.input[0].sequence;
+        // The BIP78 test vector uses additionalfeeoutputindex=0&maxadditionalfeecontribution=182
         let ctx = super::Context {
             original_psbt,
             disable_output_substitution: false,
-            fee_contribution: None,
+            substitution_scripts: super::Allowlist::default(),
+            fee_contribution: Some((bitcoin::Amount::from_sat(182), 0)),
             payee,
             input_type: InputType::SegWitV0 { ty: SegWitV0Type::Pubkey, nested: true, },
             sequence,
+            allow_additional_outputs: true,
+            additional_inputs: 0..=usize::MAX,
+            quirks: super::Quirks::NONE,
+            require_matching_rbf: false,
+            check_change_privacy: false,
+            verify_receiver_signatures: false,
+            sequence_policy: super::SequencePolicy::ExactMatch,
+            unknown_fields: super::UnknownFields::Strip,
+            max_latency: None,
+            min_fee_rate: None,
+            fee_share: None,
+            limits: crate::Limits::default(),
+            events: None,
         };
         let mut proposal = super::load_psbt_from_base64(&mut proposal).unwrap();
         eprintln!("proposal: {:#?}", proposal);
@@ -480,4 +1446,825 @@ mod tests {
         proposal.inputs[0].witness_utxo = None;
         ctx.process_proposal(proposal).unwrap();
     }
+
+    #[test]
+    fn restore_metadata() {
+        let mut original = crate::testing::original_psbt();
+        let key_source = (bitcoin::util::bip32::Fingerprint::default(), bitcoin::util::bip32::DerivationPath::master());
+        let key = bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap();
+        original.inputs[0].bip32_derivation.insert(key, key_source.clone());
+        original.outputs[0].bip32_derivation.insert(key, key_source);
+        let mut proposal = load_proposal();
+        proposal.inputs[0].witness_utxo = None;
+        proposal.inputs[0].redeem_script = None;
+        let receiver_input = proposal.inputs[1].clone();
+
+        super::restore_metadata(&mut proposal, &original);
+        assert_eq!(proposal.inputs[0].witness_utxo, original.inputs[0].witness_utxo);
+        assert_eq!(proposal.inputs[0].redeem_script, original.inputs[0].redeem_script);
+        assert_eq!(proposal.inputs[0].bip32_derivation, original.inputs[0].bip32_derivation);
+        assert!(proposal.inputs[0].final_script_sig.is_none());
+        assert_eq!(proposal.inputs[1], receiver_input);
+        assert_eq!(proposal.outputs[0].bip32_derivation, original.outputs[0].bip32_derivation);
+    }
+
+    #[test]
+    fn fallback_tx() {
+        let ctx = create_context(None);
+        let tx = ctx.fallback_tx();
+        assert_eq!(tx.txid(), ctx.original_txid());
+        assert!(!tx.input[0].script_sig.is_empty());
+        assert!(!tx.input[0].witness.is_empty());
+    }
+
+    #[test]
+    fn contribution_not_allowed() {
+        // the receiver took 182 sats from the change output
+        let error = super::ValidationError::from(create_context(None).process_proposal(load_proposal()).unwrap_err());
+        assert!(error.is_protocol_violation());
+    }
+
+    #[test]
+    fn fee_contribution_exceeds_maximum() {
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(100), 0)));
+        let error = super::ValidationError::from(ctx.process_proposal(load_proposal()).unwrap_err());
+        assert!(error.is_protocol_violation());
+        assert_eq!(error.to_string(), "fee contribution 0.00000182 BTC exceeds allowed maximum 0.00000100 BTC");
+    }
+
+    #[test]
+    fn payee_took_contributed_fee() {
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.global.unsigned_tx.output[1].value += 100;
+        let error = super::ValidationError::from(ctx.process_proposal(proposal).unwrap_err());
+        assert!(error.is_protocol_violation());
+        assert_eq!(error.to_string(), "payee tried to take fee contribution for himself: 0.00000182 BTC was contributed but the fee only increased by 0.00000082 BTC");
+    }
+
+    #[test]
+    fn multiple_receiver_inputs() {
+        // the receiver contributes two inputs and the sender pays for both
+        let sender_outpoint = create_context(None).original_psbt.global.unsigned_tx.input[0].previous_output;
+        let mut proposal = load_proposal();
+        let receiver_input = proposal.global.unsigned_tx.input.iter().position(|txin| txin.previous_output != sender_outpoint).unwrap();
+        let mut txin = proposal.global.unsigned_tx.input[receiver_input].clone();
+        txin.previous_output.vout += 1;
+        let psbtin = proposal.inputs[receiver_input].clone();
+        let value = psbtin.witness_utxo.as_ref().unwrap().value;
+        proposal.global.unsigned_tx.input.push(txin);
+        proposal.inputs.push(psbtin);
+        proposal.global.unsigned_tx.output[1].value += value;
+        proposal.global.unsigned_tx.output[0].value -= 182;
+
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(364), 0)));
+        ctx.process_proposal(proposal.clone()).unwrap();
+
+        // the contribution for two inputs is too high for one
+        let mut proposal = load_proposal();
+        proposal.global.unsigned_tx.output[0].value -= 182;
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(364), 0)));
+        let error = ctx.process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::FeeContributionPaysOutputSizeIncrease), "{:?}", error);
+    }
+
+    #[test]
+    fn compatibility_issue() {
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.inputs[0].witness_utxo = ctx.original_psbt.inputs[0].witness_utxo.clone();
+        let error = super::ValidationError::from(ctx.process_proposal(proposal).unwrap_err());
+        assert!(!error.is_protocol_violation());
+    }
+
+    #[test]
+    fn sender_input_fields() {
+        use bitcoin::blockdata::transaction::SigHashType;
+
+        use crate::testing::original_psbt as original;
+
+        fn key() -> bitcoin::PublicKey {
+            bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap()
+        }
+        type Mutation = fn(&mut super::Psbt);
+
+        // case, mutation of the sender input, expected error
+        let cases: &[(&str, Mutation, Option<&str>)] = &[
+            ("untouched", |_| (), None),
+            ("redeem script stripped", |proposal| proposal.inputs[0].redeem_script = None, None),
+            ("sighash type ALL as in the original", |proposal| proposal.inputs[0].sighash_type = original().inputs[0].sighash_type, None),
+            ("sequence changed", |proposal| proposal.global.unsigned_tx.input[0].sequence -= 1, Some("SenderTxinSequenceChanged")),
+            ("sighash type added", |proposal| proposal.inputs[0].sighash_type = Some(SigHashType::All), Some("SenderTxinSighashTypeChanged")),
+            ("unsafe sighash type", |proposal| proposal.inputs[0].sighash_type = Some(SigHashType::Single), Some("SenderTxinUnsafeSighashType")),
+            ("non-witness UTXO", |proposal| proposal.inputs[0].non_witness_utxo = Some(original().global.unsigned_tx), Some("SenderTxinContainsNonWitnessUtxo")),
+            ("witness UTXO equal to the original", |proposal| proposal.inputs[0].witness_utxo = original().inputs[0].witness_utxo.clone(), Some("SenderTxinContainsWitnessUtxo")),
+            ("foreign redeem script", |proposal| proposal.inputs[0].redeem_script = Some(bitcoin::Script::new()), Some("SenderTxinScriptMismatch")),
+            ("foreign witness script", |proposal| proposal.inputs[0].witness_script = Some(bitcoin::Script::new()), Some("SenderTxinScriptMismatch")),
+            ("final script sig", |proposal| proposal.inputs[0].final_script_sig = original().inputs[0].final_script_sig.clone(), Some("SenderTxinContainsFinalScriptSig")),
+            ("final script witness", |proposal| proposal.inputs[0].final_script_witness = original().inputs[0].final_script_witness.clone(), Some("SenderTxinContainsFinalScriptWitness")),
+            ("partial signature", |proposal| { proposal.inputs[0].partial_sigs.insert(key(), vec![0x30]); }, Some("ContainsPartialSigs")),
+            ("key path", |proposal| { proposal.inputs[0].bip32_derivation.insert(key(), Default::default()); }, Some("TxInContainsKeyPaths")),
+            ("hash preimage", |proposal| { proposal.inputs[0].sha256_preimages.insert(bitcoin::hashes::Hash::hash(&[0x42]), vec![0x42]); }, Some("SenderTxinContainsPreimages")),
+        ];
+        for (case, mutate, expected) in cases {
+            let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+            let mut proposal = load_proposal();
+            mutate(&mut proposal);
+            match (ctx.process_proposal(proposal), expected) {
+                (Ok(_), None) => (),
+                (Err(error), Some(expected)) => assert!(format!("{:?}", error).starts_with(expected), "{}: {:?}", case, error),
+                (result, _) => panic!("{}: unexpected result {:?}", case, result.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn tap_fields() {
+        use bitcoin::util::psbt::raw;
+
+        let tap_key = |type_value| raw::Key { type_value, key: vec![0x02; 32], };
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        // internal key of the sender input
+        let mut proposal = load_proposal();
+        proposal.inputs[0].unknown.insert(tap_key(0x17), vec![0x42; 32]);
+        let error = super::ValidationError::from(ctx.process_proposal(proposal).unwrap_err());
+        assert_eq!(error.to_string(), "an input in proposed transaction belonging to the sender contains taproot fields");
+
+        // key paths of the receiver input
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.inputs[1].unknown.insert(tap_key(0x16), vec![0x00]);
+        let error = ctx.process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::TxInContainsKeyPaths), "{:?}", error);
+
+        // key paths of an output
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.outputs[0].unknown.insert(tap_key(0x07), vec![0x00]);
+        let error = ctx.process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::TxOutContainsKeyPaths), "{:?}", error);
+
+        // metadata is restored for signing but signatures of the original aren't
+        let mut original = crate::testing::original_psbt();
+        original.inputs[0].unknown.insert(tap_key(0x13), vec![0x42; 64]);
+        original.inputs[0].unknown.insert(tap_key(0x17), vec![0x42; 32]);
+        original.outputs[0].unknown.insert(tap_key(0x05), vec![0x42; 32]);
+        let mut proposal = load_proposal();
+        super::restore_metadata(&mut proposal, &original);
+        assert_eq!(proposal.inputs[0].unknown.keys().collect::<Vec<_>>(), [&tap_key(0x17)]);
+        assert_eq!(proposal.outputs[0].unknown, original.outputs[0].unknown);
+    }
+
+    #[test]
+    fn serialize_url() {
+        use crate::ProtocolVersion;
+
+        let contribution = Some((bitcoin::Amount::from_sat(182), 0));
+        assert_eq!(super::serialize_url("https://example.com/pj".to_owned(), ProtocolVersion::V1, false, None, None).unwrap(), "https://example.com/pj?v=1");
+        assert_eq!(super::serialize_url("https://btcpay.example.com/BTC/pj?invoiceId=RvNgiT4dFL9PAHtyVaMDMr".to_owned(), ProtocolVersion::V1, true, contribution, None).unwrap(), "https://btcpay.example.com/BTC/pj?invoiceId=RvNgiT4dFL9PAHtyVaMDMr&v=1&disableoutputsubstitution=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182");
+        assert_eq!(super::serialize_url("https://example.com/pj".to_owned(), ProtocolVersion::V1, false, contribution, Some(crate::fee_rate::FeeRate::from_sat_per_vb(2))).unwrap(), "https://example.com/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182&minfeerate=2");
+        assert_eq!(super::serialize_url("https://example.com/pj?".to_owned(), ProtocolVersion::V1, false, None, None).unwrap(), "https://example.com/pj?v=1");
+        assert_eq!(super::serialize_url("https://example.com/pj?orderId=42&".to_owned(), ProtocolVersion::V1, false, None, None).unwrap(), "https://example.com/pj?orderId=42&v=1");
+        super::serialize_url("https://example.com/pj#fragment".to_owned(), ProtocolVersion::V1, false, None, None).unwrap_err();
+    }
+
+    #[test]
+    fn minimized_proposal() {
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        // official proposal vector with key paths and UTXO of the sender input still present
+        let mut proposal = super::load_psbt_from_base64("cHNidP8BAJwCAAAAAo8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////jye60aAl3JgZdaIERvjkeh72VYZuTGH/ps2I4l0IO4MBAAAAAP7///8CJpW4BQAAAAAXqRQd6EnwadJ0FQ46/q6NcutaawlEMIcACT0AAAAAABepFHdAltvPSGdDwi9DR+m0af6+i2d6h9MAAAAAAQEgqBvXBQAAAAAXqRTeTh6QYcpZE1sDWtXm1HmQRUNU0IcBBBYAFMeKRXJTVYKNVlgHTdUmDV/LaYUwIgYDFZrAGqDVh1TEtNi300ntHt/PCzYrT2tVEGcjooWPhRYYSFzWUDEAAIABAACAAAAAgAEAAAAAAAAAAAEBIICEHgAAAAAAF6kUyPLL+cphRyyI5GTUazV0hF2R2NWHAQcXFgAUX4BmVeWSTJIEwtUb5TlPS/ntohABCGsCRzBEAiBnu3tA3yWlT0WBClsXXS9j69Bt+waCs9JcjWtNjtv7VgIge2VYAaBeLPDB6HGFlpqOENXMldsJezF9Gs5amvDQRDQBIQJl1jz1tBt8hNx2owTm+4Du4isx0pmdKNMNIjjaMHFfrQABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUIgICygvBWB5prpfx61y1HDAwo37kYP3YRJBvAjtunBAur3wYSFzWUDEAAIABAACAAAAAgAEAAAABAAAAAAA=".as_bytes()).unwrap();
+        let sender_input = ctx.original_psbt.global.unsigned_tx.input[0].previous_output;
+        crate::psbt::minimize_proposal(&mut proposal, |outpoint| *outpoint == sender_input);
+        ctx.process_proposal(proposal).unwrap();
+    }
+
+    #[test]
+    fn strict() {
+        let params = super::Params::non_incentivizing().strict();
+        let ctx = super::Context {
+            disable_output_substitution: params.disable_output_substitution,
+            allow_additional_outputs: params.allow_additional_outputs,
+            additional_inputs: params.additional_inputs,
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        ctx.process_proposal(load_proposal()).unwrap();
+
+        let ctx = super::Context { allow_additional_outputs: false, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let mut proposal = load_proposal();
+        let extra = proposal.global.unsigned_tx.output[1].clone();
+        proposal.global.unsigned_tx.output.push(bitcoin::TxOut { value: 0, ..extra });
+        proposal.outputs.push(Default::default());
+        ctx.process_proposal(proposal).unwrap_err();
+
+        let ctx = super::Context { additional_inputs: 2..=2, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let error = super::ValidationError::from(ctx.process_proposal(load_proposal()).unwrap_err());
+        assert!(!error.is_protocol_violation());
+    }
+
+    #[test]
+    fn unfinalized_receiver_input() {
+        let ctx = || create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.inputs[1].final_script_witness = None;
+        // either field is accepted since native SegWit inputs have no script sig
+        ctx().process_proposal(proposal.clone()).unwrap();
+        proposal.inputs[1].final_script_sig = None;
+        let error = ctx().process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::ReceiverTxinNotFinalized), "{:?}", error);
+    }
+
+    #[test]
+    fn oversized_receiver_witness() {
+        let ctx = || super::Context {
+            input_type: InputType::Taproot,
+            ..create_context(Some((bitcoin::Amount::from_sat(10_000), 0)))
+        };
+        let proposal = |witness_size| {
+            let mut proposal = load_proposal();
+            let taproot = bitcoin::Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(1).unwrap(), &[42; 32]);
+            proposal.inputs[1].witness_utxo.as_mut().unwrap().script_pubkey = taproot;
+            proposal.inputs[1].final_script_sig = None;
+            proposal.inputs[1].final_script_witness = Some(vec![vec![0; witness_size]]);
+            proposal
+        };
+        // the size of Taproot inputs can't be estimated so the 182 sat contribution is refused
+        // no matter how large the witness is
+        for witness_size in [64, 100_000] {
+            let error = ctx().process_proposal(proposal(witness_size)).unwrap_err();
+            assert!(matches!(error, super::InternalValidationError::FeeContributionPaysOutputSizeIncrease), "{:?}", error);
+        }
+    }
+
+    #[test]
+    fn receiver_signatures() {
+        let ctx = || super::Context {
+            verify_receiver_signatures: true,
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        ctx().process_proposal(load_proposal()).unwrap();
+        let mut proposal = load_proposal();
+        proposal.inputs[1].final_script_witness.as_mut().unwrap()[0][10] ^= 1;
+        create_context(Some((bitcoin::Amount::from_sat(182), 0))).process_proposal(proposal.clone()).unwrap();
+        let error = ctx().process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::ReceiverTxinInvalidSignature { index: 1 }), "{:?}", error);
+    }
+
+    #[test]
+    fn reordered_outputs() {
+        let reordered = |taken_from_change: u64| {
+            let mut proposal = load_proposal();
+            proposal.global.unsigned_tx.output[0].value -= taken_from_change;
+            proposal.global.unsigned_tx.output.swap(0, 1);
+            proposal.outputs.swap(0, 1);
+            proposal
+        };
+        let ctx = || create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        // the fee output is found by its script, the official proposal takes exactly 182 sat
+        ctx().process_proposal(reordered(0)).unwrap();
+        let error = ctx().process_proposal(reordered(1)).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::FeeContributionExceedsMaximum { .. }), "{:?}", error);
+        let ctx = super::Context { disable_output_substitution: true, ..ctx() };
+        let mut proposal = reordered(0);
+        proposal.global.unsigned_tx.output[0].script_pubkey = bitcoin::Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let error = ctx.process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::DisallowedOutputSubstitution), "{:?}", error);
+
+        // another output with the script of the change, moved in front of the fee output
+        let change = crate::testing::original_psbt().global.unsigned_tx.output[0].clone();
+        let twin = bitcoin::TxOut { value: 1_000, ..change.clone() };
+        let mut original_psbt = crate::testing::original_psbt();
+        original_psbt.global.unsigned_tx.output.push(twin.clone());
+        original_psbt.outputs.push(Default::default());
+        let ctx = || super::Context { original_psbt: original_psbt.clone(), ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let with_twin = |first: bitcoin::TxOut| {
+            let mut proposal = load_proposal();
+            let last = std::mem::replace(&mut proposal.global.unsigned_tx.output[0], first);
+            proposal.global.unsigned_tx.output.push(last);
+            proposal.outputs.push(Default::default());
+            proposal
+        };
+        // reducing the twin instead of the fee output doesn't pass as contribution
+        let reduced_twin = bitcoin::TxOut { value: 1_000 - 182, ..twin };
+        let error = ctx().process_proposal(with_twin(reduced_twin)).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::FeeContributionExceedsMaximum { .. }), "{:?}", error);
+        // the twin is decreased even if paired with the reduced change
+        let mut proposal = with_twin(bitcoin::TxOut { value: change.value - 182, ..change });
+        proposal.global.unsigned_tx.output[2].value = 500;
+        let error = ctx().process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::OutputValueDecreased), "{:?}", error);
+    }
+
+    #[test]
+    fn substitution_script_policy() {
+        let substituted = |script_pubkey: bitcoin::Script| {
+            let mut proposal = load_proposal();
+            proposal.global.unsigned_tx.output[1].script_pubkey = script_pubkey;
+            proposal
+        };
+        let p2wpkh = bitcoin::Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default());
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        ctx.process_proposal(substituted(p2wpkh.clone())).unwrap();
+
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let error = ctx.process_proposal(substituted(bitcoin::Script::new_op_return(&[42]))).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::SubstitutionScriptNotAllowed(super::ScriptType::OpReturn)));
+
+        let ctx = super::Context {
+            substitution_scripts: super::Allowlist::new(vec![super::ScriptType::Taproot]),
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        let error = super::ValidationError::from(ctx.process_proposal(substituted(p2wpkh)).unwrap_err());
+        assert!(!error.is_protocol_violation());
+    }
+
+    #[test]
+    fn preview() {
+        use std::convert::TryFrom;
+
+        let uri = crate::Uri::try_from(crate::testing::URI).unwrap();
+        let params = super::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), Some(0)).fallback_endpoint("http://example.onion/pj");
+        let request = params.dry_run(crate::testing::original_psbt(), uri).unwrap();
+        let preview = request.preview();
+        let mut lines = preview.lines();
+        assert_eq!(lines.next(), Some("POST https://example.com/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182"));
+        assert_eq!(lines.next(), Some("  v: 1"));
+        assert_eq!(lines.next(), Some("  additionalfeeoutputindex: 0"));
+        assert_eq!(lines.next(), Some("  maxadditionalfeecontribution: 182"));
+        assert_eq!(lines.next(), Some("fallback: http://example.onion/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182"));
+        let json = preview.split_once("\n\n").unwrap().1;
+        let psbt = serde_json::from_str::<serde_json::Value>(json).unwrap();
+        assert_eq!(psbt["inputs"][0]["value"], 97_983_400);
+
+        let uri = crate::Uri::try_from(crate::testing::URI).unwrap();
+        let error = super::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), Some(2)).dry_run(crate::testing::original_psbt(), uri).err().unwrap();
+        assert!(error.to_string().ends_with("out of bounds"), "{}", error);
+    }
+
+    #[test]
+    fn incremental_params() {
+        use std::convert::TryFrom;
+
+        let url = |params| crate::Uri::try_from(crate::testing::URI).unwrap().create_request(crate::testing::original_psbt(), params).unwrap().0.url;
+        let contribution = bitcoin::Amount::from_sat(182);
+        assert_eq!(url(super::Params::default()), url(super::Params::non_incentivizing()));
+        let params = super::Params::default()
+            .always_disable_output_substitution(true)
+            .fee_contribution(contribution, Some(0))
+            .clamp_fee_contribution(true);
+        let expected = super::Params::with_fee_contribution(contribution, Some(0))
+            .clamp_fee_contribution(true)
+            .always_disable_output_substitution(true);
+        assert_eq!(url(params), url(expected));
+        let params = super::Params::with_fee_contribution(contribution, Some(0)).no_fee_contribution();
+        assert_eq!(url(params), url(super::Params::default()));
+    }
+
+    #[test]
+    fn min_fee_rate() {
+        use std::convert::TryFrom;
+
+        let uri = crate::Uri::try_from(crate::testing::URI).unwrap();
+        let params = super::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), Some(0)).min_fee_rate(3);
+        let (request, _) = uri.create_request(crate::testing::original_psbt(), params).unwrap();
+        assert!(request.url.ends_with("&minfeerate=3"), "{}", request.url);
+
+        // the official proposal keeps the original fee rate of 2 sat/vB
+        let ctx = |sat_per_vb| super::Context {
+            min_fee_rate: Some(crate::fee_rate::FeeRate::from_sat_per_vb(sat_per_vb)),
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        ctx(1).process_proposal(load_proposal()).unwrap();
+        let error = ctx(3).process_proposal(load_proposal()).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::FeeRateBelowMinimum { minimum: 3, .. }), "{:?}", error);
+        assert!(super::ValidationError::from(error).is_protocol_violation());
+    }
+
+    #[test]
+    fn op_return_outputs() {
+        use std::convert::TryFrom;
+
+        let op_return = bitcoin::TxOut { value: 0, script_pubkey: bitcoin::Script::new_op_return(b"order 42"), };
+        let mut original_psbt = crate::testing::original_psbt();
+        // the change pays for the new output
+        original_psbt.global.unsigned_tx.output[0].value -= 60;
+        original_psbt.global.unsigned_tx.output.push(op_return.clone());
+        original_psbt.outputs.push(Default::default());
+        // the change is still detected
+        let uri = crate::Uri::try_from(crate::testing::URI).unwrap();
+        let (_, ctx) = uri.create_request(original_psbt.clone(), super::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None)).unwrap();
+        assert_eq!(ctx.fee_contribution, Some((bitcoin::Amount::from_sat(182), 0)));
+        let uri = crate::Uri::try_from(crate::testing::URI).unwrap();
+        let error = uri.create_request(original_psbt.clone(), super::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), Some(2))).err().unwrap();
+        assert_eq!(error.to_string(), "fee output index points at an OP_RETURN output");
+
+        let ctx = || super::Context {
+            original_psbt: original_psbt.clone(),
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        let proposal = |op_return: Option<bitcoin::TxOut>| {
+            let mut proposal = load_proposal();
+            proposal.global.unsigned_tx.output[0].value -= 60;
+            if let Some(op_return) = op_return {
+                proposal.global.unsigned_tx.output.push(op_return);
+                proposal.outputs.push(Default::default());
+            }
+            proposal
+        };
+        ctx().process_proposal(proposal(Some(op_return.clone()))).unwrap();
+        let changed = bitcoin::TxOut { script_pubkey: bitcoin::Script::new_op_return(b"order 43"), ..op_return.clone() };
+        assert!(matches!(ctx().process_proposal(proposal(Some(changed))).unwrap_err(), super::InternalValidationError::OpReturnChanged));
+        let burned = bitcoin::TxOut { value: 1_000, ..op_return };
+        assert!(matches!(ctx().process_proposal(proposal(Some(burned))).unwrap_err(), super::InternalValidationError::OpReturnChanged));
+        assert!(matches!(ctx().process_proposal(proposal(None)).unwrap_err(), super::InternalValidationError::MissingOutputs));
+    }
+
+    #[test]
+    fn change_privacy() {
+        let ctx = || super::Context {
+            check_change_privacy: true,
+            ..create_context(Some((bitcoin::Amount::from_sat(182), 0)))
+        };
+        // the receiver output of the official vector grew to exactly 0.04 BTC
+        let report = ctx().process_proposal(load_proposal()).unwrap();
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].severity(), super::Severity::High);
+        assert_eq!(report.warnings[0].to_string(), "output 0 is identifiable as our change: the payment is a round amount but the change isn't");
+
+        // the receiver paid more fee than its input needed, neither output looks like a payment
+        let mut proposal = load_proposal();
+        proposal.global.unsigned_tx.output[1].value -= 1_234;
+        assert!(ctx().process_proposal(proposal).unwrap().warnings.is_empty());
+
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        assert!(ctx.process_proposal(load_proposal()).unwrap().warnings.is_empty());
+    }
+
+    #[test]
+    fn compat() {
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.inputs[0].witness_utxo = ctx.original_psbt.inputs[0].witness_utxo.clone();
+        let key_source = (bitcoin::util::bip32::Fingerprint::default(), bitcoin::util::bip32::DerivationPath::master());
+        let key = bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap();
+        proposal.outputs[1].bip32_derivation.insert(key, key_source);
+
+        ctx.process_proposal(proposal.clone()).unwrap_err();
+
+        let ctx = super::Context { quirks: super::Quirks::ALL, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let report = ctx.process_proposal(proposal.clone()).unwrap();
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.psbt.outputs[1].bip32_derivation.is_empty());
+
+        // lying about the UTXO is not tolerated
+        proposal.inputs[0].witness_utxo.as_mut().unwrap().value += 1;
+        let ctx = super::Context { quirks: super::Quirks::ALL, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        ctx.process_proposal(proposal).unwrap_err();
+    }
+
+    #[test]
+    fn quirks() {
+        use super::{Implementation, Quirks};
+
+        let mut proposal = load_proposal();
+        proposal.inputs[0].final_script_sig = Some(super::Script::new());
+        let key_source = (bitcoin::util::bip32::Fingerprint::default(), bitcoin::util::bip32::DerivationPath::master());
+        let key = bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap();
+        proposal.outputs[1].bip32_derivation.insert(key, key_source);
+
+        // JoinMarket is known to leave empty final scripts but not key paths
+        let ctx = super::Context { quirks: Quirks::of(Implementation::JoinMarket), ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let error = ctx.process_proposal(proposal.clone()).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::TxOutContainsKeyPaths), "{:?}", error);
+
+        // the key paths are detected in the response
+        let mut ctx = super::Context { quirks: Quirks::of(Implementation::JoinMarket), ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let response = base64::encode(bitcoin::consensus::serialize(&proposal));
+        ctx.tolerate(Quirks::detect(vec![("Server", "TwistedWeb/22.4.0")], response.as_bytes()));
+        let report = ctx.process_proposal(proposal).unwrap();
+        assert_eq!(report.warnings.len(), 2);
+        assert!(report.psbt.inputs[0].final_script_sig.is_none());
+        assert!(report.psbt.outputs[1].bip32_derivation.is_empty());
+    }
+
+    #[test]
+    fn compat_normalization() {
+        // Shapes of proposals produced by receivers that re-serialize the PSBT
+        let prev_tx = bitcoin::Transaction { version: 2, lock_time: 0, input: Vec::new(), output: Vec::new(), };
+        let mut ctx = super::Context { quirks: super::Quirks::ALL, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        ctx.original_psbt.inputs[0].non_witness_utxo = Some(prev_tx.clone());
+        let key_source = (bitcoin::util::bip32::Fingerprint::default(), bitcoin::util::bip32::DerivationPath::master());
+        let key = bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap();
+        let mut proposal = load_proposal();
+        // empty fields instead of missing ones
+        proposal.inputs[0].final_script_sig = Some(super::Script::new());
+        proposal.inputs[0].final_script_witness = Some(Vec::new());
+        // fields copied from the original
+        proposal.inputs[0].non_witness_utxo = Some(prev_tx);
+        // fields the receiver forgot to remove from its own input
+        proposal.inputs[1].bip32_derivation.insert(key, key_source);
+        proposal.inputs[1].partial_sigs.insert(key, vec![0x30]);
+
+        let strict_ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        strict_ctx.process_proposal(proposal.clone()).unwrap_err();
+
+        let report = ctx.process_proposal(proposal.clone()).unwrap();
+        assert_eq!(report.warnings.len(), 5);
+        assert!(report.psbt.inputs[0].final_script_sig.is_none());
+        assert!(report.psbt.inputs[0].non_witness_utxo.is_none());
+        assert!(report.psbt.inputs[1].partial_sigs.is_empty());
+
+        // a non-empty final script is still rejected
+        proposal.inputs[0].final_script_sig = Some(super::Script::from(vec![0x00]));
+        let ctx = super::Context { quirks: super::Quirks::ALL, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        ctx.process_proposal(proposal).unwrap_err();
+    }
+
+    #[test]
+    fn legacy_inputs() {
+        use bitcoin::{Transaction, TxIn, TxOut, OutPoint, Address, Network, Amount, SigHashType};
+
+        let secp = bitcoin::secp256k1::Secp256k1::signing_only();
+        let key = |n: u8| bitcoin::PublicKey {
+            compressed: true,
+            key: bitcoin::secp256k1::PublicKey::from_secret_key(&secp, &bitcoin::secp256k1::SecretKey::from_slice(&[n; 32]).unwrap()),
+        };
+        let txin = |previous_output| TxIn { previous_output, script_sig: super::Script::new(), sequence: 0xfffffffe, witness: Vec::new(), };
+        let prev_tx = |n: u8, value: u64| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![txin(OutPoint::default())],
+            output: vec![TxOut { value, script_pubkey: super::Script::new_p2pkh(&key(n).pubkey_hash()), }],
+        };
+        let sender_prev = prev_tx(1, 100_000);
+        let receiver_prev = prev_tx(2, 70_000);
+        let payee = Address::p2pkh(&key(2), Network::Bitcoin);
+        let change = super::Script::new_p2pkh(&key(3).pubkey_hash());
+
+        // fee 476 sat at weight 476
+        let original_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![txin(OutPoint::new(sender_prev.txid(), 0))],
+            output: vec![TxOut { value: 50_000, script_pubkey: payee.script_pubkey(), }, TxOut { value: 49_524, script_pubkey: change.clone(), }],
+        };
+        let mut original = super::Psbt::from_unsigned_tx(original_tx).unwrap();
+        original.inputs[0].non_witness_utxo = Some(sender_prev);
+        let uri = format!("bitcoin:{}?amount=0.0005&pj=https://example.com/pj", payee).parse::<crate::Uri>().unwrap();
+        let params = super::Params::with_fee_contribution(Amount::from_sat(600), Some(1));
+        let (_, ctx) = uri.create_request(original, params).unwrap();
+
+        // the receiver adds its own P2PKH input and takes 400 sat from the change to pay for it
+        let proposal_tx = Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![ctx.original_psbt.global.unsigned_tx.input[0].clone(), txin(OutPoint::new(receiver_prev.txid(), 0))],
+            output: vec![TxOut { value: 120_000, script_pubkey: payee.script_pubkey(), }, TxOut { value: 49_124, script_pubkey: change, }],
+        };
+        let mut proposal = super::Psbt::from_unsigned_tx(proposal_tx).unwrap();
+        proposal.inputs[1].non_witness_utxo = Some(receiver_prev);
+        let mut signature = vec![0x30; 71];
+        signature.push(SigHashType::All as u8);
+        let script_sig = bitcoin::blockdata::script::Builder::new().push_slice(&signature).push_slice(&key(2).to_bytes()).into_script();
+        proposal.inputs[1].final_script_sig = Some(script_sig);
+        ctx.process_proposal(proposal).unwrap();
+    }
+
+    #[test]
+    fn error_response() {
+        let response = br#"{"errorCode":"not-enough-money","message":"Click here to get free bitcoin"}"#;
+        let error = create_context(None).process_response_bytes(response).unwrap_err();
+        assert_eq!(error.receiver_error_code(), Some(crate::ErrorCode::NotEnoughMoney));
+        assert!(!error.is_protocol_violation());
+        assert!(!error.to_string().contains("free bitcoin"));
+
+        let error = create_context(None).process_response_bytes(br#"{"errorCode":"quantum-attack"}"#).unwrap_err();
+        assert_eq!(error.receiver_error_code(), None);
+        assert_eq!(error.to_string(), "the receiver responded with an unknown error");
+    }
+
+    #[test]
+    fn rbf_and_sighash() {
+        use bitcoin::blockdata::transaction::SigHashType;
+        use super::ValidationError;
+
+        // the official vectors don't signal RBF
+        let mut original_psbt = crate::testing::original_psbt();
+        original_psbt.global.unsigned_tx.input[0].sequence = 0xfffffffd;
+        let ctx = super::Context { original_psbt: original_psbt.clone(), ..create_context(None) };
+        let error = ValidationError::from(ctx.process_proposal(load_proposal()).unwrap_err());
+        assert_eq!(error.to_string(), "proposed transaction sequence number 4294967294 doesn't match the original 4294967293");
+        let ctx = super::Context { original_psbt, require_matching_rbf: true, ..create_context(None) };
+        let error = ValidationError::from(ctx.process_proposal(load_proposal()).unwrap_err());
+        assert_eq!(error.to_string(), "proposed transaction doesn't signal RBF but the original does");
+
+        let mut proposal = load_proposal();
+        proposal.inputs[0].sighash_type = Some(SigHashType::SinglePlusAnyoneCanPay);
+        let error = ValidationError::from(create_context(None).process_proposal(proposal).unwrap_err());
+        assert!(error.is_protocol_violation());
+    }
+
+    #[test]
+    fn sequence_policy() {
+        use super::{SequencePolicy, ValidationError};
+
+        let proposal = |sequence| {
+            let mut proposal = load_proposal();
+            proposal.global.unsigned_tx.input[1].sequence = sequence;
+            proposal
+        };
+        let process = |policy, sequence| {
+            let ctx = super::Context { sequence_policy: policy, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+            ctx.process_proposal(proposal(sequence)).map_err(|error| ValidationError::from(error).to_string())
+        };
+        // the original doesn't signal RBF
+        assert_eq!(process(SequencePolicy::ExactMatch, 0xffffffff).unwrap_err(), "inputs of proposed transaction contain mixed sequence numbers");
+        assert!(process(SequencePolicy::RequireRbfSignaling, 0xffffffff).is_ok());
+        assert_eq!(process(SequencePolicy::RequireRbfSignaling, 0xfffffffd).unwrap_err(), "input #1 in proposed transaction belonging to the receiver has disallowed sequence number 0xfffffffd");
+        assert!(process(SequencePolicy::Any, 0xfffffffd).is_ok());
+        // relative lock time
+        assert!(process(SequencePolicy::Any, 10).is_err());
+    }
+
+    #[test]
+    fn unsafe_sighash() {
+        use bitcoin::blockdata::transaction::SigHashType;
+
+        let ctx = || create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let mut proposal = load_proposal();
+        proposal.inputs[0].sighash_type = Some(SigHashType::AllPlusAnyoneCanPay);
+        let error = super::ValidationError::from(ctx().process_proposal(proposal).unwrap_err());
+        assert_eq!(error.to_string(), "proposed transaction requests sighash type SIGHASH_ALL|SIGHASH_ANYONECANPAY for an input of the sender");
+
+        let mut proposal = load_proposal();
+        let signature = proposal.inputs[1].final_script_witness.as_mut().unwrap().first_mut().unwrap();
+        *signature.last_mut().unwrap() = SigHashType::SinglePlusAnyoneCanPay as u8;
+        let error = super::ValidationError::from(ctx().process_proposal(proposal).unwrap_err());
+        assert_eq!(error.to_string(), "input #1 in proposed transaction belonging to the receiver is signed with sighash flag 0x83");
+    }
+
+    #[test]
+    fn limits() {
+        let response = crate::testing::PROPOSAL_PSBT.as_bytes();
+        let ctx = |limits| super::Context { limits, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        ctx(crate::Limits::default()).process_response(response).unwrap();
+        let limits = crate::Limits { max_psbt_size: response.len() as u64 - 1, ..Default::default() };
+        let error = ctx(limits).process_response(response).unwrap_err();
+        assert!(error.to_string().starts_with("the response is too large"));
+        assert!(!error.is_protocol_violation());
+        assert!(ctx(crate::Limits { max_inputs: 1, ..Default::default() }).process_response_bytes(response).is_err());
+
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let (request, _) = uri.create_request(crate::testing::original_psbt(), super::Params::non_incentivizing()).unwrap();
+        assert_eq!(request.max_response_size, crate::Limits::default().max_psbt_size);
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let params = super::Params::non_incentivizing().limits(crate::Limits { max_url_length: 10, ..Default::default() });
+        assert!(uri.create_request(crate::testing::original_psbt(), params).is_err());
+    }
+
+    #[test]
+    fn unknown_fields() {
+        use bitcoin::util::psbt::raw;
+
+        let mut proposal = load_proposal();
+        proposal.inputs[1].unknown.insert(raw::Key { type_value: 0xf0, key: vec![42], }, vec![42]);
+        proposal.outputs[0].proprietary.insert(raw::ProprietaryKey { prefix: b"track".to_vec(), subtype: 0, key: Vec::new(), }, vec![42]);
+
+        let report = create_context(Some((bitcoin::Amount::from_sat(182), 0))).process_proposal(proposal.clone()).unwrap();
+        assert!(report.psbt.inputs[1].unknown.is_empty());
+        assert!(report.psbt.outputs[0].proprietary.is_empty());
+
+        let ctx = super::Context { unknown_fields: super::UnknownFields::Reject, ..create_context(Some((bitcoin::Amount::from_sat(182), 0))) };
+        let error = super::ValidationError::from(ctx.process_proposal(proposal).unwrap_err());
+        assert!(!error.is_protocol_violation());
+    }
+
+    // Poor man's fuzzing: mutations of the official vector covering unusual but valid PSBTs
+    #[test]
+    fn create_request_doesnt_panic() {
+        use bitcoin::{Script, TxOut, Amount};
+        use bitcoin::blockdata::script::Builder;
+        use bitcoin::blockdata::opcodes::all::*;
+
+        // xorshift, deterministic so that failures are reproducible
+        let mut state = 0x2545f4914f6cdd1du64;
+        let mut random = move |max: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % max as u64) as usize
+        };
+
+        let original = crate::testing::original_psbt();
+        let payee = original.global.unsigned_tx.output[1].clone();
+        let key = [0x02; 33];
+        let multisig = |keys: usize| {
+            let mut builder = Builder::new().push_opcode(OP_PUSHNUM_1);
+            for _ in 0..keys {
+                builder = builder.push_slice(&key);
+            }
+            builder.push_opcode(OP_PUSHNUM_2).push_opcode(OP_CHECKMULTISIG).into_script()
+        };
+        let scripts = [
+            payee.script_pubkey.clone(),
+            original.inputs[0].witness_utxo.as_ref().unwrap().script_pubkey.clone(),
+            Script::new_v0_wpkh(&bitcoin::WPubkeyHash::default()),
+            Script::new_v0_wsh(&bitcoin::WScriptHash::default()),
+            Script::new_p2pk(&bitcoin::PublicKey::from_slice(&key).unwrap()),
+            Script::new_p2pkh(&bitcoin::PubkeyHash::default()),
+            Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(1).unwrap(), &[42; 32]),
+            Script::new_witness_program(bitcoin::bech32::u5::try_from_u8(2).unwrap(), &[42; 2]),
+            Script::new_op_return(&[42]),
+            Script::new(),
+        ];
+        let witness_items = [Vec::new(), vec![0x30; 72], multisig(2).to_bytes(), multisig(300).to_bytes()];
+        let values = [0, 1, 546, 2_000_000, 95_983_000, Amount::max_value().as_sat(), u64::MAX];
+
+        for _ in 0..2000 {
+            let mut psbt = original.clone();
+            let witness_utxo = psbt.inputs[0].witness_utxo.as_mut().unwrap();
+            witness_utxo.script_pubkey = scripts[random(scripts.len())].clone();
+            witness_utxo.value = values[random(values.len())];
+            match random(4) {
+                0 => psbt.inputs[0].final_script_sig = None,
+                1 => psbt.inputs[0].final_script_sig = Some(Builder::new().push_opcode(OP_NOP).into_script()),
+                2 => psbt.inputs[0].final_script_witness = Some(vec![witness_items[random(witness_items.len())].clone()]),
+                _ => (),
+            }
+            let output_count = random(4);
+            psbt.global.unsigned_tx.output = (0..output_count)
+                .map(|_| match random(3) {
+                    0 => payee.clone(),
+                    _ => TxOut { value: values[random(values.len())], script_pubkey: scripts[random(scripts.len())].clone(), },
+                })
+                .collect();
+            psbt.outputs = vec![Default::default(); output_count];
+            match random(10) {
+                0 => { psbt.inputs.push(Default::default()); },
+                1 => { psbt.outputs.pop(); },
+                _ => (),
+            }
+            let contribution = Amount::from_sat(values[random(values.len())] % 1_000_000);
+            let params = match random(3) {
+                0 => super::Params::non_incentivizing(),
+                1 => super::Params::with_fee_contribution(contribution, None),
+                _ => super::Params::with_fee_contribution(contribution, Some(random(4))),
+            };
+            let params = params.clamp_fee_contribution(random(2) == 0);
+            let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+            if let Ok((_, context)) = uri.create_request(psbt, params) {
+                let _ = context.process_response_bytes(crate::testing::PROPOSAL_PSBT.as_bytes());
+            }
+        }
+    }
+
+    #[test]
+    fn value_overflow() {
+        let mut proposal = load_proposal();
+        proposal.global.unsigned_tx.output[1].value = u64::MAX;
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let error = ctx.process_proposal(proposal).unwrap_err();
+        assert!(matches!(error, super::InternalValidationError::OutputValueOverflow), "{:?}", error);
+
+        let sender_outpoint = create_context(None).original_psbt.global.unsigned_tx.input[0].previous_output;
+        let mut proposal = load_proposal();
+        let receiver_input = proposal.global.unsigned_tx.input.iter().position(|txin| txin.previous_output != sender_outpoint).unwrap();
+        proposal.inputs[receiver_input].witness_utxo.as_mut().unwrap().value = super::MAX_MONEY;
+        let ctx = create_context(Some((bitcoin::Amount::from_sat(182), 0)));
+        let error = super::ValidationError::from(ctx.process_proposal(proposal).unwrap_err());
+        assert!(error.is_protocol_violation());
+        assert_eq!(error.to_string(), "total value of proposed inputs exceeds 21 million bitcoins");
+    }
+
+    // Poor man's fuzzing: the receiver controls all values in the proposal
+    #[test]
+    fn process_proposal_doesnt_panic() {
+        let mut state = 0x9e3779b97f4a7c15u64;
+        let mut random = move |max: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % max as u64) as usize
+        };
+        let values = [0, 1, 182, 95_983_000, super::MAX_MONEY, super::MAX_MONEY + 1, i64::MAX as u64, u64::MAX - 1, u64::MAX];
+
+        for _ in 0..2000 {
+            let mut proposal = load_proposal();
+            for txout in &mut proposal.global.unsigned_tx.output {
+                if random(2) == 0 {
+                    txout.value = values[random(values.len())];
+                }
+            }
+            for psbtin in &mut proposal.inputs {
+                if let Some(witness_utxo) = psbtin.witness_utxo.as_mut().filter(|_| random(2) == 0) {
+                    witness_utxo.value = values[random(values.len())];
+                }
+            }
+            let contribution = bitcoin::Amount::from_sat(values[random(values.len())] % 1_000_000);
+            let fee_contribution = match random(2) {
+                0 => None,
+                _ => Some((contribution, 0)),
+            };
+            let _ = create_context(fee_contribution).process_proposal(proposal);
+        }
+    }
 }
@@ -0,0 +1,464 @@
+//! Runtime-agnostic handling of the response with a latency budget
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+use crate::time::{Clock, Deadline, SystemClock};
+use super::{Context, EventCallback, Request, Transport, ValidationError};
+
+/// Result of a PayJoin attempt.
+///
+/// All variants except `Proposal` contain the fallback transaction which you should broadcast.
+#[derive(Debug)]
+pub enum Outcome<E> {
+    /// The proposal is valid - sign and broadcast it.
+    Proposal(Psbt),
+    /// The receiver didn't respond within `Params::max_latency()`.
+    Timeout { fallback: bitcoin::Transaction, },
+    /// The receiver responded with invalid proposal.
+    Invalid { fallback: bitcoin::Transaction, error: ValidationError, },
+    /// Sending the request or receiving the response failed.
+    Transport { fallback: bitcoin::Transaction, error: E, },
+    /// The receiver kept responding with HTTP 503 and retrying didn't help.
+    Unavailable { fallback: bitcoin::Transaction, },
+}
+
+impl<E> Outcome<E> {
+    /// Returns the transaction to be broadcasted if PayJoin failed.
+    pub fn fallback(&self) -> Option<&bitcoin::Transaction> {
+        match self {
+            Outcome::Proposal(_) => None,
+            Outcome::Timeout { fallback, } => Some(fallback),
+            Outcome::Invalid { fallback, .. } => Some(fallback),
+            Outcome::Transport { fallback, .. } => Some(fallback),
+            Outcome::Unavailable { fallback, } => Some(fallback),
+        }
+    }
+}
+
+/// Result of trying multiple endpoints, see `send_with_failover()`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct Failover<E> {
+    pub outcome: Outcome<E>,
+    /// Index into `Request::urls()` of the endpoint that responded, `None` if none did.
+    pub endpoint: Option<usize>,
+    /// Errors of endpoints that failed as (index, error).
+    ///
+    /// If all endpoints failed the error of the last one is in `Outcome::Transport` instead.
+    pub failures: Vec<(usize, E)>,
+}
+
+/// Progress of the helpers in this module, see `Params::on_event()`.
+///
+/// Funding, signing and broadcasting happen in your wallet, before and after the helpers, so
+/// report those stages yourself.
+#[derive(Debug, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum PayjoinEvent<'a> {
+    /// The request is being sent, `url` is known only to helpers trying multiple endpoints.
+    Requesting { url: Option<&'a str>, },
+    /// The endpoint couldn't be reached, the next one is tried.
+    EndpointFailed { url: &'a str, },
+    /// The receiver was unavailable, the request will be sent again after `delay`.
+    Retrying { delay: Duration, },
+    /// The response arrived and is being validated.
+    Validating,
+    /// The attempt finished, `payjoin` is `false` if the fallback transaction should be
+    /// broadcasted.
+    Completed { payjoin: bool, },
+}
+
+fn emit(events: &Option<EventCallback>, event: PayjoinEvent<'_>) {
+    if let Some(callback) = events {
+        callback(&event);
+    }
+}
+
+/// Response of the receiver as reported by your HTTP client.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Response {
+    /// Body of any response except HTTP 503 - error responses are processed by the library.
+    Body(Vec<u8>),
+    /// HTTP 503 Service Unavailable with the value of `Retry-After` header parsed by
+    /// `parse_retry_after()`.
+    Unavailable { retry_after: Option<Duration>, },
+}
+
+/// Parses the value of `Retry-After` header.
+///
+/// Only the number of seconds is supported, `None` is returned for HTTP dates.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse().ok().map(Duration::from_secs)
+}
+
+/// Limits retrying of requests the receiver was too busy to process.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Maximum number of additional requests.
+    pub max_retries: usize,
+    /// How long to wait if the receiver didn't send `Retry-After`.
+    pub default_delay: Duration,
+    /// Give up if the receiver asks to wait longer than this.
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            default_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+}
+
+enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// Polls both futures, resolves with the first one that completes.
+struct Race<A, B> {
+    left: Pin<Box<A>>,
+    right: Pin<Box<B>>,
+}
+
+impl<A: Future, B: Future> Future for Race<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(value) = this.left.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(value));
+        }
+        this.right.as_mut().poll(cx).map(Either::Right)
+    }
+}
+
+/// Waits for the response at most `Params::max_latency()` and processes it.
+///
+/// `response` is the future sending the request and returning the response body, `sleep` creates
+/// a timer future of your runtime, e.g. `tokio::time::sleep`. If the time runs out the response
+/// future is dropped (canceling the request) and `Outcome::Timeout` is returned. Without a
+/// latency budget this just awaits the response.
+pub async fn await_response<R, E, S, T>(context: Context, response: R, sleep: S) -> Outcome<E>
+    where R: Future<Output=Result<Vec<u8>, E>>, S: FnOnce(Duration) -> T, T: Future<Output=()> {
+    let budget = context.max_latency;
+    emit(&context.events, PayjoinEvent::Requesting { url: None, });
+    let response = async { response.await.map(Some) };
+    finish(context, with_budget(budget, response, sleep).await)
+}
+
+/// Same as `await_response()` but retries the request if the receiver responds with HTTP 503.
+///
+/// `send` is called for each attempt. The retries are limited by `policy` and the delays also
+/// count against `Params::max_latency()`: if the receiver asks to wait longer than what remains
+/// `Outcome::Unavailable` is returned right away so that you can broadcast the fallback
+/// transaction without waiting.
+pub async fn await_response_with_retries<F, R, E, S, T>(context: Context, mut send: F, sleep: S, policy: &RetryPolicy) -> Outcome<E>
+    where F: FnMut() -> R, R: Future<Output=Result<Response, E>>, S: Fn(Duration) -> T, T: Future<Output=()> {
+    let budget = context.max_latency;
+    let events = context.events.clone();
+    let attempts = async {
+        let mut waited = Duration::from_secs(0);
+        let mut retries = 0;
+        loop {
+            emit(&events, PayjoinEvent::Requesting { url: None, });
+            let delay = match send().await? {
+                Response::Body(body) => return Ok(Some(body)),
+                Response::Unavailable { retry_after, } => retry_after.unwrap_or(policy.default_delay),
+            };
+            let exceeds_budget = matches!(budget, Some(budget) if waited + delay >= budget);
+            if retries >= policy.max_retries || delay > policy.max_delay || exceeds_budget {
+                return Ok(None);
+            }
+            emit(&events, PayjoinEvent::Retrying { delay, });
+            sleep(delay).await;
+            waited += delay;
+            retries += 1;
+        }
+    };
+    finish(context, with_budget(budget, attempts, &sleep).await)
+}
+
+/// Sends the request to the endpoints in `Request::urls()` until one of them responds.
+///
+/// Only transport errors (unreachable host, Tor circuit failure...) move on to the next
+/// endpoint. Any response, including an error, is final since the receiver already saw the
+/// original transaction. No endpoint is tried after `Params::max_latency()` and a response
+/// arriving too late results in `Outcome::Timeout` - set the timeout of your HTTP client to
+/// `Request::timeout` so that a single endpoint can't exhaust the budget.
+pub fn send_with_failover<T: Transport>(transport: &T, request: &Request, context: Context) -> Failover<T::Error> {
+    failover(&SystemClock, transport, request, context)
+}
+
+fn failover<T: Transport>(clock: &impl Clock, transport: &T, request: &Request, context: Context) -> Failover<T::Error> {
+    let deadline = context.max_latency.map(|budget| Deadline::after(clock, budget));
+    let is_expired = || matches!(deadline, Some(deadline) if deadline.is_expired(clock));
+    let mut failures = Vec::new();
+    for (index, url) in request.urls().enumerate() {
+        if is_expired() {
+            return Failover { outcome: finish(context, Either::Right(())), endpoint: None, failures, };
+        }
+        emit(&context.events, PayjoinEvent::Requesting { url: Some(url), });
+        match transport.post(url, &request.body) {
+            Ok(_) if is_expired() => return Failover { outcome: finish(context, Either::Right(())), endpoint: Some(index), failures, },
+            Ok(body) => return Failover { outcome: finish(context, Either::Left(Ok(Some(body)))), endpoint: Some(index), failures, },
+            Err(error) => {
+                emit(&context.events, PayjoinEvent::EndpointFailed { url, });
+                failures.push((index, error));
+            },
+        }
+    }
+    let (_, error) = failures.pop().expect("there's at least one URL");
+    Failover { outcome: finish(context, Either::Left(Err(error))), endpoint: None, failures, }
+}
+
+/// Asynchronous version of `send_with_failover()` enforcing `Params::max_latency()` like
+/// `await_response()`.
+///
+/// `send` is called with each URL and should POST `Request::body` to it.
+pub async fn await_response_with_failover<F, R, E, S, T>(context: Context, request: &Request, mut send: F, sleep: S) -> Failover<E>
+    where F: FnMut(&str) -> R, R: Future<Output=Result<Vec<u8>, E>>, S: FnOnce(Duration) -> T, T: Future<Output=()> {
+    let budget = context.max_latency;
+    let events = context.events.clone();
+    let mut failures = Vec::new();
+    let mut endpoint = None;
+    let attempts = async {
+        for (index, url) in request.urls().enumerate() {
+            emit(&events, PayjoinEvent::Requesting { url: Some(url), });
+            match send(url).await {
+                Ok(body) => {
+                    endpoint = Some(index);
+                    return Ok(Some(body));
+                },
+                Err(error) => {
+                    emit(&events, PayjoinEvent::EndpointFailed { url, });
+                    failures.push((index, error));
+                },
+            }
+        }
+        let (_, error) = failures.pop().expect("there's at least one URL");
+        Err(error)
+    };
+    let response = with_budget(budget, attempts, sleep).await;
+    Failover { outcome: finish(context, response), endpoint, failures, }
+}
+
+/// Resolves with `Either::Right` if `budget` runs out before `future` completes.
+async fn with_budget<F: Future, S: FnOnce(Duration) -> T, T: Future<Output=()>>(budget: Option<Duration>, future: F, sleep: S) -> Either<F::Output, ()> {
+    match budget {
+        Some(budget) => Race { left: Box::pin(future), right: Box::pin(sleep(budget)), }.await,
+        None => Either::Left(future.await),
+    }
+}
+
+/// `None` body means the receiver was unavailable.
+fn finish<E>(context: Context, response: Either<Result<Option<Vec<u8>>, E>, ()>) -> Outcome<E> {
+    let fallback = context.fallback_tx();
+    let events = context.events.clone();
+    let outcome = match response {
+        Either::Left(Ok(Some(body))) => {
+            emit(&events, PayjoinEvent::Validating);
+            match context.process_response_bytes(&body) {
+                Ok(psbt) => Outcome::Proposal(psbt),
+                Err(error) => Outcome::Invalid { fallback, error, },
+            }
+        },
+        Either::Left(Ok(None)) => Outcome::Unavailable { fallback, },
+        Either::Left(Err(error)) => Outcome::Transport { fallback, error, },
+        Either::Right(()) => Outcome::Timeout { fallback, },
+    };
+    emit(&events, PayjoinEvent::Completed { payjoin: outcome.fallback().is_none(), });
+    outcome
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::{ready, pending};
+    use std::sync::Arc;
+    use std::task::{Wake, Waker};
+
+    struct NoopWaker;
+
+    impl Wake for NoopWaker {
+        fn wake(self: Arc<Self>) {}
+    }
+
+    // All futures in these tests are immediately ready or never ready
+    fn poll_once<F: Future>(future: F) -> F::Output {
+        let waker = Waker::from(Arc::new(NoopWaker));
+        let mut cx = TaskContext::from_waker(&waker);
+        match Box::pin(future).as_mut().poll(&mut cx) {
+            Poll::Ready(value) => value,
+            Poll::Pending => panic!("future not ready"),
+        }
+    }
+
+    fn context(max_latency: Option<Duration>) -> Context {
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap();
+        let mut params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None);
+        if let Some(max_latency) = max_latency {
+            params = params.max_latency(max_latency);
+        }
+        uri.create_request(crate::testing::original_psbt(), params).unwrap().1
+    }
+
+    #[test]
+    fn timeout() {
+        let ctx = context(Some(Duration::from_secs(30)));
+        let outcome = poll_once(await_response(ctx, pending::<Result<Vec<u8>, ()>>(), |budget| { assert_eq!(budget, Duration::from_secs(30)); ready(()) }));
+        assert!(matches!(outcome, Outcome::Timeout { .. }));
+        assert!(outcome.fallback().is_some());
+    }
+
+    #[test]
+    fn response_in_time() {
+        let response = ready(Ok::<_, ()>(crate::testing::PROPOSAL_PSBT.as_bytes().to_vec()));
+        let outcome = poll_once(await_response(context(Some(Duration::from_secs(30))), response, |_| pending()));
+        assert!(matches!(outcome, Outcome::Proposal(_)));
+    }
+
+    #[test]
+    fn no_budget() {
+        let outcome = poll_once(await_response(context(None), ready(Err::<Vec<u8>, _>("connection refused")), |_| -> std::future::Pending<()> { panic!("no timer without budget") }));
+        assert!(matches!(outcome, Outcome::Transport { error: "connection refused", .. }));
+    }
+
+    // The budget timer never fires, retry delays elapse immediately
+    fn sleep(delay: Duration) -> Pin<Box<dyn Future<Output=()>>> {
+        if delay == Duration::from_secs(30) {
+            Box::pin(pending())
+        } else {
+            Box::pin(ready(()))
+        }
+    }
+
+    #[test]
+    fn retries() {
+        use std::cell::RefCell;
+
+        let responses = RefCell::new(vec![
+            Response::Body(crate::testing::PROPOSAL_PSBT.as_bytes().to_vec()),
+            Response::Unavailable { retry_after: None, },
+            Response::Unavailable { retry_after: parse_retry_after("2"), },
+        ]);
+        let send = || ready(Ok::<_, ()>(responses.borrow_mut().pop().unwrap()));
+        let outcome = poll_once(await_response_with_retries(context(Some(Duration::from_secs(30))), send, sleep, &RetryPolicy::default()));
+        assert!(matches!(outcome, Outcome::Proposal(_)));
+        assert!(responses.borrow().is_empty());
+
+        // asks to wait too long
+        let attempts = RefCell::new(0);
+        let send = || { *attempts.borrow_mut() += 1; ready(Ok::<_, ()>(Response::Unavailable { retry_after: Some(Duration::from_secs(60)), })) };
+        let outcome = poll_once(await_response_with_retries(context(Some(Duration::from_secs(30))), send, sleep, &RetryPolicy::default()));
+        assert!(matches!(outcome, Outcome::Unavailable { .. }));
+        assert_eq!(*attempts.borrow(), 1);
+
+        // the delays would exceed the budget
+        let attempts = RefCell::new(0);
+        let send = || { *attempts.borrow_mut() += 1; ready(Ok::<_, ()>(Response::Unavailable { retry_after: Some(Duration::from_secs(20)), })) };
+        let policy = RetryPolicy { max_delay: Duration::from_secs(20), ..Default::default() };
+        let outcome = poll_once(await_response_with_retries(context(Some(Duration::from_secs(30))), send, sleep, &policy));
+        assert!(matches!(outcome, Outcome::Unavailable { .. }));
+        assert_eq!(*attempts.borrow(), 2);
+    }
+
+    #[test]
+    fn failover() {
+        use crate::testing::MockClock;
+
+        let uri = || crate::testing::URI.parse::<crate::Uri>().unwrap().with_fallback_endpoint("http://example.onion/pj").unwrap();
+        let params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None)
+            .max_latency(Duration::from_secs(30))
+            .fallback_endpoint("http://example.i2p/pj");
+        let (request, ctx) = uri().create_request(crate::testing::original_psbt(), params).unwrap();
+        assert_eq!(request.fallback_urls, [
+            "http://example.onion/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182",
+            "http://example.i2p/pj?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182",
+        ]);
+
+        let clock = MockClock::new();
+        let transport = |url: &str, _: &[u8]| {
+            clock.advance(Duration::from_secs(10));
+            if url.starts_with("http://example.onion") {
+                Ok(crate::testing::PROPOSAL_PSBT.as_bytes().to_vec())
+            } else {
+                Err(url.to_owned())
+            }
+        };
+        let result = super::failover(&clock, &transport, &request, ctx);
+        assert!(matches!(result.outcome, Outcome::Proposal(_)));
+        assert_eq!(result.endpoint, Some(1));
+        assert_eq!(result.failures.len(), 1);
+
+        let (request, ctx) = uri().create_request(crate::testing::original_psbt(), crate::sender::Params::non_incentivizing().max_latency(Duration::from_secs(15)).fallback_endpoint("http://example.i2p/pj")).unwrap();
+        let transport = |_: &str, _: &[u8]| {
+            clock.advance(Duration::from_secs(10));
+            Err::<Vec<u8>, _>("connection refused")
+        };
+        // the second endpoint is tried but the budget runs out before the third one
+        let result = super::failover(&clock, &transport, &request, ctx);
+        assert!(matches!(result.outcome, Outcome::Timeout { .. }));
+        assert_eq!(result.failures.len(), 2);
+
+        let (request, ctx) = uri().create_request(crate::testing::original_psbt(), crate::sender::Params::non_incentivizing()).unwrap();
+        let send = |url: &str| ready(if url.starts_with("https://") { Err("connection refused") } else { Ok(Vec::new()) });
+        let result = poll_once(await_response_with_failover(ctx, &request, send, |_| -> std::future::Pending<()> { panic!("no timer without budget") }));
+        assert!(matches!(result.outcome, Outcome::Invalid { .. }));
+        assert_eq!(result.endpoint, Some(1));
+        assert_eq!(result.failures, [(0, "connection refused")]);
+    }
+
+    #[test]
+    fn events() {
+        use std::sync::Mutex;
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let log = Arc::clone(&events);
+        let uri = crate::testing::URI.parse::<crate::Uri>().unwrap().with_fallback_endpoint("http://example.onion/pj").unwrap();
+        let params = crate::sender::Params::with_fee_contribution(bitcoin::Amount::from_sat(182), None)
+            .on_event(move |event| log.lock().unwrap().push(format!("{:?}", event)));
+        let (request, ctx) = uri.create_request(crate::testing::original_psbt(), params).unwrap();
+        let transport = |url: &str, _: &[u8]| if url.starts_with("http://example.onion") {
+            Ok(crate::testing::PROPOSAL_PSBT.as_bytes().to_vec())
+        } else {
+            Err(())
+        };
+        let result = send_with_failover(&transport, &request, ctx);
+        assert!(matches!(result.outcome, Outcome::Proposal(_)));
+        let url = |url: &str| format!("{}?v=1&additionalfeeoutputindex=0&maxadditionalfeecontribution=182", url);
+        assert_eq!(*events.lock().unwrap(), [
+            format!("Requesting {{ url: Some({:?}) }}", request.url),
+            format!("EndpointFailed {{ url: {:?} }}", request.url),
+            format!("Requesting {{ url: Some({:?}) }}", url("http://example.onion/pj")),
+            "Validating".to_owned(),
+            "Completed { payjoin: true }".to_owned(),
+        ]);
+
+        events.lock().unwrap().clear();
+        let log = Arc::clone(&events);
+        let params = crate::sender::Params::non_incentivizing().on_event(move |event| log.lock().unwrap().push(format!("{:?}", event)));
+        let (_, ctx) = crate::testing::URI.parse::<crate::Uri>().unwrap().create_request(crate::testing::original_psbt(), params).unwrap();
+        let send = || ready(Ok::<_, ()>(Response::Unavailable { retry_after: Some(Duration::from_secs(1)), }));
+        let policy = RetryPolicy { max_retries: 1, ..Default::default() };
+        let outcome = poll_once(await_response_with_retries(ctx, send, |_| ready(()), &policy));
+        assert!(matches!(outcome, Outcome::Unavailable { .. }));
+        assert_eq!(*events.lock().unwrap(), [
+            "Requesting { url: None }",
+            "Retrying { delay: 1s }",
+            "Requesting { url: None }",
+            "Completed { payjoin: false }",
+        ]);
+    }
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(parse_retry_after(" 120"), Some(Duration::from_secs(120)));
+        assert_eq!(parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"), None);
+    }
+}
@@ -0,0 +1,76 @@
+//! Whether the proposal gives away the change of the sender
+//!
+//! A payjoin breaks the common-input-ownership heuristic but the amounts may still tell the
+//! outputs apart. Wallets usually pay round amounts, so if the output of the receiver stays round
+//! after the receiver added its inputs while the change of the sender absorbed an odd fee delta,
+//! the change is as identifiable as in an ordinary payment. The heuristics here are enabled by
+//! `Params::check_change_privacy()` and reported as warnings in `ValidationReport`.
+
+use bitcoin::Amount;
+
+/// Outputs that are multiples of this look like payments.
+const ROUND_AMOUNT: u64 = 10_000;
+
+/// How much a warning matters, see `ValidationWarning::severity()`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum Severity {
+    /// Tolerated deviation without impact on the transaction.
+    Low,
+    /// The change is identifiable by someone who knows the amount of the payment.
+    Medium,
+    /// The change is identifiable by anyone.
+    High,
+}
+
+/// Why the change of the sender is identifiable.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub(crate) enum ChangeInference {
+    /// The output of the receiver is round, our change is not.
+    RoundPayment,
+    /// The receiver kept the amount of the payment so the other outputs are ours.
+    PaymentAmountPreserved,
+}
+
+impl ChangeInference {
+    pub(crate) fn severity(self) -> Severity {
+        match self {
+            ChangeInference::RoundPayment => Severity::High,
+            ChangeInference::PaymentAmountPreserved => Severity::Medium,
+        }
+    }
+}
+
+fn is_round(amount: Amount) -> bool {
+    let remainder = amount.as_sat() % ROUND_AMOUNT;
+    amount.as_sat() != 0 && remainder == 0
+}
+
+/// Returns what gives away our `change` output, if anything.
+///
+/// `payment` is the output of the receiver in the proposal and `original_payment` in the
+/// original transaction. Only call this if the receiver contributed inputs, the original
+/// transaction doesn't hide anything.
+pub(crate) fn change_inference(payment: Amount, original_payment: Amount, change: Amount) -> Option<ChangeInference> {
+    if is_round(payment) && !is_round(change) {
+        Some(ChangeInference::RoundPayment)
+    } else if payment == original_payment {
+        Some(ChangeInference::PaymentAmountPreserved)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inference() {
+        let sat = Amount::from_sat;
+        assert_eq!(change_inference(sat(4_000_000), sat(2_000_000), sat(95_983_211)), Some(ChangeInference::RoundPayment));
+        assert_eq!(change_inference(sat(4_000_000), sat(2_000_000), sat(95_980_000)), None);
+        assert_eq!(change_inference(sat(2_000_183), sat(2_000_183), sat(95_983_211)), Some(ChangeInference::PaymentAmountPreserved));
+        assert_eq!(change_inference(sat(2_012_345), sat(2_000_000), sat(95_983_211)), None);
+        assert!(ChangeInference::RoundPayment.severity() > ChangeInference::PaymentAmountPreserved.severity());
+    }
+}
@@ -0,0 +1,233 @@
+//! Checking whether the endpoint is alive before paying
+//!
+//! BIP78 doesn't define a dedicated capability request but it requires the receiver to respond
+//! with `version-unsupported` error listing the supported versions if it doesn't support the
+//! requested one. The probe requests version 0 which never existed, sends no PSBT and reads the
+//! list from the response.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::fmt;
+use std::time::Duration;
+use crate::time::{Clock, SystemClock, Deadline};
+use crate::ProtocolVersion;
+use super::CreateRequestError;
+
+/// Sends HTTP POST requests.
+///
+/// Closures taking URL and body and returning the body of the response implement this trait.
+/// The implementation should set `Content-Type: text/plain` and return the body even if the
+/// status code is not successful.
+pub trait Transport {
+    type Error;
+
+    fn post(&self, url: &str, body: &[u8]) -> Result<Vec<u8>, Self::Error>;
+}
+
+impl<E, F: Fn(&str, &[u8]) -> Result<Vec<u8>, E>> Transport for F {
+    type Error = E;
+
+    fn post(&self, url: &str, body: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self(url, body)
+    }
+}
+
+/// Information about the endpoint obtained by probing.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct EndpointInfo {
+    /// Numbers of versions supported by the receiver.
+    ///
+    /// `None` if the receiver didn't tell which doesn't mean it doesn't work - some receivers
+    /// don't check the version at all.
+    pub supported_versions: Option<Vec<u32>>,
+    /// How long it took the receiver to respond.
+    pub latency: Duration,
+}
+
+impl EndpointInfo {
+    /// Returns the highest version supported by both us and the receiver.
+    ///
+    /// If the receiver didn't list the versions the first one is assumed.
+    pub fn best_version(&self) -> Option<ProtocolVersion> {
+        match &self.supported_versions {
+            Some(versions) => ProtocolVersion::SUPPORTED
+                .iter()
+                .rev()
+                .find(|version| versions.contains(&version.number()))
+                .copied(),
+            None => Some(ProtocolVersion::V1),
+        }
+    }
+}
+
+/// Error returned when probing failed.
+#[derive(Debug)]
+pub enum ProbeError<E> {
+    /// The endpoint can't be used to create requests.
+    InvalidEndpoint(CreateRequestError),
+    /// Sending the request or receiving the response failed.
+    Transport(E),
+    /// The response is not a BIP78 error response.
+    NotPayjoinEndpoint,
+}
+
+impl<E: fmt::Display> fmt::Display for ProbeError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProbeError::InvalidEndpoint(_) => write!(f, "invalid endpoint"),
+            ProbeError::Transport(error) => write!(f, "failed to reach the endpoint: {}", error),
+            ProbeError::NotPayjoinEndpoint => write!(f, "the endpoint didn't respond as a payjoin receiver"),
+        }
+    }
+}
+
+impl<E: std::error::Error + 'static> std::error::Error for ProbeError<E> {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProbeError::InvalidEndpoint(error) => Some(error),
+            ProbeError::Transport(error) => Some(error),
+            ProbeError::NotPayjoinEndpoint => None,
+        }
+    }
+}
+
+/// Checks that the endpoint is alive and finds out which versions it supports.
+///
+/// This performs a blocking request using `transport`. Use `ProbeCache` to avoid probing the
+/// same endpoint repeatedly.
+pub fn probe_endpoint<T: Transport>(transport: &T, endpoint: &str) -> Result<EndpointInfo, ProbeError<T::Error>> {
+    probe(&SystemClock, transport, endpoint)
+}
+
+fn probe<T: Transport>(clock: &impl Clock, transport: &T, endpoint: &str) -> Result<EndpointInfo, ProbeError<T::Error>> {
+    let mut url = super::start_query(endpoint.to_owned()).map_err(|error| ProbeError::InvalidEndpoint(error.into()))?;
+    url.push_str("v=0");
+
+    let start = clock.now();
+    let response = transport.post(&url, &[]).map_err(ProbeError::Transport)?;
+    let latency = clock.now().duration_since(start).unwrap_or(Duration::from_secs(0));
+
+    let fields = serde_json::from_slice::<serde_json::Value>(&response).map_err(|_| ProbeError::NotPayjoinEndpoint)?;
+    // Any BIP78 error will do - other codes than version-unsupported just mean that the
+    // receiver didn't check the version and rejected the empty PSBT
+    if !matches!(fields.get("errorCode"), Some(serde_json::Value::String(_))) {
+        return Err(ProbeError::NotPayjoinEndpoint);
+    }
+    let supported_versions = fields
+        .get("supported")
+        .and_then(serde_json::Value::as_array)
+        .and_then(|versions| versions.iter().map(|version| version.as_u64().and_then(|version| u32::try_from(version).ok())).collect());
+    Ok(EndpointInfo {
+        supported_versions,
+        latency,
+    })
+}
+
+/// Caches the results of probing per endpoint.
+///
+/// Failures are not cached.
+pub struct ProbeCache<C: Clock = SystemClock> {
+    clock: C,
+    ttl: Duration,
+    entries: HashMap<String, (Deadline, EndpointInfo)>,
+}
+
+impl ProbeCache {
+    /// Creates the cache keeping the results for `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        Self::with_clock(SystemClock, ttl)
+    }
+}
+
+impl<C: Clock> ProbeCache<C> {
+    /// Creates the cache using a custom clock.
+    pub fn with_clock(clock: C, ttl: Duration) -> Self {
+        ProbeCache {
+            clock,
+            ttl,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Returns cached information about the endpoint or probes it.
+    pub fn probe<T: Transport>(&mut self, transport: &T, endpoint: &str) -> Result<EndpointInfo, ProbeError<T::Error>> {
+        if let Some((expiry, info)) = self.entries.get(endpoint) {
+            if !expiry.is_expired(&self.clock) {
+                return Ok(info.clone());
+            }
+        }
+        let info = probe(&self.clock, transport, endpoint)?;
+        self.entries.insert(endpoint.to_owned(), (Deadline::after(&self.clock, self.ttl), info.clone()));
+        Ok(info)
+    }
+
+    /// Removes expired entries.
+    pub fn prune(&mut self) {
+        let clock = &self.clock;
+        self.entries.retain(|_, (expiry, _)| !expiry.is_expired(clock));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::time::Duration;
+    use crate::testing::MockClock;
+    use crate::ProtocolVersion;
+    use super::{ProbeCache, ProbeError};
+
+    #[test]
+    fn probe() {
+        let clock = MockClock::new();
+        let requests = Cell::new(0);
+        let transport = |url: &str, body: &[u8]| {
+            assert_eq!(url, "https://example.com/pj?orderId=42&v=0");
+            assert!(body.is_empty());
+            requests.set(requests.get() + 1);
+            clock.advance(Duration::from_millis(300));
+            Ok::<_, std::io::Error>(br#"{"errorCode":"version-unsupported","supported":[1,2],"message":"nope"}"#.to_vec())
+        };
+        let mut cache = ProbeCache::with_clock(&clock, Duration::from_secs(60));
+        let info = cache.probe(&transport, "https://example.com/pj?orderId=42").unwrap();
+        assert_eq!(info.supported_versions, Some(vec![1, 2]));
+        assert_eq!(info.latency, Duration::from_millis(300));
+        assert_eq!(info.best_version(), Some(ProtocolVersion::V1));
+
+        cache.probe(&transport, "https://example.com/pj?orderId=42").unwrap();
+        assert_eq!(requests.get(), 1);
+        clock.advance(Duration::from_secs(60));
+        cache.probe(&transport, "https://example.com/pj?orderId=42").unwrap();
+        assert_eq!(requests.get(), 2);
+    }
+
+    #[test]
+    fn not_payjoin() {
+        let transport = |_: &str, _: &[u8]| Ok::<_, std::io::Error>(b"<html>Not found</html>".to_vec());
+        match super::probe_endpoint(&transport, "https://example.com/pj") {
+            Err(ProbeError::NotPayjoinEndpoint) => (),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+
+    #[test]
+    fn invalid_supported_versions() {
+        for supported in ["\"[1]\"", "[[1]]", "[1, -1]", "[4294967296]"] {
+            let response = format!(r#"{{"errorCode":"version-unsupported","supported":{}}}"#, supported);
+            let transport = |_: &str, _: &[u8]| Ok::<_, std::io::Error>(response.clone().into_bytes());
+            assert_eq!(super::probe_endpoint(&transport, "https://example.com/pj").unwrap().supported_versions, None);
+        }
+    }
+
+    #[cfg(feature = "receiver")]
+    #[test]
+    fn receiver() {
+        let transport = |url: &str, body: &[u8]| {
+            let query = &url[(url.find('?').unwrap() + 1)..];
+            let headers = crate::testing::MockHeaders::new(body.len() as u64);
+            let error = crate::receiver::UncheckedProposal::from_request_bytes(body, query, headers).err().unwrap();
+            Ok::<_, std::io::Error>(error.to_json().into_bytes())
+        };
+        let info = super::probe_endpoint(&transport, "https://example.com/pj").unwrap();
+        assert_eq!(info.supported_versions, Some(vec![1]));
+    }
+}
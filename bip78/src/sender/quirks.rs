@@ -0,0 +1,218 @@
+//! Known deviations of receivers from BIP78
+//!
+//! Receivers that re-serialize the PSBT with their own library often leave fields BIP78 forbids
+//! or doesn't expect. They are harmless but the validation has to tolerate them explicitly.
+//! Instead of scattering checks of implementation names this module describes the deviations as
+//! `Quirks`, keeps a registry of the ones known for each implementation and detects them from
+//! the response. The validation then consults the `Quirks` passed to `Params::quirks()` or
+//! `Context::tolerate()`.
+
+use bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+
+/// Payjoin implementation with known quirks.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum Implementation {
+    BtcPay,
+    JoinMarket,
+    Wasabi,
+}
+
+/// Entry of the registry.
+struct Known {
+    implementation: Implementation,
+    /// Product in the `Server` header identifying the implementation (case-insensitive).
+    server: &'static str,
+    quirks: Quirks,
+}
+
+/// The registry of implementations and their quirks.
+///
+/// Keep the quirks minimal - anything listed here is accepted from every receiver identifying
+/// itself by the header.
+const KNOWN: &[Known] = &[
+    Known {
+        implementation: Implementation::BtcPay,
+        server: "Kestrel",
+        quirks: Quirks {
+            implementation: Some(Implementation::BtcPay),
+            witness_utxo_in_sender_inputs: true,
+            output_key_paths: true,
+            ..Quirks::NONE
+        },
+    },
+    Known {
+        implementation: Implementation::JoinMarket,
+        server: "TwistedWeb",
+        quirks: Quirks {
+            implementation: Some(Implementation::JoinMarket),
+            empty_final_fields: true,
+            partial_sigs_in_finalized_inputs: true,
+            ..Quirks::NONE
+        },
+    },
+    Known {
+        implementation: Implementation::Wasabi,
+        server: "WalletWasabi",
+        quirks: Quirks {
+            implementation: Some(Implementation::Wasabi),
+            non_witness_utxo_in_sender_inputs: true,
+            input_key_paths: true,
+            ..Quirks::NONE
+        },
+    },
+];
+
+/// Deviations from BIP78 tolerated by the validation of the proposal.
+///
+/// Each tolerated deviation is removed from the resulting PSBT (or checked against the original
+/// one) and reported as a warning in `ValidationReport`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct Quirks {
+    /// The implementation these quirks were detected for, `None` if it wasn't identified.
+    pub implementation: Option<Implementation>,
+    /// Witness UTXO in sender inputs, only if it's equal to the one in the original PSBT.
+    pub witness_utxo_in_sender_inputs: bool,
+    /// Non-witness UTXO in sender inputs, only if it's equal to the one in the original PSBT.
+    pub non_witness_utxo_in_sender_inputs: bool,
+    /// Key paths in outputs.
+    pub output_key_paths: bool,
+    /// Key paths in inputs.
+    pub input_key_paths: bool,
+    /// Present but empty final script sig or witness, treated as missing.
+    pub empty_final_fields: bool,
+    /// Partial signatures in finalized inputs.
+    pub partial_sigs_in_finalized_inputs: bool,
+}
+
+impl Quirks {
+    /// Nothing is tolerated.
+    pub const NONE: Quirks = Quirks {
+        implementation: None,
+        witness_utxo_in_sender_inputs: false,
+        non_witness_utxo_in_sender_inputs: false,
+        output_key_paths: false,
+        input_key_paths: false,
+        empty_final_fields: false,
+        partial_sigs_in_finalized_inputs: false,
+    };
+
+    /// All known deviations are tolerated, used by `Params::compat()`.
+    pub const ALL: Quirks = Quirks {
+        implementation: None,
+        witness_utxo_in_sender_inputs: true,
+        non_witness_utxo_in_sender_inputs: true,
+        output_key_paths: true,
+        input_key_paths: true,
+        empty_final_fields: true,
+        partial_sigs_in_finalized_inputs: true,
+    };
+
+    /// Returns the quirks registered for `implementation`.
+    pub fn of(implementation: Implementation) -> Self {
+        KNOWN
+            .iter()
+            .find(|known| known.implementation == implementation)
+            .map(|known| known.quirks)
+            .unwrap_or(Quirks { implementation: Some(implementation), ..Quirks::NONE })
+    }
+
+    /// Identifies the implementation from the headers of the response and returns its quirks.
+    ///
+    /// Deviations visible in the `response` PSBT itself (key paths, empty final scripts, partial
+    /// signatures in finalized inputs) are detected by probing it, so receivers that aren't in
+    /// the registry work too. UTXOs in sender inputs can't be told apart from those of the
+    /// receiver without the original PSBT so they are tolerated only for known implementations.
+    /// Header names are case-insensitive, a response that isn't a PSBT is ignored.
+    pub fn detect<'a>(endpoint_headers: impl IntoIterator<Item=(&'a str, &'a str)>, response: &[u8]) -> Self {
+        let server = endpoint_headers
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("server"))
+            .map(|(_, value)| value);
+        let known = server.and_then(|server| KNOWN.iter().find(|known| server_matches(server, known.server)));
+        let quirks = known.map(|known| known.quirks).unwrap_or(Quirks::NONE);
+        match parse_response(response) {
+            Some(psbt) => quirks.union(&probe(&psbt)),
+            None => quirks,
+        }
+    }
+
+    /// Returns quirks tolerating deviations of both.
+    ///
+    /// The implementation of `self` is kept unless it's `None`.
+    pub fn union(&self, other: &Quirks) -> Self {
+        Quirks {
+            implementation: self.implementation.or(other.implementation),
+            witness_utxo_in_sender_inputs: self.witness_utxo_in_sender_inputs || other.witness_utxo_in_sender_inputs,
+            non_witness_utxo_in_sender_inputs: self.non_witness_utxo_in_sender_inputs || other.non_witness_utxo_in_sender_inputs,
+            output_key_paths: self.output_key_paths || other.output_key_paths,
+            input_key_paths: self.input_key_paths || other.input_key_paths,
+            empty_final_fields: self.empty_final_fields || other.empty_final_fields,
+            partial_sigs_in_finalized_inputs: self.partial_sigs_in_finalized_inputs || other.partial_sigs_in_finalized_inputs,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Self {
+        Quirks::NONE
+    }
+}
+
+/// Matches the product token of `Server` header, e.g. `Kestrel` or `TwistedWeb/22.4.0`.
+fn server_matches(header: &str, product: &str) -> bool {
+    header
+        .split_whitespace()
+        .filter_map(|token| token.split('/').next())
+        .any(|name| name.eq_ignore_ascii_case(product))
+}
+
+fn parse_response(response: &[u8]) -> Option<Psbt> {
+    let response = std::str::from_utf8(response).ok()?;
+    let bytes = base64::decode(response.trim()).ok()?;
+    bitcoin::consensus::deserialize(&bytes).ok()
+}
+
+/// Feature probes - deviations detectable without the original PSBT.
+fn probe(psbt: &Psbt) -> Quirks {
+    let is_empty_final = |input: &bitcoin::util::psbt::Input| {
+        matches!(&input.final_script_sig, Some(script) if script.is_empty())
+            || matches!(&input.final_script_witness, Some(witness) if witness.is_empty())
+    };
+    let is_finalized = |input: &bitcoin::util::psbt::Input| input.final_script_sig.is_some() || input.final_script_witness.is_some();
+    Quirks {
+        output_key_paths: psbt.outputs.iter().any(|output| !output.bip32_derivation.is_empty()),
+        input_key_paths: psbt.inputs.iter().any(|input| !input.bip32_derivation.is_empty()),
+        empty_final_fields: psbt.inputs.iter().any(is_empty_final),
+        partial_sigs_in_finalized_inputs: psbt.inputs.iter().any(|input| is_finalized(input) && !input.partial_sigs.is_empty()),
+        ..Quirks::NONE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect() {
+        let quirks = Quirks::detect(vec![("server", "TwistedWeb/22.4.0")], b"not a psbt");
+        assert_eq!(quirks, Quirks::of(Implementation::JoinMarket));
+        assert!(quirks.empty_final_fields);
+        assert!(!quirks.witness_utxo_in_sender_inputs);
+
+        let quirks = Quirks::detect(vec![("Content-Type", "text/plain"), ("Server", "Kestrel")], b"");
+        assert_eq!(quirks.implementation, Some(Implementation::BtcPay));
+        assert!(quirks.witness_utxo_in_sender_inputs);
+
+        assert_eq!(Quirks::detect(vec![("Server", "nginx")], b""), Quirks::NONE);
+
+        // unknown receiver leaving key paths in outputs
+        let mut psbt = crate::testing::original_psbt();
+        let key_source = (bitcoin::util::bip32::Fingerprint::default(), bitcoin::util::bip32::DerivationPath::master());
+        let key = bitcoin::PublicKey::from_slice(&[0x02; 33]).unwrap();
+        psbt.outputs[0].bip32_derivation.insert(key, key_source);
+        let response = base64::encode(bitcoin::consensus::serialize(&psbt));
+        let quirks = Quirks::detect(Vec::new(), response.as_bytes());
+        assert_eq!(quirks, Quirks { output_key_paths: true, ..Quirks::NONE });
+    }
+}
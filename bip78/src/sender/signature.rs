@@ -0,0 +1,116 @@
+//! Verification of the signatures of the receiver
+//!
+//! A receiver could contribute an input with an invalid signature. We would sign the proposal,
+//! fail to broadcast it and only broadcast the original transaction after the fallback timer
+//! expires. `Params::verify_receiver_signatures()` checks the signatures before we sign anything.
+//! There's no script interpreter here so only single-key inputs (P2PKH, P2WPKH and P2SH-P2WPKH)
+//! are verified, inputs of other types are accepted unverified.
+
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::hashes::{hash160, Hash};
+use bitcoin::secp256k1::{self, Secp256k1, VerifyOnly};
+use bitcoin::util::bip143::SigHashCache;
+use bitcoin::util::psbt::Input;
+use bitcoin::{PublicKey, Script, SigHash, SigHashType, Transaction, TxOut};
+
+/// Returns `true` if the input at `index` of `tx` spending `prevout` is finalized with a valid
+/// signature, `None` if its type is not supported.
+pub(crate) fn verify_input(secp: &Secp256k1<VerifyOnly>, tx: &Transaction, index: usize, prevout: &TxOut, input: &Input) -> Option<bool> {
+    let empty = Script::new();
+    let script_sig = input.final_script_sig.as_ref().unwrap_or(&empty);
+    let witness = input.final_script_witness.as_deref().unwrap_or(&[]);
+    let script_pubkey = &prevout.script_pubkey;
+
+    if script_pubkey.is_p2pkh() {
+        let valid = match push_data(script_sig).as_deref() {
+            Some([signature, pubkey]) if witness.is_empty() => {
+                let sighash = |_: &PublicKey| tx.signature_hash(index, script_pubkey, SigHashType::All.as_u32());
+                verify(secp, &script_pubkey.as_bytes()[3..23], signature, pubkey, sighash)
+            },
+            _ => false,
+        };
+        Some(valid)
+    } else if script_pubkey.is_v0_p2wpkh() {
+        Some(script_sig.is_empty() && verify_p2wpkh(secp, tx, index, prevout.value, script_pubkey, witness))
+    } else if script_pubkey.is_p2sh() {
+        let redeem_script = match push_data(script_sig).as_deref() {
+            Some([redeem_script]) => Script::from(redeem_script.to_vec()),
+            Some([]) => return Some(false),
+            _ => return None,
+        };
+        if hash160::Hash::hash(redeem_script.as_bytes())[..] != script_pubkey.as_bytes()[2..22] {
+            return Some(false);
+        }
+        if !redeem_script.is_v0_p2wpkh() {
+            return None;
+        }
+        Some(verify_p2wpkh(secp, tx, index, prevout.value, &redeem_script, witness))
+    } else {
+        None
+    }
+}
+
+fn verify_p2wpkh(secp: &Secp256k1<VerifyOnly>, tx: &Transaction, index: usize, value: u64, program: &Script, witness: &[Vec<u8>]) -> bool {
+    match witness {
+        [signature, pubkey] => {
+            let sighash = |pubkey: &PublicKey| SigHashCache::new(tx).signature_hash(index, &Script::new_p2pkh(&pubkey.pubkey_hash()), value, SigHashType::All);
+            verify(secp, &program.as_bytes()[2..], signature, pubkey, sighash)
+        },
+        _ => false,
+    }
+}
+
+/// Checks that `pubkey` hashes to `pubkey_hash` and that `signature` signs the `SIGHASH_ALL`
+/// sighash computed by `sighash`.
+fn verify(secp: &Secp256k1<VerifyOnly>, pubkey_hash: &[u8], signature: &[u8], pubkey: &[u8], sighash: impl FnOnce(&PublicKey) -> SigHash) -> bool {
+    if hash160::Hash::hash(pubkey)[..] != *pubkey_hash {
+        return false;
+    }
+    let der = match signature.split_last() {
+        Some((&flag, der)) if u32::from(flag) == SigHashType::All.as_u32() => der,
+        _ => return false,
+    };
+    let (pubkey, signature) = match (PublicKey::from_slice(pubkey), secp256k1::Signature::from_der(der)) {
+        (Ok(pubkey), Ok(signature)) => (pubkey, signature),
+        _ => return false,
+    };
+    let message = secp256k1::Message::from_slice(&sighash(&pubkey)[..]).expect("sighash has 32 bytes");
+    secp.verify(&message, &signature, &pubkey.key).is_ok()
+}
+
+/// Returns the pushed data if the script consists of pushes only.
+fn push_data(script: &Script) -> Option<Vec<&[u8]>> {
+    script.instructions()
+        .map(|instruction| match instruction {
+            Ok(Instruction::PushBytes(data)) => Some(data),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn official_vector() {
+        let proposal = crate::sender::load_psbt_from_base64(crate::testing::PROPOSAL_PSBT.as_bytes()).unwrap();
+        let secp = Secp256k1::verification_only();
+        let prevout = proposal.inputs[1].witness_utxo.clone().unwrap();
+        let tx = &proposal.global.unsigned_tx;
+        // the input of the receiver is P2SH-P2WPKH
+        assert_eq!(verify_input(&secp, tx, 1, &prevout, &proposal.inputs[1]), Some(true));
+
+        let mut tampered_tx = tx.clone();
+        tampered_tx.output[1].value += 1;
+        assert_eq!(verify_input(&secp, &tampered_tx, 1, &prevout, &proposal.inputs[1]), Some(false));
+        let mut tampered_input = proposal.inputs[1].clone();
+        tampered_input.final_script_witness.as_mut().unwrap()[0][10] ^= 1;
+        assert_eq!(verify_input(&secp, tx, 1, &prevout, &tampered_input), Some(false));
+        // the input of the sender is not signed
+        let prevout = crate::testing::original_psbt().inputs[0].witness_utxo.clone().unwrap();
+        assert_eq!(verify_input(&secp, tx, 0, &prevout, &proposal.inputs[0]), Some(false));
+        let bare = TxOut { script_pubkey: Script::from(vec![0x51]), ..prevout };
+        assert_eq!(verify_input(&secp, tx, 0, &bare, &proposal.inputs[0]), None);
+    }
+}
@@ -0,0 +1,130 @@
+//! Script types the payee output may be substituted with
+//!
+//! BIP78 lets the receiver replace the script of its output unless the sender disabled output
+//! substitution. Any script is valid but not every script is reasonable: a bare multisig or an
+//! `OP_RETURN` output would make the payment unusual (or burn it) while the sender still signs
+//! it. `Allowlist` restricts the substitutes, see `Params::substitution_script_policy()`.
+
+use bitcoin::blockdata::opcodes;
+use bitcoin::blockdata::script::Instruction;
+use bitcoin::Script;
+
+/// Type of an output script, see `Allowlist`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum ScriptType {
+    P2Pkh,
+    P2Sh,
+    P2Wpkh,
+    P2Wsh,
+    Taproot,
+    /// Witness program of a version without defined meaning yet (or of non-taproot size).
+    FutureSegWit,
+    /// `m <pubkey>... n OP_CHECKMULTISIG`
+    BareMultisig,
+    /// Output starting with `OP_RETURN`, unspendable.
+    OpReturn,
+    /// Anything else.
+    NonStandard,
+}
+
+impl ScriptType {
+    /// Returns the type of `script_pubkey`.
+    pub fn of(script_pubkey: &Script) -> Self {
+        use crate::output_type::OutputType;
+        use crate::input_type::SegWitV0Type;
+
+        match OutputType::from_script(script_pubkey) {
+            Some(OutputType::P2Pkh) => ScriptType::P2Pkh,
+            Some(OutputType::P2Sh) => ScriptType::P2Sh,
+            Some(OutputType::SegWitV0 { ty: SegWitV0Type::Pubkey, .. }) => ScriptType::P2Wpkh,
+            Some(OutputType::SegWitV0 { ty: SegWitV0Type::Script, .. }) | Some(OutputType::SegWitV0 { ty: SegWitV0Type::Multisig { .. }, .. }) => ScriptType::P2Wsh,
+            Some(OutputType::Taproot) => ScriptType::Taproot,
+            Some(OutputType::FutureSegWit { .. }) => ScriptType::FutureSegWit,
+            None if script_pubkey.is_op_return() => ScriptType::OpReturn,
+            None if is_bare_multisig(script_pubkey) => ScriptType::BareMultisig,
+            None => ScriptType::NonStandard,
+        }
+    }
+}
+
+/// Checks the `m <pubkey>... n OP_CHECKMULTISIG` template with up to 3 keys relayed by Bitcoin Core.
+fn is_bare_multisig(script_pubkey: &Script) -> bool {
+    let instructions = match script_pubkey.instructions().collect::<Result<Vec<_>, _>>() {
+        Ok(instructions) => instructions,
+        Err(_) => return false,
+    };
+    let small_number = |instruction: &Instruction<'_>| match instruction {
+        Instruction::Op(op) if (opcodes::all::OP_PUSHNUM_1.into_u8()..=opcodes::all::OP_PUSHNUM_16.into_u8()).contains(&op.into_u8()) => Some(usize::from(op.into_u8() - opcodes::all::OP_PUSHNUM_1.into_u8()) + 1),
+        _ => None,
+    };
+    match instructions.as_slice() {
+        [required, keys @ .., total, Instruction::Op(opcodes::all::OP_CHECKMULTISIG)] => {
+            let is_key = |instruction: &Instruction<'_>| matches!(instruction, Instruction::PushBytes(key) if key.len() == 33 || key.len() == 65);
+            match (small_number(required), small_number(total)) {
+                (Some(required), Some(total)) => total == keys.len() && required <= total && total <= 3 && keys.iter().all(is_key),
+                _ => false,
+            }
+        },
+        _ => false,
+    }
+}
+
+/// Script types the receiver may substitute the payee output with.
+///
+/// The default allows the standard payment types: P2PKH, P2SH, P2WPKH, P2WSH and Taproot. Future
+/// witness versions are not allowed by default since coins sent to them can be stolen until
+/// their meaning is defined.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Allowlist(Vec<ScriptType>);
+
+impl Allowlist {
+    /// Allows only the given types.
+    pub fn new(types: impl IntoIterator<Item=ScriptType>) -> Self {
+        Allowlist(types.into_iter().collect())
+    }
+
+    /// Allows the standard payment types, this is the default.
+    pub fn standard() -> Self {
+        Allowlist::new(vec![ScriptType::P2Pkh, ScriptType::P2Sh, ScriptType::P2Wpkh, ScriptType::P2Wsh, ScriptType::Taproot])
+    }
+
+    /// Returns `true` if the payee output may be substituted with `script_pubkey`.
+    pub fn allows(&self, script_pubkey: &Script) -> bool {
+        self.0.contains(&ScriptType::of(script_pubkey))
+    }
+}
+
+impl Default for Allowlist {
+    fn default() -> Self {
+        Allowlist::standard()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bitcoin::blockdata::script::Builder;
+    use super::*;
+
+    #[test]
+    fn script_types() {
+        let key = [0x02; 33];
+        let multisig = Builder::new()
+            .push_opcode(opcodes::all::OP_PUSHNUM_1)
+            .push_slice(&key)
+            .push_slice(&key)
+            .push_opcode(opcodes::all::OP_PUSHNUM_2)
+            .push_opcode(opcodes::all::OP_CHECKMULTISIG)
+            .into_script();
+        assert_eq!(ScriptType::of(&multisig), ScriptType::BareMultisig);
+        assert_eq!(ScriptType::of(&Script::new_op_return(&[42])), ScriptType::OpReturn);
+        assert_eq!(ScriptType::of(&Script::new_v0_wsh(&multisig.wscript_hash())), ScriptType::P2Wsh);
+        assert_eq!(ScriptType::of(&Script::from(vec![0x51])), ScriptType::NonStandard);
+
+        let allowlist = Allowlist::default();
+        assert!(allowlist.allows(&Script::new_p2sh(&multisig.script_hash())));
+        assert!(!allowlist.allows(&multisig));
+        assert!(!allowlist.allows(&Script::new_op_return(&[42])));
+        assert!(Allowlist::new(vec![ScriptType::BareMultisig]).allows(&multisig));
+    }
+}
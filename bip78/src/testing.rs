@@ -0,0 +1,92 @@
+//! Test vectors and mock types
+//!
+//! This module contains data and types that allow you to exercise the PayJoin flow without
+//! running a node or an HTTP server - in unit tests and examples. It's not part of the public
+//! API and may change in any release.
+
+/// Original PSBT test vector from BIP78.
+///
+/// Pays `0.02` BTC to `3CZZi7aWFugaCdUCS15dgrUUViupmB8bVM`, see `URI`.
+pub const ORIGINAL_PSBT: &str = "cHNidP8BAHMCAAAAAY8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////AtyVuAUAAAAAF6kUHehJ8GnSdBUOOv6ujXLrWmsJRDCHgIQeAAAAAAAXqRR3QJbbz0hnQ8IvQ0fptGn+votneofTAAAAAAEBIKgb1wUAAAAAF6kU3k4ekGHKWRNbA1rV5tR5kEVDVNCHAQcXFgAUx4pFclNVgo1WWAdN1SYNX8tphTABCGsCRzBEAiB8Q+A6dep+Rz92vhy26lT0AjZn4PRLi8Bf9qoB/CMk0wIgP/Rj2PWZ3gEjUkTlhDRNAQ0gXwTO7t9n+V14pZ6oljUBIQMVmsAaoNWHVMS02LfTSe0e388LNitPa1UQZyOihY+FFgABABYAFEb2Giu6c4KO5YW0pfw3lGp9jMUUAAA=";
+
+/// Proposal accepted by the sender in response to `ORIGINAL_PSBT`.
+///
+/// This is the proposal test vector from BIP78 with key paths and UTXO information of the
+/// sender's input stripped, as required by the specification.
+pub const PROPOSAL_PSBT: &str = "cHNidP8BAJwCAAAAAo8nutGgJdyYGXWiBEb45Hoe9lWGbkxh/6bNiOJdCDuDAAAAAAD+////jye60aAl3JgZdaIERvjkeh72VYZuTGH/ps2I4l0IO4MBAAAAAP7///8CJpW4BQAAAAAXqRQd6EnwadJ0FQ46/q6NcutaawlEMIcACT0AAAAAABepFHdAltvPSGdDwi9DR+m0af6+i2d6h9MAAAAAAQQWABTHikVyU1WCjVZYB03VJg1fy2mFMAABASCAhB4AAAAAABepFMjyy/nKYUcsiORk1Gs1dIRdkdjVhwEHFxYAFF+AZlXlkkySBMLVG+U5T0v57aIQAQhrAkcwRAIgZ7t7QN8lpU9FgQpbF10vY+vQbfsGgrPSXI1rTY7b+1YCIHtlWAGgXizwwehxhZaajhDVzJXbCXsxfRrOWprw0EQ0ASECZdY89bQbfITcdqME5vuA7uIrMdKZnSjTDSI42jBxX60AAQAWABRG9horunOCjuWFtKX8N5RqfYzFFAAA";
+
+/// Returns decoded `ORIGINAL_PSBT`.
+pub fn original_psbt() -> bitcoin::util::psbt::PartiallySignedTransaction {
+    use bitcoin::consensus::Decodable;
+
+    let mut input = ORIGINAL_PSBT.as_bytes();
+    let reader = base64::read::DecoderReader::new(&mut input, base64::STANDARD);
+    bitcoin::util::psbt::PartiallySignedTransaction::consensus_decode(reader)
+        .expect("the test vector is valid")
+}
+
+/// Payment link that is paid by `ORIGINAL_PSBT`.
+pub const URI: &str = "bitcoin:3CZZi7aWFugaCdUCS15dgrUUViupmB8bVM?amount=0.02&pj=https://example.com/pj";
+
+/// Headers of a well-formed PayJoin request.
+///
+/// Returns `text/plain` as content type and the content length given in constructor.
+#[cfg(feature = "receiver")]
+pub struct MockHeaders {
+    content_length: String,
+}
+
+#[cfg(feature = "receiver")]
+impl MockHeaders {
+    pub fn new(content_length: u64) -> MockHeaders {
+        MockHeaders { content_length: content_length.to_string(), }
+    }
+}
+
+#[cfg(feature = "receiver")]
+impl crate::receiver::Headers for MockHeaders {
+    fn get_header(&self, key: &str) -> Option<&str> {
+        match key {
+            "content-length" => Some(&self.content_length),
+            "content-type" => Some("text/plain"),
+            _ => None,
+        }
+    }
+}
+
+/// Clock that only moves when told to.
+///
+/// Starts at an arbitrary fixed time so that tests are reproducible.
+#[derive(Debug)]
+pub struct MockClock {
+    now: std::sync::Mutex<std::time::SystemTime>,
+}
+
+impl MockClock {
+    /// Creates the clock set to 2021-07-29 00:00:00 UTC.
+    pub fn new() -> Self {
+        Self::at(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_627_516_800))
+    }
+
+    /// Creates the clock set to given time.
+    pub fn at(time: std::time::SystemTime) -> Self {
+        MockClock { now: std::sync::Mutex::new(time), }
+    }
+
+    /// Moves the clock forward.
+    pub fn advance(&self, duration: std::time::Duration) {
+        *self.now.lock().expect("mutex not poisoned") += duration;
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl crate::time::Clock for MockClock {
+    fn now(&self) -> std::time::SystemTime {
+        *self.now.lock().expect("mutex not poisoned")
+    }
+}
@@ -0,0 +1,88 @@
+//! Time abstraction
+//!
+//! Timing-sensitive components (deadlines, expiry, rate limiting) don't call
+//! `SystemTime::now()` directly but ask a `Clock`. This allows deterministic tests without
+//! sleeping - see `testing::MockClock`.
+
+use std::time::{Duration, SystemTime};
+use std::sync::Arc;
+
+/// Source of current time.
+pub trait Clock {
+    fn now(&self) -> SystemTime;
+}
+
+/// Clock using the system time.
+///
+/// This is what you want to use in production.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for &C {
+    fn now(&self) -> SystemTime {
+        (*self).now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> SystemTime {
+        (**self).now()
+    }
+}
+
+/// Point in time after which some action should be taken (or not taken anymore).
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub struct Deadline(SystemTime);
+
+impl Deadline {
+    /// Creates a deadline that expires `duration` after now.
+    pub fn after(clock: &impl Clock, duration: Duration) -> Self {
+        Deadline(clock.now() + duration)
+    }
+
+    /// Creates a deadline expiring at given time.
+    pub fn at(time: SystemTime) -> Self {
+        Deadline(time)
+    }
+
+    /// Returns the time at which the deadline expires.
+    pub fn time(&self) -> SystemTime {
+        self.0
+    }
+
+    /// Returns `true` if the deadline already passed.
+    pub fn is_expired(&self, clock: &impl Clock) -> bool {
+        clock.now() >= self.0
+    }
+
+    /// Returns how much time remains until the deadline or zero if it already passed.
+    pub fn remaining(&self, clock: &impl Clock) -> Duration {
+        self.0.duration_since(clock.now()).unwrap_or(Duration::from_secs(0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::MockClock;
+
+    #[test]
+    fn deadline() {
+        let clock = MockClock::new();
+        let deadline = Deadline::after(&clock, Duration::from_secs(60));
+        assert!(!deadline.is_expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::from_secs(60));
+        clock.advance(Duration::from_secs(59));
+        assert!(!deadline.is_expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::from_secs(1));
+        clock.advance(Duration::from_secs(2));
+        assert!(deadline.is_expired(&clock));
+        assert_eq!(deadline.remaining(&clock), Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,65 @@
+//! Versions of the PayJoin protocol
+
+use std::fmt;
+
+/// Version of the PayJoin protocol.
+///
+/// The version is sent by the sender in the `v` parameter. The receiver responds with
+/// `version-unsupported` error if it doesn't know it.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[non_exhaustive]
+pub enum ProtocolVersion {
+    /// The protocol as defined in BIP78.
+    V1,
+}
+
+impl ProtocolVersion {
+    /// All versions supported by this library, oldest first.
+    pub const SUPPORTED: &'static [ProtocolVersion] = &[ProtocolVersion::V1];
+
+    /// Returns the number used in the `v` parameter.
+    pub fn number(self) -> u32 {
+        match self {
+            ProtocolVersion::V1 => 1,
+        }
+    }
+
+    /// Returns the version with given number if it's supported.
+    pub fn from_number(number: u32) -> Option<Self> {
+        Self::SUPPORTED.iter().copied().find(|version| version.number() == number)
+    }
+
+    /// Returns `true` if the sender can allow the receiver to take the fee from sender's output.
+    pub fn supports_fee_contribution(self) -> bool {
+        match self {
+            ProtocolVersion::V1 => true,
+        }
+    }
+
+    /// Returns `true` if the receiver can replace or decrease its output.
+    pub fn supports_output_substitution(self) -> bool {
+        match self {
+            ProtocolVersion::V1 => true,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.number())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ProtocolVersion;
+
+    #[test]
+    fn numbers() {
+        for version in ProtocolVersion::SUPPORTED {
+            assert_eq!(ProtocolVersion::from_number(version.number()), Some(*version));
+        }
+        assert_eq!(ProtocolVersion::from_number(0), None);
+        assert_eq!(ProtocolVersion::from_number(2), None);
+    }
+}
@@ -4,6 +4,7 @@ pub(crate) use inner::Weight;
 
 // ensure explicit constructor
 mod inner {
+    use std::convert::TryFrom;
     use std::ops::{Add, Sub, AddAssign, SubAssign, Mul, Div};
     use crate::fee_rate::FeeRate;
 
@@ -72,8 +73,10 @@ mod inner {
     impl Div<Weight> for bitcoin::Amount {
         type Output = FeeRate;
 
+        /// Saturates at the maximum fee rate instead of overflowing.
         fn div(self, rhs: Weight) -> Self::Output {
-            FeeRate::from_sat_per_wu(self.as_sat() / rhs.0)
+            let rate = u128::from(self.as_sat()) * 1000 / u128::from(rhs.0);
+            FeeRate::from_sat_per_kwu(u64::try_from(rate).unwrap_or(u64::MAX))
         }
     }
 }
@@ -99,7 +102,7 @@ pub(crate) trait ComputeSize {
     fn encoded_size(&self) -> u64;
 }
 
-fn varint_size(number: u64) -> u64 {
+pub(crate) fn varint_size(number: u64) -> u64 {
     match number {
         0..=0xfc => 1,
         0xfd..=0xffff => 3,
@@ -137,3 +140,16 @@ impl ComputeWeight for Transaction {
         Weight::manual_from_u64(self.get_weight() as u64)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Weight;
+    use bitcoin::Amount;
+
+    #[test]
+    fn fee_rate() {
+        assert_eq!((Amount::from_sat(500) / Weight::from_witness_data_size(1000)).to_sat_per_kwu(), 500);
+        assert_eq!((Amount::from_sat(u64::MAX / 10) / Weight::from_witness_data_size(1000)).to_sat_per_kwu(), u64::MAX / 10);
+        assert_eq!((Amount::from_sat(u64::MAX) / Weight::from_witness_data_size(1)).to_sat_per_kwu(), u64::MAX);
+    }
+}
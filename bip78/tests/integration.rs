@@ -0,0 +1,253 @@
+//! End-to-end payjoins across script types
+//!
+//! Runs the sender and the receiver against each other for every combination of address types
+//! of the sender and the receiver (legacy, p2sh-segwit, bech32, bech32m) and both contribution
+//! strategies. The wallets are synthetic: each one owns a single key and signs with signatures
+//! of realistic size so the weights match what a real wallet would produce, which is all the
+//! fee checks of either side look at.
+
+#![cfg(all(feature = "sender", feature = "receiver"))]
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+use bip78::bitcoin::blockdata::script::Builder;
+use bip78::bitcoin::secp256k1::{self, Secp256k1};
+use bip78::bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bip78::bitcoin::{self, Address, Amount, Network, OutPoint, Script, Transaction, TxIn, TxOut};
+use bip78::receiver::{OriginalChecks, PayjoinReceiver, PrevoutStatus, Request, RequestMeta, Strategy};
+use bip78::sender::Params;
+use bip78::testing::MockHeaders;
+use bip78::Uri;
+
+const PAYMENT: u64 = 100_000;
+const FEE_RATE: u64 = 2;
+const MAX_FEE_CONTRIBUTION: u64 = 10_000;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum AddressType {
+    Legacy,
+    P2shSegwit,
+    Bech32,
+    Bech32m,
+}
+
+impl AddressType {
+    const ALL: [AddressType; 4] = [AddressType::Legacy, AddressType::P2shSegwit, AddressType::Bech32, AddressType::Bech32m];
+
+    /// The receiver can contribute to senders of this type.
+    ///
+    /// The fee of the contributed input is computed from the expected weight of the inputs of
+    /// the sender which is not known for taproot.
+    fn is_contributable(self) -> bool {
+        self != AddressType::Bech32m
+    }
+}
+
+/// Wallet owning a single key.
+#[derive(Clone)]
+struct Wallet {
+    address_type: AddressType,
+    key: bitcoin::PublicKey,
+}
+
+impl Wallet {
+    fn new(address_type: AddressType, seed: u8) -> Self {
+        let secret_key = secp256k1::SecretKey::from_slice(&[seed; 32]).expect("valid key");
+        let key = secp256k1::PublicKey::from_secret_key(&Secp256k1::signing_only(), &secret_key);
+        Wallet { address_type, key: bitcoin::PublicKey { compressed: true, key, }, }
+    }
+
+    fn witness_program(&self) -> Script {
+        Script::new_v0_wpkh(&self.key.wpubkey_hash().expect("compressed key"))
+    }
+
+    fn script_pubkey(&self) -> Script {
+        match self.address_type {
+            AddressType::Legacy => Script::new_p2pkh(&self.key.pubkey_hash()),
+            AddressType::P2shSegwit => Script::new_p2sh(&self.witness_program().script_hash()),
+            AddressType::Bech32 => self.witness_program(),
+            AddressType::Bech32m => {
+                let version = bitcoin::bech32::u5::try_from_u8(1).expect("valid version");
+                Script::new_witness_program(version, &self.key.to_bytes()[1..])
+            },
+        }
+    }
+
+    /// Returns a coin of this wallet worth `value`.
+    fn coin(&self, value: u64, seed: u32) -> (OutPoint, psbt::Input) {
+        let funding = Transaction {
+            version: 2,
+            lock_time: seed,
+            input: vec![TxIn::default()],
+            output: vec![TxOut { value, script_pubkey: self.script_pubkey(), }],
+        };
+        let mut input = psbt::Input::default();
+        if self.address_type == AddressType::Legacy {
+            input.non_witness_utxo = Some(funding.clone());
+        } else {
+            input.witness_utxo = Some(funding.output[0].clone());
+        }
+        (OutPoint::new(funding.txid(), 0), input)
+    }
+
+    /// Finalizes `input` with a signature of the size produced by real signers.
+    fn sign(&self, input: &mut psbt::Input) {
+        // DER-encoded signature with 32-byte R and S followed by SIGHASH_ALL
+        let mut signature = vec![0x30, 0x44, 0x02, 0x20];
+        signature.extend_from_slice(&[0x11; 32]);
+        signature.extend_from_slice(&[0x02, 0x20]);
+        signature.extend_from_slice(&[0x22; 32]);
+        signature.push(0x01);
+        let key = self.key.to_bytes();
+        match self.address_type {
+            AddressType::Legacy => {
+                input.final_script_sig = Some(Builder::new().push_slice(&signature).push_slice(&key).into_script());
+            },
+            AddressType::P2shSegwit => {
+                input.final_script_sig = Some(Builder::new().push_slice(self.witness_program().as_bytes()).into_script());
+                input.final_script_witness = Some(vec![signature, key]);
+            },
+            AddressType::Bech32 => input.final_script_witness = Some(vec![signature, key]),
+            AddressType::Bech32m => input.final_script_witness = Some(vec![vec![0x33; 64]]),
+        }
+    }
+}
+
+struct Node {
+    locked: Mutex<HashSet<OutPoint>>,
+}
+
+impl OriginalChecks for Node {
+    type Error = std::io::Error;
+
+    fn prevout_status(&self, _: &OutPoint) -> Result<PrevoutStatus, Self::Error> {
+        Ok(PrevoutStatus::Unspent)
+    }
+
+    fn can_broadcast(&self, _: &Transaction) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+
+    fn lock_inputs(&self, outpoints: &[OutPoint]) -> Result<bool, Self::Error> {
+        let mut locked = self.locked.lock().unwrap();
+        if outpoints.iter().any(|outpoint| locked.contains(outpoint)) {
+            return Ok(false);
+        }
+        locked.extend(outpoints);
+        Ok(true)
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum StrategyKind {
+    BestInput,
+    Decoy,
+}
+
+/// Signed original transaction of `sender` paying `payee`, the change is the first output.
+fn original_psbt(sender: &Wallet, payee: &Script) -> Psbt {
+    let (outpoint, mut input) = sender.coin(1_000_000, 0);
+    let mut tx = Transaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn { previous_output: outpoint, sequence: 0xfffffffd, ..Default::default() }],
+        output: vec![
+            TxOut { value: 0, script_pubkey: sender.script_pubkey(), },
+            TxOut { value: PAYMENT, script_pubkey: payee.clone(), },
+        ],
+    };
+    sender.sign(&mut input);
+    let mut signed = tx.clone();
+    signed.input[0].script_sig = input.final_script_sig.clone().unwrap_or_default();
+    signed.input[0].witness = input.final_script_witness.clone().unwrap_or_default();
+    let fee = FEE_RATE * signed.get_weight() as u64 / 4;
+    tx.output[0].value = 1_000_000 - PAYMENT - fee;
+    let mut psbt = Psbt::from_unsigned_tx(tx).expect("unsigned");
+    psbt.inputs[0] = input;
+    psbt
+}
+
+/// Fee and weight of the finalized transaction, `psbt` must have UTXO information of all inputs.
+fn fee_and_weight(psbt: &Psbt) -> (u64, u64) {
+    let input_value = psbt.inputs
+        .iter()
+        .zip(&psbt.global.unsigned_tx.input)
+        .map(|(input, txin)| match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(txout), _) => txout.value,
+            (None, Some(tx)) => tx.output[txin.previous_output.vout as usize].value,
+            (None, None) => panic!("missing UTXO information"),
+        })
+        .sum::<u64>();
+    let output_value = psbt.global.unsigned_tx.output.iter().map(|output| output.value).sum::<u64>();
+    (input_value - output_value, psbt.clone().extract_tx().get_weight() as u64)
+}
+
+/// Pays `receiver` from `sender`, returns `true` if the receiver contributed an input.
+///
+/// Panics if either side rejects the payjoin or the receiver didn't pay for its input.
+fn payjoin(sender: &Wallet, receiver: &Wallet, strategy: StrategyKind) -> bool {
+    let payee = receiver.script_pubkey();
+    let original = original_psbt(sender, &payee);
+    let (original_fee, original_weight) = fee_and_weight(&original);
+
+    let address = Address::from_script(&payee, Network::Bitcoin).expect("standard script");
+    let uri = Uri::new(address, Amount::from_sat(PAYMENT), "https://example.com/pj").expect("valid endpoint");
+    let params = Params::with_fee_contribution(Amount::from_sat(MAX_FEE_CONTRIBUTION), Some(0));
+    let (request, context) = uri.create_request(original.clone(), params).expect("valid original");
+    let query = request.url.split_once('?').map_or("", |(_, query)| query);
+
+    // large enough to fund a decoy mimicking the change of the sender
+    let coin = receiver.coin(2_000_000, 1);
+    let signing_wallet = receiver.clone();
+    let signer = move |psbt: &Psbt, index: usize| {
+        let mut input = psbt.inputs[index].clone();
+        signing_wallet.sign(&mut input);
+        Ok::<_, std::io::Error>(input)
+    };
+    let strategy = match strategy {
+        StrategyKind::BestInput => Strategy::best_input(),
+        StrategyKind::Decoy => {
+            let decoy = Wallet::new(receiver.address_type, 3).script_pubkey();
+            Strategy::decoy(move || Ok::<_, std::io::Error>(decoy.clone()))
+        },
+    };
+    let payjoin_receiver = PayjoinReceiver::builder()
+        .checks(Node { locked: Mutex::new(HashSet::new()), })
+        .utxo_source(move || Ok::<_, std::io::Error>(vec![coin.clone()]), signer)
+        .strategy(strategy)
+        .build();
+    let response = payjoin_receiver.process(Request {
+        body: &request.body,
+        query,
+        headers: MockHeaders::new(request.body.len() as u64),
+        issued_script: &payee,
+        meta: RequestMeta::default(),
+    });
+    assert_eq!(response.status, 200, "{}", String::from_utf8_lossy(&response.body));
+
+    let mut proposal = context.process_response_bytes(&response.body).expect("the sender accepts the proposal");
+    let contributed = proposal.inputs.len() > original.inputs.len();
+    // signed by the sender again
+    let sender_outpoint = original.global.unsigned_tx.input[0].previous_output;
+    let sender_index = proposal.global.unsigned_tx.input.iter().position(|txin| txin.previous_output == sender_outpoint).expect("the input of the sender");
+    proposal.inputs[sender_index] = original.inputs[0].clone();
+    let (fee, weight) = fee_and_weight(&proposal);
+    // the input of the receiver is paid at the fee rate of the original transaction, with
+    // tolerance for the difference between its expected and actual weight
+    assert!(fee * original_weight * 100 >= original_fee * weight * 99, "fee rate dropped from {}/{} to {}/{} sat/WU", original_fee, original_weight, fee, weight);
+    assert!(fee <= original_fee + MAX_FEE_CONTRIBUTION + FEE_RATE * (weight - original_weight) / 4);
+    contributed
+}
+
+#[test]
+fn script_type_matrix() {
+    for &sender_type in &AddressType::ALL {
+        for &receiver_type in &AddressType::ALL {
+            for &strategy in &[StrategyKind::BestInput, StrategyKind::Decoy] {
+                let contributed = payjoin(&Wallet::new(sender_type, 1), &Wallet::new(receiver_type, 2), strategy);
+                let expected = sender_type == receiver_type && sender_type.is_contributable();
+                assert_eq!(contributed, expected, "{:?} paying {:?} using {:?}", sender_type, receiver_type, strategy);
+            }
+        }
+    }
+}
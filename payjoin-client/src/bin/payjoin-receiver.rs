@@ -0,0 +1,332 @@
+//! Manual receiver
+//!
+//! `propose` crafts a proposal from an original PSBT without any network access. The proposal
+//! is signed only if `--signer` is given, otherwise sign it with your wallet before sending it
+//! back. With `--nostr` the original PSBT is received through a Nostr relay instead and the
+//! signed proposal is sent back the same way (experimental, requires the `nostr` feature).
+//!
+//! `gen-vectors` produces test vectors for other implementations using a regtest node, see
+//! `payjoin_client::vectors`.
+
+use std::cell::RefCell;
+use std::ffi::OsString;
+use bip78::bitcoin::{Address, Amount, OutPoint, Script, TxOut, Txid};
+use bip78::bitcoin::hashes::hex::FromHex;
+use bip78::bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use std::error::Error;
+use bip78::receiver::{Headers, ReceiverOptions, UncheckedProposal};
+use payjoin_client::{config, load_psbt_from_base64, serialize_psbt, vectors};
+use payjoin_client::signer::{self, Signer};
+
+const USAGE: &str = "Usage: payjoin-receiver propose (--original <base64 PSBT> | --nostr <secret key> --relay <url>) --payee <address> --add-utxo <txid:vout:amount:script> [--add-utxo ...] [--disable-output-substitution] [--signer <signer>] [--config <file> [--profile <name>]]
+       payjoin-receiver gen-vectors --port <RPC port> --cookie <cookie file> [--out <file>]
+
+The amount is in satoshis and the script is hex-encoded script_pubkey. The proposal is printed to
+stdout, fee and weight changes to stderr. The receiver section of the profile (default if not
+given) sets output substitution, the signer and allowed input types of the sender.
+
+--nostr waits for a request sent to the hex-encoded secret key through the relay (ws:// or wss://),
+responds with the signed proposal or the error and exits. It requires a signer and the nostr
+feature. The parameters of the request are used instead of --disable-output-substitution.
+
+gen-vectors runs payjoins between all script types of the regtest wallet and writes them as JSON to
+the file or stdout.
+
+Signers:
+    core:<port>:<cookie_file>       walletprocesspsbt of a local bitcoind
+    hwi[:<fingerprint>[:<chain>]]   hardware wallet accessed using hwi, chain is e.g. test
+    wif:<private key>[,...]         private keys of P2WPKH or P2SH-P2WPKH UTXOs (testing only!)";
+
+/// Where the original PSBT comes from.
+enum Source {
+    Original(String),
+    Nostr { secret_key: String, relay: String, },
+}
+
+/// Sends the status and the body back to the sender of a received request.
+type Respond = Box<dyn FnOnce(u16, &[u8])>;
+
+struct Args {
+    source: Source,
+    payee: Address,
+    utxos: Vec<(OutPoint, TxOut)>,
+    disable_output_substitution: bool,
+    signer: Option<Box<dyn Signer>>,
+    options: ReceiverOptions,
+}
+
+fn parse_utxo(utxo: &str) -> Result<(OutPoint, TxOut), String> {
+    let mut parts = utxo.splitn(4, ':');
+    let mut part = |name| parts.next().ok_or_else(|| format!("missing {} in UTXO {}", name, utxo));
+    let txid = part("txid")?.parse::<Txid>().map_err(|error| format!("invalid txid: {}", error))?;
+    let vout = part("vout")?.parse::<u32>().map_err(|error| format!("invalid vout: {}", error))?;
+    let value = part("amount")?.parse::<u64>().map_err(|error| format!("invalid amount: {}", error))?;
+    let script = Vec::<u8>::from_hex(part("script")?).map_err(|error| format!("invalid script: {}", error))?;
+    Ok((OutPoint { txid, vout, }, TxOut { value, script_pubkey: Script::from(script), }))
+}
+
+struct GenVectorsArgs {
+    port: u16,
+    cookie: String,
+    out: Option<String>,
+}
+
+enum Command {
+    Propose(Box<Args>),
+    GenVectors(GenVectorsArgs),
+}
+
+fn parse_gen_vectors_args(mut args: impl Iterator<Item=Result<String, String>>) -> Result<GenVectorsArgs, String> {
+    let mut port = None;
+    let mut cookie = None;
+    let mut out = None;
+    while let Some(arg) = args.next() {
+        let arg = arg?;
+        let mut value = || args.next().unwrap_or_else(|| Err(format!("missing value of {}", arg)));
+        match &*arg {
+            "--port" => port = Some(value()?.parse::<u16>().map_err(|error| format!("invalid port: {}", error))?),
+            "--cookie" => cookie = Some(value()?),
+            "--out" => out = Some(value()?),
+            "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+    Ok(GenVectorsArgs {
+        port: port.ok_or("missing --port")?,
+        cookie: cookie.ok_or("missing --cookie")?,
+        out,
+    })
+}
+
+fn parse_args(args: impl Iterator<Item=OsString>) -> Result<Command, String> {
+    let mut original = None;
+    let mut nostr = None;
+    let mut relay = None;
+    let mut payee = None;
+    let mut utxos = Vec::new();
+    let mut disable_output_substitution = false;
+    let mut signer = None;
+    let mut config = None;
+    let mut profile = None;
+
+    let mut args = args.map(|arg| arg.into_string().map_err(|arg| format!("argument {:?} is not UTF-8", arg)));
+    match args.next().transpose()?.as_deref() {
+        Some("propose") => (),
+        Some("gen-vectors") => return parse_gen_vectors_args(args).map(Command::GenVectors),
+        Some("--help") | None => return Err(String::new()),
+        Some(command) => return Err(format!("unknown command {}", command)),
+    }
+    while let Some(arg) = args.next() {
+        let arg = arg?;
+        let mut value = || args.next().unwrap_or_else(|| Err(format!("missing value of {}", arg)));
+        match &*arg {
+            "--original" => original = Some(value()?),
+            "--nostr" => nostr = Some(value()?),
+            "--relay" => relay = Some(value()?),
+            "--payee" => payee = Some(value()?.parse::<Address>().map_err(|error| format!("invalid payee: {}", error))?),
+            "--add-utxo" => utxos.push(parse_utxo(&value()?)?),
+            "--disable-output-substitution" => disable_output_substitution = true,
+            "--signer" => signer = Some(value()?),
+            "--config" => config = Some(value()?),
+            "--profile" => profile = Some(value()?),
+            "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+
+    let profile = match config {
+        Some(config) => config::load(config, profile.as_deref().unwrap_or("default")).map_err(|error| match error.source() {
+            Some(source) => format!("{}: {}", error, source),
+            None => error.to_string(),
+        })?,
+        None if profile.is_some() => return Err("--profile requires --config".to_owned()),
+        None => config::Profile::default(),
+    };
+    let signer = match signer.or(profile.signer) {
+        Some(signer) => Some(signer::from_arg(&signer)?),
+        None => None,
+    };
+
+    let source = match (original, nostr, relay) {
+        (Some(original), None, None) => Source::Original(original),
+        (None, Some(_), Some(_)) if signer.is_none() => return Err("--nostr requires a signer".to_owned()),
+        (None, Some(secret_key), Some(relay)) => Source::Nostr { secret_key, relay, },
+        (None, Some(_), None) => return Err("--nostr requires --relay".to_owned()),
+        (None, None, _) => return Err("missing --original".to_owned()),
+        _ => return Err("--original conflicts with --nostr and --relay".to_owned()),
+    };
+
+    Ok(Command::Propose(Box::new(Args {
+        source,
+        payee: payee.ok_or("missing --payee")?,
+        utxos,
+        disable_output_substitution: disable_output_substitution || profile.receiver.disable_output_substitution,
+        signer,
+        options: profile.receiver.options(),
+    })))
+}
+
+/// Headers of a request that was never sent.
+struct OfflineHeaders(String);
+
+impl Headers for OfflineHeaders {
+    fn get_header(&self, key: &str) -> Option<&str> {
+        match key {
+            "content-type" => Some("text/plain"),
+            "content-length" => Some(&self.0),
+            _ => None,
+        }
+    }
+}
+
+/// Returns the values of inputs and outputs.
+fn totals(psbt: &Psbt) -> (u64, u64) {
+    let inputs = psbt.global.unsigned_tx.input
+        .iter()
+        .zip(&psbt.inputs)
+        .map(|(txin, input)| match (&input.witness_utxo, &input.non_witness_utxo) {
+            (Some(txout), _) => txout.value,
+            (None, Some(tx)) => tx.output[txin.previous_output.vout as usize].value,
+            (None, None) => panic!("missing UTXO of input {}", txin.previous_output),
+        })
+        .sum();
+    let outputs = psbt.global.unsigned_tx.output.iter().map(|output| output.value).sum();
+    (inputs, outputs)
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    std::process::exit(1);
+}
+
+#[cfg(feature = "nostr")]
+fn receive_nostr(secret_key: &str, relay: &str) -> (String, String, Respond) {
+    use bip78::bitcoin::secp256k1::SecretKey;
+    use bip78::nostr::{Endpoint, Keys};
+
+    let secret_key = secret_key.parse::<SecretKey>().unwrap_or_else(|error| fail(format_args!("invalid secret key: {}", error)));
+    let keys = Keys::from_secret_key(secret_key);
+    eprintln!("waiting for a request to {}", Endpoint { receiver: *keys.public_key(), relay: relay.to_owned(), });
+    let incoming = payjoin_client::nostr::receive(keys, relay, None).unwrap_or_else(|error| match error.source() {
+        Some(source) => fail(format_args!("{}: {}", error, source)),
+        None => fail(error),
+    });
+    let body = incoming.body.clone();
+    let query = incoming.query.clone();
+    let respond = move |status, body: &[u8]| {
+        incoming.respond(status, body).unwrap_or_else(|error| fail(format_args!("failed to send the response: {}", error)))
+    };
+    (body, query, Box::new(respond))
+}
+
+#[cfg(not(feature = "nostr"))]
+fn receive_nostr(_secret_key: &str, _relay: &str) -> (String, String, Respond) {
+    fail("--nostr requires the nostr feature")
+}
+
+fn gen_vectors(args: GenVectorsArgs) {
+    let auth = bitcoincore_rpc::Auth::CookieFile(args.cookie.into());
+    let client = bitcoincore_rpc::Client::new(format!("http://127.0.0.1:{}", args.port), auth)
+        .unwrap_or_else(|error| fail(format_args!("failed to connect to the node: {}", error)));
+    let vectors = vectors::generate(&client).unwrap_or_else(|error| match error.source() {
+        Some(source) => fail(format_args!("{}: {}", error, source)),
+        None => fail(error),
+    });
+    let json = serde_json::to_string_pretty(&vectors).expect("vectors are serializable");
+    match args.out {
+        Some(out) => std::fs::write(&out, json).unwrap_or_else(|error| fail(format_args!("failed to write {}: {}", out, error))),
+        None => println!("{}", json),
+    }
+    eprintln!("generated {} vectors", vectors.len());
+}
+
+fn main() {
+    let args = match parse_args(std::env::args_os().skip(1)) {
+        Ok(Command::Propose(args)) => *args,
+        Ok(Command::GenVectors(args)) => return gen_vectors(args),
+        Err(error) => {
+            if !error.is_empty() {
+                eprintln!("Error: {}\n", error);
+            }
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    };
+    let (original, query, respond) = match &args.source {
+        Source::Original(original) => {
+            let query = if args.disable_output_substitution { "v=1&disableoutputsubstitution=1" } else { "v=1" };
+            (original.trim().to_owned(), query.to_owned(), None)
+        },
+        Source::Nostr { secret_key, relay, } => {
+            let (body, query, respond) = receive_nostr(secret_key, relay);
+            (body, query, Some(respond))
+        },
+    };
+    let body = original.as_bytes();
+    let payee = args.payee.script_pubkey();
+    let proposal = UncheckedProposal::from_request_bytes(body, &query, OfflineHeaders(body.len().to_string()))
+        .map_err(|error| (error.to_string(), error.to_json()))
+        .and_then(|proposal| {
+            proposal
+                .check_pays_issued_script(&payee)
+                .and_then(|proposal| proposal.check_sender_input_types(&args.options))
+                .and_then(|proposal| proposal.check_op_return_outputs(&args.options))
+                .map_err(|error| (error.to_string(), error.to_json()))
+        });
+    let (proposal, respond) = match (proposal, respond) {
+        (Ok(proposal), respond) => (proposal, respond),
+        (Err((message, json)), respond) => {
+            if let Some(respond) = respond {
+                respond(400, json.as_bytes());
+            }
+            fail(message)
+        },
+    };
+    let original = load_psbt_from_base64(body).expect("the proposal was parsed from it");
+    // The operator checks the original manually
+    let mut proposal = proposal.this_is_purely_interactive_wallet().assume_locked();
+    for (outpoint, txout) in args.utxos {
+        let input = psbt::Input { witness_utxo: Some(txout), ..Default::default() };
+        proposal
+            .contribute_input(outpoint, input, &payee)
+            .unwrap_or_else(|error| fail(format_args!("can't contribute {}: {}", outpoint, error)));
+    }
+
+    let (original_in, original_out) = totals(&original);
+    let original_fee = original_in - original_out;
+    let original_weight = original.clone().extract_tx().get_weight() as u64;
+    let (proposed_in, proposed_out) = totals(proposal.psbt());
+    let proposed_fee = proposed_in - proposed_out;
+    eprintln!("original: {} inputs, {} outputs, fee {}, weight {} ({} sat/vB)", original.inputs.len(), original.outputs.len(), Amount::from_sat(original_fee), original_weight, original_fee * 4 / original_weight);
+    eprintln!("proposal: {} inputs, {} outputs, fee {} (+{} paid by the receiver)", proposal.psbt().inputs.len(), proposal.psbt().outputs.len(), Amount::from_sat(proposed_fee), Amount::from_sat(proposed_fee - original_fee));
+    // the original fee rate pays for the weight added by the receiver
+    if let Some(weight) = ((proposed_fee - original_fee) * original_weight).checked_div(original_fee) {
+        eprintln!("estimated weight increase: {}", weight);
+    }
+    for (index, output) in proposal.psbt().global.unsigned_tx.output.iter().enumerate() {
+        let original_value = original.global.unsigned_tx.output
+            .iter()
+            .find(|original| original.script_pubkey == output.script_pubkey)
+            .map_or(0, |original| original.value);
+        eprintln!("output {}: {} ({:+} sat)", index, Amount::from_sat(output.value), output.value as i64 - original_value as i64);
+    }
+    if let Some(signer) = &args.signer {
+        // signs all inputs at once so that hardware wallets ask for confirmation only once
+        let signed = RefCell::new(None);
+        proposal
+            .sign_contributed_inputs(&|psbt: &Psbt, index: usize| {
+                let mut signed = signed.borrow_mut();
+                if signed.is_none() {
+                    *signed = Some(signer.sign(psbt)?);
+                }
+                Ok::<_, signer::Error>(signed.as_ref().expect("signed above").inputs[index].clone())
+            })
+            .unwrap_or_else(|error| fail(format_args!("failed to sign the proposal: {}", error)));
+        proposal.minimize_response();
+    }
+    let proposal = serialize_psbt(proposal.psbt());
+    if let Some(respond) = respond {
+        respond(200, proposal.as_bytes());
+    }
+    println!("{}", proposal);
+}
@@ -0,0 +1,516 @@
+//! Broadcasting transactions
+//!
+//! The original transaction is broadcasted long after the request was processed (fallback,
+//! punishing probing senders) and the payjoin transaction after the sender signed it. By then
+//! the inputs may be spent or the fee may be too low for the current mempool, so `Broadcaster`
+//! first checks that the transaction would be accepted and reports why it wouldn't be as
+//! a typed `Error`. Bitcoin Core RPC, Esplora and Electrum servers are supported.
+
+use std::fmt;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use bip78::bitcoin::{Amount, OutPoint, Transaction, TxOut, Txid};
+use bip78::bitcoin::consensus::{deserialize, serialize};
+use bip78::bitcoin::hashes::{sha256, Hash};
+use bip78::bitcoin::hashes::hex::{FromHex, ToHex};
+use bip78::receiver::FallbackScheduler;
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::jsonrpc::serde_json;
+
+/// Timeout of connecting to an Electrum server and of each read and write.
+const ELECTRUM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits the response line of an Electrum server, fits the hex of the largest transactions.
+const MAX_ELECTRUM_RESPONSE_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub enum Error {
+    /// An input is spent or doesn't exist (yet).
+    MissingInputs,
+    /// The transaction (or a conflicting one with the same txid) is already known.
+    AlreadyInMempool,
+    /// The fee is below the minimum of the mempool or `FeeRateSanity`.
+    FeeTooLow(String),
+    /// The fee rate (sat/vB) exceeds the maximum of `FeeRateSanity`.
+    FeeTooHigh { fee_rate: u64, maximum: u64, },
+    /// Rejected for another reason, contains the reason returned by the backend.
+    Rejected(String),
+    /// Failed to communicate with the backend.
+    Backend(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::MissingInputs => write!(f, "inputs of the transaction are missing or spent"),
+            Error::AlreadyInMempool => write!(f, "the transaction is already in mempool"),
+            Error::FeeTooLow(reason) => write!(f, "the fee is too low: {}", reason),
+            Error::FeeTooHigh { fee_rate, maximum, } => write!(f, "the fee rate {} sat/vB exceeds the maximum of {} sat/vB", fee_rate, maximum),
+            Error::Rejected(reason) => write!(f, "the transaction was rejected: {}", reason),
+            Error::Backend(_) => write!(f, "failed to communicate with the broadcasting backend"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::MissingInputs => None,
+            Error::AlreadyInMempool => None,
+            Error::FeeTooLow(_) => None,
+            Error::FeeTooHigh { .. } => None,
+            Error::Rejected(_) => None,
+            Error::Backend(error) => Some(&**error),
+        }
+    }
+}
+
+impl Error {
+    /// Maps the reject reason of Bitcoin Core (also returned by Esplora and Electrum servers).
+    fn from_reject_reason(reason: &str) -> Self {
+        let contains = |pattern| reason.contains(pattern);
+        if contains("missing-inputs") || contains("missingorspent") || contains("Missing inputs") {
+            Error::MissingInputs
+        } else if contains("txn-already-in-mempool") || contains("txn-already-known") || contains("already in block chain") {
+            Error::AlreadyInMempool
+        } else if contains("min relay fee not met") || contains("mempool min fee not met") || contains("insufficient fee") {
+            Error::FeeTooLow(reason.to_owned())
+        } else {
+            Error::Rejected(reason.to_owned())
+        }
+    }
+
+    fn backend(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Error::Backend(error.into())
+    }
+}
+
+/// The fee and size of a transaction that would be accepted to mempool.
+#[derive(Debug, Copy, Clone)]
+pub struct Acceptance {
+    pub fee: Amount,
+    pub vsize: u64,
+}
+
+impl Acceptance {
+    /// Returns the fee rate in sat/vB.
+    pub fn fee_rate(&self) -> u64 {
+        self.fee.as_sat() / self.vsize.max(1)
+    }
+}
+
+/// Bounds of the fee rate (in sat/vB) checked before broadcasting.
+///
+/// The maximum protects against fees mistakenly paid in BTC instead of sats. Defaults to 1 and
+/// 1000 sat/vB.
+#[derive(Debug, Copy, Clone)]
+pub struct FeeRateSanity {
+    pub min: u64,
+    pub max: u64,
+}
+
+impl FeeRateSanity {
+    pub fn check(&self, acceptance: &Acceptance) -> Result<(), Error> {
+        let fee_rate = acceptance.fee_rate();
+        if fee_rate < self.min {
+            return Err(Error::FeeTooLow(format!("the fee rate {} sat/vB is below {} sat/vB", fee_rate, self.min)));
+        }
+        if fee_rate > self.max {
+            return Err(Error::FeeTooHigh { fee_rate, maximum: self.max, });
+        }
+        Ok(())
+    }
+}
+
+impl Default for FeeRateSanity {
+    fn default() -> Self {
+        FeeRateSanity { min: 1, max: 1000, }
+    }
+}
+
+/// Something able to broadcast transactions.
+pub trait Broadcaster {
+    /// Checks that the transaction would be accepted to mempool now without broadcasting it.
+    fn test_accept(&self, tx: &Transaction) -> Result<Acceptance, Error>;
+
+    /// Sends the transaction to the network without any checks.
+    fn send(&self, tx: &Transaction) -> Result<Txid, Error>;
+
+    /// Re-validates the transaction, checks its fee rate and broadcasts it.
+    fn broadcast(&self, tx: &Transaction, sanity: &FeeRateSanity) -> Result<Txid, Error> {
+        let acceptance = self.test_accept(tx)?;
+        sanity.check(&acceptance)?;
+        self.send(tx)
+    }
+}
+
+/// Computes the fee from the outputs spent by `tx`, `prevout` returns `None` for spent outputs.
+fn accept_with_prevouts(tx: &Transaction, mut prevout: impl FnMut(&OutPoint) -> Result<Option<TxOut>, Error>) -> Result<Acceptance, Error> {
+    let mut input_value = 0u64;
+    for txin in &tx.input {
+        let txout = prevout(&txin.previous_output)?.ok_or(Error::MissingInputs)?;
+        input_value = input_value.checked_add(txout.value).ok_or_else(|| Error::Rejected("input values overflow".to_owned()))?;
+    }
+    let output_value = tx.output.iter().try_fold(0u64, |sum, txout| sum.checked_add(txout.value)).ok_or_else(|| Error::Rejected("output values overflow".to_owned()))?;
+    let fee = input_value.checked_sub(output_value).ok_or_else(|| Error::Rejected("outputs exceed inputs".to_owned()))?;
+    Ok(Acceptance {
+        fee: Amount::from_sat(fee),
+        vsize: (tx.get_weight() as u64).div_ceil(4),
+    })
+}
+
+/// Broadcasts using `testmempoolaccept` and `sendrawtransaction`.
+impl Broadcaster for bitcoincore_rpc::Client {
+    fn test_accept(&self, tx: &Transaction) -> Result<Acceptance, Error> {
+        let result = self.test_mempool_accept(&[tx]).map_err(Error::backend)?;
+        let result = result.into_iter().next().ok_or("testmempoolaccept returned no result").map_err(Error::backend)?;
+        if !result.allowed {
+            return Err(Error::from_reject_reason(result.reject_reason.as_deref().unwrap_or("unknown reason")));
+        }
+        match (result.fees, result.vsize) {
+            (Some(fees), Some(vsize)) => Ok(Acceptance { fee: fees.base, vsize, }),
+            // Bitcoin Core older than 0.21 doesn't return them
+            _ => accept_with_prevouts(tx, |outpoint| {
+                let txout = self.get_tx_out(&outpoint.txid, outpoint.vout, Some(true)).map_err(Error::backend)?;
+                Ok(txout.map(|txout| TxOut { value: txout.value.as_sat(), script_pubkey: txout.script_pub_key.script().unwrap_or_default(), }))
+            }),
+        }
+    }
+
+    fn send(&self, tx: &Transaction) -> Result<Txid, Error> {
+        self.send_raw_transaction(tx).map_err(|error| match error {
+            bitcoincore_rpc::Error::JsonRpc(bitcoincore_rpc::jsonrpc::Error::Rpc(error)) => Error::from_reject_reason(&error.message),
+            error => Error::backend(error),
+        })
+    }
+}
+
+/// Broadcasts using the REST API of an Esplora instance, e.g. `https://blockstream.info/api`.
+///
+/// Esplora can't test mempool acceptance so the inputs are checked to exist and be unspent and
+/// the fee is computed from them.
+pub struct Esplora {
+    url: String,
+    http: reqwest::blocking::Client,
+}
+
+impl Esplora {
+    pub fn new(url: String, http: reqwest::blocking::Client) -> Self {
+        Esplora {
+            url: url.trim_end_matches('/').to_owned(),
+            http,
+        }
+    }
+
+    /// Returns the body of a successful response, `None` if not found.
+    fn get(&self, path: &str) -> Result<Option<String>, Error> {
+        let response = self.http.get(format!("{}{}", self.url, path)).send().map_err(Error::backend)?;
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response.error_for_status().map_err(Error::backend)?;
+        response.text().map(Some).map_err(Error::backend)
+    }
+}
+
+impl Broadcaster for Esplora {
+    fn test_accept(&self, tx: &Transaction) -> Result<Acceptance, Error> {
+        if self.get(&format!("/tx/{}/status", tx.txid()))?.is_some() {
+            return Err(Error::AlreadyInMempool);
+        }
+        accept_with_prevouts(tx, |outpoint| {
+            let outspend = match self.get(&format!("/tx/{}/outspend/{}", outpoint.txid, outpoint.vout))? {
+                Some(outspend) => serde_json::from_str::<serde_json::Value>(&outspend).map_err(Error::backend)?,
+                None => return Ok(None),
+            };
+            if outspend.get("spent").and_then(serde_json::Value::as_bool) != Some(false) {
+                return Ok(None);
+            }
+            let prev_tx = match self.get(&format!("/tx/{}/hex", outpoint.txid))? {
+                Some(hex) => hex,
+                None => return Ok(None),
+            };
+            let prev_tx = Vec::<u8>::from_hex(prev_tx.trim()).map_err(Error::backend)?;
+            let prev_tx = deserialize::<Transaction>(&prev_tx).map_err(Error::backend)?;
+            Ok(prev_tx.output.get(outpoint.vout as usize).cloned())
+        })
+    }
+
+    fn send(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let response = self.http
+            .post(format!("{}/tx", self.url))
+            .body(serialize(tx).to_hex())
+            .send()
+            .map_err(Error::backend)?;
+        let is_rejected = response.status().is_client_error();
+        let body = response.text().map_err(Error::backend)?;
+        if is_rejected {
+            return Err(Error::from_reject_reason(&body));
+        }
+        body.trim().parse().map_err(Error::backend)
+    }
+}
+
+/// Broadcasts using an Electrum server.
+///
+/// Like with `Esplora` the fee is computed from the inputs which are checked to be unspent by
+/// listing the unspent outputs of their scripts.
+pub struct Electrum {
+    host: String,
+    port: u16,
+    tls: Option<native_tls::TlsConnector>,
+}
+
+impl Electrum {
+    /// Connects to the server over plain TCP, use only with local servers or onion services.
+    pub fn new(host: String, port: u16) -> Self {
+        Electrum { host, port, tls: None, }
+    }
+
+    /// Connects to the server over TLS.
+    pub fn tls(host: String, port: u16) -> Result<Self, Error> {
+        let connector = native_tls::TlsConnector::new().map_err(Error::backend)?;
+        Ok(Electrum { host, port, tls: Some(connector), })
+    }
+
+    fn call(&self, method: &str, params: serde_json::Value) -> Result<serde_json::Value, CallError> {
+        let request = serde_json::json!({ "jsonrpc": "2.0", "id": 0, "method": method, "params": params, });
+        let stream = self.connect().map_err(|error| CallError::Backend(Error::backend(error)))?;
+        let response = match &self.tls {
+            Some(connector) => {
+                let stream = connector.connect(&self.host, stream).map_err(|error| CallError::Backend(Error::backend(error.to_string())))?;
+                exchange(stream, &request)
+            },
+            None => exchange(stream, &request),
+        }.map_err(|error| CallError::Backend(Error::backend(error)))?;
+        match response.get("error") {
+            Some(error) if !error.is_null() => {
+                let message = error.get("message").and_then(serde_json::Value::as_str).map_or_else(|| error.to_string(), str::to_owned);
+                Err(CallError::Rpc(message))
+            },
+            _ => response.get("result").cloned().ok_or_else(|| CallError::Backend(Error::backend("the response contains no result"))),
+        }
+    }
+
+    /// Tries the resolved addresses in order, each within `ELECTRUM_TIMEOUT`.
+    fn connect(&self) -> Result<TcpStream, std::io::Error> {
+        let mut last_error = None;
+        for address in (&*self.host, self.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&address, ELECTRUM_TIMEOUT) {
+                Ok(stream) => {
+                    stream.set_read_timeout(Some(ELECTRUM_TIMEOUT))?;
+                    stream.set_write_timeout(Some(ELECTRUM_TIMEOUT))?;
+                    return Ok(stream);
+                },
+                Err(error) => last_error = Some(error),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| std::io::Error::other(format!("{} resolved to no addresses", self.host))))
+    }
+}
+
+enum CallError {
+    /// The server returned an error, e.g. the reject reason of a transaction.
+    Rpc(String),
+    Backend(Error),
+}
+
+impl From<CallError> for Error {
+    fn from(error: CallError) -> Self {
+        match error {
+            CallError::Rpc(message) => Error::Backend(message.into()),
+            CallError::Backend(error) => error,
+        }
+    }
+}
+
+/// Sends one line-delimited JSON-RPC request and reads the response of at most
+/// `MAX_ELECTRUM_RESPONSE_SIZE` bytes.
+fn exchange<S: Read + Write>(mut stream: S, request: &serde_json::Value) -> Result<serde_json::Value, std::io::Error> {
+    let mut request = serde_json::to_vec(request)?;
+    request.push(b'\n');
+    stream.write_all(&request)?;
+    let mut line = String::new();
+    // one more byte to detect exceeding the limit
+    BufReader::new(stream.take(MAX_ELECTRUM_RESPONSE_SIZE + 1)).read_line(&mut line)?;
+    if line.len() as u64 > MAX_ELECTRUM_RESPONSE_SIZE {
+        return Err(std::io::Error::other("the response is too large"));
+    }
+    Ok(serde_json::from_str(&line)?)
+}
+
+impl Broadcaster for Electrum {
+    fn test_accept(&self, tx: &Transaction) -> Result<Acceptance, Error> {
+        match self.call("blockchain.transaction.get", serde_json::json!([tx.txid().to_hex()])) {
+            Ok(_) => return Err(Error::AlreadyInMempool),
+            // unknown transaction
+            Err(CallError::Rpc(_)) => (),
+            Err(CallError::Backend(error)) => return Err(error),
+        }
+        accept_with_prevouts(tx, |outpoint| {
+            let prev_tx = match self.call("blockchain.transaction.get", serde_json::json!([outpoint.txid.to_hex()])) {
+                Ok(prev_tx) => prev_tx,
+                Err(CallError::Rpc(_)) => return Ok(None),
+                Err(CallError::Backend(error)) => return Err(error),
+            };
+            let prev_tx = Vec::<u8>::from_hex(prev_tx.as_str().unwrap_or_default()).map_err(Error::backend)?;
+            let prev_tx = deserialize::<Transaction>(&prev_tx).map_err(Error::backend)?;
+            let txout = match prev_tx.output.get(outpoint.vout as usize) {
+                Some(txout) => txout.clone(),
+                None => return Ok(None),
+            };
+            // Electrum identifies scripts by their reversed SHA256
+            let mut script_hash = sha256::Hash::hash(txout.script_pubkey.as_bytes()).into_inner();
+            script_hash.reverse();
+            let unspent = self.call("blockchain.scripthash.listunspent", serde_json::json!([script_hash.to_hex()]))?;
+            let is_unspent = unspent.as_array().into_iter().flatten().any(|utxo| {
+                utxo.get("tx_hash").and_then(serde_json::Value::as_str) == Some(&outpoint.txid.to_hex())
+                    && utxo.get("tx_pos").and_then(serde_json::Value::as_u64) == Some(outpoint.vout.into())
+            });
+            Ok(if is_unspent { Some(txout) } else { None })
+        })
+    }
+
+    fn send(&self, tx: &Transaction) -> Result<Txid, Error> {
+        let txid = self.call("blockchain.transaction.broadcast", serde_json::json!([serialize(tx).to_hex()]))
+            .map_err(|error| match error {
+                CallError::Rpc(reason) => Error::from_reject_reason(&reason),
+                CallError::Backend(error) => error,
+            })?;
+        txid.as_str().unwrap_or_default().parse().map_err(Error::backend)
+    }
+}
+
+/// Broadcasts the fallback transactions that are due, returns the failed ones.
+///
+/// Transactions already in mempool or with spent inputs (the payjoin transaction or another
+/// payment of the sender got there first) are considered done.
+pub fn broadcast_due(scheduler: &FallbackScheduler, broadcaster: &dyn Broadcaster, sanity: &FeeRateSanity) -> Vec<(Transaction, Error)> {
+    scheduler
+        .take_due()
+        .into_iter()
+        .filter_map(|tx| match broadcaster.broadcast(&tx, sanity) {
+            Ok(_) | Err(Error::AlreadyInMempool) | Err(Error::MissingInputs) => None,
+            Err(error) => Some((tx, error)),
+        })
+        .collect()
+}
+
+/// Creates the broadcaster from a command line argument.
+///
+/// Supported: `core:<port>:<cookie_file>`, `esplora:<url>`, `electrum:<host>:<port>` (TCP) and
+/// `electrum-tls:<host>:<port>`.
+pub fn from_arg(arg: &str) -> Result<Box<dyn Broadcaster>, String> {
+    let mut parts = arg.splitn(2, ':');
+    let host_port = |params: &str| -> Result<(String, u16), String> {
+        let mut params = params.rsplitn(2, ':');
+        let port = params.next().unwrap_or_default().parse::<u16>().map_err(|error| format!("invalid port: {}", error))?;
+        let host = params.next().filter(|host| !host.is_empty()).ok_or("missing host")?;
+        Ok((host.to_owned(), port))
+    };
+    match (parts.next(), parts.next()) {
+        (Some("core"), Some(params)) => {
+            let mut params = params.splitn(2, ':');
+            let port = params.next().unwrap_or_default().parse::<u16>().map_err(|error| format!("invalid port: {}", error))?;
+            let cookie_file = params.next().ok_or("missing cookie file")?;
+            let client = bitcoincore_rpc::Client::new(format!("http://127.0.0.1:{}", port), bitcoincore_rpc::Auth::CookieFile(cookie_file.into()))
+                .map_err(|error| format!("failed to connect to bitcoind: {}", error))?;
+            Ok(Box::new(client))
+        },
+        (Some("esplora"), Some(url)) => Ok(Box::new(Esplora::new(url.to_owned(), reqwest::blocking::Client::new()))),
+        (Some("electrum"), Some(params)) => {
+            let (host, port) = host_port(params)?;
+            Ok(Box::new(Electrum::new(host, port)))
+        },
+        (Some("electrum-tls"), Some(params)) => {
+            let (host, port) = host_port(params)?;
+            Ok(Box::new(Electrum::tls(host, port).map_err(|error| error.to_string())?))
+        },
+        _ => Err(format!("unknown broadcaster {}", arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bip78::bitcoin::{Script, TxIn};
+
+    fn transaction(input_count: u32, output_values: &[u64]) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time: 0,
+            input: (0..input_count).map(|vout| TxIn {
+                previous_output: OutPoint { txid: Default::default(), vout, },
+                script_sig: Script::new(),
+                sequence: 0xfffffffd,
+                witness: Vec::new(),
+            }).collect(),
+            output: output_values.iter().map(|value| TxOut { value: *value, script_pubkey: Script::new(), }).collect(),
+        }
+    }
+
+    fn accept(tx: &Transaction, prevout_values: &[u64]) -> Result<Acceptance, Error> {
+        accept_with_prevouts(tx, |outpoint| Ok(prevout_values.get(outpoint.vout as usize).map(|value| TxOut { value: *value, script_pubkey: Script::new(), })))
+    }
+
+    #[test]
+    fn fee_from_prevouts() {
+        let tx = transaction(2, &[15_000, 4_000]);
+        let acceptance = accept(&tx, &[10_000, 10_000]).unwrap();
+        assert_eq!(acceptance.fee, Amount::from_sat(1_000));
+        assert_eq!(acceptance.vsize, (tx.get_weight() as u64).div_ceil(4));
+    }
+
+    #[test]
+    fn invalid_prevouts() {
+        assert!(matches!(accept(&transaction(2, &[1_000]), &[10_000]), Err(Error::MissingInputs)));
+        assert!(matches!(accept(&transaction(2, &[1_000]), &[u64::MAX, 1]), Err(Error::Rejected(_))));
+        assert!(matches!(accept(&transaction(1, &[u64::MAX, 1]), &[10_000]), Err(Error::Rejected(_))));
+        assert!(matches!(accept(&transaction(1, &[10_001]), &[10_000]), Err(Error::Rejected(_))));
+    }
+
+    #[test]
+    fn broadcaster_args() {
+        let error = |arg| from_arg(arg).err().unwrap();
+        assert!(from_arg("esplora:https://blockstream.info/api").is_ok());
+        assert!(from_arg("electrum:localhost:50001").is_ok());
+        assert!(from_arg("electrum:[::1]:50001").is_ok());
+        assert!(error("electrum:localhost").starts_with("invalid port"));
+        assert_eq!(error("electrum:50001"), "missing host");
+        assert_eq!(error("electrum::50001"), "missing host");
+        assert_eq!(error("electrum::50001x"), "invalid port: invalid digit found in string");
+        assert!(error("core:8332").starts_with("missing cookie file"));
+        assert!(error("core:rpc:/cookie").starts_with("invalid port"));
+        assert_eq!(error("electrum"), "unknown broadcaster electrum");
+        assert_eq!(error("bitcoind:8332:/cookie"), "unknown broadcaster bitcoind:8332:/cookie");
+    }
+
+    /// Responds with `response` and discards the request.
+    struct Server<R>(R);
+
+    impl<R: Read> Read for Server<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.0.read(buf)
+        }
+    }
+
+    impl<R> Write for Server<R> {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn response_limit() {
+        let request = serde_json::json!({ "id": 0, });
+        let response = exchange(Server(&b"{\"id\":0,\"result\":null}\n{}"[..]), &request).unwrap();
+        assert_eq!(response, serde_json::json!({ "id": 0, "result": null, }));
+        // never ends the line
+        assert!(exchange(Server(std::io::repeat(b' ')), &request).is_err());
+    }
+}
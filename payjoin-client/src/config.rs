@@ -0,0 +1,375 @@
+//! Profiles of settings loaded from TOML files
+//!
+//! Operators keep their settings in a file instead of repeating command line arguments. Each
+//! top-level table of the file is a named profile selected using `--profile` (`default` if not
+//! given):
+//!
+//! ```toml
+//! [default]
+//! signer = "hwi"
+//!
+//! [default.sender]
+//! max_fee_contribution = 10000
+//! min_fee_rate = 1
+//! proxy = "socks5h://127.0.0.1:9050"
+//!
+//! [default.receiver]
+//! endpoint = "https://example.com/pj"
+//! dust_limit = 1000
+//! bump_fee = "subtract_our_fee_output"
+//! allowed_sender_input_types = ["p2wpkh", "p2sh_p2wpkh"]
+//! reject_op_return = true
+//! ```
+//!
+//! Amounts are in satoshis and fee rates in sat/vB. Command line arguments take precedence.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use std::time::Duration;
+use bip78::bitcoin::{Address, Amount};
+use bip78::receiver::{BumpFeePolicy, InputScriptType, OpReturnPolicy, PayjoinReceiverBuilder, ReceiverOptions};
+use bip78::sender::PayjoinSender;
+use serde::Deserialize;
+
+/// Settings of one profile, see the module documentation.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Value of `--signer`, see `signer::from_arg()`.
+    pub signer: Option<String>,
+    #[serde(default)]
+    pub sender: SenderProfile,
+    #[serde(default)]
+    pub receiver: ReceiverProfile,
+}
+
+/// Settings of `payjoin-client`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SenderProfile {
+    /// Fee rate of the original transaction.
+    pub fee_rate: Option<u64>,
+    pub max_fee_contribution: Option<u64>,
+    pub fee_contribution_rate: Option<u64>,
+    pub change_index: Option<usize>,
+    pub clamp_fee_contribution: Option<bool>,
+    pub min_fee_rate: Option<u64>,
+    #[serde(default)]
+    pub disable_output_substitution: bool,
+    #[serde(default)]
+    pub strict: bool,
+    #[serde(default)]
+    pub compat: bool,
+    pub max_latency_secs: Option<u64>,
+    /// Proxy of the HTTP client, e.g. `socks5h://127.0.0.1:9050` for Tor.
+    pub proxy: Option<String>,
+    pub timeout_secs: Option<u64>,
+}
+
+/// Settings of `payjoin-receiver`.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReceiverProfile {
+    /// `pj` endpoint put into URIs.
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub disable_output_substitution: bool,
+    pub dust_limit: Option<u64>,
+    pub bump_fee: Option<BumpFee>,
+    pub allowed_sender_input_types: Option<Vec<InputType>>,
+    /// Reject originals with `OP_RETURN` outputs instead of preserving them.
+    #[serde(default)]
+    pub reject_op_return: bool,
+    /// Reject requests that didn't come through the onion service.
+    #[serde(default)]
+    pub onion_only: bool,
+}
+
+/// See `BumpFeePolicy`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BumpFee {
+    FailOnInsufficient,
+    SubtractOurFeeOutput,
+}
+
+/// See `InputScriptType`.
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputType {
+    P2pk,
+    P2pkh,
+    P2sh,
+    P2shP2wpkh,
+    P2shP2wsh,
+    P2wpkh,
+    P2wsh,
+    Taproot,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Read(std::io::Error),
+    Parse(toml::de::Error),
+    UnknownProfile(String),
+    Conflict { first: &'static str, second: &'static str, },
+    Invalid { option: &'static str, reason: String, },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Read(_) => write!(f, "failed to read the configuration file"),
+            Error::Parse(_) => write!(f, "failed to parse the configuration file"),
+            Error::UnknownProfile(name) => write!(f, "the profile {} doesn't exist", name),
+            Error::Conflict { first, second, } => write!(f, "{} conflicts with {}", first, second),
+            Error::Invalid { option, reason, } => write!(f, "invalid {}: {}", option, reason),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Read(error) => Some(error),
+            Error::Parse(error) => Some(error),
+            Error::UnknownProfile(_) => None,
+            Error::Conflict { .. } => None,
+            Error::Invalid { .. } => None,
+        }
+    }
+}
+
+/// Loads and validates the profile `name` from the file at `path`.
+pub fn load(path: impl AsRef<Path>, name: &str) -> Result<Profile, Error> {
+    let contents = std::fs::read_to_string(path).map_err(Error::Read)?;
+    from_toml(&contents, name)
+}
+
+/// Parses and validates the profile `name`.
+pub fn from_toml(contents: &str, name: &str) -> Result<Profile, Error> {
+    let mut profiles = toml::from_str::<HashMap<String, Profile>>(contents).map_err(Error::Parse)?;
+    let profile = profiles.remove(name).ok_or_else(|| Error::UnknownProfile(name.to_owned()))?;
+    profile.validate()?;
+    Ok(profile)
+}
+
+impl Profile {
+    /// Checks for conflicting and invalid options.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.sender.validate()?;
+        self.receiver.validate()
+    }
+}
+
+impl SenderProfile {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.strict && self.compat {
+            return Err(Error::Conflict { first: "strict", second: "compat", });
+        }
+        if self.max_fee_contribution.is_some() && self.fee_contribution_rate.is_some() {
+            return Err(Error::Conflict { first: "max_fee_contribution", second: "fee_contribution_rate", });
+        }
+        let contributes = self.max_fee_contribution.is_some() || self.fee_contribution_rate.is_some();
+        if self.change_index.is_some() && !contributes {
+            return Err(Error::Invalid { option: "change_index", reason: "no fee contribution is offered".to_owned(), });
+        }
+        if self.clamp_fee_contribution.is_some() && !contributes {
+            return Err(Error::Invalid { option: "clamp_fee_contribution", reason: "no fee contribution is offered".to_owned(), });
+        }
+        if matches!(self.max_fee_contribution, Some(amount) if amount > 21_000_000 * 100_000_000) {
+            return Err(Error::Invalid { option: "max_fee_contribution", reason: "exceeds 21 million bitcoins".to_owned(), });
+        }
+        if let Some(proxy) = &self.proxy {
+            reqwest::Proxy::all(proxy).map_err(|error| Error::Invalid { option: "proxy", reason: error.to_string(), })?;
+        }
+        Ok(())
+    }
+
+    /// Applies the request parameters to `sender`.
+    pub fn apply<'a>(&self, mut sender: PayjoinSender<'a>) -> PayjoinSender<'a> {
+        if let Some(max_fee_contribution) = self.max_fee_contribution {
+            sender = sender.max_fee_contribution(Amount::from_sat(max_fee_contribution));
+        }
+        if let Some(rate) = self.fee_contribution_rate {
+            sender = sender.fee_contribution_rate(rate);
+        }
+        if let Some(change_index) = self.change_index {
+            sender = sender.change_index(change_index);
+        }
+        if let Some(clamp) = self.clamp_fee_contribution {
+            sender = sender.clamp_fee_contribution(clamp);
+        }
+        if let Some(min_fee_rate) = self.min_fee_rate {
+            sender = sender.min_fee_rate(min_fee_rate);
+        }
+        if self.strict {
+            sender = sender.strict();
+        }
+        if self.compat {
+            sender = sender.compat();
+        }
+        if let Some(max_latency) = self.max_latency_secs {
+            sender = sender.max_latency(Duration::from_secs(max_latency));
+        }
+        if self.disable_output_substitution {
+            sender = sender.always_disable_output_substitution(true);
+        }
+        sender
+    }
+
+    /// Creates the HTTP client using the proxy and timeout.
+    pub fn http_client(&self) -> reqwest::Result<reqwest::blocking::Client> {
+        let mut builder = reqwest::blocking::Client::builder();
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(reqwest::Proxy::all(proxy)?);
+        }
+        if let Some(timeout) = self.timeout_secs {
+            builder = builder.timeout(Duration::from_secs(timeout));
+        }
+        builder.build()
+    }
+}
+
+impl ReceiverProfile {
+    pub fn validate(&self) -> Result<(), Error> {
+        if self.disable_output_substitution && self.bump_fee == Some(BumpFee::SubtractOurFeeOutput) {
+            return Err(Error::Conflict { first: "disable_output_substitution", second: "bump_fee = \"subtract_our_fee_output\"", });
+        }
+        if matches!(&self.allowed_sender_input_types, Some(types) if types.is_empty()) {
+            return Err(Error::Invalid { option: "allowed_sender_input_types", reason: "all original PSBTs would be rejected".to_owned(), });
+        }
+        if let Some(endpoint) = &self.endpoint {
+            // the address is irrelevant, the URI checks the endpoint
+            let address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse::<Address>().expect("valid address");
+            bip78::Uri::new(address, Amount::ONE_SAT, endpoint).map_err(|error| Error::Invalid { option: "endpoint", reason: error.to_string(), })?;
+        }
+        Ok(())
+    }
+
+    /// Creates the URI requesting `amount` to `address` if the endpoint is set.
+    pub fn uri(&self, address: Address, amount: Amount) -> Option<bip78::Uri<'static>> {
+        let endpoint = self.endpoint.as_ref()?;
+        let uri = bip78::Uri::new(address, amount, endpoint.clone()).expect("validated in from_toml()");
+        Some(uri.disable_output_substitution(self.disable_output_substitution))
+    }
+
+    /// Returns the options of the stages of `bip78::receiver`.
+    pub fn options(&self) -> ReceiverOptions {
+        let mut options = ReceiverOptions::default();
+        if let Some(dust_limit) = self.dust_limit {
+            options = options.dust_limit(Amount::from_sat(dust_limit));
+        }
+        if let Some(bump_fee) = self.bump_fee {
+            options = options.bump_fee_policy(bump_fee.into());
+        }
+        if let Some(types) = &self.allowed_sender_input_types {
+            options = options.allowed_sender_input_types(types.iter().copied().map(Into::into));
+        }
+        if self.reject_op_return {
+            options = options.op_return_policy(OpReturnPolicy::Reject);
+        }
+        options.onion_only(self.onion_only)
+    }
+
+    /// Applies the options to `builder`.
+    pub fn apply<C>(&self, mut builder: PayjoinReceiverBuilder<C>) -> PayjoinReceiverBuilder<C> {
+        if let Some(dust_limit) = self.dust_limit {
+            builder = builder.dust_limit(Amount::from_sat(dust_limit));
+        }
+        if let Some(bump_fee) = self.bump_fee {
+            builder = builder.bump_policy(bump_fee.into());
+        }
+        if let Some(types) = &self.allowed_sender_input_types {
+            builder = builder.allowed_sender_input_types(types.iter().copied().map(Into::into));
+        }
+        if self.reject_op_return {
+            builder = builder.op_return_policy(OpReturnPolicy::Reject);
+        }
+        builder.onion_only(self.onion_only)
+    }
+}
+
+impl From<BumpFee> for BumpFeePolicy {
+    fn from(value: BumpFee) -> Self {
+        match value {
+            BumpFee::FailOnInsufficient => BumpFeePolicy::FailOnInsufficient,
+            BumpFee::SubtractOurFeeOutput => BumpFeePolicy::SubtractOurFeeOutput,
+        }
+    }
+}
+
+impl From<InputType> for InputScriptType {
+    fn from(value: InputType) -> Self {
+        match value {
+            InputType::P2pk => InputScriptType::P2Pk,
+            InputType::P2pkh => InputScriptType::P2Pkh,
+            InputType::P2sh => InputScriptType::P2Sh,
+            InputType::P2shP2wpkh => InputScriptType::P2ShP2Wpkh,
+            InputType::P2shP2wsh => InputScriptType::P2ShP2Wsh,
+            InputType::P2wpkh => InputScriptType::P2Wpkh,
+            InputType::P2wsh => InputScriptType::P2Wsh,
+            InputType::Taproot => InputScriptType::Taproot,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(contents: &str) -> String {
+        from_toml(contents, "default").unwrap_err().to_string()
+    }
+
+    #[test]
+    fn valid_profile() {
+        let contents = r#"
+            [default]
+            signer = "hwi"
+
+            [default.sender]
+            max_fee_contribution = 10000
+            change_index = 0
+            min_fee_rate = 1
+            proxy = "socks5h://127.0.0.1:9050"
+
+            [default.receiver]
+            endpoint = "https://example.com/pj"
+            bump_fee = "subtract_our_fee_output"
+            allowed_sender_input_types = ["p2wpkh", "p2sh_p2wpkh"]
+
+            [empty]
+        "#;
+        let profile = from_toml(contents, "default").unwrap();
+        assert_eq!(profile.signer.as_deref(), Some("hwi"));
+        assert_eq!(profile.sender.max_fee_contribution, Some(10000));
+        assert_eq!(profile.receiver.bump_fee, Some(BumpFee::SubtractOurFeeOutput));
+        assert_eq!(profile.receiver.allowed_sender_input_types, Some(vec![InputType::P2wpkh, InputType::P2shP2wpkh]));
+        let address = "1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2".parse::<Address>().unwrap();
+        assert_eq!(profile.receiver.uri(address, Amount::from_sat(1000)).unwrap().to_string(), "bitcoin:1BvBMSEYstWetqTFn5Au4m4GFg7xJaNVN2?amount=0.00001&pj=https://example.com/pj");
+        assert!(from_toml(contents, "empty").unwrap().receiver.endpoint.is_none());
+        assert_eq!(from_toml(contents, "other").unwrap_err().to_string(), "the profile other doesn't exist");
+    }
+
+    #[test]
+    fn conflicts() {
+        assert_eq!(error("[default.sender]\nstrict = true\ncompat = true"), "strict conflicts with compat");
+        assert_eq!(error("[default.sender]\nmax_fee_contribution = 1\nfee_contribution_rate = 1"), "max_fee_contribution conflicts with fee_contribution_rate");
+        assert_eq!(error("[default.receiver]\ndisable_output_substitution = true\nbump_fee = \"subtract_our_fee_output\""), "disable_output_substitution conflicts with bump_fee = \"subtract_our_fee_output\"");
+    }
+
+    #[test]
+    fn invalid_options() {
+        assert_eq!(error("[default.sender]\nchange_index = 0"), "invalid change_index: no fee contribution is offered");
+        assert_eq!(error("[default.sender]\nclamp_fee_contribution = true"), "invalid clamp_fee_contribution: no fee contribution is offered");
+        assert_eq!(error("[default.sender]\nmax_fee_contribution = 2100000000000001"), "invalid max_fee_contribution: exceeds 21 million bitcoins");
+        assert!(error("[default.sender]\nproxy = \"not a url\"").starts_with("invalid proxy: "));
+        assert_eq!(error("[default.receiver]\nallowed_sender_input_types = []"), "invalid allowed_sender_input_types: all original PSBTs would be rejected");
+        assert!(error("[default.receiver]\nendpoint = \"ftp://example.com\"").starts_with("invalid endpoint: "));
+        // unknown options are typos
+        assert_eq!(error("[default.sender]\nmax_fee_contributon = 1"), "failed to parse the configuration file");
+        assert_eq!(error("[default.receiver]\nbump_fee = \"sometimes\""), "failed to parse the configuration file");
+    }
+}
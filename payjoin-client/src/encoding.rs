@@ -0,0 +1,121 @@
+//! Decoding compressed response bodies
+//!
+//! Receivers may compress the response if the request allowed it (`Accept-Encoding`). The body
+//! is decompressed here so that `bip78` only ever sees the base64 PSBT. Compressed data can
+//! expand enormously, so both the compressed and the decompressed size are limited.
+
+use std::fmt;
+use std::io::Read;
+use flate2::read::{GzDecoder, ZlibDecoder};
+
+/// Value of `Accept-Encoding` header listing the supported encodings.
+pub const ACCEPT_ENCODING: &str = "gzip, deflate";
+
+#[derive(Debug)]
+pub enum Error {
+    /// `Content-Encoding` other than `gzip`, `deflate` or `identity`.
+    UnsupportedEncoding(String),
+    Io(std::io::Error),
+    /// The (decompressed) body is larger than allowed.
+    TooLarge,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedEncoding(encoding) => write!(f, "unsupported content encoding {}", encoding),
+            Error::Io(_) => write!(f, "failed to decode the response"),
+            Error::TooLarge => write!(f, "the response is too large"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UnsupportedEncoding(_) => None,
+            Error::Io(error) => Some(error),
+            Error::TooLarge => None,
+        }
+    }
+}
+
+/// Reads the body encoded with `content_encoding` and returns at most `max_size` decoded bytes.
+///
+/// Pass `Request::max_response_size` of `bip78` as `max_size`. The compressed body is limited to
+/// the same size.
+pub fn read_body(content_encoding: Option<&str>, body: impl Read, max_size: u64) -> Result<Vec<u8>, Error> {
+    let encoding = content_encoding.map(str::trim).unwrap_or("identity");
+    let is = |name| encoding.eq_ignore_ascii_case(name);
+    if is("identity") {
+        read_limited(body, max_size)
+    } else if is("gzip") || is("x-gzip") {
+        read_limited(GzDecoder::new(&*read_limited(body, max_size)?), max_size)
+    } else if is("deflate") {
+        read_limited(ZlibDecoder::new(&*read_limited(body, max_size)?), max_size)
+    } else {
+        Err(Error::UnsupportedEncoding(encoding.to_owned()))
+    }
+}
+
+fn read_limited(reader: impl Read, max_size: u64) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    // one more byte to detect exceeding the limit
+    reader.take(max_size.saturating_add(1)).read_to_end(&mut bytes).map_err(Error::Io)?;
+    if bytes.len() as u64 > max_size {
+        return Err(Error::TooLarge);
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use flate2::Compression;
+    use flate2::write::{GzEncoder, ZlibEncoder};
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    #[test]
+    fn gzip_bomb() {
+        let bomb = gzip(&vec![b'A'; 10_000_000]);
+        // the compressed body itself is within the limit
+        assert!(bomb.len() < 100_000);
+        assert!(matches!(read_body(Some("gzip"), &*bomb, 100_000), Err(Error::TooLarge)));
+        let body = gzip(b"cHNidP8B");
+        assert_eq!(read_body(Some(" GZIP "), &*body, 100_000).unwrap(), b"cHNidP8B");
+    }
+
+    #[test]
+    fn deflate_limit() {
+        let data = vec![b'A'; 1000];
+        assert_eq!(read_body(Some("deflate"), &*deflate(&data), 1000).unwrap(), data);
+        assert!(matches!(read_body(Some("deflate"), &*deflate(&vec![b'A'; 1001]), 1000), Err(Error::TooLarge)));
+    }
+
+    #[test]
+    fn identity_limit() {
+        assert_eq!(read_body(None, &b"abc"[..], 3).unwrap(), b"abc");
+        assert_eq!(read_body(Some("identity"), &b"abc"[..], 3).unwrap(), b"abc");
+        assert!(matches!(read_body(None, &b"abcd"[..], 3), Err(Error::TooLarge)));
+    }
+
+    #[test]
+    fn unsupported_encoding() {
+        match read_body(Some("br"), &b"abc"[..], 100) {
+            Err(Error::UnsupportedEncoding(encoding)) => assert_eq!(encoding, "br"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
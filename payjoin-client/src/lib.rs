@@ -0,0 +1,49 @@
+//! Code shared by the binaries
+
+use bip78::bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
+
+pub mod broadcast;
+pub mod config;
+pub mod encoding;
+#[cfg(feature = "nostr")]
+pub mod nostr;
+pub mod pinned;
+pub mod remote;
+pub mod signer;
+pub mod vectors;
+
+pub fn load_psbt_from_base64(mut input: impl std::io::Read) -> Result<Psbt, bip78::bitcoin::consensus::encode::Error> {
+    use bip78::bitcoin::consensus::Decodable;    
+ 
+    let reader = base64::read::DecoderReader::new(&mut input, base64::Config::new(base64::CharacterSet::Standard, true));
+    Psbt::consensus_decode(reader)    
+}
+
+/// Describes why the proposal is invalid, one line per detail.
+pub fn describe_validation_error(error: &bip78::sender::ValidationError) -> String {
+    use std::fmt::Write;
+
+    let mut description = format!("invalid: {}", error);
+    let mut source = std::error::Error::source(error);
+    while let Some(error) = source {
+        write!(description, "\ncaused by: {}", error).expect("writing to string doesn't fail");
+        source = error.source();
+    }
+    if let Some(code) = error.receiver_error_code() {
+        write!(description, "\nreceiver error code: {}", code.as_str()).expect("writing to string doesn't fail");
+    }
+    if error.is_protocol_violation() {
+        description.push_str("\nthe receiver broke the protocol deliberately");
+    }
+    description
+}
+
+pub fn serialize_psbt(psbt: &Psbt) -> String {
+    use bip78::bitcoin::consensus::Encodable;
+                                    
+    let mut encoder = base64::write::EncoderWriter::new(Vec::new(), base64::STANDARD);
+    psbt.consensus_encode(&mut encoder)
+        .expect("Vec doesn't return errors in its write implementation");
+    String::from_utf8(encoder.finish()
+        .expect("Vec doesn't return errors in its write implementation")).unwrap()
+}
@@ -1,15 +1,34 @@
 use std::collections::HashMap;
-use bip78::bitcoin::util::psbt::PartiallySignedTransaction as Psbt;
 use bitcoincore_rpc::RpcApi;
+use bip78::sender::PayjoinSender;
+use payjoin_client::{load_psbt_from_base64, serialize_psbt};
+
+mod preview;
+mod verify;
 
 fn main() {
-    let mut args = std::env::args_os();
+    let mut args = std::env::args_os().peekable();
     let _program_name = args
         .next()
         .expect("not even program name given");
+    if matches!(args.peek(), Some(arg) if arg == "verify") {
+        args.next();
+        verify::run(args);
+        return;
+    }
+    if matches!(args.peek(), Some(arg) if arg == "preview") {
+        args.next();
+        preview::run(args);
+        return;
+    }
+    if matches!(args.peek(), Some(arg) if arg == "decode") {
+        args.next();
+        preview::decode(args);
+        return;
+    }
     let port = args
         .next()
-        .expect("Missing arguments: port cookie_file bip21")
+        .expect("Missing arguments: port cookie_file bip21 [--signer <signer>] [--broadcaster <broadcaster>] [--config <file> [--profile <name>]] [--verbose] (or verify/preview/decode --help)")
         .into_string()
         .expect("port is not UTF-8")
         .parse::<u16>()
@@ -25,15 +44,65 @@ fn main() {
         .into_string()
         .expect("bip21 is not UTF-8");
 
+    // signs with the wallet of the node by default
+    let mut signer = None;
+    // broadcasts through the node by default
+    let mut broadcaster = None;
+    let mut config = None;
+    let mut profile = None;
+    let mut verbose = false;
+    while let Some(arg) = args.next() {
+        if arg == "--signer" {
+            let arg = args
+                .next()
+                .expect("Missing value of --signer")
+                .into_string()
+                .expect("signer is not UTF-8");
+            signer = Some(arg);
+        } else if arg == "--broadcaster" {
+            let arg = args
+                .next()
+                .expect("Missing value of --broadcaster")
+                .into_string()
+                .expect("broadcaster is not UTF-8");
+            broadcaster = Some(payjoin_client::broadcast::from_arg(&arg).unwrap());
+        } else if arg == "--config" {
+            config = Some(args.next().expect("Missing value of --config"));
+        } else if arg == "--profile" {
+            profile = Some(args.next().expect("Missing value of --profile").into_string().expect("profile is not UTF-8"));
+        } else if arg == "--verbose" {
+            verbose = true;
+        } else {
+            panic!("unknown argument {:?}", arg);
+        }
+    }
+
+    // without a configuration file the sender offers to pay up to 10000 sat
+    let profile = match config {
+        Some(config) => payjoin_client::config::load(config, profile.as_deref().unwrap_or("default")).unwrap(),
+        None => {
+            let mut profile = payjoin_client::config::Profile::default();
+            profile.sender.max_fee_contribution = Some(10000);
+            profile
+        },
+    };
+    let signer = signer
+        .or_else(|| profile.signer.clone())
+        .map(|signer| payjoin_client::signer::from_arg(&signer).unwrap());
 
     let link = bip21.parse::<bip78::Uri>().unwrap();
+    let amount = link.amount().unwrap_or_else(|| {
+        eprintln!("the link doesn't specify the amount, donation links are not supported");
+        std::process::exit(1);
+    });
     let mut outputs = HashMap::with_capacity(1);
-    outputs.insert(link.address().to_string(), link.amount());
+    outputs.insert(link.address().to_string(), amount);
 
     let client = bitcoincore_rpc::Client::new(format!("http://127.0.0.1:{}", port), bitcoincore_rpc::Auth::CookieFile(cookie_file.into())).unwrap();
     let options = bitcoincore_rpc::json::WalletCreateFundedPsbtOptions {
         lock_unspent: Some(true),
-        fee_rate: Some(bip78::bitcoin::Amount::from_sat(2000)),
+        // sat/kvB
+        fee_rate: Some(bip78::bitcoin::Amount::from_sat(profile.sender.fee_rate.unwrap_or(2) * 1000)),
         ..Default::default()
     };
     let psbt = client.wallet_create_funded_psbt(
@@ -41,51 +110,130 @@ fn main() {
         &outputs,
         None, // locktime
         Some(options),
-        None,
+        Some(true), // bip32derivs
     ).expect("failed to create PSBT").psbt;
-    let psbt = client
-        .wallet_process_psbt(&psbt, None, None, None)
-        .unwrap()
-        .psbt;
-    let psbt = load_psbt_from_base64(psbt.as_bytes()).unwrap();
+    // keeps key origins which are stripped from the request and the finalized inputs
+    let unsigned = load_psbt_from_base64(psbt.as_bytes()).unwrap();
+    let signer = signer.as_deref().unwrap_or(&client);
+    let psbt = signer.sign(&unsigned).unwrap();
     println!("Original psbt: {:#?}", psbt);
-    let pj_params = bip78::sender::Params::with_fee_contribution(bip78::bitcoin::Amount::from_sat(10000), None);
-    let (req, ctx) = link.create_request(psbt, pj_params).unwrap();
-    let response = reqwest::blocking::Client::new()
-        .post(&req.url)
-        .body(req.body)
-        .header("Content-Type", "text/plain")
-        .send()
-        .expect("failed to communicate");
-        //.error_for_status()
-        //.unwrap();
-    let psbt = ctx.process_response(response).unwrap();
+    let (req, ctx) = profile.sender.apply(PayjoinSender::new(link).psbt(psbt)).build().unwrap();
+    let http = profile.sender.http_client().expect("failed to create the HTTP client");
+    // max_latency covers all the endpoints tried
+    let deadline = req.timeout.map(|max_latency| std::time::Instant::now() + max_latency);
+    let remaining = || deadline.map(|deadline| deadline.saturating_duration_since(std::time::Instant::now()));
+    let is_expired = || remaining() == Some(std::time::Duration::ZERO);
+    let timeout = || match (profile.sender.timeout_secs.map(std::time::Duration::from_secs), remaining()) {
+        (Some(timeout), Some(remaining)) => Some(timeout.min(remaining)),
+        (timeout, remaining) => timeout.or(remaining),
+    };
+    // tries the other endpoints only if the previous ones are unreachable
+    let (endpoint, response, substituted) = req
+        .urls()
+        .take_while(|_| !is_expired())
+        .find_map(|url| {
+            // onion services authenticate themselves so pins only apply to TLS
+            let pinned = !req.certificate_pins.is_empty() && matches!(url.get(..8), Some(scheme) if scheme.eq_ignore_ascii_case("https://"));
+            let nostr = matches!(url.get(..6), Some(scheme) if scheme.eq_ignore_ascii_case("nostr:"));
+            // the header is informational, the proposal is validated the same way without it
+            let response: Result<(Vec<u8>, bool), Box<dyn std::error::Error>> = if nostr {
+                send_nostr(url, &req.body, timeout()).map(|body| (body, false))
+            } else if !pinned {
+                let mut request = http.post(url);
+                if let Some(remaining) = remaining() {
+                    request = request.timeout(remaining);
+                }
+                request
+                    .body(req.body.clone())
+                    .header("Content-Type", "text/plain")
+                    .header("Accept-Encoding", payjoin_client::encoding::ACCEPT_ENCODING)
+                    .send()
+                    //.error_for_status()
+                    .map_err(Into::into)
+                    .and_then(|response| {
+                        let encoding = response.headers().get("content-encoding").and_then(|value| value.to_str().ok()).map(str::to_owned);
+                        let substituted = response.headers().get(bip78::SUBSTITUTED_HEADER).and_then(|value| value.to_str().ok()) == Some("1");
+                        payjoin_client::encoding::read_body(encoding.as_deref(), response, req.max_response_size)
+                            .map(|body| (body, substituted))
+                            .map_err(Into::into)
+                    })
+            } else if profile.sender.proxy.is_some() {
+                Err("pinned certificates can't be checked through a proxy".into())
+            } else {
+                payjoin_client::pinned::post(url, &req.body, &req.certificate_pins, timeout(), req.max_response_size)
+                    .map(|body| (body, false))
+                    .map_err(Into::into)
+            };
+            match response {
+                Ok((response, substituted)) => Some((url, response, substituted)),
+                Err(error) => {
+                    eprintln!("failed to communicate with {}: {}", url, error);
+                    None
+                },
+            }
+        })
+        .unwrap_or_else(|| {
+            if is_expired() {
+                eprintln!("no endpoint responded within max_latency");
+            } else {
+                eprintln!("all endpoints failed");
+            }
+            std::process::exit(1);
+        });
+    if is_expired() {
+        eprintln!("{} responded after max_latency", endpoint);
+        std::process::exit(1);
+    }
+    println!("Used endpoint {}", endpoint);
+    if substituted {
+        println!("The receiver reports it substituted its output");
+    }
+    let mut psbt = if verbose {
+        let audit = ctx.audit_response(&response);
+        match audit.result {
+            Ok(report) => {
+                for warning in &report.warnings {
+                    println!("warning: {}", warning);
+                }
+                report.psbt
+            },
+            Err(error) => {
+                println!("{}", payjoin_client::describe_validation_error(&error));
+                if let Some(diff) = &audit.diff {
+                    println!("changes made by the receiver:\n{}", diff);
+                }
+                std::process::exit(1);
+            },
+        }
+    } else {
+        ctx.process_response_bytes(&response).unwrap()
+    };
     println!("Proposed psbt: {:#?}", psbt);
-    let psbt = client
-        .wallet_process_psbt(&serialize_psbt(&psbt), None, None, None)
-        .unwrap()
-        .psbt;
+    // external signers such as hardware wallets can't sign inputs without UTXOs and key origins
+    bip78::sender::restore_metadata(&mut psbt, &unsigned);
+    let psbt = signer.sign(&psbt).unwrap();
     let tx = client
-        .finalize_psbt(&psbt, Some(true))
+        .finalize_psbt(&serialize_psbt(&psbt), Some(true))
         .unwrap()
         .hex
         .expect("incomplete psbt");
-    client.send_raw_transaction(&tx).unwrap();
+    let tx = bip78::bitcoin::consensus::deserialize(&tx).expect("the node returned invalid transaction");
+    let broadcaster = broadcaster.as_deref().unwrap_or(&client);
+    match broadcaster.broadcast(&tx, &Default::default()) {
+        Ok(txid) => println!("Broadcasted {}", txid),
+        Err(error) => {
+            eprintln!("failed to broadcast the payjoin transaction: {}", error);
+            std::process::exit(1);
+        },
+    }
 }
 
-fn load_psbt_from_base64(mut input: impl std::io::Read) -> Result<Psbt, bip78::bitcoin::consensus::encode::Error> {
-    use bip78::bitcoin::consensus::Decodable;    
- 
-    let reader = base64::read::DecoderReader::new(&mut input, base64::Config::new(base64::CharacterSet::Standard, true));
-    Psbt::consensus_decode(reader)    
+#[cfg(feature = "nostr")]
+fn send_nostr(url: &str, body: &[u8], timeout: Option<std::time::Duration>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    payjoin_client::nostr::send(url, body, timeout).map_err(Into::into)
 }
 
-fn serialize_psbt(psbt: &Psbt) -> String {
-    use bip78::bitcoin::consensus::Encodable;
-                                    
-    let mut encoder = base64::write::EncoderWriter::new(Vec::new(), base64::STANDARD);
-    psbt.consensus_encode(&mut encoder)
-        .expect("Vec doesn't return errors in its write implementation");
-    String::from_utf8(encoder.finish()
-        .expect("Vec doesn't return errors in its write implementation")).unwrap()
+#[cfg(not(feature = "nostr"))]
+fn send_nostr(_url: &str, _body: &[u8], _timeout: Option<std::time::Duration>) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    Err("nostr endpoints require the nostr feature".into())
 }
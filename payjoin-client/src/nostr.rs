@@ -0,0 +1,505 @@
+//! Exchanging payjoin messages through Nostr relays (experimental)
+//!
+//! `bip78::nostr` builds and opens the events, this module talks to the relay using a minimal
+//! WebSocket client. The sender uses `send()` for `nostr:` endpoints in place of an HTTP
+//! request, the receiver waits for the request using `receive()` and answers it through the
+//! returned `Incoming`.
+
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::{Duration, Instant};
+use bip78::bitcoin::hashes::{sha1, Hash};
+use bip78::nostr::{self, Endpoint, Event, Filter, Keys, Message, RelayMessage};
+
+/// Messages larger than this are rejected, payjoin events are limited by NIP-44 to ~90 kB.
+const MAX_MESSAGE_SIZE: u64 = 256 * 1024;
+/// Defined by RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+#[derive(Debug)]
+pub enum Error {
+    /// Only `ws` and `wss` relay URLs without user info are supported.
+    UnsupportedUrl(String),
+    Io(std::io::Error),
+    Tls(native_tls::Error),
+    Handshake(native_tls::HandshakeError<TcpStream>),
+    /// The relay didn't upgrade the connection to WebSocket.
+    UpgradeRejected(String),
+    /// The relay violated the WebSocket protocol or closed the connection.
+    WebSocket(&'static str),
+    Nostr(nostr::Error),
+    /// The relay rejected the event or closed the subscription.
+    Rejected(String),
+    /// No response arrived before the timeout.
+    Timeout,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedUrl(url) => write!(f, "unsupported relay URL {}", url),
+            Error::Io(_) => write!(f, "failed to communicate with the relay"),
+            Error::Tls(_) => write!(f, "TLS failed"),
+            Error::Handshake(_) => write!(f, "TLS handshake failed"),
+            Error::UpgradeRejected(status) => write!(f, "the relay rejected the WebSocket connection: {}", status),
+            Error::WebSocket(reason) => write!(f, "WebSocket error: {}", reason),
+            Error::Nostr(_) => write!(f, "invalid nostr message"),
+            Error::Rejected(reason) => write!(f, "the relay rejected the request: {}", reason),
+            Error::Timeout => write!(f, "no response arrived in time"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UnsupportedUrl(_) => None,
+            Error::Io(error) => Some(error),
+            Error::Tls(error) => Some(error),
+            Error::Handshake(error) => Some(error),
+            Error::UpgradeRejected(_) => None,
+            Error::WebSocket(_) => None,
+            Error::Nostr(error) => Some(error),
+            Error::Rejected(_) => None,
+            Error::Timeout => None,
+        }
+    }
+}
+
+impl From<nostr::Error> for Error {
+    fn from(value: nostr::Error) -> Self {
+        Error::Nostr(value)
+    }
+}
+
+enum Stream {
+    Plain(TcpStream),
+    Tls(Box<native_tls::TlsStream<TcpStream>>),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.read(buf),
+            Stream::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Plain(stream) => stream.write(buf),
+            Stream::Tls(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Plain(stream) => stream.flush(),
+            Stream::Tls(stream) => stream.flush(),
+        }
+    }
+}
+
+/// Bytes for WebSocket keys and masks, they only need to be unpredictable to proxies.
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    for chunk in bytes.chunks_mut(8) {
+        let random = RandomState::new().build_hasher().finish().to_le_bytes();
+        chunk.copy_from_slice(&random[..chunk.len()]);
+    }
+    bytes
+}
+
+/// Connection to a relay.
+pub struct Relay {
+    stream: Stream,
+    url: String,
+}
+
+impl Relay {
+    /// Connects to `ws://` or `wss://` relay, `timeout` applies to each read and write.
+    pub fn connect(url: &str, timeout: Option<Duration>) -> Result<Self, Error> {
+        let (tls, authority, host, port, path) = split_url(url).ok_or_else(|| Error::UnsupportedUrl(url.to_owned()))?;
+        let tcp = TcpStream::connect((host, port)).map_err(Error::Io)?;
+        tcp.set_read_timeout(timeout).map_err(Error::Io)?;
+        tcp.set_write_timeout(timeout).map_err(Error::Io)?;
+        let mut stream = if tls {
+            let connector = native_tls::TlsConnector::new().map_err(Error::Tls)?;
+            Stream::Tls(Box::new(connector.connect(host, tcp).map_err(Error::Handshake)?))
+        } else {
+            Stream::Plain(tcp)
+        };
+
+        let key = base64::encode(random_bytes::<16>());
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {}\r\nSec-WebSocket-Version: 13\r\n\r\n", path, authority, key);
+        stream.write_all(request.as_bytes()).map_err(Error::Io)?;
+        stream.flush().map_err(Error::Io)?;
+        // reads byte by byte so that no frame is consumed with the head
+        let mut head = Vec::new();
+        while !head.ends_with(b"\r\n\r\n") {
+            if head.len() > 64 * 1024 {
+                return Err(Error::UpgradeRejected("the response head is too large".to_owned()));
+            }
+            let mut byte = [0];
+            stream.read_exact(&mut byte).map_err(Error::Io)?;
+            head.push(byte[0]);
+        }
+        let head = String::from_utf8_lossy(&head);
+        let mut lines = head.split("\r\n");
+        let status = lines.next().unwrap_or_default();
+        if status.split_whitespace().nth(1) != Some("101") {
+            return Err(Error::UpgradeRejected(status.to_owned()));
+        }
+        let expected_accept = base64::encode(sha1::Hash::hash(format!("{}{}", key, WEBSOCKET_GUID).as_bytes()));
+        let accept = lines
+            .filter_map(|line| line.split_once(':'))
+            .find(|(name, _)| name.trim().eq_ignore_ascii_case("sec-websocket-accept"))
+            .map(|(_, value)| value.trim());
+        if accept != Some(&*expected_accept) {
+            return Err(Error::UpgradeRejected("invalid Sec-WebSocket-Accept".to_owned()));
+        }
+        Ok(Relay { stream, url: url.to_owned(), })
+    }
+
+    /// Returns the URL of the relay.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Publishes the event and waits until the relay accepts it.
+    pub fn publish(&mut self, event: &Event) -> Result<(), Error> {
+        self.send_text(&nostr::publish_message(event))?;
+        let id = bip78::bitcoin::hashes::hex::ToHex::to_hex(&event.id[..]);
+        loop {
+            match self.next_message()? {
+                RelayMessage::Ok { event_id, accepted: true, .. } if event_id == id => return Ok(()),
+                RelayMessage::Ok { event_id, accepted: false, message, } if event_id == id => return Err(Error::Rejected(message)),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn subscribe(&mut self, subscription_id: &str, filter: &Filter) -> Result<(), Error> {
+        self.send_text(&nostr::subscribe_message(subscription_id, filter))
+    }
+
+    /// Returns the next event of the subscription.
+    pub fn next_event(&mut self, subscription_id: &str) -> Result<Event, Error> {
+        loop {
+            match self.next_message()? {
+                RelayMessage::Event { subscription_id: id, event, } if id == subscription_id => return Ok(event),
+                RelayMessage::Closed { subscription_id: id, message, } if id == subscription_id => return Err(Error::Rejected(message)),
+                _ => (),
+            }
+        }
+    }
+
+    /// Returns the next message of the relay, skipping messages that can't be parsed.
+    fn next_message(&mut self) -> Result<RelayMessage, Error> {
+        read_message(&mut self.stream)
+    }
+
+    fn send_text(&mut self, text: &str) -> Result<(), Error> {
+        write_frame(&mut self.stream, 0x1, text.as_bytes())
+    }
+}
+
+/// Reads the next message of the relay, skipping messages that can't be parsed.
+fn read_message<S: Read + Write>(stream: &mut S) -> Result<RelayMessage, Error> {
+    loop {
+        let text = read_text(stream)?;
+        if let Ok(message) = RelayMessage::parse(&text) {
+            return Ok(message);
+        }
+    }
+}
+
+/// Encodes the frame of a client, clients must mask the payload.
+fn encode_frame(opcode: u8, payload: &[u8], mask: [u8; 4]) -> Vec<u8> {
+    let mut frame = vec![0x80 | opcode];
+    match payload.len() {
+        len if len < 126 => frame.push(0x80 | len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(0x80 | 126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        },
+        len => {
+            frame.push(0x80 | 127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        },
+    }
+    frame.extend_from_slice(&mask);
+    frame.extend(payload.iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask));
+    frame
+}
+
+fn write_frame<S: Write>(stream: &mut S, opcode: u8, payload: &[u8]) -> Result<(), Error> {
+    stream.write_all(&encode_frame(opcode, payload, random_bytes())).map_err(Error::Io)?;
+    stream.flush().map_err(Error::Io)
+}
+
+/// Reads the next text message, answering pings.
+fn read_text<S: Read + Write>(stream: &mut S) -> Result<String, Error> {
+    let mut message = Vec::new();
+    loop {
+        let mut header = [0; 2];
+        stream.read_exact(&mut header).map_err(Error::Io)?;
+        let is_final = header[0] & 0x80 != 0;
+        let opcode = header[0] & 0x0f;
+        if header[1] & 0x80 != 0 {
+            return Err(Error::WebSocket("the relay masked the frame"));
+        }
+        let len = match header[1] & 0x7f {
+            126 => {
+                let mut len = [0; 2];
+                stream.read_exact(&mut len).map_err(Error::Io)?;
+                u16::from_be_bytes(len).into()
+            },
+            127 => {
+                let mut len = [0; 8];
+                stream.read_exact(&mut len).map_err(Error::Io)?;
+                u64::from_be_bytes(len)
+            },
+            len => len.into(),
+        };
+        if (message.len() as u64).saturating_add(len) > MAX_MESSAGE_SIZE {
+            return Err(Error::WebSocket("the message is too large"));
+        }
+        let mut payload = vec![0; len as usize];
+        stream.read_exact(&mut payload).map_err(Error::Io)?;
+        match opcode {
+            // text and continuation
+            0x1 | 0x0 => {
+                message.extend_from_slice(&payload);
+                if is_final {
+                    return String::from_utf8(message).map_err(|_| Error::WebSocket("the message is not UTF-8"));
+                }
+            },
+            0x8 => return Err(Error::WebSocket("the relay closed the connection")),
+            0x9 => write_frame(stream, 0xa, &payload)?,
+            // pong and binary messages are ignored
+            _ => (),
+        }
+    }
+}
+
+/// Splits `ws[s]://host[:port]/path` into TLS flag, authority, host, port and path.
+fn split_url(url: &str) -> Option<(bool, &str, &str, u16, &str)> {
+    let (tls, rest) = if url.get(..6)?.eq_ignore_ascii_case("wss://") {
+        (true, &url[6..])
+    } else if url.get(..5)?.eq_ignore_ascii_case("ws://") {
+        (false, &url[5..])
+    } else {
+        return None;
+    };
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_len);
+    if authority.contains('@') || authority.is_empty() {
+        return None;
+    }
+    let path = path.split('#').next().unwrap_or_default();
+    let path = if path.is_empty() { "/" } else { path };
+    // the colons of IPv6 addresses are enclosed in brackets
+    let (host, port) = match authority.rsplit_once(':').filter(|(_, port)| !port.ends_with(']')) {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, if tls { 443 } else { 80 }),
+    };
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Some((tls, authority, host, port, path))
+}
+
+/// Sends the request to the `nostr:` endpoint and returns the body of the response.
+///
+/// Uses fresh keys for each request. The status code isn't returned, like with HTTP the error
+/// responses are JSON processed by `bip78`. Requests larger than `nostr::MAX_MESSAGE_SIZE` fail
+/// before connecting to the relay.
+pub fn send(url: &str, body: &[u8], timeout: Option<Duration>) -> Result<Vec<u8>, Error> {
+    let (endpoint, query) = Endpoint::from_url(url)?;
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let keys = Keys::generate();
+    let request = keys.seal_request(&endpoint.receiver, &query, body)?;
+    let mut relay = Relay::connect(&endpoint.relay, timeout)?;
+    // subscribing first so that a quick response isn't missed
+    relay.subscribe("payjoin", &Filter::responses_to(&request))?;
+    relay.publish(&request)?;
+    loop {
+        if let Some(deadline) = deadline {
+            if Instant::now() > deadline {
+                return Err(Error::Timeout);
+            }
+        }
+        let event = relay.next_event("payjoin")?;
+        // anyone can publish events tagging us, only the response of the receiver matters
+        let opened = match keys.open(&event) {
+            Ok(opened) if opened.sender == endpoint.receiver && opened.reply_to == Some(request.id) => opened,
+            _ => continue,
+        };
+        if let Message::Response { body, .. } = opened.message {
+            return Ok(body.into_bytes());
+        }
+    }
+}
+
+/// Request received through a relay.
+pub struct Incoming {
+    relay: Relay,
+    keys: Keys,
+    request: Event,
+    /// The query of the request, pass it to `UncheckedProposal::from_request_bytes()`.
+    pub query: String,
+    pub body: String,
+}
+
+impl Incoming {
+    /// Sends the response (status code and the proposal or the JSON error) to the sender.
+    pub fn respond(mut self, status: u16, body: &[u8]) -> Result<(), Error> {
+        let response = self.keys.seal_response(&self.request, status, body)?;
+        self.relay.publish(&response)
+    }
+}
+
+/// Waits for a request sent to `keys` through the relay.
+///
+/// `timeout` applies to each read, `None` waits indefinitely. Events that aren't valid requests
+/// are skipped.
+pub fn receive(keys: Keys, relay_url: &str, timeout: Option<Duration>) -> Result<Incoming, Error> {
+    let mut relay = Relay::connect(relay_url, timeout)?;
+    relay.subscribe("payjoin", &Filter::requests_to(keys.public_key()))?;
+    loop {
+        let event = relay.next_event("payjoin")?;
+        if let Ok(nostr::Opened { message: Message::Request { query, body, }, .. }) = keys.open(&event) {
+            return Ok(Incoming { relay, keys, request: event, query, body, });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Relay answering with `input` and recording what the client writes.
+    struct Peer {
+        input: std::io::Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Peer {
+        fn new(frames: &[Vec<u8>]) -> Self {
+            Peer { input: std::io::Cursor::new(frames.concat()), output: Vec::new(), }
+        }
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Encodes the unmasked frame of a relay.
+    fn relay_frame(is_final: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = encode_frame(opcode, payload, [0; 4]);
+        if !is_final {
+            frame[0] &= 0x7f;
+        }
+        frame[1] &= 0x7f;
+        // the mask
+        let header_len = frame.len() - payload.len() - 4;
+        frame.drain(header_len..(header_len + 4));
+        frame
+    }
+
+    #[test]
+    fn encoding() {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        for &(len, header) in &[(125, &[0x81, 0x80 | 125][..]), (126, &[0x81, 0x80 | 126, 0x00, 126][..]), (65536, &[0x81, 0x80 | 127, 0, 0, 0, 0, 0, 1, 0, 0][..])] {
+            let payload = (0..len).map(|i| i as u8).collect::<Vec<_>>();
+            let frame = encode_frame(0x1, &payload, mask);
+            assert_eq!(&frame[..header.len()], header, "{}", len);
+            assert_eq!(frame[header.len()..(header.len() + 4)], mask);
+            let masked = &frame[(header.len() + 4)..];
+            assert_eq!(masked.len(), len);
+            assert_ne!(masked, &payload[..]);
+            let unmasked = masked.iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask).collect::<Vec<_>>();
+            assert_eq!(unmasked, payload);
+        }
+    }
+
+    #[test]
+    fn decoding() {
+        for &len in &[0, 125, 126, 65535, 65536] {
+            let text = "x".repeat(len);
+            let mut peer = Peer::new(&[relay_frame(true, 0x1, text.as_bytes())]);
+            assert_eq!(read_text(&mut peer).unwrap(), text, "{}", len);
+            assert!(peer.output.is_empty());
+        }
+
+        // fragmented message interleaved with a ping
+        let mut peer = Peer::new(&[relay_frame(false, 0x1, b"hel"), relay_frame(true, 0x9, b"ping"), relay_frame(true, 0x0, b"lo")]);
+        assert_eq!(read_text(&mut peer).unwrap(), "hello");
+        assert_eq!(peer.output[0], 0x8a);
+        assert_eq!(peer.output[1], 0x80 | 4);
+        let mask = &peer.output[2..6];
+        let pong = peer.output[6..].iter().zip(mask.iter().cycle()).map(|(byte, mask)| byte ^ mask).collect::<Vec<_>>();
+        assert_eq!(pong, b"ping");
+
+        let masked = encode_frame(0x1, b"hello", [1, 2, 3, 4]);
+        assert!(matches!(read_text(&mut Peer::new(&[masked])), Err(Error::WebSocket(_))));
+        let too_large = [0x81, 127, 0, 0, 0, 0, 0, 4, 0, 1].to_vec();
+        assert!(matches!(read_text(&mut Peer::new(&[too_large])), Err(Error::WebSocket(_))));
+        let huge = [0x81, 127, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff].to_vec();
+        assert!(matches!(read_text(&mut Peer::new(&[huge])), Err(Error::WebSocket(_))));
+        assert!(matches!(read_text(&mut Peer::new(&[relay_frame(true, 0x8, b"")])), Err(Error::WebSocket(_))));
+        assert!(matches!(read_text(&mut Peer::new(&[relay_frame(true, 0x1, b"\xff")])), Err(Error::WebSocket(_))));
+        assert!(matches!(read_text(&mut Peer::new(&[relay_frame(true, 0x1, b"hello")[..4].to_vec()])), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn relay_messages() {
+        let frames = [
+            relay_frame(true, 0x1, b"not json"),
+            relay_frame(true, 0x2, b"[\"NOTICE\",\"binary\"]"),
+            relay_frame(true, 0x1, b"[\"NOTICE\",\"hello\"]"),
+            relay_frame(true, 0x1, b"[\"OK\",\"abcd\",false,\"blocked: spam\"]"),
+            relay_frame(true, 0x1, b"[\"CLOSED\",\"payjoin\",\"error: shutting down\"]"),
+            relay_frame(true, 0x1, b"[\"AUTH\",\"challenge\"]"),
+        ];
+        let mut peer = Peer::new(&frames);
+        assert!(matches!(read_message(&mut peer).unwrap(), RelayMessage::Notice { message } if message == "hello"));
+        assert!(matches!(read_message(&mut peer).unwrap(), RelayMessage::Ok { event_id, accepted: false, message, } if event_id == "abcd" && message == "blocked: spam"));
+        assert!(matches!(read_message(&mut peer).unwrap(), RelayMessage::Closed { subscription_id, message, } if subscription_id == "payjoin" && message == "error: shutting down"));
+        assert!(matches!(read_message(&mut peer).unwrap(), RelayMessage::Other));
+        assert!(matches!(read_message(&mut peer), Err(Error::Io(_))));
+    }
+
+    #[test]
+    fn request_size() {
+        let keys = Keys::generate();
+        let url = format!("{}", Endpoint { receiver: *keys.public_key(), relay: "ws://127.0.0.1:1".to_owned(), });
+        let body = "A".repeat(nostr::MAX_MESSAGE_SIZE);
+        // fails before connecting to the relay
+        assert!(matches!(send(&url, body.as_bytes(), None), Err(Error::Nostr(error)) if error.to_string().contains("at most")));
+    }
+
+    #[test]
+    fn url() {
+        assert_eq!(split_url("wss://relay.example.com"), Some((true, "relay.example.com", "relay.example.com", 443, "/")));
+        assert_eq!(split_url("ws://[::1]:7000/nostr?x#y"), Some((false, "[::1]:7000", "::1", 7000, "/nostr?x")));
+        assert_eq!(split_url("https://relay.example.com"), None);
+        assert_eq!(split_url("wss://user@relay.example.com"), None);
+    }
+}
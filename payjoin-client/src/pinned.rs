@@ -0,0 +1,168 @@
+//! Sending requests to endpoints with pinned certificates
+//!
+//! `reqwest` doesn't expose the certificate of the connection so requests to links with `pjpin`
+//! go through a minimal HTTP/1.0 client which checks the certificate right after the handshake,
+//! before sending the PSBT. The certificate must still be valid for the host - the pin restricts
+//! which keys are accepted, it doesn't replace the validation.
+
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+use bip78::CertificatePin;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Only `https` URLs without user info are supported.
+    UnsupportedUrl(String),
+    Io(std::io::Error),
+    Tls(native_tls::Error),
+    Handshake(native_tls::HandshakeError<TcpStream>),
+    /// The endpoint presented a certificate with a key not matching any pin.
+    PinMismatch { host: String, },
+    InvalidResponse,
+    /// The response body is larger than allowed.
+    ResponseTooLarge,
+    Decode(crate::encoding::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::UnsupportedUrl(url) => write!(f, "can't pin the certificate of {}, only https URLs are supported", url),
+            Error::Io(_) => write!(f, "failed to communicate with the endpoint"),
+            Error::Tls(_) => write!(f, "TLS failed"),
+            Error::Handshake(_) => write!(f, "TLS handshake failed"),
+            Error::PinMismatch { host, } => write!(f, "the certificate of {} doesn't match the pinned keys", host),
+            Error::InvalidResponse => write!(f, "the response is not valid HTTP"),
+            Error::ResponseTooLarge => write!(f, "the response is too large"),
+            Error::Decode(_) => write!(f, "failed to decode the response body"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::UnsupportedUrl(_) => None,
+            Error::Io(error) => Some(error),
+            Error::Tls(error) => Some(error),
+            Error::Handshake(error) => Some(error),
+            Error::PinMismatch { .. } => None,
+            Error::InvalidResponse => None,
+            Error::ResponseTooLarge => None,
+            Error::Decode(error) => Some(error),
+        }
+    }
+}
+
+/// Headers of the response larger than this are considered invalid.
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+
+/// Sends the body as `POST` to `url` if the certificate of the endpoint matches one of `pins`.
+///
+/// Returns the body of the response regardless of the status code, decompressed if the endpoint
+/// used `Content-Encoding`. At most `max_response_size` bytes of the body are read, see
+/// `bip78::sender::Request::max_response_size`.
+pub fn post(url: &str, body: &[u8], pins: &[CertificatePin], timeout: Option<Duration>, max_response_size: u64) -> Result<Vec<u8>, Error> {
+    let (authority, host, port, path) = split_url(url).ok_or_else(|| Error::UnsupportedUrl(url.to_owned()))?;
+    let stream = TcpStream::connect((host, port)).map_err(Error::Io)?;
+    stream.set_read_timeout(timeout).map_err(Error::Io)?;
+    stream.set_write_timeout(timeout).map_err(Error::Io)?;
+    let connector = native_tls::TlsConnector::new().map_err(Error::Tls)?;
+    let mut stream = connector.connect(host, stream).map_err(Error::Handshake)?;
+    let certificate = stream.peer_certificate().map_err(Error::Tls)?;
+    let der = certificate.map(|certificate| certificate.to_der()).transpose().map_err(Error::Tls)?;
+    if !matches!(&der, Some(der) if pins.iter().any(|pin| pin.matches_certificate(der))) {
+        return Err(Error::PinMismatch { host: host.to_owned(), });
+    }
+
+    // HTTP/1.0 avoids chunked responses
+    let header = format!("POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nAccept-Encoding: {}\r\nConnection: close\r\n\r\n", path, authority, body.len(), crate::encoding::ACCEPT_ENCODING);
+    stream.write_all(header.as_bytes()).map_err(Error::Io)?;
+    stream.write_all(body).map_err(Error::Io)?;
+    stream.flush().map_err(Error::Io)?;
+    let mut response = Vec::new();
+    // one more byte to detect exceeding the limit
+    let limit = max_response_size.saturating_add(MAX_HEADER_SIZE).saturating_add(1);
+    match (&mut stream).take(limit).read_to_end(&mut response) {
+        Ok(_) => (),
+        // some servers close the connection without TLS close_notify
+        Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof && !response.is_empty() => (),
+        Err(error) => return Err(Error::Io(error)),
+    }
+    let body_start = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or(Error::InvalidResponse)? + 4;
+    if body_start as u64 > MAX_HEADER_SIZE {
+        return Err(Error::InvalidResponse);
+    }
+    if (response.len() - body_start) as u64 > max_response_size {
+        return Err(Error::ResponseTooLarge);
+    }
+    let head = std::str::from_utf8(&response[..body_start]).map_err(|_| Error::InvalidResponse)?;
+    crate::encoding::read_body(content_encoding(head), &response[body_start..], max_response_size).map_err(Error::Decode)
+}
+
+/// Returns the value of `Content-Encoding` header.
+fn content_encoding(head: &str) -> Option<&str> {
+    head
+        .split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-encoding"))
+        .map(|(_, value)| value.trim())
+}
+
+/// Splits `https://host[:port]/path?query` into authority, host, port and path with query.
+fn split_url(url: &str) -> Option<(&str, &str, u16, String)> {
+    let scheme_len = "https://".len();
+    if !url.get(..scheme_len)?.eq_ignore_ascii_case("https://") {
+        return None;
+    }
+    let rest = &url[scheme_len..];
+    let authority_len = rest.find(['/', '?', '#']).unwrap_or(rest.len());
+    let (authority, path) = rest.split_at(authority_len);
+    if authority.contains('@') {
+        return None;
+    }
+    let path = path.split('#').next().unwrap_or_default();
+    let path = if path.starts_with('/') { path.to_owned() } else { format!("/{}", path) };
+    // the colons of IPv6 addresses are enclosed in brackets
+    let (host, port) = match authority.rsplit_once(':').filter(|(_, port)| !port.ends_with(']')) {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 443),
+    };
+    let host = host.strip_prefix('[').and_then(|host| host.strip_suffix(']')).unwrap_or(host);
+    Some((authority, host, port, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(split_url("https://example.com"), Some(("example.com", "example.com", 443, "/".to_owned())));
+        assert_eq!(split_url("HTTPS://example.com:8443/pj?v=1#frag"), Some(("example.com:8443", "example.com", 8443, "/pj?v=1".to_owned())));
+        assert_eq!(split_url("https://example.com?v=1"), Some(("example.com", "example.com", 443, "/?v=1".to_owned())));
+        assert_eq!(split_url("https://[::1]/pj"), Some(("[::1]", "::1", 443, "/pj".to_owned())));
+        assert_eq!(split_url("https://[::1]:8443/pj"), Some(("[::1]:8443", "::1", 8443, "/pj".to_owned())));
+    }
+
+    #[test]
+    fn split_invalid() {
+        assert_eq!(split_url("http://example.com/pj"), None);
+        assert_eq!(split_url("https:/"), None);
+        assert_eq!(split_url("https://user@example.com/pj"), None);
+        assert_eq!(split_url("https://example.com:port/pj"), None);
+        assert_eq!(split_url("https://example.com:65536/pj"), None);
+    }
+
+    #[test]
+    fn encoding() {
+        let head = "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\ncontent-ENCODING:  gzip \r\n\r\n";
+        assert_eq!(content_encoding(head), Some("gzip"));
+        assert_eq!(content_encoding("HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\n"), None);
+        // the status line isn't a header
+        assert_eq!(content_encoding("Content-Encoding: gzip\r\n\r\n"), None);
+    }
+}
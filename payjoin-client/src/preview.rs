@@ -0,0 +1,112 @@
+//! `preview` and `decode` subcommands
+//!
+//! Show what a receiver would get for an original PSBT without contacting it and decode PSBTs
+//! e.g. from logs, for debugging integrations.
+
+use std::convert::TryFrom;
+use std::ffi::OsString;
+use bip78::bitcoin::Amount;
+use bip78::sender::Params;
+
+const USAGE: &str = "Usage: payjoin-client preview --original <base64 PSBT> --uri <bip21> [options]
+
+Options:
+    --max-fee-contribution <sat>    offer maxadditionalfeecontribution
+    --change-index <index>          additionalfeeoutputindex, auto-detected by default
+    --min-fee-rate <sat/vB>         require minfeerate
+    --disable-output-substitution   send disableoutputsubstitution=1";
+
+const DECODE_USAGE: &str = "Usage: payjoin-client decode <base64 PSBT>";
+
+struct Args {
+    original: String,
+    uri: String,
+    max_fee_contribution: Option<Amount>,
+    change_index: Option<usize>,
+    min_fee_rate: Option<u64>,
+    disable_output_substitution: bool,
+}
+
+fn parse_args(args: impl Iterator<Item=OsString>) -> Result<Args, String> {
+    let mut original = None;
+    let mut uri = None;
+    let mut max_fee_contribution = None;
+    let mut change_index = None;
+    let mut min_fee_rate = None;
+    let mut disable_output_substitution = false;
+
+    let mut args = args.map(|arg| arg.into_string().map_err(|arg| format!("argument {:?} is not UTF-8", arg)));
+    while let Some(arg) = args.next() {
+        let arg = arg?;
+        let mut value = || args.next().unwrap_or_else(|| Err(format!("missing value of {}", arg)));
+        match &*arg {
+            "--original" => original = Some(value()?),
+            "--uri" => uri = Some(value()?),
+            "--max-fee-contribution" => max_fee_contribution = Some(Amount::from_sat(value()?.parse().map_err(|error| format!("invalid fee contribution: {}", error))?)),
+            "--change-index" => change_index = Some(value()?.parse().map_err(|error| format!("invalid change index: {}", error))?),
+            "--min-fee-rate" => min_fee_rate = Some(value()?.parse().map_err(|error| format!("invalid fee rate: {}", error))?),
+            "--disable-output-substitution" => disable_output_substitution = true,
+            "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        original: original.ok_or("missing --original")?,
+        uri: uri.ok_or("missing --uri")?,
+        max_fee_contribution,
+        change_index,
+        min_fee_rate,
+        disable_output_substitution,
+    })
+}
+
+fn usage_error(error: String, usage: &str) -> ! {
+    if !error.is_empty() {
+        eprintln!("Error: {}\n", error);
+    }
+    eprintln!("{}", usage);
+    std::process::exit(2);
+}
+
+/// Prints the request, exits with 1 if the original PSBT or the options can't be used and 2 if
+/// the arguments are invalid.
+pub fn run(args: impl Iterator<Item=OsString>) {
+    let args = parse_args(args).unwrap_or_else(|error| usage_error(error, USAGE));
+    let original = payjoin_client::load_psbt_from_base64(args.original.trim().as_bytes()).unwrap_or_else(|error| {
+        eprintln!("Error: invalid original PSBT: {}", error);
+        std::process::exit(2);
+    });
+    let uri = bip78::Uri::try_from(&*args.uri).unwrap_or_else(|error| {
+        eprintln!("Error: invalid URI: {}", error);
+        std::process::exit(2);
+    });
+    let mut params = Params::default()
+        .always_disable_output_substitution(args.disable_output_substitution);
+    if let Some(max_fee_contribution) = args.max_fee_contribution {
+        params = params.fee_contribution(max_fee_contribution, args.change_index);
+    }
+    if let Some(min_fee_rate) = args.min_fee_rate {
+        params = params.min_fee_rate(min_fee_rate);
+    }
+    match params.dry_run(original, uri) {
+        Ok(request) => println!("{}", request.preview()),
+        Err(error) => {
+            println!("the original PSBT can't be used: {}", error);
+            std::process::exit(1);
+        },
+    }
+}
+
+/// Prints the PSBT as JSON, exits with 2 if it's invalid.
+pub fn decode(mut args: impl Iterator<Item=OsString>) {
+    let psbt = match (args.next().map(OsString::into_string), args.next()) {
+        (Some(Ok(psbt)), None) if psbt != "--help" => psbt,
+        _ => usage_error(String::new(), DECODE_USAGE),
+    };
+    let psbt = payjoin_client::load_psbt_from_base64(psbt.trim().as_bytes()).unwrap_or_else(|error| {
+        eprintln!("Error: invalid PSBT: {}", error);
+        std::process::exit(2);
+    });
+    println!("{}", bip78::psbt::to_json(&psbt));
+}
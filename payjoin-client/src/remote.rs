@@ -0,0 +1,183 @@
+//! Remote UTXO service over HTTP
+//!
+//! Reference implementation of `RemoteUtxoSource` and `RemoteSigner` for custodians exposing a
+//! PSBT-funding API. The service is expected to answer two requests, both with a base64 PSBT in
+//! the body (`text/plain`):
+//!
+//! * `POST <url>/candidates` with the original PSBT of the sender. The response contains the
+//!   inputs offered for contribution along with their UTXO information, the outputs of the
+//!   response are ignored.
+//! * `POST <url>/sign?inputs=<index>[,<index>...]` with the proposal. The response is the same
+//!   transaction with the listed inputs signed and finalized.
+//!
+//! Each request times out at the deadline given by the receiver.
+
+use std::fmt;
+use bip78::bitcoin::util::psbt::{Input, PartiallySignedTransaction as Psbt};
+use bip78::bitcoin::OutPoint;
+use bip78::receiver::{RemoteSigner, RemoteUtxoSource};
+use bip78::time::{Deadline, SystemClock};
+use crate::{load_psbt_from_base64, serialize_psbt};
+
+#[derive(Debug)]
+pub enum Error {
+    Http(reqwest::Error),
+    /// The service responded with a status other than 200.
+    Status(u16),
+    InvalidPsbt(bip78::bitcoin::consensus::encode::Error),
+    /// The service signed a different transaction than the proposal.
+    TransactionChanged,
+    /// The deadline passed before the request was sent.
+    DeadlineExpired,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(_) => write!(f, "failed to communicate with the UTXO service"),
+            Error::Status(status) => write!(f, "the UTXO service responded with status {}", status),
+            Error::InvalidPsbt(_) => write!(f, "the UTXO service returned an invalid PSBT"),
+            Error::TransactionChanged => write!(f, "the UTXO service signed a different transaction"),
+            Error::DeadlineExpired => write!(f, "no time is left to ask the UTXO service"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(error) => Some(error),
+            Error::Status(_) => None,
+            Error::InvalidPsbt(error) => Some(error),
+            Error::TransactionChanged => None,
+            Error::DeadlineExpired => None,
+        }
+    }
+}
+
+/// Client of the UTXO service, see the module documentation.
+pub struct HttpUtxoService {
+    url: String,
+    http: reqwest::blocking::Client,
+    bearer_token: Option<String>,
+}
+
+impl HttpUtxoService {
+    /// Uses the service at `url`, the paths of the requests are appended to it.
+    pub fn new(url: impl Into<String>, http: reqwest::blocking::Client) -> Self {
+        let mut url = url.into();
+        if url.ends_with('/') {
+            url.pop();
+        }
+        HttpUtxoService {
+            url,
+            http,
+            bearer_token: None,
+        }
+    }
+
+    /// Authenticates the requests using the `Authorization: Bearer` header.
+    pub fn bearer_token(mut self, token: String) -> Self {
+        self.bearer_token = Some(token);
+        self
+    }
+
+    fn post(&self, path: &str, psbt: &Psbt, deadline: Deadline) -> Result<Psbt, Error> {
+        if deadline.is_expired(&SystemClock) {
+            return Err(Error::DeadlineExpired);
+        }
+        let mut request = self.http
+            .post(format!("{}{}", self.url, path))
+            .header("Content-Type", "text/plain")
+            .body(serialize_psbt(psbt))
+            .timeout(deadline.remaining(&SystemClock));
+        if let Some(token) = &self.bearer_token {
+            request = request.bearer_auth(token);
+        }
+        let response = request.send().map_err(Error::Http)?;
+        if response.status() != reqwest::StatusCode::OK {
+            return Err(Error::Status(response.status().as_u16()));
+        }
+        let body = response.bytes().map_err(Error::Http)?;
+        load_psbt_from_base64(&*body).map_err(Error::InvalidPsbt)
+    }
+}
+
+impl RemoteUtxoSource for HttpUtxoService {
+    type Error = Error;
+
+    fn fetch_candidates(&self, original: &Psbt, deadline: Deadline) -> Result<Vec<(OutPoint, Input)>, Self::Error> {
+        self.post("/candidates", original, deadline).map(candidates)
+    }
+}
+
+impl RemoteSigner for HttpUtxoService {
+    type Error = Error;
+
+    fn sign_inputs(&self, proposal: &Psbt, indices: &[usize], deadline: Deadline) -> Result<Vec<Input>, Self::Error> {
+        let signed = self.post(&sign_path(indices), proposal, deadline)?;
+        signed_inputs(proposal, signed, indices)
+    }
+}
+
+/// Pairs the inputs of the `/candidates` response with their outpoints.
+fn candidates(response: Psbt) -> Vec<(OutPoint, Input)> {
+    let outpoints = response.global.unsigned_tx.input.iter().map(|txin| txin.previous_output);
+    outpoints.zip(response.inputs).collect()
+}
+
+fn sign_path(indices: &[usize]) -> String {
+    let indices = indices.iter().map(ToString::to_string).collect::<Vec<_>>().join(",");
+    format!("/sign?inputs={}", indices)
+}
+
+/// Picks the inputs at `indices` from the `/sign` response, missing ones are left unsigned.
+fn signed_inputs(proposal: &Psbt, signed: Psbt, indices: &[usize]) -> Result<Vec<Input>, Error> {
+    if signed.global.unsigned_tx.txid() != proposal.global.unsigned_tx.txid() {
+        return Err(Error::TransactionChanged);
+    }
+    Ok(indices.iter().map(|&index| signed.inputs.get(index).cloned().unwrap_or_default()).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proposal() -> Psbt {
+        load_psbt_from_base64(bip78::testing::PROPOSAL_PSBT.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn url() {
+        let http = reqwest::blocking::Client::new();
+        assert_eq!(HttpUtxoService::new("https://custodian.example/api/", http.clone()).url, "https://custodian.example/api");
+        assert_eq!(HttpUtxoService::new("https://custodian.example/api", http).url, "https://custodian.example/api");
+        assert_eq!(sign_path(&[1]), "/sign?inputs=1");
+        assert_eq!(sign_path(&[0, 2, 5]), "/sign?inputs=0,2,5");
+    }
+
+    #[test]
+    fn candidate_outpoints() {
+        let proposal = proposal();
+        let candidates = candidates(proposal.clone());
+        assert_eq!(candidates.len(), 2);
+        for (i, (outpoint, input)) in candidates.into_iter().enumerate() {
+            assert_eq!(outpoint, proposal.global.unsigned_tx.input[i].previous_output);
+            assert_eq!(input, proposal.inputs[i]);
+        }
+    }
+
+    #[test]
+    fn signed() {
+        let proposal = proposal();
+        let mut unsigned = proposal.clone();
+        unsigned.inputs[1] = Input::default();
+        assert_eq!(signed_inputs(&unsigned, proposal.clone(), &[1]).unwrap(), vec![proposal.inputs[1].clone()]);
+        // missing inputs are returned unsigned
+        assert_eq!(signed_inputs(&unsigned, proposal.clone(), &[1, 7]).unwrap(), vec![proposal.inputs[1].clone(), Input::default()]);
+
+        let mut changed = proposal.clone();
+        changed.global.unsigned_tx.output[0].value -= 1;
+        assert!(matches!(signed_inputs(&unsigned, changed, &[1]), Err(Error::TransactionChanged)));
+    }
+}
@@ -0,0 +1,292 @@
+//! Signing of PSBTs
+//!
+//! The binaries don't care who holds the keys: a Bitcoin Core wallet, a hardware wallet accessed
+//! through HWI or plain private keys all implement `Signer`.
+
+use std::process::Command;
+use bip78::bitcoin::{PrivateKey, PublicKey, Script};
+use bip78::bitcoin::blockdata::script::Builder;
+use bip78::bitcoin::blockdata::transaction::SigHashType;
+use bip78::bitcoin::secp256k1::{Message, Secp256k1};
+use bip78::bitcoin::util::bip143::SigHashCache;
+use bip78::bitcoin::util::psbt::{Input, PartiallySignedTransaction as Psbt};
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::jsonrpc::serde_json;
+use crate::{load_psbt_from_base64, serialize_psbt};
+
+pub type Error = Box<dyn std::error::Error + Send + Sync>;
+
+/// Something able to sign PSBTs.
+pub trait Signer {
+    /// Signs and finalizes the inputs the signer has keys for.
+    ///
+    /// Other inputs are left untouched so the same PSBT can be passed to multiple signers.
+    fn sign(&self, psbt: &Psbt) -> Result<Psbt, Error>;
+}
+
+/// Signs using `walletprocesspsbt`.
+impl Signer for bitcoincore_rpc::Client {
+    fn sign(&self, psbt: &Psbt) -> Result<Psbt, Error> {
+        let signed = self.wallet_process_psbt(&serialize_psbt(psbt), Some(true), None, None)?.psbt;
+        Ok(load_psbt_from_base64(signed.as_bytes())?)
+    }
+}
+
+/// Signs using a hardware wallet by calling `hwi signtx`.
+///
+/// HWI doesn't finalize the inputs so single-key segwit inputs are finalized afterwards.
+pub struct Hwi {
+    fingerprint: Option<String>,
+    args: Vec<String>,
+}
+
+impl Hwi {
+    /// Uses the only connected device.
+    pub fn new() -> Self {
+        Hwi {
+            fingerprint: None,
+            args: Vec::new(),
+        }
+    }
+
+    /// Selects the device with the given master key fingerprint.
+    pub fn fingerprint(mut self, fingerprint: String) -> Self {
+        self.fingerprint = Some(fingerprint);
+        self
+    }
+
+    /// Passes an additional argument to `hwi`, e.g. `--chain test`.
+    pub fn arg(mut self, arg: String) -> Self {
+        self.args.push(arg);
+        self
+    }
+}
+
+impl Default for Hwi {
+    fn default() -> Self {
+        Hwi::new()
+    }
+}
+
+impl Signer for Hwi {
+    fn sign(&self, psbt: &Psbt) -> Result<Psbt, Error> {
+        let mut command = Command::new("hwi");
+        if let Some(fingerprint) = &self.fingerprint {
+            command.arg("--fingerprint").arg(fingerprint);
+        }
+        let output = command
+            .args(&self.args)
+            .arg("signtx")
+            .arg(serialize_psbt(psbt))
+            .output()
+            .map_err(|error| format!("failed to run hwi: {}", error))?;
+        let response = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+            .map_err(|error| format!("hwi returned invalid JSON: {}", error))?;
+        if let Some(error) = response.get("error") {
+            return Err(format!("hwi failed: {}", error).into());
+        }
+        let signed = response
+            .get("psbt")
+            .and_then(serde_json::Value::as_str)
+            .ok_or("hwi didn't return a PSBT")?;
+        let mut signed = load_psbt_from_base64(signed.as_bytes())?;
+        for index in 0..signed.inputs.len() {
+            let input = &mut signed.inputs[index];
+            if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+                continue;
+            }
+            let script_pubkey = match &input.witness_utxo {
+                Some(txout) => txout.script_pubkey.clone(),
+                None => continue,
+            };
+            let signature = input.partial_sigs
+                .iter()
+                .find(|(key, _)| wpkh_redeem_script(&script_pubkey, key).is_some())
+                .map(|(key, signature)| (*key, signature.clone()));
+            if let Some((key, signature)) = signature {
+                finalize_wpkh(input, &script_pubkey, key, signature);
+            }
+        }
+        Ok(signed)
+    }
+}
+
+/// Signs P2WPKH and P2SH-P2WPKH inputs with private keys.
+///
+/// Meant for testing and throw-away wallets only - keeping private keys in command line
+/// arguments or files is dangerous.
+pub struct RawKeys {
+    keys: Vec<PrivateKey>,
+}
+
+impl RawKeys {
+    pub fn new(keys: Vec<PrivateKey>) -> Self {
+        RawKeys {
+            keys,
+        }
+    }
+}
+
+impl Signer for RawKeys {
+    fn sign(&self, psbt: &Psbt) -> Result<Psbt, Error> {
+        let secp = Secp256k1::signing_only();
+        let mut signed = psbt.clone();
+        let mut cache = SigHashCache::new(&psbt.global.unsigned_tx);
+        for (index, input) in signed.inputs.iter_mut().enumerate() {
+            if input.final_script_sig.is_some() || input.final_script_witness.is_some() {
+                continue;
+            }
+            let txout = match &input.witness_utxo {
+                Some(txout) => txout,
+                None => continue,
+            };
+            let key = self.keys
+                .iter()
+                .map(|key| (key, key.public_key(&secp)))
+                .find(|(_, public_key)| wpkh_redeem_script(&txout.script_pubkey, public_key).is_some());
+            let (key, public_key) = match key {
+                Some(key) => key,
+                None => continue,
+            };
+            let sighash_type = input.sighash_type.unwrap_or(SigHashType::All);
+            let script_code = Script::new_p2pkh(&public_key.pubkey_hash());
+            let sighash = cache.signature_hash(index, &script_code, txout.value, sighash_type);
+            let message = Message::from_slice(&sighash[..]).expect("sighash is 32 bytes long");
+            let mut signature = secp.sign(&message, &key.key).serialize_der().to_vec();
+            signature.push(sighash_type.as_u32() as u8);
+            let script_pubkey = txout.script_pubkey.clone();
+            finalize_wpkh(input, &script_pubkey, public_key, signature);
+        }
+        Ok(signed)
+    }
+}
+
+/// Returns the redeem script (empty for native segwit) if `script_pubkey` is P2WPKH or
+/// P2SH-P2WPKH of `key`.
+fn wpkh_redeem_script(script_pubkey: &Script, key: &PublicKey) -> Option<Script> {
+    let program = Script::new_v0_wpkh(&key.wpubkey_hash()?);
+    if *script_pubkey == program {
+        Some(Script::new())
+    } else if *script_pubkey == Script::new_p2sh(&program.script_hash()) {
+        Some(program)
+    } else {
+        None
+    }
+}
+
+fn finalize_wpkh(input: &mut Input, script_pubkey: &Script, key: PublicKey, signature: Vec<u8>) {
+    let redeem_script = wpkh_redeem_script(script_pubkey, &key).expect("the key was matched with the script");
+    if !redeem_script.is_empty() {
+        input.final_script_sig = Some(Builder::new().push_slice(redeem_script.as_bytes()).into_script());
+    }
+    input.final_script_witness = Some(vec![signature, key.to_bytes()]);
+    input.partial_sigs.clear();
+    input.redeem_script = None;
+    input.bip32_derivation.clear();
+}
+
+/// Parses the `--signer` argument shared by the binaries.
+///
+/// Accepted formats are `core:<port>:<cookie_file>`, `hwi[:<fingerprint>[:<chain>]]` and
+/// `wif:<private key>[,<private key>...]`.
+pub fn from_arg(arg: &str) -> Result<Box<dyn Signer>, String> {
+    let mut parts = arg.splitn(2, ':');
+    match (parts.next(), parts.next()) {
+        (Some("core"), Some(params)) => {
+            let mut params = params.splitn(2, ':');
+            let port = params.next().unwrap_or_default().parse::<u16>().map_err(|error| format!("invalid port: {}", error))?;
+            let cookie_file = params.next().ok_or("missing cookie file")?;
+            let client = bitcoincore_rpc::Client::new(format!("http://127.0.0.1:{}", port), bitcoincore_rpc::Auth::CookieFile(cookie_file.into()))
+                .map_err(|error| format!("failed to connect to bitcoind: {}", error))?;
+            Ok(Box::new(client))
+        },
+        (Some("hwi"), None) => Ok(Box::new(Hwi::new())),
+        (Some("hwi"), Some(params)) => {
+            let mut params = params.splitn(2, ':');
+            let mut hwi = Hwi::new().fingerprint(params.next().unwrap_or_default().to_owned());
+            if let Some(chain) = params.next() {
+                hwi = hwi.arg("--chain".to_owned()).arg(chain.to_owned());
+            }
+            Ok(Box::new(hwi))
+        },
+        (Some("wif"), Some(keys)) => {
+            let keys = keys
+                .split(',')
+                .map(|key| PrivateKey::from_wif(key).map_err(|error| format!("invalid private key: {}", error)))
+                .collect::<Result<_, _>>()?;
+            Ok(Box::new(RawKeys::new(keys)))
+        },
+        _ => Err(format!("unknown signer {}", arg)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bip78::bitcoin::{Network, Transaction, TxOut};
+    use bip78::bitcoin::hashes::hex::FromHex;
+    use bip78::bitcoin::secp256k1::{SecretKey, Signature};
+
+    fn key(hex: &str) -> PrivateKey {
+        PrivateKey {
+            compressed: true,
+            network: Network::Bitcoin,
+            key: SecretKey::from_slice(&Vec::from_hex(hex).unwrap()).unwrap(),
+        }
+    }
+
+    fn psbt(unsigned_tx: &str) -> Psbt {
+        let tx = bip78::bitcoin::consensus::deserialize::<Transaction>(&Vec::from_hex(unsigned_tx).unwrap()).unwrap();
+        Psbt::from_unsigned_tx(tx).unwrap()
+    }
+
+    fn script(hex: &str) -> Script {
+        Script::from(Vec::from_hex(hex).unwrap())
+    }
+
+    /// Checks that the witness holds a valid signature of `sighash` by `public_key`.
+    fn check_witness(input: &Input, sighash: &str, public_key: &str) {
+        let witness = input.final_script_witness.as_ref().unwrap();
+        assert_eq!(witness.len(), 2);
+        assert_eq!(witness[1], Vec::from_hex(public_key).unwrap());
+        let (sighash_type, der) = witness[0].split_last().unwrap();
+        assert_eq!(*sighash_type, SigHashType::All.as_u32() as u8);
+        let message = Message::from_slice(&Vec::from_hex(sighash).unwrap()).unwrap();
+        let public_key = PublicKey::from_slice(&witness[1]).unwrap();
+        Secp256k1::verification_only().verify(&message, &Signature::from_der(der).unwrap(), &public_key.key).unwrap();
+        assert!(input.partial_sigs.is_empty());
+        assert_eq!(input.redeem_script, None);
+    }
+
+    // the native P2WPKH example of BIP143
+    #[test]
+    fn p2wpkh() {
+        let mut psbt = psbt("0100000002fff7f7881a8099afa6940d42d1e7f6362bec38171ea3edf433541db4e4ad969f0000000000eeffffffef51e1b804cc89d182d279655c3aa89e815b1b309fe287d9b2b55d57b90ec68a0100000000ffffffff02202cb206000000001976a9148280b37df378db99f66f85c95a783a76ac7a6d5988ac9093510d000000001976a9143bde42dbee7e4dbe6a21b2d50ce2f0167faa815988ac11000000");
+        psbt.inputs[1].witness_utxo = Some(TxOut { value: 600_000_000, script_pubkey: script("00141d0f172a0ecb48aee1be1f2687d2963ae33f71a1"), });
+        let signer = RawKeys::new(vec![key("619c335025c7f4012e556c2a58b2506e30b8511b53ade95ea316fd8c3286feb9")]);
+        let signed = signer.sign(&psbt).unwrap();
+        // the P2PK input isn't ours
+        assert_eq!(signed.inputs[0], psbt.inputs[0]);
+        assert_eq!(signed.inputs[1].final_script_sig, None);
+        check_witness(&signed.inputs[1], "c37af31116d1b27caf68aae9e3ac82f1477929014d5b917657d0eb49478cb670", "025476c2e83188368da1ff3e292e7acafcdb3566bb0ad253f62fc70f07aeee6357");
+
+        // finalized inputs and inputs of other keys are left untouched
+        assert_eq!(signer.sign(&signed).unwrap().inputs, signed.inputs);
+        let other = RawKeys::new(vec![key("eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf")]);
+        assert_eq!(other.sign(&psbt).unwrap().inputs, psbt.inputs);
+    }
+
+    // the P2SH-P2WPKH example of BIP143
+    #[test]
+    fn p2sh_p2wpkh() {
+        let mut psbt = psbt("0100000001db6b1b20aa0fd7b23880be2ecbd4a98130974cf4748fb66092ac4d3ceb1a54770100000000feffffff02b8b4eb0b000000001976a914a457b684d7f0d539a46a45bbc043f35b59d0d96388ac0008af2f000000001976a914fd270b1ee6abcaea97fea7ad0402e8bd8ad6d77c88ac92040000");
+        let redeem_script = script("001479091972186c449eb1ded22b78e40d009bdf0089");
+        psbt.inputs[0].witness_utxo = Some(TxOut { value: 1_000_000_000, script_pubkey: script("a9144733f37cf4db86fbc2efed2500b4f4e49f31202387"), });
+        psbt.inputs[0].redeem_script = Some(redeem_script.clone());
+        let signer = RawKeys::new(vec![key("eb696a065ef48a2192da5b28b694f87544b30fae8327c4510137a922f32c6dcf")]);
+        let signed = signer.sign(&psbt).unwrap();
+        // the script sig only pushes the redeem script
+        assert_eq!(signed.inputs[0].final_script_sig, Some(Builder::new().push_slice(redeem_script.as_bytes()).into_script()));
+        check_witness(&signed.inputs[0], "64f3b0f4dd2bb3aa1ce8566d220cc74dda9df97d8490cc81d89d735c92e59fb6", "03ad1d8e89212f0b92c74d23bb710c00662ad1470198ac48c43f7d6f93a2a26873");
+    }
+}
@@ -0,0 +1,344 @@
+//! Generator of test vectors
+//!
+//! Runs payjoins on regtest for every combination of sender script type, receiver script type
+//! and contribution strategy and records the original PSBT, the request parameters, the proposal
+//! and whether the sender accepted it. Other implementations can replay the vectors to check that
+//! they produce and judge proposals the same way.
+
+use std::collections::HashMap;
+use std::fmt;
+use bip78::bitcoin::{Address, Amount, OutPoint, Transaction};
+use bip78::bitcoin::util::psbt::{self, PartiallySignedTransaction as Psbt};
+use bip78::receiver::{DefaultScorer, ErrorCode, Headers, ReceiverOptions, UncheckedProposal};
+use bip78::sender::PayjoinSender;
+use bitcoincore_rpc::RpcApi;
+use bitcoincore_rpc::json::{AddressType, WalletCreateFundedPsbtOptions};
+use serde::Serialize;
+use crate::broadcast::{Broadcaster, FeeRateSanity};
+use crate::signer::{self, Signer};
+use crate::{load_psbt_from_base64, serialize_psbt};
+
+const SCRIPT_TYPES: [AddressType; 3] = [AddressType::Legacy, AddressType::P2shSegwit, AddressType::Bech32];
+const ENDPOINT: &str = "https://example.com/pj";
+// amounts in satoshis
+const PAYMENT: u64 = 10_000_000;
+const SENDER_FUNDS: u64 = 100_000_000;
+const RECEIVER_FUNDS: u64 = 50_000_000;
+const MAX_FEE_CONTRIBUTION: u64 = 10_000;
+
+/// How the receiver contributes its input.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Strategy {
+    /// `Proposal::contribute_best_input()`
+    BestInput,
+    /// `Proposal::contribute_input_with_decoy()`
+    Decoy,
+}
+
+impl Strategy {
+    pub const ALL: [Strategy; 2] = [Strategy::BestInput, Strategy::Decoy];
+
+    fn name(self) -> &'static str {
+        match self {
+            Strategy::BestInput => "best_input",
+            Strategy::Decoy => "decoy",
+        }
+    }
+}
+
+/// One generated payjoin.
+#[derive(Debug, Clone, Serialize)]
+pub struct Vector {
+    pub sender_script_type: &'static str,
+    pub receiver_script_type: &'static str,
+    pub strategy: &'static str,
+    /// Base64-encoded original PSBT as sent in the request.
+    pub original: String,
+    /// Query string of the request.
+    pub params: String,
+    /// Base64-encoded proposal, `None` if the receiver responded with an error.
+    pub proposal: Option<String>,
+    pub expected: Expected,
+}
+
+/// Result a conforming implementation should arrive at.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum Expected {
+    /// The sender accepts the proposal.
+    Accepted,
+    /// The receiver responds with the error code.
+    ReceiverError { error_code: &'static str, },
+    /// The sender rejects the proposal.
+    SenderError { message: String, },
+}
+
+#[derive(Debug)]
+pub enum Error {
+    NotRegtest(String),
+    Rpc(bitcoincore_rpc::Error),
+    Signing(Box<dyn std::error::Error + Send + Sync>),
+    Decode(bip78::bitcoin::consensus::encode::Error),
+    IncompleteFunding,
+    CreateRequest(bip78::sender::CreateRequestError),
+    Broadcast(crate::broadcast::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::NotRegtest(chain) => write!(f, "test vectors are only generated on regtest, the node runs {}", chain),
+            Error::Rpc(_) => write!(f, "RPC call failed"),
+            Error::Signing(_) => write!(f, "failed to sign"),
+            Error::Decode(_) => write!(f, "failed to decode a transaction returned by the node"),
+            Error::IncompleteFunding => write!(f, "the wallet didn't sign the funding transaction completely"),
+            Error::CreateRequest(_) => write!(f, "failed to create the request"),
+            Error::Broadcast(_) => write!(f, "failed to broadcast the funding transaction"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::NotRegtest(_) => None,
+            Error::Rpc(error) => Some(error),
+            Error::Signing(error) => Some(&**error),
+            Error::Decode(error) => Some(error),
+            Error::IncompleteFunding => None,
+            Error::CreateRequest(error) => Some(error),
+            Error::Broadcast(error) => Some(error),
+        }
+    }
+}
+
+impl From<bitcoincore_rpc::Error> for Error {
+    fn from(value: bitcoincore_rpc::Error) -> Self {
+        Error::Rpc(value)
+    }
+}
+
+impl From<signer::Error> for Error {
+    fn from(value: signer::Error) -> Self {
+        Error::Signing(value)
+    }
+}
+
+impl From<bip78::bitcoin::consensus::encode::Error> for Error {
+    fn from(value: bip78::bitcoin::consensus::encode::Error) -> Self {
+        Error::Decode(value)
+    }
+}
+
+/// Headers of a request that was never sent.
+struct OfflineHeaders(String);
+
+impl Headers for OfflineHeaders {
+    fn get_header(&self, key: &str) -> Option<&str> {
+        match key {
+            "content-type" => Some("text/plain"),
+            "content-length" => Some(&self.0),
+            _ => None,
+        }
+    }
+}
+
+fn script_type_name(script_type: AddressType) -> &'static str {
+    match script_type {
+        AddressType::Legacy => "p2pkh",
+        AddressType::P2shSegwit => "p2sh_p2wpkh",
+        AddressType::Bech32 => "p2wpkh",
+    }
+}
+
+/// Generates the vectors using the wallet of a regtest node.
+///
+/// Mines blocks and creates transactions in the wallet. The payjoins themselves are never
+/// broadcasted.
+pub fn generate(client: &bitcoincore_rpc::Client) -> Result<Vec<Vector>, Error> {
+    let chain = client.get_blockchain_info()?.chain;
+    if chain != "regtest" {
+        return Err(Error::NotRegtest(chain));
+    }
+    let miner = client.get_new_address(None, None)?;
+    if client.get_balance(Some(1), None)? < Amount::from_sat(50 * 100_000_000) {
+        client.generate_to_address(101, &miner)?;
+    }
+
+    combinations()
+        .map(|(sender_type, receiver_type, strategy)| generate_one(client, &miner, sender_type, receiver_type, strategy))
+        .collect()
+}
+
+/// Returns every combination of sender script type, receiver script type and strategy.
+fn combinations() -> impl Iterator<Item=(AddressType, AddressType, Strategy)> {
+    SCRIPT_TYPES.iter().flat_map(|&sender_type| {
+        SCRIPT_TYPES.iter().flat_map(move |&receiver_type| Strategy::ALL.iter().map(move |&strategy| (sender_type, receiver_type, strategy)))
+    })
+}
+
+fn generate_one(client: &bitcoincore_rpc::Client, miner: &Address, sender_type: AddressType, receiver_type: AddressType, strategy: Strategy) -> Result<Vector, Error> {
+    let sender_address = client.get_new_address(None, Some(sender_type))?;
+    let receiver_address = client.get_new_address(None, Some(receiver_type))?;
+    let payee = client.get_new_address(None, Some(receiver_type))?;
+    // both UTXOs are created by one transaction so that the wallet can't spend one to create the other
+    let funding = fund(client, &[(&sender_address, Amount::from_sat(SENDER_FUNDS)), (&receiver_address, Amount::from_sat(RECEIVER_FUNDS))])?;
+    client.generate_to_address(1, miner)?;
+    let sender_outpoint = find_output(&funding, &sender_address);
+    let receiver_outpoint = find_output(&funding, &receiver_address);
+
+    let mut outputs = HashMap::with_capacity(1);
+    outputs.insert(payee.to_string(), Amount::from_sat(PAYMENT));
+    let options = WalletCreateFundedPsbtOptions {
+        add_inputs: Some(false),
+        change_type: Some(sender_type),
+        // sat/kvB
+        fee_rate: Some(Amount::from_sat(2000)),
+        ..Default::default()
+    };
+    let inputs = [bitcoincore_rpc::json::CreateRawTransactionInput { txid: sender_outpoint.txid, vout: sender_outpoint.vout, sequence: None, }];
+    let unsigned = client.wallet_create_funded_psbt(&inputs, &outputs, None, Some(options), Some(true))?.psbt;
+    let original = client.sign(&load_psbt_from_base64(unsigned.as_bytes())?)?;
+
+    let uri = bip78::Uri::new(payee.clone(), Amount::from_sat(PAYMENT), ENDPOINT).expect("the endpoint is valid");
+    let (request, context) = PayjoinSender::new(uri)
+        .psbt(original)
+        .max_fee_contribution(Amount::from_sat(MAX_FEE_CONTRIBUTION))
+        .build()
+        .map_err(Error::CreateRequest)?;
+    let params = request.url.split_once('?').map_or("", |(_, query)| query).to_owned();
+
+    let mut vector = Vector {
+        sender_script_type: script_type_name(sender_type),
+        receiver_script_type: script_type_name(receiver_type),
+        strategy: strategy.name(),
+        original: String::from_utf8(request.body.clone()).expect("base64 is ASCII"),
+        params,
+        proposal: None,
+        expected: Expected::Accepted,
+    };
+    let mut receiver_input = psbt::Input::default();
+    match receiver_type {
+        AddressType::Legacy => receiver_input.non_witness_utxo = Some(funding.clone()),
+        _ => receiver_input.witness_utxo = Some(funding.output[receiver_outpoint.vout as usize].clone()),
+    }
+    let proposal = match propose(client, &request.body, &vector.params, &payee, strategy, receiver_type, (receiver_outpoint, receiver_input))? {
+        Ok(proposal) => proposal,
+        Err(error_code) => {
+            vector.expected = Expected::ReceiverError { error_code: error_code.as_str(), };
+            return Ok(vector);
+        },
+    };
+    if let Err(error) = context.process_response_bytes(proposal.as_bytes()) {
+        vector.expected = Expected::SenderError { message: error.to_string(), };
+    }
+    vector.proposal = Some(proposal);
+    Ok(vector)
+}
+
+/// Runs the receiver, the inner result is the error code sent to the sender.
+fn propose(client: &bitcoincore_rpc::Client, body: &[u8], query: &str, payee: &Address, strategy: Strategy, receiver_type: AddressType, candidate: (OutPoint, psbt::Input)) -> Result<Result<String, ErrorCode>, Error> {
+    let payee = payee.script_pubkey();
+    let proposal = match UncheckedProposal::from_request_bytes(body, query, OfflineHeaders(body.len().to_string())) {
+        Ok(proposal) => proposal,
+        Err(error) => return Ok(Err(error.error_code())),
+    };
+    let proposal = match proposal.check_pays_issued_script(&payee) {
+        Ok(proposal) => proposal,
+        Err(error) => return Ok(Err(error.error_code())),
+    };
+    let original = proposal.get_transaction_to_check_broadcast();
+    if !client.test_mempool_accept(&[&original])?.iter().all(|result| result.allowed) {
+        return Ok(Err(ErrorCode::OriginalPsbtRejected));
+    }
+    // the UTXOs are fresh so there's nothing to lock
+    let mut proposal = proposal.assume_broadcastability_was_verified().assume_locked();
+    let contributed = match strategy {
+        Strategy::BestInput => proposal.contribute_best_input(vec![candidate], &payee, &DefaultScorer::default()).map(drop),
+        Strategy::Decoy => {
+            let decoy = client.get_new_address(None, Some(receiver_type))?.script_pubkey();
+            proposal.contribute_input_with_decoy(candidate.0, candidate.1, &payee, decoy, &ReceiverOptions::default())
+        },
+    };
+    if let Err(error) = contributed {
+        return Ok(Err(error.error_code()));
+    }
+    proposal
+        .sign_contributed_inputs(&|psbt: &Psbt, index: usize| client.sign(psbt).map(|signed| signed.inputs[index].clone()))
+        .map_err(|error| Error::Signing(error.into()))?;
+    proposal.minimize_response();
+    Ok(Ok(serialize_psbt(proposal.psbt())))
+}
+
+/// Broadcasts a transaction paying `outputs` from the wallet.
+fn fund(client: &bitcoincore_rpc::Client, outputs: &[(&Address, Amount)]) -> Result<Transaction, Error> {
+    let outputs = outputs.iter().map(|(address, amount)| (address.to_string(), *amount)).collect::<HashMap<_, _>>();
+    let psbt = client.wallet_create_funded_psbt(&[], &outputs, None, None, None)?.psbt;
+    let signed = client.wallet_process_psbt(&psbt, Some(true), None, None)?.psbt;
+    let tx = client.finalize_psbt(&signed, Some(true))?.hex.ok_or(Error::IncompleteFunding)?;
+    let tx = bip78::bitcoin::consensus::deserialize(&tx)?;
+    client.broadcast(&tx, &FeeRateSanity::default()).map_err(Error::Broadcast)?;
+    Ok(tx)
+}
+
+fn find_output(tx: &Transaction, address: &Address) -> OutPoint {
+    let script_pubkey = address.script_pubkey();
+    let vout = tx.output
+        .iter()
+        .position(|output| output.script_pubkey == script_pubkey)
+        .expect("the funding transaction pays the address");
+    OutPoint { txid: tx.txid(), vout: vout as u32, }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn matrix() {
+        let names = combinations()
+            .map(|(sender_type, receiver_type, strategy)| (script_type_name(sender_type), script_type_name(receiver_type), strategy.name()))
+            .collect::<Vec<_>>();
+        assert_eq!(names.len(), 18);
+        assert_eq!(names.iter().collect::<HashSet<_>>().len(), names.len());
+        assert_eq!(names[0], ("p2pkh", "p2pkh", "best_input"));
+        assert_eq!(names[1], ("p2pkh", "p2pkh", "decoy"));
+        assert_eq!(names[17], ("p2wpkh", "p2wpkh", "decoy"));
+        for &name in &["p2pkh", "p2sh_p2wpkh", "p2wpkh"] {
+            assert_eq!(names.iter().filter(|(sender, _, _)| *sender == name).count(), 6);
+            assert_eq!(names.iter().filter(|(_, receiver, _)| *receiver == name).count(), 6);
+        }
+    }
+
+    #[test]
+    fn record() {
+        let vector = Vector {
+            sender_script_type: "p2wpkh",
+            receiver_script_type: "p2sh_p2wpkh",
+            strategy: Strategy::Decoy.name(),
+            original: "cHNidP8=".to_owned(),
+            params: "v=1".to_owned(),
+            proposal: None,
+            expected: Expected::ReceiverError { error_code: ErrorCode::NotEnoughMoney.as_str(), },
+        };
+        assert_eq!(serde_json::to_value(&vector).unwrap(), serde_json::json!({
+            "sender_script_type": "p2wpkh",
+            "receiver_script_type": "p2sh_p2wpkh",
+            "strategy": "decoy",
+            "original": "cHNidP8=",
+            "params": "v=1",
+            "proposal": null,
+            "expected": { "result": "receiver_error", "error_code": "not-enough-money", },
+        }));
+        let expected = |expected| serde_json::to_value(Vector { expected, proposal: Some("cHNidP8=".to_owned()), ..vector.clone() }).unwrap()["expected"].clone();
+        assert_eq!(expected(Expected::Accepted), serde_json::json!({ "result": "accepted", }));
+        assert_eq!(expected(Expected::SenderError { message: "invalid proposal".to_owned(), }), serde_json::json!({ "result": "sender_error", "message": "invalid proposal", }));
+    }
+
+    #[test]
+    fn offline_headers() {
+        let body = bip78::testing::ORIGINAL_PSBT.as_bytes();
+        assert!(UncheckedProposal::from_request_bytes(body, "v=1", OfflineHeaders(body.len().to_string())).is_ok());
+    }
+}
@@ -0,0 +1,142 @@
+//! `verify` subcommand
+//!
+//! Runs the validation of the sender offline on an original PSBT and a proposal taken e.g. from
+//! logs of a failed payjoin.
+
+use std::ffi::OsString;
+use bip78::bitcoin::{Address, Amount, Denomination};
+use bip78::sender::PayjoinSender;
+
+const USAGE: &str = "Usage: payjoin-client verify --original <base64 PSBT> --proposal <base64 PSBT or response body> --payee <address> --amount <BTC> [options]
+
+Options:
+    --max-fee-contribution <sat>    maxadditionalfeecontribution sent in the request
+    --change-index <index>          additionalfeeoutputindex sent in the request
+    --min-fee-rate <sat/vB>         minfeerate sent in the request
+    --disable-output-substitution   disableoutputsubstitution=1 was sent or pjos=0 was in the URI
+    --strict                        validate using Params::strict()
+    --compat                        tolerate harmless deviations and report them as warnings
+    --verbose                       print changes made by the receiver if the proposal is invalid";
+
+struct Args {
+    original: String,
+    proposal: String,
+    payee: Address,
+    amount: Amount,
+    max_fee_contribution: Option<Amount>,
+    change_index: Option<usize>,
+    min_fee_rate: Option<u64>,
+    disable_output_substitution: bool,
+    strict: bool,
+    compat: bool,
+    verbose: bool,
+}
+
+fn parse_args(args: impl Iterator<Item=OsString>) -> Result<Args, String> {
+    let mut original = None;
+    let mut proposal = None;
+    let mut payee = None;
+    let mut amount = None;
+    let mut max_fee_contribution = None;
+    let mut change_index = None;
+    let mut min_fee_rate = None;
+    let mut disable_output_substitution = false;
+    let mut strict = false;
+    let mut compat = false;
+    let mut verbose = false;
+
+    let mut args = args.map(|arg| arg.into_string().map_err(|arg| format!("argument {:?} is not UTF-8", arg)));
+    while let Some(arg) = args.next() {
+        let arg = arg?;
+        let mut value = || args.next().unwrap_or_else(|| Err(format!("missing value of {}", arg)));
+        match &*arg {
+            "--original" => original = Some(value()?),
+            "--proposal" => proposal = Some(value()?),
+            "--payee" => payee = Some(value()?.parse::<Address>().map_err(|error| format!("invalid payee: {}", error))?),
+            "--amount" => amount = Some(Amount::from_str_in(&value()?, Denomination::Bitcoin).map_err(|error| format!("invalid amount: {}", error))?),
+            "--max-fee-contribution" => max_fee_contribution = Some(Amount::from_sat(value()?.parse().map_err(|error| format!("invalid fee contribution: {}", error))?)),
+            "--change-index" => change_index = Some(value()?.parse().map_err(|error| format!("invalid change index: {}", error))?),
+            "--min-fee-rate" => min_fee_rate = Some(value()?.parse().map_err(|error| format!("invalid fee rate: {}", error))?),
+            "--disable-output-substitution" => disable_output_substitution = true,
+            "--strict" => strict = true,
+            "--compat" => compat = true,
+            "--verbose" => verbose = true,
+            "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown argument {}", arg)),
+        }
+    }
+
+    Ok(Args {
+        original: original.ok_or("missing --original")?,
+        proposal: proposal.ok_or("missing --proposal")?,
+        payee: payee.ok_or("missing --payee")?,
+        amount: amount.ok_or("missing --amount")?,
+        max_fee_contribution,
+        change_index,
+        min_fee_rate,
+        disable_output_substitution,
+        strict,
+        compat,
+        verbose,
+    })
+}
+
+/// Exits with 0 if the proposal is valid, 1 if it's invalid and 2 if the arguments are invalid.
+pub fn run(args: impl Iterator<Item=OsString>) {
+    let args = match parse_args(args) {
+        Ok(args) => args,
+        Err(error) => {
+            if !error.is_empty() {
+                eprintln!("Error: {}\n", error);
+            }
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        },
+    };
+    let original = payjoin_client::load_psbt_from_base64(args.original.trim().as_bytes()).unwrap_or_else(|error| {
+        eprintln!("Error: invalid original PSBT: {}", error);
+        std::process::exit(2);
+    });
+    // the endpoint is never contacted
+    let uri = bip78::Uri::new(args.payee, args.amount, "https://localhost/")
+        .expect("valid endpoint")
+        .disable_output_substitution(args.disable_output_substitution);
+    let mut sender = PayjoinSender::new(uri).psbt(original);
+    if let Some(max_fee_contribution) = args.max_fee_contribution {
+        sender = sender.max_fee_contribution(max_fee_contribution);
+    }
+    if let Some(change_index) = args.change_index {
+        sender = sender.change_index(change_index);
+    }
+    if let Some(min_fee_rate) = args.min_fee_rate {
+        sender = sender.min_fee_rate(min_fee_rate);
+    }
+    if args.strict {
+        sender = sender.strict();
+    }
+    if args.compat {
+        sender = sender.compat();
+    }
+    let (_, context) = sender.build().unwrap_or_else(|error| {
+        eprintln!("Error: the original PSBT can't be used: {}", error);
+        std::process::exit(2);
+    });
+
+    let audit = context.audit_response(args.proposal.trim().as_bytes());
+    match audit.result {
+        Ok(report) => {
+            println!("valid");
+            for warning in &report.warnings {
+                println!("warning: {}", warning);
+            }
+            println!("txid: {}", report.psbt.global.unsigned_tx.txid());
+        },
+        Err(error) => {
+            println!("{}", payjoin_client::describe_validation_error(&error));
+            if let (true, Some(diff)) = (args.verbose, &audit.diff) {
+                println!("changes made by the receiver:\n{}", diff);
+            }
+            std::process::exit(1);
+        },
+    }
+}